@@ -0,0 +1,448 @@
+// Saving built BVHs to self-describing blobs, so they can be shipped with
+// assets instead of being rebuilt on every load.
+//
+// Blob layout, little endian:
+//   [0..4)   magic "WBVH"
+//   [4..8)   BVH_BLOB_VERSION
+//   [8..12)  kind, 0 for a BLAS, 1 for a TLAS
+//   [12..16) NodeFormat
+//   [16..32) num_nodes, num_primitive_refs, num_inactive, num_degenerate
+//   [32..56) root aabb min.xyz, max.xyz
+//   [56..64) geometry hash
+//   [64..76) byte lengths of the sections that follow: nodes, primitive refs
+//            (BLAS), instance leaf nodes (TLAS)
+
+use crate::error::{BvhBuildError, BvhLoadError, DescriptorLocation};
+use crate::layout::{self, read_f32, read_u32};
+use crate::GeometryType;
+use crate::{blas_geometries, staging_buffer, utils};
+use crate::{with_staging_buffers, BuiltBvh, NodeFormat, Primitive, StagingBuffer};
+use bvh::aabb::AABB;
+use bvh::Point3;
+use std::hash::Hasher;
+use wasm_bindgen::prelude::*;
+
+const MAGIC: &[u8; 4] = b"WBVH";
+/// Bump whenever a GPU node or primitive ref struct, or the blob layout,
+/// changes. Blobs of other versions fail to load and must be rebuilt.
+pub const BVH_BLOB_VERSION: u32 = 4;
+const HEADER_SIZE: usize = 76;
+
+const KIND_BLAS: u32 = 0;
+const KIND_TLAS: u32 = 1;
+
+/// Saves a BLAS built by `build_blas` or `compact_blas` from the descriptor
+/// in `blas_descriptor_buffer_id`, along with a hash of its geometries. The
+/// returned staging buffer holds the blob. Throws for TLASes.
+#[wasm_bindgen]
+pub fn save_blas(
+    built: &BuiltBvh,
+    blas_descriptor_buffer_id: u32,
+) -> Result<StagingBuffer, JsValue> {
+    utils::set_panic_hook();
+    let blob = with_staging_buffers(|map| -> Result<_, BvhBuildError> {
+        let hash = blas_geometry_hash(&blas_geometries(map, blas_descriptor_buffer_id)?);
+        let refs = built
+            .primitive_refs
+            .as_ref()
+            .ok_or(BvhBuildError::NotABlas {
+                at: DescriptorLocation::Descriptor,
+            })?;
+        let saved = SavedBvh {
+            primitive_refs: &map[refs.id],
+            ..SavedBvh::of(built, &map[built.serialized.id])
+        };
+        Ok(write_blob(KIND_BLAS, &saved, hash))
+    })?;
+    Ok(StagingBuffer::from_existing_buffer(blob)?)
+}
+
+/// Saves a TLAS built by `build_tlas` from the descriptor in
+/// `tlas_descriptor_buffer_id`, see `save_blas`. Throws for BLASes.
+#[wasm_bindgen]
+pub fn save_tlas(
+    built: &BuiltBvh,
+    tlas_descriptor_buffer_id: u32,
+) -> Result<StagingBuffer, JsValue> {
+    utils::set_panic_hook();
+    let blob = with_staging_buffers(|map| -> Result<_, BvhBuildError> {
+        let descriptor = staging_buffer(
+            map,
+            tlas_descriptor_buffer_id,
+            DescriptorLocation::Descriptor,
+        )?;
+        let hash = tlas_instance_hash(descriptor);
+        let leaf_nodes = built
+            .instance_leaf_nodes
+            .as_ref()
+            .ok_or(BvhBuildError::NotATlas {
+                at: DescriptorLocation::Descriptor,
+            })?;
+        let saved = SavedBvh {
+            instance_leaf_nodes: &map[leaf_nodes.id],
+            ..SavedBvh::of(built, &map[built.serialized.id])
+        };
+        Ok(write_blob(KIND_TLAS, &saved, hash))
+    })?;
+    Ok(StagingBuffer::from_existing_buffer(blob)?)
+}
+
+/// Loads a BLAS saved by `save_blas` from the staging buffer
+/// `blob_buffer_id`. Fails with a `BvhLoadError` message if the blob is of
+/// another version or if the geometries of the descriptor in
+/// `blas_descriptor_buffer_id` changed since saving. The result owns new
+/// staging buffers, the blob can be freed afterwards.
+#[wasm_bindgen]
+pub fn load_blas(blob_buffer_id: u32, blas_descriptor_buffer_id: u32) -> Result<BuiltBvh, JsValue> {
+    utils::set_panic_hook();
+    let hash = with_staging_buffers(|map| {
+        blas_geometries(map, blas_descriptor_buffer_id).map(|g| blas_geometry_hash(&g))
+    })?;
+    Ok(load_blob(blob_buffer_id, KIND_BLAS, hash)?)
+}
+
+/// Loads a TLAS saved by `save_tlas`, see `load_blas`. The instances of the
+/// descriptor in `tlas_descriptor_buffer_id` must be the saved ones.
+#[wasm_bindgen]
+pub fn load_tlas(blob_buffer_id: u32, tlas_descriptor_buffer_id: u32) -> Result<BuiltBvh, JsValue> {
+    utils::set_panic_hook();
+    let hash = with_staging_buffers(|map| {
+        let at = DescriptorLocation::Descriptor;
+        staging_buffer(map, tlas_descriptor_buffer_id, at).map(|d| tlas_instance_hash(d))
+    })?;
+    Ok(load_blob(blob_buffer_id, KIND_TLAS, hash)?)
+}
+
+fn load_blob(blob_buffer_id: u32, kind: u32, geometry_hash: u64) -> Result<BuiltBvh, BvhLoadError> {
+    // copied out of the registry, new staging buffers cannot be allocated
+    // while it is borrowed
+    let blob = with_staging_buffers(|map| {
+        staging_buffer(map, blob_buffer_id, DescriptorLocation::Descriptor).map(Vec::clone)
+    })?;
+    let saved = read_blob(&blob, kind, geometry_hash)?;
+    let buffer = |section: &[u8]| StagingBuffer::from_existing_buffer(section.to_vec());
+    Ok(BuiltBvh {
+        serialized: buffer(saved.nodes)?,
+        num_nodes: saved.num_nodes,
+        format: saved.format,
+        aabb: saved.aabb,
+        primitive_refs: (kind == KIND_BLAS)
+            .then(|| buffer(saved.primitive_refs))
+            .transpose()?,
+        num_primitive_refs: saved.num_primitive_refs,
+        instance_leaf_nodes: (kind == KIND_TLAS)
+            .then(|| buffer(saved.instance_leaf_nodes))
+            .transpose()?,
+        num_inactive: saved.num_inactive,
+        num_degenerate: saved.num_degenerate,
+        // not saved, updates of a loaded TLAS fall back to root box bounds
+        blas_bounds: Vec::new(),
+    })
+}
+
+// The header and sections of a blob, validated against the current layout.
+#[derive(Debug)]
+struct SavedBvh<'a> {
+    format: NodeFormat,
+    num_nodes: u32,
+    num_primitive_refs: u32,
+    num_inactive: u32,
+    num_degenerate: u32,
+    aabb: AABB,
+    nodes: &'a [u8],
+    primitive_refs: &'a [u8],
+    instance_leaf_nodes: &'a [u8],
+}
+
+impl<'a> SavedBvh<'a> {
+    // the header fields and nodes of `built`, without the other sections
+    fn of(built: &BuiltBvh, nodes: &'a [u8]) -> Self {
+        SavedBvh {
+            format: built.format,
+            num_nodes: built.num_nodes,
+            num_primitive_refs: built.num_primitive_refs,
+            num_inactive: built.num_inactive,
+            num_degenerate: built.num_degenerate,
+            aabb: built.aabb,
+            nodes,
+            primitive_refs: &[],
+            instance_leaf_nodes: &[],
+        }
+    }
+}
+
+fn write_blob(kind: u32, saved: &SavedBvh, geometry_hash: u64) -> Vec<u8> {
+    let sections = [saved.nodes, saved.primitive_refs, saved.instance_leaf_nodes];
+    let mut blob =
+        Vec::with_capacity(HEADER_SIZE + sections.iter().map(|s| s.len()).sum::<usize>());
+    blob.extend_from_slice(MAGIC);
+    let words = [
+        BVH_BLOB_VERSION,
+        kind,
+        saved.format as u32,
+        saved.num_nodes,
+        saved.num_primitive_refs,
+        saved.num_inactive,
+        saved.num_degenerate,
+    ];
+    let (min, max) = (saved.aabb.min, saved.aabb.max);
+    let bounds = [min.x, min.y, min.z, max.x, max.y, max.z];
+    blob.extend(words.iter().flat_map(|w| w.to_le_bytes()));
+    blob.extend(bounds.iter().flat_map(|f| f.to_le_bytes()));
+    blob.extend_from_slice(&geometry_hash.to_le_bytes());
+    for section in &sections {
+        blob.extend_from_slice(&(section.len() as u32).to_le_bytes());
+    }
+    debug_assert_eq!(blob.len(), HEADER_SIZE);
+    for section in &sections {
+        blob.extend_from_slice(section);
+    }
+    blob
+}
+
+fn read_blob(blob: &[u8], kind: u32, geometry_hash: u64) -> Result<SavedBvh<'_>, BvhLoadError> {
+    if blob.len() < 8 || &blob[0..4] != MAGIC {
+        return Err(BvhLoadError::NotABlob);
+    }
+    let version = read_u32(blob, 4);
+    if version != BVH_BLOB_VERSION {
+        return Err(BvhLoadError::VersionMismatch {
+            expected: BVH_BLOB_VERSION,
+            actual: version,
+        });
+    }
+    if blob.len() < HEADER_SIZE {
+        return Err(BvhLoadError::LengthMismatch {
+            expected: HEADER_SIZE,
+            actual: blob.len(),
+        });
+    }
+    if read_u32(blob, 8) != kind {
+        return Err(BvhLoadError::KindMismatch);
+    }
+    let saved_hash = u64::from_le_bytes(blob[56..64].try_into().unwrap());
+    if saved_hash != geometry_hash {
+        return Err(BvhLoadError::GeometryChanged {
+            expected: saved_hash,
+            actual: geometry_hash,
+        });
+    }
+    let format = match read_u32(blob, 12) {
+        0 => NodeFormat::Full,
+        2 => NodeFormat::Quantized16,
+        3 => NodeFormat::Wide4,
+        4 => NodeFormat::Wide8,
+        format => return Err(BvhLoadError::UnknownFormat { format }),
+    };
+    let num_nodes = read_u32(blob, 16);
+    let num_primitive_refs = read_u32(blob, 20);
+
+    let section_lengths = [64, 68, 72].map(|offset| read_u32(blob, offset) as usize);
+    let expected = HEADER_SIZE + section_lengths.iter().sum::<usize>();
+    if blob.len() != expected {
+        return Err(BvhLoadError::LengthMismatch {
+            expected,
+            actual: blob.len(),
+        });
+    }
+    let mut sections = [&blob[..0]; 3];
+    let mut offset = HEADER_SIZE;
+    for (section, len) in sections.iter_mut().zip(section_lengths) {
+        *section = &blob[offset..offset + len];
+        offset += len;
+    }
+    let [nodes, primitive_refs, instance_leaf_nodes] = sections;
+
+    // catches layout changes without a version bump, and corrupt headers
+    let node_stride = if kind == KIND_BLAS {
+        layout::blas_node_format_stride(format)
+    } else {
+        layout::tlas_node_stride()
+    };
+    let expected_nodes = num_nodes as usize * node_stride;
+    if nodes.len() != expected_nodes {
+        return Err(BvhLoadError::LengthMismatch {
+            expected: expected_nodes,
+            actual: nodes.len(),
+        });
+    }
+    let expected_refs = num_primitive_refs as usize * layout::primitive_ref_stride();
+    if primitive_refs.len() < expected_refs {
+        return Err(BvhLoadError::LengthMismatch {
+            expected: expected_refs,
+            actual: primitive_refs.len(),
+        });
+    }
+
+    let bound = |offset| {
+        Point3::new(
+            read_f32(blob, offset),
+            read_f32(blob, offset + 4),
+            read_f32(blob, offset + 8),
+        )
+    };
+    Ok(SavedBvh {
+        format,
+        num_nodes,
+        num_primitive_refs,
+        num_inactive: read_u32(blob, 24),
+        num_degenerate: read_u32(blob, 28),
+        aabb: AABB::with_bounds(bound(32), bound(44)),
+        nodes,
+        primitive_refs,
+        instance_leaf_nodes,
+    })
+}
+
+// 64 bit FNV-1a, stable across builds and platforms unlike DefaultHasher.
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+// Hashes what the tree is built from: the type, count, flags, formats and
+// transform of each geometry and the contents of its vertex, end vertex and
+// index buffers. Buffer ids are left out, they differ between page loads.
+pub(crate) fn blas_geometry_hash(geometries: &[(Primitive, u32)]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    let write_u32 = |hasher: &mut Fnv1a, x: u32| hasher.write(&x.to_le_bytes());
+    write_u32(&mut hasher, geometries.len() as u32);
+    for (g, num_primitives) in geometries {
+        write_u32(&mut hasher, g.geometry_type as u32);
+        write_u32(&mut hasher, *num_primitives);
+        write_u32(&mut hasher, g.flags.bits());
+        if g.geometry_type == GeometryType::Triangle {
+            write_u32(&mut hasher, g.vertex_format as u32);
+            write_u32(&mut hasher, g.vertex_stride as u32);
+            write_u32(&mut hasher, g.index_format as u32);
+            write_u32(&mut hasher, g.ibuf.is_some() as u32);
+            if let Some(ibuf) = g.ibuf {
+                hasher.write(ibuf);
+            }
+            write_u32(&mut hasher, g.transform.is_some() as u32);
+            for t in g.transform.unwrap_or(&[]) {
+                hasher.write(&t.to_le_bytes());
+            }
+            write_u32(&mut hasher, g.end_vbuf.is_some() as u32);
+            if let Some(end_vbuf) = g.end_vbuf {
+                hasher.write(end_vbuf);
+            }
+        }
+        hasher.write(g.vbuf);
+    }
+    hasher.finish()
+}
+
+// TLAS descriptors reference BLASes by offsets, not buffer ids, the whole
+// descriptor is hashed.
+fn tlas_instance_hash(descriptor: &[u8]) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write(descriptor);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{blas_geometry_hash, read_blob, write_blob, SavedBvh, KIND_BLAS, KIND_TLAS};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::error::BvhLoadError;
+    use crate::layout::primitive_ref_stride;
+    use crate::traverse::tests::{geometry, quad_vertices};
+    use crate::{GeometryType, NodeFormat};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_blob_round_trip() {
+        let boxes = vec![
+            AABB::with_bounds(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0)),
+            AABB::with_bounds(Point3::new(2.0, 0.0, 0.0), Point3::new(3.0, 1.0, 1.0)),
+        ];
+        let bvh = Bvh::build(&boxes, &BuildOptions::default());
+        let nodes = crate::serialize_blas_nodes(&bvh);
+        let refs = vec![7u8; 2 * primitive_ref_stride()];
+        let saved = SavedBvh {
+            format: NodeFormat::Full,
+            num_nodes: bvh.nodes.len() as u32,
+            num_primitive_refs: 2,
+            num_inactive: 1,
+            num_degenerate: 0,
+            aabb: bvh.nodes[0].aabb,
+            nodes: &nodes,
+            primitive_refs: &refs,
+            instance_leaf_nodes: &[],
+        };
+        let blob = write_blob(KIND_BLAS, &saved, 42);
+        let loaded = read_blob(&blob, KIND_BLAS, 42).unwrap();
+        assert_eq!(
+            (loaded.nodes, loaded.primitive_refs),
+            (&nodes[..], &refs[..])
+        );
+        assert!(loaded.instance_leaf_nodes.is_empty());
+        assert_eq!(loaded.num_nodes, saved.num_nodes);
+        assert_eq!((loaded.num_inactive, loaded.format), (1, NodeFormat::Full));
+        assert_eq!(loaded.aabb.max, Point3::new(3.0, 1.0, 1.0));
+
+        assert_eq!(
+            read_blob(&blob, KIND_BLAS, 43).unwrap_err(),
+            BvhLoadError::GeometryChanged {
+                expected: 42,
+                actual: 43
+            }
+        );
+        assert_eq!(
+            read_blob(&blob, KIND_TLAS, 42).unwrap_err(),
+            BvhLoadError::KindMismatch
+        );
+        assert!(matches!(
+            read_blob(&blob[..blob.len() - 1], KIND_BLAS, 42),
+            Err(BvhLoadError::LengthMismatch { .. })
+        ));
+        let mut old = blob.clone();
+        old[4] = 0;
+        assert!(matches!(
+            read_blob(&old, KIND_BLAS, 42),
+            Err(BvhLoadError::VersionMismatch { actual: 0, .. })
+        ));
+        assert_eq!(
+            read_blob(&nodes, KIND_BLAS, 42).unwrap_err(),
+            BvhLoadError::NotABlob
+        );
+        // a node count that does not match the node layout
+        let mut wrong_count = blob.clone();
+        wrong_count[16] += 1;
+        assert!(matches!(
+            read_blob(&wrong_count, KIND_BLAS, 42),
+            Err(BvhLoadError::LengthMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_geometry_hash_changes_with_vertices() {
+        let hash = |vbuf: &[u8], num_primitives| {
+            blas_geometry_hash(&[(geometry(GeometryType::Triangle, vbuf), num_primitives)])
+        };
+        let quad = quad_vertices();
+        let mut moved = quad.clone();
+        moved[0..4].copy_from_slice(&0.5f32.to_le_bytes());
+        assert_eq!(hash(&quad, 2), hash(&quad_vertices(), 2));
+        assert_ne!(hash(&quad, 2), hash(&moved, 2));
+        assert_ne!(hash(&quad, 2), hash(&quad, 1));
+    }
+}
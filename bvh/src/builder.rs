@@ -0,0 +1,801 @@
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::instance_bounds::InstanceBounds;
+use crate::parallel;
+use bvh::aabb::AABB;
+use wasm_bindgen::prelude::*;
+
+// see GPURayTracingAccelerationContainerUsage in types.ts
+pub const CONTAINER_USAGE_PREFER_FAST_TRACE: u32 = 4;
+pub const CONTAINER_USAGE_PREFER_FAST_BUILD: u32 = 8;
+
+/// Parameters of the binned SAH builder.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BuildOptions {
+    /// Number of centroid bins per axis evaluated for each split.
+    pub num_bins: u32,
+    /// Nodes with more primitives than this are always split.
+    pub max_leaf_size: u32,
+    /// Cost of visiting an interior node, relative to `intersection_cost`.
+    pub traversal_cost: f32,
+    /// Cost of intersecting a single primitive.
+    pub intersection_cost: f32,
+    /// 2 for a binary BLAS, 4 or 8 to collapse it into a wide BVH. TLASes are
+    /// always binary.
+    pub branching_factor: u32,
+    /// Maximum number of duplicate primitive references created by spatial
+    /// splits, as a fraction of the primitive count. 0 disables spatial
+    /// splits.
+    pub spatial_split_budget: f32,
+    /// How the instance bounds of a TLAS are computed, ignored for BLASes.
+    pub instance_bounds: InstanceBounds,
+}
+
+#[wasm_bindgen]
+impl BuildOptions {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// More bins and smaller leaves, for containers that are traced a lot.
+    pub fn prefer_fast_trace() -> Self {
+        BuildOptions {
+            num_bins: 32,
+            max_leaf_size: 2,
+            traversal_cost: 1.0,
+            intersection_cost: 1.5,
+            branching_factor: 2,
+            spatial_split_budget: 0.3,
+            instance_bounds: InstanceBounds::NodeBoxes,
+        }
+    }
+
+    /// Few bins and larger leaves, for containers that are rebuilt often.
+    pub fn prefer_fast_build() -> Self {
+        BuildOptions {
+            num_bins: 8,
+            max_leaf_size: 8,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            branching_factor: 2,
+            spatial_split_budget: 0.0,
+            instance_bounds: InstanceBounds::RootBox,
+        }
+    }
+
+    /// Picks the preset matching a `GPURayTracingAccelerationContainerUsage`.
+    pub fn from_usage(usage: u32) -> Self {
+        if usage & CONTAINER_USAGE_PREFER_FAST_TRACE != 0 {
+            Self::prefer_fast_trace()
+        } else if usage & CONTAINER_USAGE_PREFER_FAST_BUILD != 0 {
+            Self::prefer_fast_build()
+        } else {
+            Self::default()
+        }
+    }
+}
+
+impl BuildOptions {
+    // Fails for options no build supports, before any primitive is read.
+    pub(crate) fn validate(&self) -> Result<(), BvhBuildError> {
+        match self.branching_factor {
+            2 | 4 | 8 => Ok(()),
+            branching_factor => Err(BvhBuildError::UnsupportedBranchingFactor {
+                at: DescriptorLocation::Descriptor,
+                branching_factor,
+            }),
+        }
+    }
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            num_bins: 16,
+            max_leaf_size: 4,
+            traversal_cost: 1.0,
+            intersection_cost: 1.0,
+            branching_factor: 2,
+            spatial_split_budget: 0.0,
+            instance_bounds: InstanceBounds::RootBox,
+        }
+    }
+}
+
+/// A node of a BVH stored in depth-first pre-order: the first child of an
+/// interior node at `i` is at `i + 1`, the second child is at the
+/// `exit_index` of the first child.
+#[derive(Debug, Clone, Copy)]
+pub struct FlatNode {
+    pub aabb: AABB,
+    // index of the node following this subtree, `nodes.len()` for the last
+    // subtree
+    pub exit_index: u32,
+    // leaf only, range in `Bvh::prim_indices`
+    pub first_prim: u32,
+    // 0 for interior nodes
+    pub prim_count: u32,
+}
+
+impl FlatNode {
+    pub fn is_leaf(&self) -> bool {
+        self.prim_count > 0
+    }
+}
+
+#[derive(Debug)]
+pub struct Bvh {
+    pub nodes: Vec<FlatNode>,
+    // primitive indices reordered so that each leaf references a contiguous
+    // range
+    pub prim_indices: Vec<u32>,
+}
+
+impl Bvh {
+    pub fn build(prim_aabbs: &[AABB], options: &BuildOptions) -> Bvh {
+        Self::build_with_splitter(prim_aabbs, options, None, parallel::MIN_PARALLEL_REFS)
+    }
+
+    /// Like `build`, with spatial splits enabled by
+    /// `options.spatial_split_budget`. `split_primitive(prim, axis, pos)`
+    /// returns the bounds of the parts of a primitive below and above the
+    /// plane at `pos` along `axis`, either may be empty.
+    pub fn build_spatial<F: Fn(u32, usize, f32) -> (AABB, AABB)>(
+        prim_aabbs: &[AABB],
+        options: &BuildOptions,
+        split_primitive: F,
+    ) -> Bvh {
+        Self::build_with_splitter(
+            prim_aabbs,
+            options,
+            Some(&split_primitive),
+            parallel::MIN_PARALLEL_REFS,
+        )
+    }
+
+    fn build_with_splitter(
+        prim_aabbs: &[AABB],
+        options: &BuildOptions,
+        split_primitive: Option<&dyn Fn(u32, usize, f32) -> (AABB, AABB)>,
+        min_parallel_refs: usize,
+    ) -> Bvh {
+        let refs: Vec<Ref> = prim_aabbs
+            .iter()
+            .enumerate()
+            .map(|(i, aabb)| Ref {
+                prim: i as u32,
+                aabb: *aabb,
+            })
+            .collect();
+        let mut root_aabb = AABB::empty();
+        for aabb in prim_aabbs {
+            root_aabb.join_mut(aabb);
+        }
+        let duplicate_budget = match split_primitive {
+            Some(_) => (options.spatial_split_budget.max(0.0) * prim_aabbs.len() as f32) as usize,
+            None => 0,
+        };
+        let mut builder = SahBuilder {
+            options: BuildOptions {
+                num_bins: options.num_bins.max(2),
+                max_leaf_size: options.max_leaf_size.max(1),
+                ..*options
+            },
+            split_primitive,
+            duplicate_budget,
+            root_area: half_area(&root_aabb),
+            min_parallel_refs,
+            prim_indices: Vec::with_capacity(prim_aabbs.len()),
+            nodes: Vec::with_capacity(2 * prim_aabbs.len()),
+        };
+        if !refs.is_empty() {
+            builder.build_recursive(refs);
+        }
+        Bvh {
+            nodes: builder.nodes,
+            prim_indices: builder.prim_indices,
+        }
+    }
+
+    pub fn leaf_prims(&self, node: &FlatNode) -> &[u32] {
+        let first = node.first_prim as usize;
+        &self.prim_indices[first..first + node.prim_count as usize]
+    }
+}
+
+// half of the surface area is enough for comparing SAH costs
+pub(crate) fn half_area(aabb: &AABB) -> f32 {
+    if aabb.is_empty() {
+        return 0.0;
+    }
+    let d = aabb.size();
+    d.x * d.y + d.y * d.z + d.z * d.x
+}
+
+fn intersection(a: &AABB, b: &AABB) -> AABB {
+    AABB::with_bounds(a.min.max(b.min), a.max.min(b.max))
+}
+
+// Spatial splits are only tried when the children of the best object split
+// overlap by more than this fraction of the root area, see "Spatial Splits in
+// Bounding Volume Hierarchies", Stich et al. 2009.
+const SPATIAL_SPLIT_ALPHA: f32 = 1e-5;
+
+// A primitive, or with spatial splits the part of it inside `aabb`.
+#[derive(Clone, Copy)]
+struct Ref {
+    prim: u32,
+    aabb: AABB,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    aabb: AABB,
+    count: u32,
+}
+
+#[derive(Clone, Copy)]
+struct SpatialBin {
+    aabb: AABB,
+    // number of references starting and ending in this bin
+    entries: u32,
+    exits: u32,
+}
+
+#[derive(Clone, Copy)]
+struct ObjectSplit {
+    axis: usize,
+    // first bin of the right side
+    bin: usize,
+    cost: f32,
+    // half area of the intersection of both children
+    overlap: f32,
+}
+
+#[derive(Clone, Copy)]
+struct SpatialSplit {
+    axis: usize,
+    pos: f32,
+    cost: f32,
+}
+
+struct SahBuilder<'a> {
+    options: BuildOptions,
+    split_primitive: Option<&'a dyn Fn(u32, usize, f32) -> (AABB, AABB)>,
+    // duplicate references spatial splits may still create
+    duplicate_budget: usize,
+    root_area: f32,
+    // nodes with fewer references are built on the current thread
+    min_parallel_refs: usize,
+    prim_indices: Vec<u32>,
+    nodes: Vec<FlatNode>,
+}
+
+impl<'a> SahBuilder<'a> {
+    fn build_recursive(&mut self, refs: Vec<Ref>) {
+        let mut aabb = AABB::empty();
+        let mut centroid_bounds = AABB::empty();
+        for r in &refs {
+            aabb.join_mut(&r.aabb);
+            centroid_bounds.grow_mut(&r.aabb.center());
+        }
+
+        let node_index = self.nodes.len();
+        self.nodes.push(FlatNode {
+            aabb,
+            exit_index: 0,
+            first_prim: 0,
+            prim_count: refs.len() as u32,
+        });
+
+        let count = refs.len();
+        match self.find_split(refs, &aabb, &centroid_bounds) {
+            // subtrees with spatial splits left share the duplicate budget,
+            // they are built in order
+            Ok((left, right)) if count >= self.min_parallel_refs && self.duplicate_budget == 0 => {
+                self.nodes[node_index].prim_count = 0;
+                self.build_subtrees_parallel(left, right);
+            }
+            Ok((left, right)) => {
+                self.nodes[node_index].prim_count = 0;
+                self.build_recursive(left);
+                self.build_recursive(right);
+            }
+            Err(refs) => {
+                self.nodes[node_index].first_prim = self.prim_indices.len() as u32;
+                self.prim_indices.extend(refs.iter().map(|r| r.prim));
+            }
+        }
+        self.nodes[node_index].exit_index = self.nodes.len() as u32;
+    }
+
+    // Builds both children at the same time and appends them, offset to
+    // where build_recursive would have put them.
+    fn build_subtrees_parallel(&mut self, left: Vec<Ref>, right: Vec<Ref>) {
+        let options = self.options;
+        let root_area = self.root_area;
+        let min_parallel_refs = self.min_parallel_refs;
+        let build = move |refs: Vec<Ref>| {
+            let mut builder = SahBuilder {
+                options,
+                split_primitive: None,
+                duplicate_budget: 0,
+                root_area,
+                min_parallel_refs,
+                prim_indices: Vec::new(),
+                nodes: Vec::new(),
+            };
+            builder.build_recursive(refs);
+            (builder.nodes, builder.prim_indices)
+        };
+        let (left, right) = parallel::join(|| build(left), || build(right));
+        for (nodes, prim_indices) in [left, right] {
+            let node_offset = self.nodes.len() as u32;
+            let prim_offset = self.prim_indices.len() as u32;
+            self.nodes.extend(nodes.into_iter().map(|node| FlatNode {
+                exit_index: node.exit_index + node_offset,
+                first_prim: if node.is_leaf() {
+                    node.first_prim + prim_offset
+                } else {
+                    0
+                },
+                ..node
+            }));
+            self.prim_indices.extend(prim_indices);
+        }
+    }
+
+    // Returns the references of both children, or gives `refs` back if they
+    // should become a leaf.
+    fn find_split(
+        &mut self,
+        refs: Vec<Ref>,
+        aabb: &AABB,
+        centroid_bounds: &AABB,
+    ) -> Result<(Vec<Ref>, Vec<Ref>), Vec<Ref>> {
+        let count = refs.len();
+        if count <= 1 {
+            return Err(refs);
+        }
+        let object = self.find_object_split(&refs, centroid_bounds);
+        let spatial = match object {
+            Some(o) if o.overlap <= SPATIAL_SPLIT_ALPHA * self.root_area => None,
+            _ => self.find_spatial_split(&refs, aabb),
+        };
+        let use_spatial = match (object, spatial) {
+            (Some(o), Some(s)) => s.cost < o.cost,
+            (None, Some(_)) => true,
+            _ => false,
+        };
+
+        // compare un-normalized costs, parent area may be zero
+        let parent_area = half_area(aabb);
+        let leaf_cost = self.options.intersection_cost * count as f32 * parent_area;
+        let must_split = count > self.options.max_leaf_size as usize;
+        let best_cost = if use_spatial {
+            spatial.map(|s| s.cost)
+        } else {
+            object.map(|o| o.cost)
+        };
+        if let Some(cost) = best_cost {
+            let split_cost =
+                self.options.traversal_cost * parent_area + self.options.intersection_cost * cost;
+            if !must_split && leaf_cost <= split_cost {
+                return Err(refs);
+            }
+        }
+
+        let refs = if use_spatial {
+            match self.spatial_partition(refs, &spatial.unwrap()) {
+                Ok(children) => return Ok(children),
+                Err(refs) => refs,
+            }
+        } else {
+            refs
+        };
+        self.object_partition(refs, object, centroid_bounds, must_split)
+    }
+
+    fn object_partition(
+        &self,
+        mut refs: Vec<Ref>,
+        object: Option<ObjectSplit>,
+        centroid_bounds: &AABB,
+        must_split: bool,
+    ) -> Result<(Vec<Ref>, Vec<Ref>), Vec<Ref>> {
+        let mid = match object {
+            Some(o) => {
+                let num_bins = self.options.num_bins as usize;
+                let min = centroid_bounds.min[o.axis];
+                let extent = centroid_bounds.size()[o.axis];
+                partition(&mut refs, |r| {
+                    bin_index(r.aabb.center()[o.axis], min, extent, num_bins) < o.bin
+                })
+            }
+            // all centroids coincide, any split is as good as another
+            None if must_split => refs.len() / 2,
+            None => return Err(refs),
+        };
+        let right = refs.split_off(mid);
+        Ok((refs, right))
+    }
+
+    fn find_object_split(&self, refs: &[Ref], centroid_bounds: &AABB) -> Option<ObjectSplit> {
+        let num_bins = self.options.num_bins as usize;
+        let splits = self.per_axis(refs.len(), |axis| {
+            object_split(refs, centroid_bounds, axis, num_bins)
+        });
+        // the first of equal costs, like a search over the axes in order
+        let mut best: Option<ObjectSplit> = None;
+        for split in splits.into_iter().flatten() {
+            if best.map_or(true, |b| split.cost < b.cost) {
+                best = Some(split);
+            }
+        }
+        best
+    }
+
+    fn find_spatial_split(&self, refs: &[Ref], aabb: &AABB) -> Option<SpatialSplit> {
+        if self.split_primitive.is_none() || self.duplicate_budget == 0 {
+            return None;
+        }
+        let num_bins = self.options.num_bins as usize;
+        let duplicate_budget = self.duplicate_budget;
+        let splits = self.per_axis(refs.len(), |axis| {
+            spatial_split(refs, aabb, axis, num_bins, duplicate_budget)
+        });
+        let mut best: Option<SpatialSplit> = None;
+        for split in splits.into_iter().flatten() {
+            if best.map_or(true, |b| split.cost < b.cost) {
+                best = Some(split);
+            }
+        }
+        best
+    }
+
+    // `f` of each axis, evaluated at the same time for large nodes.
+    fn per_axis<T: Send, F: Fn(usize) -> T + Sync>(&self, count: usize, f: F) -> [T; 3] {
+        if count >= self.min_parallel_refs {
+            let (x, (y, z)) = parallel::join(|| f(0), || parallel::join(|| f(1), || f(2)));
+            [x, y, z]
+        } else {
+            [f(0), f(1), f(2)]
+        }
+    }
+
+    // Splits the references straddling the plane, gives `refs` back if one
+    // side ends up empty.
+    fn spatial_partition(
+        &mut self,
+        refs: Vec<Ref>,
+        split: &SpatialSplit,
+    ) -> Result<(Vec<Ref>, Vec<Ref>), Vec<Ref>> {
+        let split_primitive = self.split_primitive.unwrap();
+        let axis = split.axis;
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+        for r in &refs {
+            if r.aabb.max[axis] <= split.pos {
+                left.push(*r);
+            } else if r.aabb.min[axis] >= split.pos {
+                right.push(*r);
+            } else {
+                let (below, above) = split_primitive(r.prim, axis, split.pos);
+                let mut l = intersection(&below, &r.aabb);
+                l.max[axis] = l.max[axis].min(split.pos);
+                let mut h = intersection(&above, &r.aabb);
+                h.min[axis] = h.min[axis].max(split.pos);
+                match (l.is_empty(), h.is_empty()) {
+                    (false, false) => {
+                        left.push(Ref {
+                            prim: r.prim,
+                            aabb: l,
+                        });
+                        right.push(Ref {
+                            prim: r.prim,
+                            aabb: h,
+                        });
+                    }
+                    (false, true) => left.push(Ref {
+                        prim: r.prim,
+                        aabb: l,
+                    }),
+                    (true, false) => right.push(Ref {
+                        prim: r.prim,
+                        aabb: h,
+                    }),
+                    // numerically on the plane
+                    (true, true) => left.push(*r),
+                }
+            }
+        }
+        let duplicates = left.len() + right.len() - refs.len();
+        if left.is_empty() || right.is_empty() || duplicates > self.duplicate_budget {
+            return Err(refs);
+        }
+        self.duplicate_budget -= duplicates;
+        Ok((left, right))
+    }
+}
+
+// The cheapest object split along `axis`, the first of equal costs.
+fn object_split(
+    refs: &[Ref],
+    centroid_bounds: &AABB,
+    axis: usize,
+    num_bins: usize,
+) -> Option<ObjectSplit> {
+    let extent = centroid_bounds.size();
+    if !(extent[axis] > 0.0) {
+        return None;
+    }
+    let mut bins = vec![
+        Bin {
+            aabb: AABB::empty(),
+            count: 0,
+        };
+        num_bins
+    ];
+    for r in refs {
+        let b = bin_index(
+            r.aabb.center()[axis],
+            centroid_bounds.min[axis],
+            extent[axis],
+            num_bins,
+        );
+        bins[b].aabb.join_mut(&r.aabb);
+        bins[b].count += 1;
+    }
+
+    // right_aabbs[i], right_counts[i]: union of bins[i..]
+    let mut right_aabbs = vec![AABB::empty(); num_bins];
+    let mut right_counts = vec![0u32; num_bins];
+    let mut acc = AABB::empty();
+    let mut acc_count = 0;
+    for i in (1..num_bins).rev() {
+        acc.join_mut(&bins[i].aabb);
+        acc_count += bins[i].count;
+        right_aabbs[i] = acc;
+        right_counts[i] = acc_count;
+    }
+
+    let mut best: Option<ObjectSplit> = None;
+    let mut acc = AABB::empty();
+    let mut acc_count = 0;
+    for i in 1..num_bins {
+        acc.join_mut(&bins[i - 1].aabb);
+        acc_count += bins[i - 1].count;
+        if acc_count == 0 || right_counts[i] == 0 {
+            continue;
+        }
+        let cost = half_area(&acc) * acc_count as f32
+            + half_area(&right_aabbs[i]) * right_counts[i] as f32;
+        if best.map_or(true, |b| cost < b.cost) {
+            best = Some(ObjectSplit {
+                axis,
+                bin: i,
+                cost,
+                overlap: half_area(&intersection(&acc, &right_aabbs[i])),
+            });
+        }
+    }
+    best
+}
+
+// The cheapest spatial split along `axis` creating at most
+// `duplicate_budget` duplicate references.
+fn spatial_split(
+    refs: &[Ref],
+    aabb: &AABB,
+    axis: usize,
+    num_bins: usize,
+    duplicate_budget: usize,
+) -> Option<SpatialSplit> {
+    let extent = aabb.size();
+    if !(extent[axis] > 0.0) {
+        return None;
+    }
+    let bin_width = extent[axis] / num_bins as f32;
+    let mut bins = vec![
+        SpatialBin {
+            aabb: AABB::empty(),
+            entries: 0,
+            exits: 0,
+        };
+        num_bins
+    ];
+    for r in refs {
+        let first = bin_index(r.aabb.min[axis], aabb.min[axis], extent[axis], num_bins);
+        let last = bin_index(r.aabb.max[axis], aabb.min[axis], extent[axis], num_bins);
+        // clipping the reference box to the bins is cheaper than splitting
+        // the primitive and good enough for binning
+        for b in first..=last {
+            let mut clipped = r.aabb;
+            clipped.min[axis] = clipped.min[axis].max(aabb.min[axis] + b as f32 * bin_width);
+            clipped.max[axis] = clipped.max[axis].min(aabb.min[axis] + (b + 1) as f32 * bin_width);
+            bins[b].aabb.join_mut(&clipped);
+        }
+        bins[first].entries += 1;
+        bins[last].exits += 1;
+    }
+
+    let mut right_aabbs = vec![AABB::empty(); num_bins];
+    let mut right_counts = vec![0u32; num_bins];
+    let mut acc = AABB::empty();
+    let mut acc_count = 0;
+    for i in (1..num_bins).rev() {
+        acc.join_mut(&bins[i].aabb);
+        acc_count += bins[i].exits;
+        right_aabbs[i] = acc;
+        right_counts[i] = acc_count;
+    }
+
+    let mut best: Option<SpatialSplit> = None;
+    let mut acc = AABB::empty();
+    let mut acc_count = 0;
+    for i in 1..num_bins {
+        acc.join_mut(&bins[i - 1].aabb);
+        acc_count += bins[i - 1].entries;
+        if acc_count == 0 || right_counts[i] == 0 {
+            continue;
+        }
+        let duplicates = (acc_count + right_counts[i]) as usize - refs.len();
+        if duplicates > duplicate_budget {
+            continue;
+        }
+        let cost = half_area(&acc) * acc_count as f32
+            + half_area(&right_aabbs[i]) * right_counts[i] as f32;
+        if best.map_or(true, |b| cost < b.cost) {
+            best = Some(SpatialSplit {
+                axis,
+                pos: aabb.min[axis] + i as f32 * bin_width,
+                cost,
+            });
+        }
+    }
+    best
+}
+
+fn bin_index(c: f32, min: f32, extent: f32, num_bins: usize) -> usize {
+    let b = ((c - min) / extent * num_bins as f32) as usize;
+    b.min(num_bins - 1)
+}
+
+// Moves elements satisfying `pred` to the front, returns their number.
+fn partition<T, F: Fn(&T) -> bool>(items: &mut [T], pred: F) -> usize {
+    let mut first = 0;
+    for i in 0..items.len() {
+        if pred(&items[i]) {
+            items.swap(first, i);
+            first += 1;
+        }
+    }
+    first
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildOptions, Bvh};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    fn unit_boxes(n: usize) -> Vec<AABB> {
+        (0..n)
+            .map(|i| {
+                let x = (i * 7 % n) as f32 * 2.0;
+                AABB::with_bounds(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 1.0, 1.0))
+            })
+            .collect()
+    }
+
+    fn check_tree(bvh: &Bvh, num_prims: usize, max_leaf_size: u32) {
+        let mut seen = vec![false; num_prims];
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            assert!(node.exit_index as usize > i);
+            if node.is_leaf() {
+                assert!(node.prim_count <= max_leaf_size);
+                for &pi in bvh.leaf_prims(node) {
+                    assert!(!seen[pi as usize]);
+                    seen[pi as usize] = true;
+                }
+            } else {
+                // both children are inside the parent subtree
+                let left = &bvh.nodes[i + 1];
+                let right = &bvh.nodes[left.exit_index as usize];
+                assert_eq!(right.exit_index, node.exit_index);
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+        assert_eq!(bvh.nodes[0].exit_index as usize, bvh.nodes.len());
+    }
+
+    #[test]
+    fn test_presets_produce_valid_trees() {
+        let boxes = unit_boxes(100);
+        for options in [
+            BuildOptions::default(),
+            BuildOptions::prefer_fast_trace(),
+            BuildOptions::prefer_fast_build(),
+        ] {
+            let bvh = Bvh::build(&boxes, &options);
+            check_tree(&bvh, boxes.len(), options.max_leaf_size);
+        }
+    }
+
+    #[test]
+    fn test_parallel_build_matches_sequential() {
+        let boxes: Vec<AABB> = (0..1000)
+            .map(|i| {
+                let p = Point3::new((i * 7 % 31) as f32, (i * 13 % 17) as f32, (i % 5) as f32);
+                AABB::with_bounds(p, p + Point3::new(1.5, 0.5, 2.0))
+            })
+            .collect();
+        let options = BuildOptions::default();
+        let sequential = Bvh::build_with_splitter(&boxes, &options, None, usize::MAX);
+        let parallel = Bvh::build_with_splitter(&boxes, &options, None, 16);
+        check_tree(&parallel, boxes.len(), options.max_leaf_size);
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", sequential));
+    }
+
+    #[test]
+    fn test_coincident_centroids_respect_max_leaf_size() {
+        let boxes =
+            vec![AABB::with_bounds(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0)); 9];
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&boxes, &options);
+        check_tree(&bvh, boxes.len(), 1);
+        assert_eq!(bvh.nodes.len(), 2 * boxes.len() - 1);
+    }
+
+    #[test]
+    fn test_spatial_splits_respect_budget() {
+        // long overlapping slabs, object splits cannot separate them
+        let boxes: Vec<AABB> = (0..64)
+            .map(|i| {
+                let y = i as f32 * 0.5;
+                AABB::with_bounds(Point3::new(0.0, y, 0.0), Point3::new(100.0, y + 0.25, 1.0))
+            })
+            .chain((0..64).map(|i| {
+                let x = i as f32 * 1.5;
+                AABB::with_bounds(Point3::new(x, 0.0, 0.0), Point3::new(x + 0.5, 32.0, 1.0))
+            }))
+            .collect();
+        let split = |prim: u32, axis: usize, pos: f32| {
+            let aabb = boxes[prim as usize];
+            let (mut below, mut above) = (aabb, aabb);
+            below.max[axis] = below.max[axis].min(pos);
+            above.min[axis] = above.min[axis].max(pos);
+            (below, above)
+        };
+        let options = BuildOptions {
+            spatial_split_budget: 0.5,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build_spatial(&boxes, &options, split);
+
+        let mut seen = vec![false; boxes.len()];
+        for (i, node) in bvh.nodes.iter().enumerate() {
+            assert!(node.exit_index as usize > i);
+            if node.is_leaf() {
+                for &pi in bvh.leaf_prims(node) {
+                    seen[pi as usize] = true;
+                }
+            }
+        }
+        assert!(seen.iter().all(|&s| s));
+        let duplicates = bvh.prim_indices.len() - boxes.len();
+        assert!(duplicates > 0);
+        assert!(duplicates <= boxes.len() / 2);
+
+        let options = BuildOptions {
+            spatial_split_budget: 0.0,
+            ..options
+        };
+        let bvh = Bvh::build_spatial(&boxes, &options, split);
+        assert_eq!(bvh.prim_indices.len(), boxes.len());
+    }
+}
@@ -0,0 +1,277 @@
+// Quantized BLAS node encodings, see GPUBlasBvhNodeQuantized16 and
+// common.glsl.
+//
+// Bounds are quantized relative to the BLAS root box (the quantization frame)
+// rather than to the immediate parent: a stackless traversal arriving at a
+// node through exit_index does not have the parent box at hand. The frame is
+// stored in the TLAS leaves referencing the BLAS (blas_aabb). Codes are 16
+// bits, with 8 bits the boxes of nodes deep in the tree would grow to 1/255
+// of the root box and traversal would visit most of their siblings.
+
+use crate::layout::{blas_node_format_stride, read_blas_node, read_u32, BlasNode};
+use crate::{GPUBlasBvhNodeQuantized16, NodeFormat};
+use bvh::aabb::AABB;
+use bvh::Point3;
+use crevice::std430;
+
+const QUANTIZATION_BITS: u32 = 16;
+
+// The root box grown by a few ulps, so that the largest code still
+// dequantizes to at least the root max after rounding.
+pub(crate) fn quantization_frame(root: &AABB) -> AABB {
+    let mut frame = *root;
+    for axis in 0..3 {
+        let (min, max) = (root.min[axis], root.max[axis]);
+        let pad = ((max - min) * 1e-5)
+            .max(min.abs().max(max.abs()) * 4.0 * f32::EPSILON)
+            .max(f32::MIN_POSITIVE);
+        frame.min[axis] = min - pad;
+        frame.max[axis] = max + pad;
+    }
+    frame
+}
+
+// The frame of a BLAS with root bounds `root`. Empty BLASes get any finite
+// frame, their empty root stays empty when quantized.
+pub(crate) fn blas_quantization_frame(root: &AABB, num_primitive_refs: u32) -> AABB {
+    if num_primitive_refs == 0 {
+        quantization_frame(&AABB::with_bounds(Point3::ZERO, Point3::ZERO))
+    } else {
+        quantization_frame(root)
+    }
+}
+
+// Same arithmetic as decodeBlasNodeAabb in trace.glsl.
+fn frame_scale(frame: &AABB, qmax: u32) -> Point3 {
+    (frame.max - frame.min) / qmax as f32
+}
+
+fn dequantize(frame_min: f32, scale: f32, q: u32) -> f32 {
+    frame_min + q as f32 * scale
+}
+
+fn quantize_down(v: f32, frame_min: f32, scale: f32, qmax: u32) -> u32 {
+    let mut q = ((v - frame_min) / scale).floor().max(0.0).min(qmax as f32) as u32;
+    while q > 0 && dequantize(frame_min, scale, q) > v {
+        q -= 1;
+    }
+    q
+}
+
+fn quantize_up(v: f32, frame_min: f32, scale: f32, qmax: u32) -> u32 {
+    let mut q = ((v - frame_min) / scale).ceil().max(0.0).min(qmax as f32) as u32;
+    while q < qmax && dequantize(frame_min, scale, q) < v {
+        q += 1;
+    }
+    q
+}
+
+// Conservative [min.xyz, max.xyz] codes of `aabb` within `frame`.
+fn quantize_aabb(aabb: &AABB, frame: &AABB) -> [u32; 6] {
+    let qmax = (1u32 << QUANTIZATION_BITS) - 1;
+    let scale = frame_scale(frame, qmax);
+    let mut q = [0u32; 6];
+    for axis in 0..3 {
+        q[axis] = quantize_down(aabb.min[axis], frame.min[axis], scale[axis], qmax);
+        q[axis + 3] = quantize_up(aabb.max[axis], frame.min[axis], scale[axis], qmax);
+    }
+    q
+}
+
+fn dequantize_aabb(q: &[u32; 6], frame: &AABB) -> AABB {
+    let qmax = (1u32 << QUANTIZATION_BITS) - 1;
+    let scale = frame_scale(frame, qmax);
+    let mut aabb = *frame;
+    for axis in 0..3 {
+        aabb.min[axis] = dequantize(frame.min[axis], scale[axis], q[axis]);
+        aabb.max[axis] = dequantize(frame.min[axis], scale[axis], q[axis + 3]);
+    }
+    aabb
+}
+
+// Re-encodes full precision GPUBlasBvhNodes, indices are kept.
+pub(crate) fn serialize_blas_nodes_quantized(
+    nodes: &[u8],
+    num_nodes: u32,
+    frame: &AABB,
+    format: NodeFormat,
+) -> Vec<u8> {
+    let mut serialized = Vec::with_capacity(num_nodes as usize * blas_node_format_stride(format));
+    let mut writer = std430::Writer::new(&mut serialized);
+    for i in 0..num_nodes {
+        let n = read_blas_node(nodes, i);
+        let q = quantize_aabb(&n.aabb, frame);
+        match format {
+            NodeFormat::Quantized16 => writer
+                .write(&GPUBlasBvhNodeQuantized16 {
+                    bounds0: q[0] | q[1] << 16,
+                    bounds1: q[2] | q[3] << 16,
+                    bounds2: q[4] | q[5] << 16,
+                    entry_index_or_first_primitive: n.entry_index_or_first_primitive,
+                    exit_index: n.exit_index,
+                    primitive_count: n.primitive_count,
+                })
+                .unwrap(),
+            _ => panic!("not a quantized format"),
+        };
+    }
+    serialized
+}
+
+// GPUBlasBvhNodeQuantized16, bounds0: min.xy, bounds1: min.z max.x,
+// bounds2: max.yz
+const QUANTIZED16_BOUNDS: usize = 0;
+const QUANTIZED16_ENTRY_INDEX_OR_FIRST_PRIMITIVE: usize = 12;
+const QUANTIZED16_EXIT_INDEX: usize = 16;
+const QUANTIZED16_PRIMITIVE_COUNT: usize = 20;
+
+// Reads back a node of any stackless format, quantized bounds are decoded
+// like loadBlasNode in trace.glsl.
+pub(crate) fn read_blas_node_quantized(
+    nodes: &[u8],
+    index: u32,
+    frame: &AABB,
+    format: NodeFormat,
+) -> BlasNode {
+    let offset = index as usize * blas_node_format_stride(format);
+    let (q, entry, exit, count) = match format {
+        NodeFormat::Quantized16 => {
+            let b0 = read_u32(nodes, offset + QUANTIZED16_BOUNDS);
+            let b1 = read_u32(nodes, offset + QUANTIZED16_BOUNDS + 4);
+            let b2 = read_u32(nodes, offset + QUANTIZED16_BOUNDS + 8);
+            (
+                [
+                    b0 & 0xffff,
+                    b0 >> 16,
+                    b1 & 0xffff,
+                    b1 >> 16,
+                    b2 & 0xffff,
+                    b2 >> 16,
+                ],
+                QUANTIZED16_ENTRY_INDEX_OR_FIRST_PRIMITIVE,
+                QUANTIZED16_EXIT_INDEX,
+                QUANTIZED16_PRIMITIVE_COUNT,
+            )
+        }
+        _ => return read_blas_node(nodes, index),
+    };
+    BlasNode {
+        aabb: dequantize_aabb(&q, frame),
+        entry_index_or_first_primitive: read_u32(nodes, offset + entry),
+        exit_index: read_u32(nodes, offset + exit),
+        primitive_count: read_u32(nodes, offset + count),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quantization_frame, read_blas_node_quantized, serialize_blas_nodes_quantized};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::layout::{blas_node_format_stride, read_blas_node};
+    use crate::NodeFormat;
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_quantized_bounds_are_conservative() {
+        let boxes: Vec<AABB> = (0..200)
+            .map(|i| {
+                let x = 1000.0 + i as f32 * 0.37;
+                let y = -(i % 13) as f32 * 1.1;
+                AABB::with_bounds(Point3::new(x, y, 0.1), Point3::new(x + 0.25, y + 0.5, 0.3))
+            })
+            .collect();
+        let bvh = Bvh::build(&boxes, &BuildOptions::default());
+        let num_nodes = bvh.nodes.len() as u32;
+        let full = crate::serialize_blas_nodes(&bvh);
+        let frame = quantization_frame(&bvh.nodes[0].aabb);
+        let format = NodeFormat::Quantized16;
+        let bytes = serialize_blas_nodes_quantized(&full, num_nodes, &frame, format);
+        assert_eq!(
+            bytes.len(),
+            num_nodes as usize * blas_node_format_stride(format)
+        );
+        assert!(bytes.len() < full.len());
+        for i in 0..num_nodes {
+            let expected = read_blas_node(&full, i);
+            let node = read_blas_node_quantized(&bytes, i, &frame, format);
+            for axis in 0..3 {
+                assert!(node.aabb.min[axis] <= expected.aabb.min[axis]);
+                assert!(node.aabb.max[axis] >= expected.aabb.max[axis]);
+            }
+            assert_eq!(
+                node.entry_index_or_first_primitive,
+                expected.entry_index_or_first_primitive
+            );
+            assert_eq!(node.exit_index, expected.exit_index);
+            assert_eq!(node.primitive_count, expected.primitive_count);
+        }
+    }
+
+    // Nodes visited by a stackless traversal of a ray from `origin` along -y.
+    fn count_visits(nodes: &[u8], frame: &AABB, format: NodeFormat, origin: Point3) -> u32 {
+        let mut visits = 0;
+        let mut cur = 0;
+        while cur != u32::MAX {
+            visits += 1;
+            let node = read_blas_node_quantized(nodes, cur, frame, format);
+            let (min, max) = (node.aabb.min, node.aabb.max);
+            let hit = (min.x..=max.x).contains(&origin.x)
+                && (min.z..=max.z).contains(&origin.z)
+                && min.y <= origin.y;
+            cur = if hit && node.primitive_count == 0 {
+                node.entry_index_or_first_primitive
+            } else {
+                node.exit_index
+            };
+        }
+        visits
+    }
+
+    #[test]
+    fn test_quantized_traversal_visits_as_many_nodes() {
+        // a 64 x 64 grid of small boxes, as far apart as they are wide, in a
+        // root box 100 times larger. With 8 bit codes within the root box
+        // each box would grow over hundreds of its neighbours.
+        let mut boxes: Vec<AABB> = (0..64 * 64)
+            .map(|i| {
+                let (x, z) = ((i % 64) as f32 * 0.02, (i / 64) as f32 * 0.02);
+                AABB::with_bounds(
+                    Point3::new(x, 0.0, z),
+                    Point3::new(x + 0.01, 0.01, z + 0.01),
+                )
+            })
+            .collect();
+        boxes.push(AABB::with_bounds(
+            Point3::new(100.0, 0.0, 100.0),
+            Point3::new(101.0, 1.0, 101.0),
+        ));
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&boxes, &options);
+        let num_nodes = bvh.nodes.len() as u32;
+        let full = crate::serialize_blas_nodes(&bvh);
+        let frame = quantization_frame(&bvh.nodes[0].aabb);
+        let format = NodeFormat::Quantized16;
+        let quantized = serialize_blas_nodes_quantized(&full, num_nodes, &frame, format);
+        let root = bvh.nodes[0].aabb;
+        let (mut full_visits, mut quantized_visits) = (0, 0);
+        // rays through the boxes and through the gaps between them
+        for i in 0..128 {
+            for j in 0..128 {
+                let origin = Point3::new(i as f32 * 0.01 + 0.005, 1.0, j as f32 * 0.01 + 0.005);
+                full_visits += count_visits(&full, &root, NodeFormat::Full, origin);
+                quantized_visits += count_visits(&quantized, &frame, format, origin);
+            }
+        }
+        assert!(full_visits > 0);
+        assert!(
+            quantized_visits <= full_visits + full_visits / 100,
+            "{} visits quantized, {} at full precision",
+            quantized_visits,
+            full_visits
+        );
+    }
+}
@@ -0,0 +1,253 @@
+// Errors for malformed build descriptors, thrown to JS as Error objects.
+
+use crate::NodeFormat;
+use std::fmt;
+use wasm_bindgen::JsValue;
+
+/// Where in a build descriptor an error was found.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DescriptorLocation {
+    /// The descriptor header, e.g. the geometry or instance count.
+    Descriptor,
+    /// The BLAS geometry at this index.
+    Geometry(u32),
+    /// The TLAS instance (or instance update) at this index.
+    Instance(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BvhBuildError {
+    /// No staging buffer is registered under `buffer_id`.
+    UnknownBufferId {
+        at: DescriptorLocation,
+        buffer_id: u32,
+    },
+    /// `buffer_id` refers to a staging buffer that has been freed.
+    StaleBufferId {
+        at: DescriptorLocation,
+        buffer_id: u32,
+    },
+    /// A buffer or count does not match the size implied by the descriptor.
+    LengthMismatch {
+        at: DescriptorLocation,
+        expected: usize,
+        actual: usize,
+    },
+    /// A vertex, index, byte offset or instance index past the end of what
+    /// it refers to.
+    IndexOutOfRange {
+        at: DescriptorLocation,
+        index: usize,
+        len: usize,
+    },
+    /// A buffer, byte offset or stride that is not a multiple of `alignment`.
+    MisalignedBuffer {
+        at: DescriptorLocation,
+        buffer_id: u32,
+        alignment: usize,
+    },
+    UnknownGeometryType {
+        at: DescriptorLocation,
+        geometry_type: i32,
+    },
+    /// An unknown vertex or index format.
+    UnknownFormat { at: DescriptorLocation, format: i32 },
+    /// A negative geometry, primitive or instance count.
+    InvalidCount { at: DescriptorLocation, count: i32 },
+    /// Unknown geometry or instance flag bits, or instance flags forcing
+    /// both opaque and non-opaque.
+    InvalidFlags { at: DescriptorLocation, flags: u32 },
+    /// An unknown instance motion type, see `InstanceMotionType`.
+    UnknownMotionType {
+        at: DescriptorLocation,
+        motion_type: u32,
+    },
+    /// A `BuildOptions::branching_factor` other than 2, 4 or 8.
+    UnsupportedBranchingFactor {
+        at: DescriptorLocation,
+        branching_factor: u32,
+    },
+    /// A TLAS passed where a BLAS is expected.
+    NotABlas { at: DescriptorLocation },
+    /// A BLAS passed where a TLAS is expected.
+    NotATlas { at: DescriptorLocation },
+    /// A node format the operation does not support, e.g. compacting a
+    /// compacted BLAS or into a wide format.
+    UnsupportedNodeFormat {
+        at: DescriptorLocation,
+        format: NodeFormat,
+    },
+    /// All staging buffer ids are in use or retired, see `StagingBufferMap`.
+    StagingBuffersExhausted { at: DescriptorLocation },
+}
+
+impl BvhBuildError {
+    pub fn location(&self) -> DescriptorLocation {
+        match *self {
+            BvhBuildError::UnknownBufferId { at, .. }
+            | BvhBuildError::StaleBufferId { at, .. }
+            | BvhBuildError::LengthMismatch { at, .. }
+            | BvhBuildError::IndexOutOfRange { at, .. }
+            | BvhBuildError::MisalignedBuffer { at, .. }
+            | BvhBuildError::UnknownGeometryType { at, .. }
+            | BvhBuildError::UnknownFormat { at, .. }
+            | BvhBuildError::InvalidCount { at, .. }
+            | BvhBuildError::InvalidFlags { at, .. }
+            | BvhBuildError::UnknownMotionType { at, .. }
+            | BvhBuildError::UnsupportedBranchingFactor { at, .. }
+            | BvhBuildError::NotABlas { at }
+            | BvhBuildError::NotATlas { at }
+            | BvhBuildError::UnsupportedNodeFormat { at, .. }
+            | BvhBuildError::StagingBuffersExhausted { at } => at,
+        }
+    }
+}
+
+impl fmt::Display for DescriptorLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DescriptorLocation::Descriptor => write!(f, "descriptor"),
+            DescriptorLocation::Geometry(i) => write!(f, "geometry {}", i),
+            DescriptorLocation::Instance(i) => write!(f, "instance {}", i),
+        }
+    }
+}
+
+impl fmt::Display for BvhBuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BvhBuildError::UnknownBufferId { at, buffer_id } => {
+                write!(f, "{}: unknown staging buffer id {}", at, buffer_id)
+            }
+            BvhBuildError::StaleBufferId { at, buffer_id } => {
+                write!(f, "{}: staging buffer {} has been freed", at, buffer_id)
+            }
+            BvhBuildError::LengthMismatch {
+                at,
+                expected,
+                actual,
+            } => write!(f, "{}: expected length {}, got {}", at, expected, actual),
+            BvhBuildError::IndexOutOfRange { at, index, len } => {
+                write!(f, "{}: index {} out of range (len {})", at, index, len)
+            }
+            BvhBuildError::MisalignedBuffer {
+                at,
+                buffer_id,
+                alignment,
+            } => write!(
+                f,
+                "{}: staging buffer {} is not {} byte aligned",
+                at, buffer_id, alignment
+            ),
+            BvhBuildError::UnknownGeometryType { at, geometry_type } => {
+                write!(f, "{}: unknown geometry type {}", at, geometry_type)
+            }
+            BvhBuildError::UnknownFormat { at, format } => {
+                write!(f, "{}: unknown vertex or index format {}", at, format)
+            }
+            BvhBuildError::InvalidCount { at, count } => {
+                write!(f, "{}: invalid count {}", at, count)
+            }
+            BvhBuildError::InvalidFlags { at, flags } => {
+                write!(f, "{}: invalid flags {:#x}", at, flags)
+            }
+            BvhBuildError::UnknownMotionType { at, motion_type } => {
+                write!(f, "{}: unknown motion type {}", at, motion_type)
+            }
+            BvhBuildError::UnsupportedBranchingFactor {
+                at,
+                branching_factor,
+            } => write!(
+                f,
+                "{}: unsupported branching factor {}",
+                at, branching_factor
+            ),
+            BvhBuildError::NotABlas { at } => write!(f, "{}: not a BLAS", at),
+            BvhBuildError::NotATlas { at } => write!(f, "{}: not a TLAS", at),
+            BvhBuildError::UnsupportedNodeFormat { at, format } => {
+                write!(f, "{}: unsupported node format {:?}", at, format)
+            }
+            BvhBuildError::StagingBuffersExhausted { at } => {
+                write!(f, "{}: too many live or retired staging buffers", at)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BvhBuildError {}
+
+impl From<BvhBuildError> for JsValue {
+    fn from(e: BvhBuildError) -> Self {
+        js_sys::Error::new(&e.to_string()).into()
+    }
+}
+
+/// Why a BVH saved by `save_bvh` cannot be loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BvhLoadError {
+    /// The buffer does not start with the blob magic.
+    NotABlob,
+    /// Saved with another node layout or blob version.
+    VersionMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// The blob is truncated or a section does not match the header.
+    LengthMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    UnknownFormat {
+        format: u32,
+    },
+    /// The blob holds a BLAS where a TLAS was loaded or the other way round.
+    KindMismatch,
+    /// The geometries (BLAS) or instances (TLAS) of the descriptor differ
+    /// from the ones the BVH was built from.
+    GeometryChanged {
+        expected: u64,
+        actual: u64,
+    },
+    /// The descriptor or a buffer it references is malformed.
+    Descriptor(BvhBuildError),
+}
+
+impl fmt::Display for BvhLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BvhLoadError::NotABlob => write!(f, "not a saved BVH"),
+            BvhLoadError::VersionMismatch { expected, actual } => write!(
+                f,
+                "saved BVH version {}, expected version {}",
+                actual, expected
+            ),
+            BvhLoadError::LengthMismatch { expected, actual } => {
+                write!(f, "saved BVH: expected length {}, got {}", expected, actual)
+            }
+            BvhLoadError::UnknownFormat { format } => {
+                write!(f, "saved BVH: unknown node format {}", format)
+            }
+            BvhLoadError::KindMismatch => write!(f, "saved BVH is not a BLAS or not a TLAS"),
+            BvhLoadError::GeometryChanged { expected, actual } => write!(
+                f,
+                "geometry hash {:016x} does not match the saved BVH ({:016x})",
+                actual, expected
+            ),
+            BvhLoadError::Descriptor(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for BvhLoadError {}
+
+impl From<BvhBuildError> for BvhLoadError {
+    fn from(e: BvhBuildError) -> Self {
+        BvhLoadError::Descriptor(e)
+    }
+}
+
+impl From<BvhLoadError> for JsValue {
+    fn from(e: BvhLoadError) -> Self {
+        js_sys::Error::new(&e.to_string()).into()
+    }
+}
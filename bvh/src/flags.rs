@@ -0,0 +1,140 @@
+// Instance and geometry flags, the bits of
+// GPURayTracingAccelerationInstanceUsage and
+// GPURayTracingAccelerationGeometryUsage in types.ts. They match
+// VkGeometryInstanceFlagBitsKHR and VkGeometryFlagBitsKHR.
+
+use std::ops::BitOr;
+
+/// Flags of a TLAS instance, stored in the `flags` of its TLAS leaf node.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct InstanceFlags(u32);
+
+impl InstanceFlags {
+    pub const NONE: Self = InstanceFlags(0);
+    /// Triangles are not culled by the facing cull ray flags.
+    pub const TRIANGLE_FACING_CULL_DISABLE: Self = InstanceFlags(1);
+    /// Triangles facing away from the ray are front facing instead, for
+    /// counterclockwise front faces.
+    pub const TRIANGLE_FRONT_COUNTERCLOCKWISE: Self = InstanceFlags(2);
+    /// All geometries of the instance are opaque.
+    pub const FORCE_OPAQUE: Self = InstanceFlags(4);
+    /// No geometry of the instance is opaque.
+    pub const FORCE_NO_OPAQUE: Self = InstanceFlags(8);
+
+    const ALL: u32 = 0xf;
+
+    /// None for unknown bits, and for FORCE_OPAQUE together with
+    /// FORCE_NO_OPAQUE.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        let flags = InstanceFlags(bits);
+        if bits & !Self::ALL != 0 || flags.contains(Self::FORCE_OPAQUE | Self::FORCE_NO_OPAQUE) {
+            return None;
+        }
+        Some(flags)
+    }
+
+    /// Drops unknown bits, for flags read back from a serialized tree.
+    pub fn from_bits_truncate(bits: u32) -> Self {
+        InstanceFlags(bits & Self::ALL)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether primitives of a geometry with `geometry` flags are opaque in
+    /// this instance. Opaque primitives are hit without invoking the any-hit
+    /// shader.
+    pub fn is_opaque(self, geometry: GeometryFlags) -> bool {
+        if self.contains(Self::FORCE_OPAQUE) {
+            true
+        } else if self.contains(Self::FORCE_NO_OPAQUE) {
+            false
+        } else {
+            geometry.contains(GeometryFlags::OPAQUE)
+        }
+    }
+}
+
+impl BitOr for InstanceFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        InstanceFlags(self.0 | rhs.0)
+    }
+}
+
+/// Flags of a BLAS geometry, stored in the primitive refs of its primitives.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GeometryFlags(u32);
+
+impl GeometryFlags {
+    pub const NONE: Self = GeometryFlags(0);
+    pub const OPAQUE: Self = GeometryFlags(1);
+    /// The any-hit shader is invoked at most once per primitive and ray.
+    /// BLASes with such geometries are built without spatial splits, which
+    /// would reference a primitive from several leaves.
+    pub const NO_DUPLICATE_ANY_HIT_INVOCATION: Self = GeometryFlags(2);
+
+    const ALL: u32 = 0x3;
+
+    /// None for unknown bits.
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::ALL != 0 {
+            return None;
+        }
+        Some(GeometryFlags(bits))
+    }
+
+    /// Drops unknown bits, for flags read back from a serialized tree.
+    pub fn from_bits_truncate(bits: u32) -> Self {
+        GeometryFlags(bits & Self::ALL)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for GeometryFlags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        GeometryFlags(self.0 | rhs.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GeometryFlags, InstanceFlags};
+
+    #[test]
+    fn test_flag_validation() {
+        assert_eq!(
+            InstanceFlags::from_bits(5),
+            Some(InstanceFlags::TRIANGLE_FACING_CULL_DISABLE | InstanceFlags::FORCE_OPAQUE)
+        );
+        // both force bits
+        assert_eq!(InstanceFlags::from_bits(12), None);
+        assert_eq!(InstanceFlags::from_bits(16), None);
+        assert_eq!(
+            GeometryFlags::from_bits(3).map(GeometryFlags::bits),
+            Some(3)
+        );
+        assert_eq!(GeometryFlags::from_bits(4), None);
+
+        let opaque = GeometryFlags::OPAQUE;
+        assert!(InstanceFlags::NONE.is_opaque(opaque));
+        assert!(!InstanceFlags::NONE.is_opaque(GeometryFlags::NONE));
+        assert!(InstanceFlags::FORCE_OPAQUE.is_opaque(GeometryFlags::NONE));
+        assert!(!InstanceFlags::FORCE_NO_OPAQUE.is_opaque(opaque));
+    }
+}
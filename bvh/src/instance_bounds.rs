@@ -0,0 +1,201 @@
+// Tighter TLAS instance bounds than the transformed BLAS root box. The root
+// box of a rotated instance can be much larger than its geometry, so
+// instances may instead be bounded by transforming finer object space boxes
+// of their BLAS, selected by BuildOptions::instance_bounds.
+
+use crate::builder::{half_area, FlatNode};
+use crate::{GeometryType, Primitive, PrimitiveClass};
+use bvh::aabb::{Bounded, AABB};
+use wasm_bindgen::prelude::*;
+
+// The most node boxes kept for InstanceBounds::NodeBoxes.
+const MAX_NODE_BOXES: usize = 8;
+
+/// How TLAS instance bounds are computed, from the cheapest and loosest to
+/// the slowest and tightest. Bounds other than `RootBox` need the BLASes, so
+/// they are only used by `SceneBatch` and
+/// `build_tlas_from_instances_with_bounds`.
+#[wasm_bindgen]
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceBounds {
+    /// The BLAS root box, transformed.
+    RootBox = 0,
+    /// The boxes of up to 8 BLAS nodes near the root, each transformed.
+    NodeBoxes = 1,
+    /// The vertices of the BLAS primitives, transformed. Exact for static
+    /// triangles.
+    Vertices = 2,
+}
+
+/// Object space boxes covering the primitives of a BLAS, its instances are
+/// bounded by the union of the transformed boxes. Vertices are boxes with
+/// `min == max`.
+#[derive(Debug, Clone, Default)]
+pub struct BlasBounds {
+    pub boxes: Vec<AABB>,
+}
+
+impl BlasBounds {
+    // The bounds of a BLAS with root box `aabb` and `node_boxes` (see
+    // BuiltBlas::node_boxes), built from `primitives`.
+    pub(crate) fn from_primitives(
+        mode: InstanceBounds,
+        aabb: &AABB,
+        node_boxes: &[AABB],
+        primitives: &[Primitive],
+    ) -> Self {
+        let boxes = match mode {
+            InstanceBounds::RootBox => vec![*aabb],
+            InstanceBounds::NodeBoxes => node_boxes.to_vec(),
+            InstanceBounds::Vertices => primitive_points(primitives),
+        };
+        BlasBounds {
+            boxes: boxes.into_iter().filter(|b| !b.is_empty()).collect(),
+        }
+    }
+}
+
+// Boxes of nodes that together cover all leaves, found by opening the
+// largest interior node until there are MAX_NODE_BOXES.
+pub(crate) fn node_boxes(nodes: &[FlatNode]) -> Vec<AABB> {
+    if nodes.is_empty() {
+        return Vec::new();
+    }
+    let mut frontier = vec![0usize];
+    while frontier.len() < MAX_NODE_BOXES {
+        let largest = frontier
+            .iter()
+            .enumerate()
+            .filter(|(_, &i)| !nodes[i].is_leaf())
+            .max_by(|(_, &a), (_, &b)| {
+                half_area(&nodes[a].aabb).total_cmp(&half_area(&nodes[b].aabb))
+            })
+            .map(|(k, _)| k);
+        let i = match largest {
+            Some(k) => frontier.swap_remove(k),
+            None => break,
+        };
+        frontier.push(i + 1);
+        frontier.push(nodes[i + 1].exit_index as usize);
+    }
+    frontier.iter().map(|&i| nodes[i].aabb).collect()
+}
+
+// The distinct vertices of the primitives that go into the tree, as empty
+// boxes. AABB primitives are kept as boxes.
+fn primitive_points(primitives: &[Primitive]) -> Vec<AABB> {
+    let mut boxes = Vec::new();
+    let mut points = Vec::<[u32; 3]>::new();
+    for p in primitives {
+        if !matches!(p.classify(), PrimitiveClass::Active(_)) {
+            continue;
+        }
+        match p.geometry_type {
+            GeometryType::Triangle => {
+                // a moving triangle stays within the hull of its keyframes
+                for v in p
+                    .triangle_vertices()
+                    .iter()
+                    .chain(p.end_triangle_vertices().iter().flatten())
+                {
+                    points.push([v.x.to_bits(), v.y.to_bits(), v.z.to_bits()]);
+                }
+            }
+            GeometryType::Aabb => boxes.push(p.aabb()),
+        }
+    }
+    // indexed meshes repeat their vertices
+    points.sort_unstable();
+    points.dedup();
+    boxes.extend(points.iter().map(|p| {
+        let p = bvh::Point3::new(
+            f32::from_bits(p[0]),
+            f32::from_bits(p[1]),
+            f32::from_bits(p[2]),
+        );
+        AABB::with_bounds(p, p)
+    }));
+    boxes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_boxes, BlasBounds, InstanceBounds};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::traverse::tests::geometry;
+    use crate::{GeometryType, Primitive, TlasInstance, TlasInstanceDescriptor};
+    use bvh::aabb::{Bounded, AABB};
+    use bvh::Point3;
+
+    #[test]
+    fn test_node_boxes_cover_leaves() {
+        let boxes: Vec<AABB> = (0..32)
+            .map(|i| {
+                let p = Point3::new(i as f32, 0.0, 0.0);
+                AABB::with_bounds(p, p + Point3::ONE)
+            })
+            .collect();
+        let bvh = Bvh::build(
+            &boxes,
+            &BuildOptions {
+                max_leaf_size: 1,
+                ..BuildOptions::default()
+            },
+        );
+        let frontier = node_boxes(&bvh.nodes);
+        assert_eq!(frontier.len(), 8);
+        for b in &boxes {
+            assert!(frontier
+                .iter()
+                .any(|f| f.contains(&b.min) && f.contains(&b.max)));
+        }
+        assert!(node_boxes(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_vertex_bounds_of_rotated_instance() {
+        // a thin triangle along the diagonal of the xy plane, rotated by 45
+        // degrees about z onto the x axis
+        let vertices: Vec<u8> = [0.0f32, 0.0, 0.0, 10.0, 10.0, 0.0, 10.0, 10.1, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let triangle = geometry(GeometryType::Triangle, &vertices);
+        let root = triangle.aabb();
+        let primitives: Vec<Primitive> = vec![triangle, triangle];
+        let bounds = BlasBounds::from_primitives(InstanceBounds::Vertices, &root, &[], &primitives);
+        // shared vertices are kept once
+        assert_eq!(bounds.boxes.len(), 3);
+
+        let (s, c) = std::f32::consts::FRAC_PI_4.sin_cos();
+        let instance = TlasInstance {
+            mask: 0xff,
+            flags: 0,
+            instance_id: 0,
+            sbt_instance_offset: 0,
+            instance_custom_index: 0,
+            blas_entry_index: 0,
+            blas_geometry_id_offset: 0,
+            blas_primitive_ref_offset: 0,
+            blas_aabb: [
+                root.min.x, root.min.y, root.min.z, root.max.x, root.max.y, root.max.z,
+            ],
+            transform_to_world_4x3: [c, -s, 0.0, s, c, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        };
+        let mut descriptor = TlasInstanceDescriptor::new(0, &instance).unwrap();
+        let root_box = descriptor.aabb;
+        descriptor.bound_by(&bounds);
+        let tight = descriptor.aabb;
+        assert!(root_box.max.y - root_box.min.y > 14.0);
+        assert!(tight.max.y - tight.min.y < 0.1);
+        assert!(root_box.contains(&tight.min) && root_box.contains(&tight.max));
+
+        let bounds = BlasBounds::from_primitives(InstanceBounds::RootBox, &root, &[], &[]);
+        assert_eq!(bounds.boxes.len(), 1);
+        let empty = BlasBounds::from_primitives(InstanceBounds::RootBox, &AABB::empty(), &[], &[]);
+        assert!(empty.boxes.is_empty());
+    }
+}
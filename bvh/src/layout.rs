@@ -0,0 +1,327 @@
+// Reads back the std430 node layouts serialized by build_blas/build_tlas.
+// The byte offsets below must be kept in sync with the AsStd430 structs in
+// lib.rs, see common.glsl.
+
+use crate::{GeometryFlags, InstanceFlags, InstanceMotionType, NodeFormat, TransformClass};
+use bvh::aabb::AABB;
+use bvh::Point3;
+use crevice::std430;
+use std::convert::{TryFrom, TryInto};
+
+// GPUAabb
+const AABB_MIN: usize = 0;
+const AABB_MAX: usize = 16; // vec3 alignment
+
+// GPUBlasBvhNode
+pub(crate) const BLAS_NODE_AABB: usize = 0;
+pub(crate) const BLAS_NODE_ENTRY_INDEX_OR_FIRST_PRIMITIVE: usize = 32;
+pub(crate) const BLAS_NODE_EXIT_INDEX: usize = 36;
+pub(crate) const BLAS_NODE_PRIMITIVE_COUNT: usize = 40;
+
+// GPUTlasBvhNode
+pub(crate) const TLAS_NODE_AABB: usize = 0;
+pub(crate) const TLAS_NODE_ENTRY_INDEX: usize = 32;
+pub(crate) const TLAS_NODE_EXIT_INDEX: usize = 36;
+pub(crate) const TLAS_NODE_IS_LEAF: usize = 40;
+pub(crate) const TLAS_NODE_MASK: usize = 44;
+pub(crate) const TLAS_NODE_FLAGS: usize = 48;
+pub(crate) const TLAS_NODE_INSTANCE_ID: usize = 52;
+pub(crate) const TLAS_NODE_SBT_INSTANCE_OFFSET: usize = 56;
+pub(crate) const TLAS_NODE_INSTANCE_CUSTOM_INDEX: usize = 60;
+pub(crate) const TLAS_NODE_TRANSFORM_TO_WORLD: usize = 64;
+pub(crate) const TLAS_NODE_TRANSFORM_TO_OBJECT: usize = 112;
+pub(crate) const TLAS_NODE_BLAS_GEOMETRY_ID_OFFSET: usize = 160;
+pub(crate) const TLAS_NODE_BLAS_PRIMITIVE_REF_OFFSET: usize = 164;
+pub(crate) const TLAS_NODE_BLAS_AABB: usize = 176; // vec3 alignment
+pub(crate) const TLAS_NODE_MOTION_TYPE: usize = 208;
+pub(crate) const TLAS_NODE_MOTION_START: usize = 212;
+pub(crate) const TLAS_NODE_MOTION_END: usize = 276;
+pub(crate) const TLAS_NODE_TRANSFORM_CLASS: usize = 340;
+
+// GPUBlasPrimitiveRef
+pub(crate) const PRIMITIVE_REF_GEOMETRY_ID: usize = 0;
+pub(crate) const PRIMITIVE_REF_PRIMITIVE_ID: usize = 4;
+pub(crate) const PRIMITIVE_REF_FLAGS: usize = 8;
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_i32(bytes: &[u8], offset: usize) -> i32 {
+    i32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_f32(bytes: &[u8], offset: usize) -> f32 {
+    f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn write_i32(bytes: &mut [u8], offset: usize, v: i32) {
+    bytes[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+pub(crate) fn write_f32(bytes: &mut [u8], offset: usize, v: f32) {
+    bytes[offset..offset + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn read_vec3(bytes: &[u8], offset: usize) -> Point3 {
+    Point3::new(
+        read_f32(bytes, offset),
+        read_f32(bytes, offset + 4),
+        read_f32(bytes, offset + 8),
+    )
+}
+
+fn write_vec3(bytes: &mut [u8], offset: usize, v: &Point3) {
+    write_f32(bytes, offset, v.x);
+    write_f32(bytes, offset + 4, v.y);
+    write_f32(bytes, offset + 8, v.z);
+}
+
+pub(crate) fn read_aabb(bytes: &[u8], offset: usize) -> AABB {
+    AABB::with_bounds(
+        read_vec3(bytes, offset + AABB_MIN),
+        read_vec3(bytes, offset + AABB_MAX),
+    )
+}
+
+pub(crate) fn write_aabb(bytes: &mut [u8], offset: usize, aabb: &AABB) {
+    write_vec3(bytes, offset + AABB_MIN, &aabb.min);
+    write_vec3(bytes, offset + AABB_MAX, &aabb.max);
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BlasNode {
+    pub aabb: AABB,
+    pub entry_index_or_first_primitive: u32,
+    pub exit_index: u32,
+    pub primitive_count: u32,
+}
+
+impl BlasNode {
+    pub fn is_leaf(&self) -> bool {
+        self.primitive_count > 0
+    }
+}
+
+pub(crate) fn read_blas_node(nodes: &[u8], index: u32) -> BlasNode {
+    let offset = index as usize * blas_node_stride();
+    BlasNode {
+        aabb: read_aabb(nodes, offset + BLAS_NODE_AABB),
+        entry_index_or_first_primitive: read_u32(
+            nodes,
+            offset + BLAS_NODE_ENTRY_INDEX_OR_FIRST_PRIMITIVE,
+        ),
+        exit_index: read_u32(nodes, offset + BLAS_NODE_EXIT_INDEX),
+        primitive_count: read_u32(nodes, offset + BLAS_NODE_PRIMITIVE_COUNT),
+    }
+}
+
+pub(crate) fn write_blas_node_aabb(nodes: &mut [u8], index: u32, aabb: &AABB) {
+    let offset = index as usize * blas_node_stride();
+    write_aabb(nodes, offset + BLAS_NODE_AABB, aabb);
+}
+
+// (geometry_id, primitive_id)
+pub(crate) fn read_primitive_ref(refs: &[u8], index: u32) -> (i32, u32) {
+    let offset = index as usize * primitive_ref_stride();
+    (
+        read_i32(refs, offset + PRIMITIVE_REF_GEOMETRY_ID),
+        read_u32(refs, offset + PRIMITIVE_REF_PRIMITIVE_ID),
+    )
+}
+
+pub(crate) fn read_primitive_ref_flags(refs: &[u8], index: u32) -> GeometryFlags {
+    let offset = index as usize * primitive_ref_stride();
+    GeometryFlags::from_bits_truncate(read_u32(refs, offset + PRIMITIVE_REF_FLAGS))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlasNode {
+    pub aabb: AABB,
+    pub entry_index: u32,
+    pub exit_index: u32,
+    pub is_leaf: bool,
+
+    // leaf data
+    pub mask: u32,
+    pub flags: InstanceFlags,
+    pub instance_id: u32,
+    pub sbt_instance_offset: u32,
+    pub instance_custom_index: i32,
+    // 4x3 column major
+    pub transform_to_world: [f32; 12],
+    pub transform_to_object: [f32; 12],
+    pub blas_geometry_id_offset: u32,
+    pub blas_primitive_ref_offset: u32,
+    pub blas_aabb: AABB,
+    // transform_to_world is the transform at time 0
+    pub motion_type: InstanceMotionType,
+    // at time 0 and 1, 4x3 matrices padded to 16 words or SrtTransforms
+    pub motion_keyframes: [[f32; 16]; 2],
+    // over time with motion, Identity for interior nodes
+    pub transform_class: TransformClass,
+}
+
+fn read_floats<const N: usize>(bytes: &[u8], offset: usize) -> [f32; N] {
+    let mut m = [0f32; N];
+    for (k, v) in m.iter_mut().enumerate() {
+        *v = read_f32(bytes, offset + 4 * k);
+    }
+    m
+}
+
+pub(crate) fn read_tlas_node(nodes: &[u8], index: u32) -> TlasNode {
+    let offset = index as usize * tlas_node_stride();
+    TlasNode {
+        aabb: read_aabb(nodes, offset + TLAS_NODE_AABB),
+        entry_index: read_u32(nodes, offset + TLAS_NODE_ENTRY_INDEX),
+        exit_index: read_u32(nodes, offset + TLAS_NODE_EXIT_INDEX),
+        is_leaf: read_u32(nodes, offset + TLAS_NODE_IS_LEAF) != 0,
+        mask: read_u32(nodes, offset + TLAS_NODE_MASK),
+        flags: InstanceFlags::from_bits_truncate(read_u32(nodes, offset + TLAS_NODE_FLAGS)),
+        instance_id: read_u32(nodes, offset + TLAS_NODE_INSTANCE_ID),
+        sbt_instance_offset: read_u32(nodes, offset + TLAS_NODE_SBT_INSTANCE_OFFSET),
+        instance_custom_index: read_i32(nodes, offset + TLAS_NODE_INSTANCE_CUSTOM_INDEX),
+        transform_to_world: read_floats(nodes, offset + TLAS_NODE_TRANSFORM_TO_WORLD),
+        transform_to_object: read_floats(nodes, offset + TLAS_NODE_TRANSFORM_TO_OBJECT),
+        blas_geometry_id_offset: read_u32(nodes, offset + TLAS_NODE_BLAS_GEOMETRY_ID_OFFSET),
+        blas_primitive_ref_offset: read_u32(nodes, offset + TLAS_NODE_BLAS_PRIMITIVE_REF_OFFSET),
+        blas_aabb: read_aabb(nodes, offset + TLAS_NODE_BLAS_AABB),
+        // nodes are only written by tlas_leaf_node
+        motion_type: InstanceMotionType::try_from(read_u32(nodes, offset + TLAS_NODE_MOTION_TYPE))
+            .unwrap_or(InstanceMotionType::Static),
+        motion_keyframes: [
+            read_floats(nodes, offset + TLAS_NODE_MOTION_START),
+            read_floats(nodes, offset + TLAS_NODE_MOTION_END),
+        ],
+        transform_class: TransformClass::try_from(read_u32(
+            nodes,
+            offset + TLAS_NODE_TRANSFORM_CLASS,
+        ))
+        .unwrap_or(TransformClass::Affine),
+    }
+}
+
+pub(crate) fn write_tlas_node_aabb(nodes: &mut [u8], index: u32, aabb: &AABB) {
+    let offset = index as usize * tlas_node_stride();
+    write_aabb(nodes, offset + TLAS_NODE_AABB, aabb);
+}
+
+pub(crate) fn write_tlas_node(nodes: &mut [u8], index: u32, node: &crate::GPUTlasBvhNode) {
+    let mut bytes = Vec::with_capacity(tlas_node_stride());
+    std430::Writer::new(&mut bytes).write(node).unwrap();
+    let offset = index as usize * tlas_node_stride();
+    nodes[offset..offset + bytes.len()].copy_from_slice(&bytes);
+}
+
+pub(crate) fn blas_node_stride() -> usize {
+    crate::std430_array_stride::<crate::GPUBlasBvhNode>()
+}
+
+pub(crate) fn blas_node_format_stride(format: NodeFormat) -> usize {
+    match format {
+        NodeFormat::Full => blas_node_stride(),
+        NodeFormat::Quantized16 => crate::std430_array_stride::<crate::GPUBlasBvhNodeQuantized16>(),
+        NodeFormat::Wide4 => crate::std430_array_stride::<crate::GPUBlasBvhNodeWide4>(),
+        NodeFormat::Wide8 => crate::std430_array_stride::<crate::GPUBlasBvhNodeWide8>(),
+    }
+}
+
+pub(crate) fn primitive_ref_stride() -> usize {
+    crate::std430_array_stride::<crate::GPUBlasPrimitiveRef>()
+}
+
+pub(crate) fn tlas_node_stride() -> usize {
+    crate::std430_array_stride::<crate::GPUTlasBvhNode>()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_primitive_ref_flags;
+    use super::{blas_node_format_stride, read_blas_node, read_f32, read_primitive_ref, read_u32};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::{GeometryFlags, NodeFormat};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_blas_round_trip() {
+        let boxes: Vec<AABB> = (0..10)
+            .map(|i| {
+                let x = i as f32;
+                AABB::with_bounds(Point3::new(x, -x, 0.5), Point3::new(x + 1.0, 1.0, 2.5))
+            })
+            .collect();
+        let bvh = Bvh::build(&boxes, &BuildOptions::default());
+        let bytes = crate::serialize_blas_nodes(&bvh);
+        assert_eq!(bytes.len(), bvh.nodes.len() * super::blas_node_stride());
+        for (i, n) in bvh.nodes.iter().enumerate() {
+            let node = read_blas_node(&bytes, i as u32);
+            assert_eq!(node.aabb.min, n.aabb.min);
+            assert_eq!(node.aabb.max, n.aabb.max);
+            assert_eq!(node.primitive_count, n.prim_count);
+            if n.is_leaf() {
+                assert_eq!(node.entry_index_or_first_primitive, n.first_prim);
+            } else {
+                assert_eq!(node.entry_index_or_first_primitive, i as u32 + 1);
+            }
+            if (n.exit_index as usize) < bvh.nodes.len() {
+                assert_eq!(node.exit_index, n.exit_index);
+            } else {
+                assert_eq!(node.exit_index, u32::max_value());
+            }
+        }
+
+        let mut refs = Vec::new();
+        let mut writer = crevice::std430::Writer::new(&mut refs);
+        writer
+            .write(&crate::GPUBlasPrimitiveRef {
+                geometry_id: 3,
+                primitive_id: 7,
+                flags: GeometryFlags::OPAQUE.bits(),
+            })
+            .unwrap();
+        writer
+            .write(&crate::GPUBlasPrimitiveRef {
+                geometry_id: -1,
+                primitive_id: 9,
+                flags: 0,
+            })
+            .unwrap();
+        assert_eq!(read_primitive_ref(&refs, 0), (3, 7));
+        assert_eq!(read_primitive_ref(&refs, 1), (-1, 9));
+        assert_eq!(read_primitive_ref_flags(&refs, 0), GeometryFlags::OPAQUE);
+        assert_eq!(read_primitive_ref_flags(&refs, 1), GeometryFlags::NONE);
+    }
+
+    #[test]
+    fn test_wide_node_lanes() {
+        let boxes: Vec<AABB> = (0..3)
+            .map(|i| {
+                let x = i as f32 * 10.0;
+                AABB::with_bounds(Point3::new(x, 0.0, 0.0), Point3::new(x + 1.0, 2.0, 3.0))
+            })
+            .collect();
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&boxes, &options);
+        let (bytes, num_nodes) = crate::serialize_blas_nodes_wide(&bvh, NodeFormat::Wide4);
+        assert_eq!(num_nodes, 1);
+        assert_eq!(bytes.len(), blas_node_format_stride(NodeFormat::Wide4));
+        // min_x, min_y, min_z, max_x, max_y, max_z, child index, primitive count
+        let lane = |v: usize, k: usize| v * 16 + k * 4;
+        let mut min_x: Vec<f32> = (0..3).map(|k| read_f32(&bytes, lane(0, k))).collect();
+        min_x.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(min_x, vec![0.0, 10.0, 20.0]);
+        for k in 0..3 {
+            assert_eq!(read_f32(&bytes, lane(5, k)), 3.0);
+            assert_eq!(read_u32(&bytes, lane(7, k)), 1);
+        }
+        // unused lane
+        assert_eq!(read_f32(&bytes, lane(0, 3)), f32::MAX);
+        assert_eq!(read_u32(&bytes, lane(6, 3)), crate::WIDE_NODE_EMPTY_CHILD);
+        assert_eq!(read_u32(&bytes, lane(7, 3)), 0);
+    }
+}
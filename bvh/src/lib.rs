@@ -1,103 +1,231 @@
-mod utils;
-
-use bvh::aabb::{Bounded, AABB};
-use bvh::bounding_hierarchy::BHShape;
-use bvh::bvh::BVH;
-use bvh::Point3;
-use crevice::std430::{self, AsStd430, Std430};
-use glam::Affine3A;
-use std::collections::HashMap;
-use std::convert::TryFrom;
-use std::mem;
-use wasm_bindgen::prelude::*;
-
 // A macro to provide `println!(..)`-style syntax for `console.log` logging.
+// Does nothing outside of wasm, where there is no console.
 macro_rules! log {
   ( $( $t:tt )* ) => {
+    #[cfg(target_arch = "wasm32")]
     #[allow(unused_unsafe)]
     unsafe {
       web_sys::console::debug_1(&format!( $( $t )* ).into());
       // print!( $( $t )* );
     }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+      let _ = format_args!( $( $t )* );
+    }
   }
 }
 
+mod archive;
+mod builder;
+mod compact;
+mod error;
+mod flags;
+mod instance_bounds;
+mod layout;
+mod motion;
+mod native;
+mod parallel;
+mod query;
+mod refit;
+mod registry;
+mod scene;
+mod scene_build;
+mod stats;
+mod transform;
+mod traverse;
+mod utils;
+mod vertex;
+mod wide;
+
+pub use archive::{load_blas, load_tlas, save_blas, save_tlas, BVH_BLOB_VERSION};
+pub use builder::BuildOptions;
+pub use error::{BvhBuildError, BvhLoadError, DescriptorLocation};
+pub use flags::{GeometryFlags, InstanceFlags};
+pub use instance_bounds::{BlasBounds, InstanceBounds};
+pub use layout::{BlasNode, TlasNode};
+pub use motion::{InstanceMotionType, SrtTransform};
+pub use native::{build_blas_from_geometries, build_blases_from_geometries};
+pub use native::{build_tlas_from_instances, build_tlas_from_instances_with_bounds};
+pub use native::{BuiltBlas, BuiltTlas, Geometry};
+pub use query::{closest_point, overlaps, ClosestPoint, Overlap, Volume};
+pub use registry::{live_staging_buffer_ids, staging_buffer_stats, StagingBufferStats};
+pub use scene::{BvhScene, RayHits};
+pub use scene_build::{BuiltScene, SceneBatch, SceneGeometry};
+pub use stats::{bvh_stats, BvhStats};
+pub use transform::{classify_transform, TransformClass};
+pub use traverse::{trace_ray, BlasBuffers, Hit, HitReport, Ray, Trace};
+pub use traverse::{HIT_KIND_BACK_FACING_TRIANGLE, HIT_KIND_FRONT_FACING_TRIANGLE};
+
+use builder::Bvh;
+use bvh::aabb::{Bounded, AABB};
+use bvh::Point3;
+use crevice::std430::{self, AsStd430, Std430};
+use glam::Affine3A;
+use motion::InstanceMotion;
+use registry::StagingBufferMap;
+use std::cell::RefCell;
+use std::convert::TryFrom;
+use std::mem;
+use std::sync::Arc;
+use vertex::{IndexFormat, VertexFormat};
+use wasm_bindgen::prelude::*;
+
+#[cfg(all(feature = "parallel", target_arch = "wasm32"))]
+pub use wasm_bindgen_rayon::init_thread_pool;
+
+/// An owned buffer in the staging buffer registry, freed when dropped: by
+/// `free()`, or when the JS object is garbage collected if the bindings are
+/// generated with weak refs.
 #[wasm_bindgen]
-#[derive(Clone, Copy)]
+#[derive(Debug)]
 pub struct StagingBuffer {
+    #[wasm_bindgen(readonly)]
     pub id: u32,
 }
 
+/// Encoding of the nodes in `BuiltBvh::serialized`, tags which GPU node
+/// struct a built BVH was serialized with.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeFormat {
+    // GPUBlasBvhNode or GPUTlasBvhNode
+    Full = 0,
+    // GPUBlasBvhNodeQuantized16. 1 was 8 bit bounds, too coarse within the
+    // root box, see compact.rs
+    Quantized16 = 2,
+    // GPUBlasBvhNodeWide4
+    Wide4 = 3,
+    // GPUBlasBvhNodeWide8
+    Wide8 = 4,
+}
+
+/// Owns its staging buffers, they are freed with it.
 #[wasm_bindgen]
 pub struct BuiltBvh {
+    #[wasm_bindgen(skip)]
     pub serialized: StagingBuffer,
     pub num_nodes: u32,
+    pub format: NodeFormat,
+    // root bounds, the quantization frame of quantized formats
+    aabb: AABB,
+    // BLAS only, the primitives referenced by leaf nodes, see GPUBlasPrimitiveRef
+    #[wasm_bindgen(skip)]
+    pub primitive_refs: Option<StagingBuffer>,
+    pub num_primitive_refs: u32,
+    // TLAS only, the leaf node index of each instance, for update_tlas
+    #[wasm_bindgen(skip)]
+    pub instance_leaf_nodes: Option<StagingBuffer>,
+    // primitives (BLAS) or instances (TLAS) left out of the tree, see
+    // PrimitiveClass and TransformClass
+    pub num_inactive: u32,
+    pub num_degenerate: u32,
+    // TLAS only, the bounds of the BLAS of each instance, reused by
+    // update_tlas. Empty for root box bounds, see InstanceBounds.
+    blas_bounds: Vec<Arc<BlasBounds>>,
+}
+
+#[wasm_bindgen]
+impl BuiltBvh {
+    /// Root bounds as [min.xyz, max.xyz], to be passed as the blas_aabb of
+    /// TLAS instances.
+    pub fn aabb(&self) -> Vec<f32> {
+        vec![
+            self.aabb.min.x,
+            self.aabb.min.y,
+            self.aabb.min.z,
+            self.aabb.max.x,
+            self.aabb.max.y,
+            self.aabb.max.z,
+        ]
+    }
+
+    /// Views of the staging buffers, valid until wasm memory grows.
+    pub fn serialized_view(&self) -> JsValue {
+        self.serialized.u8_view()
+    }
+
+    /// Null for TLASes.
+    pub fn primitive_refs_view(&self) -> JsValue {
+        self.primitive_refs
+            .as_ref()
+            .map_or(JsValue::null(), |refs| refs.u8_view())
+    }
+
+    /// Null for BLASes.
+    pub fn instance_leaf_nodes_view(&self) -> JsValue {
+        self.instance_leaf_nodes
+            .as_ref()
+            .map_or(JsValue::null(), |leaf_nodes| leaf_nodes.u8_view())
+    }
 }
 
 #[wasm_bindgen]
 impl StagingBuffer {
-    pub fn free(&self) {
-        staging_buffers_map().remove(&self.id);
+    // Reads the buffer, no staging buffer can be allocated or freed in `f`.
+    fn with_buffer<R>(&self, f: impl FnOnce(&Vec<u8>) -> R) -> R {
+        with_staging_buffers(|map| f(map.get(self.id).expect("stale staging buffer")))
     }
 
-    fn buffer(&self) -> &mut Vec<u8> {
-        staging_buffers_map().get_mut(&self.id).unwrap()
+    // Writes the buffer. It is taken out of the registry while `f` runs and
+    // reads as empty there.
+    fn with_buffer_mut<R>(&self, f: impl FnOnce(&mut Vec<u8>) -> R) -> R {
+        let take = |map: &mut StagingBufferMap| {
+            mem::take(map.get_mut(self.id).expect("stale staging buffer"))
+        };
+        let mut buffer = with_staging_buffers_mut(take);
+        let result = f(&mut buffer);
+        with_staging_buffers_mut(|map| *map.get_mut(self.id).unwrap() = buffer);
+        result
     }
 
     pub fn u8_view(&self) -> JsValue {
-        if let Some(buf) = staging_buffers_map().get_mut(&self.id) {
-            let array = unsafe { js_sys::Uint8Array::view(buf) };
-            return JsValue::from(array);
-        }
-        return JsValue::null();
+        with_staging_buffers(|map| match map.get(self.id) {
+            Some(buf) => JsValue::from(unsafe { js_sys::Uint8Array::view(buf) }),
+            None => JsValue::null(),
+        })
     }
 
+    /// Throws when no staging buffer id is left.
     #[wasm_bindgen(constructor)]
-    pub fn new(byte_length: usize) -> Self {
-        //} Box<[JsValue]> {
-        let map = staging_buffers_map();
-        let buffer: Vec<u8> = vec![0; byte_length as usize];
-        let id = unsafe { NEXT_BUFFER_ID };
-        map.insert(id, buffer);
-        unsafe {
-            NEXT_BUFFER_ID += 1;
-        }
-        log!("alloacted buffer of size {}: {}", id, byte_length);
-        return StagingBuffer { id };
+    pub fn new(byte_length: usize) -> Result<StagingBuffer, JsValue> {
+        let buffer = Self::from_existing_buffer(vec![0; byte_length])?;
+        log!("alloacted buffer of size {}: {}", buffer.id, byte_length);
+        Ok(buffer)
     }
 
-    fn from_existing_buffer(buf: Vec<u8>) -> Self {
-        let map = staging_buffers_map();
-        let id = unsafe { NEXT_BUFFER_ID };
-        map.insert(id, buf);
-        unsafe {
-            NEXT_BUFFER_ID += 1;
-        }
-        return StagingBuffer { id };
+    fn from_existing_buffer(buf: Vec<u8>) -> Result<Self, BvhBuildError> {
+        let id = with_staging_buffers_mut(|map| map.insert(buf))?;
+        Ok(StagingBuffer { id })
     }
 }
 
-type StagingBufferMap = HashMap<u32, Vec<u8>>;
-// lazy_static! {
-//     static ref STAGING_BUFFERS: Mutex<StagingBufferMap> = Mutex::new(HashMap::new());
-// }
-// STAGING_BUFFERS.lock().unwrap();
-
-static mut NEXT_BUFFER_ID: u32 = 0;
-static mut STAGING_BUFFERS: Option<StagingBufferMap> = None;
-fn staging_buffers_map() -> &'static mut StagingBufferMap {
-    if let Some(b) = unsafe { &mut STAGING_BUFFERS } {
-        return b;
-    }
-    let b = StagingBufferMap::new();
-    unsafe {
-        STAGING_BUFFERS = Some(b);
-        let x = STAGING_BUFFERS.as_mut().unwrap();
-        x
+impl Drop for StagingBuffer {
+    fn drop(&mut self) {
+        // the registry may be gone already when dropped at thread exit
+        let _ = STAGING_BUFFERS.try_with(|map| map.borrow_mut().remove(self.id));
     }
 }
 
-#[derive(Debug, PartialEq)]
+thread_local! {
+    // The registry of the JS thread. The rayon workers of the `parallel`
+    // feature have their own, empty ones: parallel builds borrow the map of
+    // the JS thread as a shared `&StagingBufferMap` before calling
+    // `parallel::map` instead.
+    static STAGING_BUFFERS: RefCell<StagingBufferMap> = RefCell::new(StagingBufferMap::new());
+}
+
+// Reads the registry. Staging buffers cannot be allocated or freed in `f`,
+// that panics with "already borrowed", so BuiltBvhs are created after it
+// returns.
+fn with_staging_buffers<R>(f: impl FnOnce(&StagingBufferMap) -> R) -> R {
+    STAGING_BUFFERS.with(|map| f(&map.borrow()))
+}
+
+fn with_staging_buffers_mut<R>(f: impl FnOnce(&mut StagingBufferMap) -> R) -> R {
+    STAGING_BUFFERS.with(|map| f(&mut map.borrow_mut()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum GeometryType {
     Triangle = 0,
     Aabb = 1,
@@ -122,12 +250,25 @@ enum GeometryDescriptorField {
     VbufByteOffset = 3,
     IbufId = 4,
     IbufByteOffset = 5,
+    VertexFormat = 6,
+    VertexStride = 7,
+    IndexFormat = 8,
+    // triangles only, 0 or 1
+    HasTransform = 9,
+    // 4x3 column major f32, see TlasInstanceDescriptor::transform_to_world_4x3
+    Transform4x3 = 10,
+    // GeometryFlags
+    Flags = 22,
+    // triangles only, positions at time 1 for motion blur, same format and
+    // stride as VbufId, -1 if the geometry does not move
+    EndVbufId = 23,
+    EndVbufByteOffset = 24,
 
-    NumFields = 6,
+    NumFields = 25,
 }
 
 // TODO: maybe make this an enum struct, Aabb/Triangle
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Primitive<'a> {
     blas_local_geometry_id: u32,
     within_blas_primitive_id: u32,
@@ -136,83 +277,216 @@ struct Primitive<'a> {
     primitive_id: u32,
     // geometry_descriptor: &'a [i32; 3],
     geometry_type: GeometryType,
-    vbuf: &'a [f32],
-    ibuf: Option<&'a [u32]>,
+    vbuf: &'a [u8],
+    // vertices at time 1, triangles only
+    end_vbuf: Option<&'a [u8]>,
+    vertex_format: VertexFormat,
+    vertex_stride: usize,
+    ibuf: Option<&'a [u8]>,
+    index_format: IndexFormat,
+    // 4x3 column major, applied to the vertices
+    transform: Option<&'a [f32]>,
+    flags: GeometryFlags,
+}
+
+// Whether a primitive goes into the tree. Inactive primitives are those with
+// a NaN first vertex x (triangles) or min.x (AABBs), like Vulkan; degenerate
+// ones are zero-area triangles and inverted AABBs. Neither can be hit.
+#[derive(Debug, Clone, Copy)]
+enum PrimitiveClass {
+    Active(AABB),
+    Inactive,
+    Degenerate,
+}
+
+impl<'a> Primitive<'a> {
+    fn classify(&self) -> PrimitiveClass {
+        if self.geometry_type == GeometryType::Triangle {
+            let v = self.triangle_vertices();
+            if v[0].x.is_nan() {
+                return PrimitiveClass::Inactive;
+            }
+            // also rejects NaNs in the other components
+            let has_area = |v: [Point3; 3]| (v[1] - v[0]).cross(v[2] - v[0]).length_squared() > 0.0;
+            // moving triangles only need an area at one of the keyframes
+            let degenerate = match self.end_triangle_vertices() {
+                Some(e) => e.iter().any(|p| p.is_nan()) || !(has_area(v) || has_area(e)),
+                None => !has_area(v),
+            };
+            if degenerate {
+                return PrimitiveClass::Degenerate;
+            }
+        } else {
+            let aabb = self.aabb();
+            if aabb.min.x.is_nan() {
+                return PrimitiveClass::Inactive;
+            }
+            if !(0..3).all(|a| aabb.min[a] <= aabb.max[a]) {
+                return PrimitiveClass::Degenerate;
+            }
+        }
+        PrimitiveClass::Active(self.aabb())
+    }
+
+    // The vertices at time 0.
+    fn triangle_vertices(&self) -> [Point3; 3] {
+        self.transform_vertices(self.vertices_in(self.vbuf))
+    }
+
+    // The vertices at time 1 of a moving triangle.
+    fn end_triangle_vertices(&self) -> Option<[Point3; 3]> {
+        self.end_vbuf
+            .map(|vbuf| self.transform_vertices(self.vertices_in(vbuf)))
+    }
+
+    // The vertices at `time`, interpolated before they are transformed like
+    // getTriangleVertexPositions in geom.glsl.
+    fn triangle_vertices_at(&self, time: f32) -> [Point3; 3] {
+        let v = self.vertices_in(self.vbuf);
+        let v = match self.end_vbuf {
+            Some(vbuf) => {
+                let e = self.vertices_in(vbuf);
+                [0, 1, 2].map(|i| v[i] * (1.0 - time) + e[i] * time)
+            }
+            None => v,
+        };
+        self.transform_vertices(v)
+    }
+
+    // The untransformed vertices in `vbuf`, the start or end vertices.
+    fn vertices_in(&self, vbuf: &[u8]) -> [Point3; 3] {
+        let offset = (3 * self.primitive_id) as usize;
+        let indices = if let Some(ibuf) = self.ibuf {
+            [
+                self.index_format.read_index(ibuf, offset),
+                self.index_format.read_index(ibuf, offset + 1),
+                self.index_format.read_index(ibuf, offset + 2),
+            ]
+        } else {
+            [offset, offset + 1, offset + 2]
+        };
+        indices.map(|i| {
+            self.vertex_format
+                .decode_position(vbuf, i * self.vertex_stride)
+        })
+    }
+
+    fn transform_vertices(&self, vertices: [Point3; 3]) -> [Point3; 3] {
+        match self.transform {
+            Some(m) => {
+                let m = Affine3A::from_cols_slice(m);
+                vertices.map(|v| m.transform_point3(v))
+            }
+            None => vertices,
+        }
+    }
+
+    // Bounds of the parts below and above the plane at `pos` along `axis`,
+    // for spatial splits, which moving triangles are built without.
+    fn split_aabb(&self, axis: usize, pos: f32) -> (AABB, AABB) {
+        let mut below = AABB::empty();
+        let mut above = AABB::empty();
+        if self.geometry_type == GeometryType::Triangle {
+            let v = self.triangle_vertices();
+            for i in 0..3 {
+                let (a, b) = (v[i], v[(i + 1) % 3]);
+                if a[axis] <= pos {
+                    below.grow_mut(&a);
+                }
+                if a[axis] >= pos {
+                    above.grow_mut(&a);
+                }
+                // edge crossing the plane
+                if (a[axis] < pos && b[axis] > pos) || (a[axis] > pos && b[axis] < pos) {
+                    let t = (pos - a[axis]) / (b[axis] - a[axis]);
+                    let mut p = a + (b - a) * t;
+                    p[axis] = pos;
+                    below.grow_mut(&p);
+                    above.grow_mut(&p);
+                }
+            }
+        } else {
+            let aabb = self.aabb();
+            if aabb.min[axis] <= pos {
+                below = aabb;
+                below.max[axis] = below.max[axis].min(pos);
+            }
+            if aabb.max[axis] >= pos {
+                above = aabb;
+                above.min[axis] = above.min[axis].max(pos);
+            }
+        }
+        (below, above)
+    }
 }
 
 impl<'a> Bounded for Primitive<'a> {
     fn aabb(&self) -> AABB {
         if self.geometry_type == GeometryType::Triangle {
-            let mut indices = vec![0; 3];
-            let offset = (3 * self.primitive_id) as usize;
-            if let Some(ibuf) = self.ibuf {
-                indices[0] = ibuf[offset] as usize;
-                indices[1] = ibuf[offset + 1] as usize;
-                indices[2] = ibuf[offset + 2] as usize;
-            } else {
-                indices[0] = offset;
-                indices[1] = offset + 1;
-                indices[2] = offset + 2;
-            }
+            // a moving triangle stays within the bounds of its keyframes
             let mut aabb = AABB::empty();
-            for i in 0..3 {
-                // NOTE: hardcoded 3xfloats vbo stride for our compact staging buffers
-                let vi = indices[i] * 3;
-                aabb.grow_mut(&Point3::new(
-                    self.vbuf[vi],
-                    self.vbuf[vi + 1],
-                    self.vbuf[vi + 2],
-                ));
+            for v in self
+                .triangle_vertices()
+                .iter()
+                .chain(self.end_triangle_vertices().iter().flatten())
+            {
+                aabb.grow_mut(v);
             }
             aabb
         } else {
             // staging buffers for AABBs are all unique
             AABB::with_bounds(
-                Point3::new(self.vbuf[0], self.vbuf[1], self.vbuf[2]),
-                Point3::new(self.vbuf[3], self.vbuf[4], self.vbuf[5]),
+                VertexFormat::Float32x3.decode_position(self.vbuf, 0),
+                VertexFormat::Float32x3.decode_position(self.vbuf, 12),
             )
         }
     }
 }
 
-impl<'a> BHShape for Primitive<'a> {
-    fn set_bh_node_index(&mut self, _index: usize) {
-        // dont care?
-        // self.node_index = index;
-    }
-
-    fn bh_node_index(&self) -> usize {
-        panic!("unimplemented bh_node_index")
-        //  self.node_index
-    }
-}
-
 #[derive(Debug)]
 struct TlasInstanceDescriptor {
     mask: u32,
-    flags: u32,
+    flags: InstanceFlags,
     instance_id: u32,
     sbt_instance_offset: u32,
     instance_custom_index: i32,
+    // at time 0
     transform_to_world_4x3: [f32; 12],
+    motion: InstanceMotion,
 
     blas_entry_index: u32,
     blas_geometry_id_offset: u32,
-    aabb: AABB, // aabb(transform_to_world * blas_aabb)
+    blas_primitive_ref_offset: u32,
+    blas_aabb: AABB,
+    aabb: AABB, // aabb(transform_to_world * blas bounds), over time with motion
+    transform_class: TransformClass, // over time with motion
 }
 
-#[derive(Debug)]
+/// A TLAS instance, laid out like the instances of a TLAS descriptor
+/// written by wasm_bvh_builder.ts.
+#[derive(Debug, Clone, Copy)]
 #[repr(C)]
-struct TlasInstanceDescriptorJsInput {
-    mask: u32,
-    flags: u32,
-    instance_id: u32,
-    sbt_instance_offset: u32,
-    instance_custom_index: i32,
-    blas_entry_index: u32,
-    blas_geometry_id_offset: u32,
-    blas_aabb: [f32; 6],
-    transform_to_world_4x3: [f32; 12], // 4x3 column major
+pub struct TlasInstance {
+    pub mask: u32,
+    /// InstanceFlags bits.
+    pub flags: u32,
+    pub instance_id: u32,
+    pub sbt_instance_offset: u32,
+    pub instance_custom_index: i32,
+    /// The node and offsets of the BLAS within the concatenated BLASes.
+    pub blas_entry_index: u32,
+    pub blas_geometry_id_offset: u32,
+    pub blas_primitive_ref_offset: u32,
+    /// Root bounds of the BLAS as min.xyz, max.xyz, see `BuiltBvh::aabb`.
+    pub blas_aabb: [f32; 6],
+    /// 4x3 column major, at time 0 for moving instances.
+    pub transform_to_world_4x3: [f32; 12],
+    /// InstanceMotionType, 0 for instances that do not move.
+    pub motion_type: u32,
+    /// Matrix motion: the 4x3 column major transform at time 1 in the first
+    /// 12 words. SRT motion: the `SrtTransform`s at time 0 and 1, which
+    /// replace `transform_to_world_4x3`.
+    pub motion_keyframes: [f32; 32],
 }
 
 // #[wasm_bindgen(typescript_custom_section)]
@@ -226,124 +500,756 @@ impl Bounded for TlasInstanceDescriptor {
     }
 }
 
-impl BHShape for TlasInstanceDescriptor {
-    fn set_bh_node_index(&mut self, _index: usize) {
-        // dont care?
-        // self.node_index = index;
-    }
-
-    fn bh_node_index(&self) -> usize {
-        panic!("unimplemented bh_node_index")
-        //  self.node_index
-    }
-}
-
 // TODO: buffer data layout?
 // see common.glsl
 #[derive(Debug, AsStd430)]
 struct GPUBlasBvhNode {
     aabb: GPUAabb,
-    entry_index_or_primitive_id: u32,
+    // interior: offset of the first child
+    // leaf: offset of the first primitive in GPUBlasPrimitiveRef array
+    entry_index_or_first_primitive: u32,
     exit_index: u32,
+    // 0 for interior nodes
+    primitive_count: u32,
+}
+
+// Bounds quantized to 16 bits per component within the BLAS root box, see
+// compact.rs and common.glsl.
+#[derive(Debug, AsStd430)]
+struct GPUBlasBvhNodeQuantized16 {
+    // min.x | min.y << 16
+    bounds0: u32,
+    // min.z | max.x << 16
+    bounds1: u32,
+    // max.y | max.z << 16
+    bounds2: u32,
+    entry_index_or_first_primitive: u32,
+    exit_index: u32,
+    primitive_count: u32,
+}
+
+// Child boxes of a 4-wide node stored side by side, one lane per child, see
+// wide.rs and common.glsl. Unused lanes have an empty box and
+// WIDE_NODE_EMPTY_CHILD as index.
+#[derive(Debug, AsStd430)]
+struct GPUBlasBvhNodeWide4 {
+    min_x: mint::Vector4<f32>,
+    min_y: mint::Vector4<f32>,
+    min_z: mint::Vector4<f32>,
+    max_x: mint::Vector4<f32>,
+    max_y: mint::Vector4<f32>,
+    max_z: mint::Vector4<f32>,
+    // interior child: node index, leaf child: first GPUBlasPrimitiveRef
+    child_index_or_first_primitive: mint::Vector4<u32>,
+    // 0 for interior children
+    child_primitive_count: mint::Vector4<u32>,
+}
+
+// Same as GPUBlasBvhNodeWide4 with children 0-3 in the _lo and 4-7 in the
+// _hi lanes.
+#[derive(Debug, AsStd430)]
+struct GPUBlasBvhNodeWide8 {
+    min_x_lo: mint::Vector4<f32>,
+    min_x_hi: mint::Vector4<f32>,
+    min_y_lo: mint::Vector4<f32>,
+    min_y_hi: mint::Vector4<f32>,
+    min_z_lo: mint::Vector4<f32>,
+    min_z_hi: mint::Vector4<f32>,
+    max_x_lo: mint::Vector4<f32>,
+    max_x_hi: mint::Vector4<f32>,
+    max_y_lo: mint::Vector4<f32>,
+    max_y_hi: mint::Vector4<f32>,
+    max_z_lo: mint::Vector4<f32>,
+    max_z_hi: mint::Vector4<f32>,
+    child_index_or_first_primitive_lo: mint::Vector4<u32>,
+    child_index_or_first_primitive_hi: mint::Vector4<u32>,
+    child_primitive_count_lo: mint::Vector4<u32>,
+    child_primitive_count_hi: mint::Vector4<u32>,
+}
+
+const WIDE_NODE_EMPTY_CHILD: u32 = u32::MAX;
+
+// see common.glsl
+#[derive(Debug, AsStd430)]
+struct GPUBlasPrimitiveRef {
     geometry_id: i32,
+    primitive_id: u32,
+    // GeometryFlags of the geometry
+    flags: u32,
 }
 
-const INTERIOR_NODE_GEOMETRY_ID: i32 = -1;
+fn staging_buffer(
+    map: &StagingBufferMap,
+    buffer_id: u32,
+    at: DescriptorLocation,
+) -> Result<&Vec<u8>, BvhBuildError> {
+    map.get(buffer_id).ok_or(if map.is_stale(buffer_id) {
+        BvhBuildError::StaleBufferId { at, buffer_id }
+    } else {
+        BvhBuildError::UnknownBufferId { at, buffer_id }
+    })
+}
 
-#[wasm_bindgen]
-pub fn build_blas(blas_descriptor_buffer_id: u32) -> BuiltBvh {
-    utils::set_panic_hook();
-    let map = staging_buffers_map();
-    // TODO: error handling
-    let buf = map.get(&blas_descriptor_buffer_id).unwrap();
-    let buf_i32_le: &[i32] = unsafe { buf.align_to().1 };
+// Reinterprets a whole staging buffer as a slice of T.
+fn cast_staging_buffer<T>(
+    buf: &[u8],
+    buffer_id: u32,
+    at: DescriptorLocation,
+) -> Result<&[T], BvhBuildError> {
+    let (prefix, body, suffix) = unsafe { buf.align_to::<T>() };
+    if !prefix.is_empty() {
+        return Err(BvhBuildError::MisalignedBuffer {
+            at,
+            buffer_id,
+            alignment: mem::align_of::<T>(),
+        });
+    }
+    if !suffix.is_empty() {
+        return Err(BvhBuildError::LengthMismatch {
+            at,
+            expected: body.len() * mem::size_of::<T>(),
+            actual: buf.len(),
+        });
+    }
+    Ok(body)
+}
+
+// The bytes of a staging buffer from `byte_offset`, which must be a multiple
+// of `alignment` for the word reads in geom.glsl.
+fn staging_buffer_from(
+    buf: &[u8],
+    buffer_id: u32,
+    byte_offset: i32,
+    alignment: usize,
+    at: DescriptorLocation,
+) -> Result<&[u8], BvhBuildError> {
+    let byte_offset = byte_offset as u32 as usize;
+    if byte_offset > buf.len() {
+        return Err(BvhBuildError::IndexOutOfRange {
+            at,
+            index: byte_offset,
+            len: buf.len(),
+        });
+    }
+    if byte_offset % alignment != 0 {
+        return Err(BvhBuildError::MisalignedBuffer {
+            at,
+            buffer_id,
+            alignment,
+        });
+    }
+    Ok(&buf[byte_offset..])
+}
+
+// Collects the primitives of all geometries, in geometry order. Every vertex
+// and index referenced by a primitive is checked to be within its buffer.
+fn blas_primitives<'a>(
+    map: &'a StagingBufferMap,
+    blas_descriptor_buffer_id: u32,
+) -> Result<Vec<Primitive<'a>>, BvhBuildError> {
+    Ok(geometry_primitives(&blas_geometries(
+        map,
+        blas_descriptor_buffer_id,
+    )?))
+}
+
+// The primitives of geometries returned by blas_geometries, in geometry
+// order.
+fn geometry_primitives<'a>(geometries: &[(Primitive<'a>, u32)]) -> Vec<Primitive<'a>> {
+    let num_total_primitives = geometries.iter().map(|(_, np)| *np as usize).sum();
+    let mut primitives = Vec::<Primitive>::with_capacity(num_total_primitives);
+    for (g, np) in geometries {
+        for pi in 0..*np {
+            primitives.push(Primitive {
+                primitive_id: pi,
+                within_blas_primitive_id: primitives.len() as u32,
+                ..*g
+            });
+        }
+    }
+    primitives
+}
+
+// The geometries of a BLAS descriptor in order, each as its first primitive,
+// with its primitive count.
+fn blas_geometries<'a>(
+    map: &'a StagingBufferMap,
+    blas_descriptor_buffer_id: u32,
+) -> Result<Vec<(Primitive<'a>, u32)>, BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    let buf = staging_buffer(map, blas_descriptor_buffer_id, at)?;
+    let buf_i32_le: &[i32] = cast_staging_buffer(buf, blas_descriptor_buffer_id, at)?;
+    if buf_i32_le.len() < 2 {
+        return Err(BvhBuildError::LengthMismatch {
+            at,
+            expected: 2,
+            actual: buf_i32_le.len(),
+        });
+    }
     let num_geoms = buf_i32_le[0];
     let num_total_primitives = buf_i32_le[1];
     log!("blas geom desc: {}, {}", num_geoms, num_total_primitives);
-    assert!(num_geoms > 0 && num_total_primitives > 0,);
-    assert!(buf_i32_le.len() as i32 == 2 + num_geoms * (GeometryDescriptorField::NumFields as i32));
-    let mut primitives = Vec::<Primitive>::with_capacity(num_total_primitives as usize);
+    // empty BLASes are allowed
+    for count in [num_geoms, num_total_primitives] {
+        if count < 0 {
+            return Err(BvhBuildError::InvalidCount { at, count });
+        }
+    }
+    let expected_len = 2 + num_geoms as usize * (GeometryDescriptorField::NumFields as usize);
+    if buf_i32_le.len() != expected_len {
+        return Err(BvhBuildError::LengthMismatch {
+            at,
+            expected: expected_len,
+            actual: buf_i32_le.len(),
+        });
+    }
+    let mut geometries = Vec::with_capacity(num_geoms as usize);
+    let mut num_primitives = 0usize;
     for gi in 0..num_geoms as u32 {
+        let at = DescriptorLocation::Geometry(gi);
         let offset = 2 + (GeometryDescriptorField::NumFields as usize) * gi as usize;
-        // [geom_type, num_primitives, vbuf_id, ibuf_id]
+        // [geom_type, num_primitives, vbuf_id, vbuf_byte_offset, ibuf_id, ibuf_byte_offset,
+        //  vertex_format, vertex_stride, index_format, has_transform, transform_4x3, flags,
+        //  end_vbuf_id, end_vbuf_byte_offset]
         let geom: &[i32] =
             &buf_i32_le[offset..offset + (GeometryDescriptorField::NumFields as usize)];
+        let geom_f32: &[f32] = unsafe { geom.align_to().1 };
+        let geometry_type = geom[GeometryDescriptorField::Type as usize];
+        let geometry_type = GeometryType::try_from(geometry_type)
+            .map_err(|_| BvhBuildError::UnknownGeometryType { at, geometry_type })?;
         let np = geom[GeometryDescriptorField::NumPrimitives as usize];
-        let vbuf = map
-            .get(&(geom[GeometryDescriptorField::VbufId as usize] as u32))
-            .unwrap();
-        let vbuf_byte_offset = geom[GeometryDescriptorField::VbufByteOffset as usize] as u32;
-        let vbuf_f32_le: &[f32] = unsafe { vbuf[(vbuf_byte_offset as usize)..].align_to().1 };
-        let mut ibuf_u32_le: Option<&[u32]> = None;
-        if geom[GeometryDescriptorField::Type as usize] == GeometryType::Triangle as i32
-            && geom[GeometryDescriptorField::IbufId as usize] >= 0
-        {
-            let ibuf = map
-                .get(&(geom[GeometryDescriptorField::IbufId as usize] as u32))
-                .unwrap();
-            let ibuf_byte_offset = geom[GeometryDescriptorField::IbufByteOffset as usize] as u32;
-            ibuf_u32_le = Some(unsafe { ibuf[(ibuf_byte_offset as usize)..].align_to().1 });
+        if np < 0 {
+            return Err(BvhBuildError::InvalidCount { at, count: np });
         }
-        for pi in 0..np as u32 {
-            primitives.push(Primitive {
-                blas_local_geometry_id: gi,
-                primitive_id: pi,
-                within_blas_primitive_id: primitives.len() as u32,
-                geometry_type: GeometryType::try_from(geom[GeometryDescriptorField::Type as usize])
-                    .unwrap(),
-                vbuf: vbuf_f32_le,
-                ibuf: ibuf_u32_le,
+        let flags = geom[GeometryDescriptorField::Flags as usize] as u32;
+        let flags =
+            GeometryFlags::from_bits(flags).ok_or(BvhBuildError::InvalidFlags { at, flags })?;
+        let vbuf_id = geom[GeometryDescriptorField::VbufId as usize] as u32;
+        let vbuf = staging_buffer_from(
+            staging_buffer(map, vbuf_id, at)?,
+            vbuf_id,
+            geom[GeometryDescriptorField::VbufByteOffset as usize],
+            4,
+            at,
+        )?;
+
+        let mut vertex_format = VertexFormat::Float32x3;
+        let mut vertex_stride = 0;
+        let mut end_vbuf = None;
+        let mut ibuf_le: Option<&[u8]> = None;
+        let mut index_format = IndexFormat::Uint32;
+        let mut transform = None;
+        if geometry_type == GeometryType::Triangle {
+            let format = geom[GeometryDescriptorField::VertexFormat as usize];
+            vertex_format = VertexFormat::try_from(format)
+                .map_err(|_| BvhBuildError::UnknownFormat { at, format })?;
+            vertex_stride = geom[GeometryDescriptorField::VertexStride as usize] as u32 as usize;
+            if vertex_stride < vertex_format.byte_size() {
+                return Err(BvhBuildError::LengthMismatch {
+                    at,
+                    expected: vertex_format.byte_size(),
+                    actual: vertex_stride,
+                });
+            }
+            if vertex_stride % 4 != 0 {
+                return Err(BvhBuildError::MisalignedBuffer {
+                    at,
+                    buffer_id: vbuf_id,
+                    alignment: 4,
+                });
+            }
+            let count_vertices = |vbuf: &[u8]| {
+                if vbuf.len() >= vertex_format.byte_size() {
+                    (vbuf.len() - vertex_format.byte_size()) / vertex_stride + 1
+                } else {
+                    0
+                }
+            };
+            let mut num_vertices = count_vertices(vbuf);
+            let end_vbuf_id = geom[GeometryDescriptorField::EndVbufId as usize];
+            if end_vbuf_id >= 0 {
+                let end = staging_buffer_from(
+                    staging_buffer(map, end_vbuf_id as u32, at)?,
+                    end_vbuf_id as u32,
+                    geom[GeometryDescriptorField::EndVbufByteOffset as usize],
+                    4,
+                    at,
+                )?;
+                // indices must be within both keyframes
+                num_vertices = num_vertices.min(count_vertices(end));
+                end_vbuf = Some(end);
+            }
+            let num_indices = 3 * np as usize;
+
+            let ibuf_id = geom[GeometryDescriptorField::IbufId as usize];
+            if ibuf_id >= 0 {
+                let format = geom[GeometryDescriptorField::IndexFormat as usize];
+                index_format = IndexFormat::try_from(format)
+                    .map_err(|_| BvhBuildError::UnknownFormat { at, format })?;
+                let ibuf = staging_buffer_from(
+                    staging_buffer(map, ibuf_id as u32, at)?,
+                    ibuf_id as u32,
+                    geom[GeometryDescriptorField::IbufByteOffset as usize],
+                    index_format.byte_size(),
+                    at,
+                )?;
+                if ibuf.len() < num_indices * index_format.byte_size() {
+                    return Err(BvhBuildError::LengthMismatch {
+                        at,
+                        expected: num_indices * index_format.byte_size(),
+                        actual: ibuf.len(),
+                    });
+                }
+                for i in 0..num_indices {
+                    let index = index_format.read_index(ibuf, i);
+                    if index >= num_vertices {
+                        return Err(BvhBuildError::IndexOutOfRange {
+                            at,
+                            index,
+                            len: num_vertices,
+                        });
+                    }
+                }
+                ibuf_le = Some(ibuf);
+            } else if num_indices > num_vertices {
+                return Err(BvhBuildError::IndexOutOfRange {
+                    at,
+                    index: num_indices - 1,
+                    len: num_vertices,
+                });
+            }
+
+            if geom[GeometryDescriptorField::HasTransform as usize] != 0 {
+                let t = GeometryDescriptorField::Transform4x3 as usize;
+                transform = Some(&geom_f32[t..t + 12]);
+            }
+        } else if np > 0 && vbuf.len() < 24 {
+            // min.xyz, max.xyz
+            return Err(BvhBuildError::LengthMismatch {
+                at,
+                expected: 24,
+                actual: vbuf.len(),
             });
         }
-    }
 
-    // log!("building from primitives: {:?}", primitives);
-    let bvh = BVH::build(&mut primitives);
-    let num_bvh_nodes = bvh.nodes.len() as u32;
-    // log!("bvh tree: {:?}", bvh.nodes);
+        geometries.push((
+            Primitive {
+                blas_local_geometry_id: gi,
+                primitive_id: 0,
+                within_blas_primitive_id: num_primitives as u32,
+                geometry_type,
+                vbuf,
+                end_vbuf,
+                vertex_format,
+                vertex_stride,
+                ibuf: ibuf_le,
+                index_format,
+                transform,
+                flags,
+            },
+            np as u32,
+        ));
+        num_primitives += np as usize;
+    }
+    if num_primitives != num_total_primitives as usize {
+        return Err(BvhBuildError::LengthMismatch {
+            at: DescriptorLocation::Descriptor,
+            expected: num_total_primitives as usize,
+            actual: num_primitives,
+        });
+    }
+    Ok(geometries)
+}
 
+fn std430_array_stride<T: AsStd430>() -> usize {
     let mut sizer = std430::Sizer::new();
-    sizer.add::<GPUBlasBvhNode>();
-    let node_array_stride = sizer.add::<GPUBlasBvhNode>();
-    let staging_buffer = StagingBuffer::new(num_bvh_nodes as usize * node_array_stride);
-    let staging_buffer_u8 = staging_buffer.buffer();
-    staging_buffer_u8.truncate(0); // !!
-    let mut writer = std430::Writer::new(staging_buffer_u8);
-
-    let mut blas_node_ctor = |aabb: &AABB, entry, mut exit, within_blas_primitive_id| {
-        if exit >= num_bvh_nodes {
-            exit = u32::max_value();
-        }
-        let node = if entry == u32::max_value() {
-            // leaf
-            let p = &primitives[within_blas_primitive_id as usize];
-            // currently leaf only contains single shape/primitive
-            GPUBlasBvhNode {
-                aabb: (&p.aabb()).into(),                    // not inf->-inf
-                entry_index_or_primitive_id: p.primitive_id, // local
-                exit_index: exit,
-                geometry_id: p.blas_local_geometry_id as i32,
-            }
+    sizer.add::<T>();
+    sizer.add::<T>()
+}
+
+fn serialize_blas_nodes(bvh: &Bvh) -> Vec<u8> {
+    let num_bvh_nodes = bvh.nodes.len() as u32;
+    let mut serialized =
+        Vec::with_capacity(num_bvh_nodes as usize * std430_array_stride::<GPUBlasBvhNode>());
+    let mut writer = std430::Writer::new(&mut serialized);
+    for (i, n) in bvh.nodes.iter().enumerate() {
+        let exit = if n.exit_index >= num_bvh_nodes {
+            u32::max_value()
         } else {
-            GPUBlasBvhNode {
-                aabb: aabb.into(),
-                entry_index_or_primitive_id: entry,
-                exit_index: exit,
-                geometry_id: INTERIOR_NODE_GEOMETRY_ID, // interior
-            }
+            n.exit_index
+        };
+        let node = GPUBlasBvhNode {
+            aabb: (&n.aabb).into(), // not inf->-inf
+            entry_index_or_first_primitive: if n.is_leaf() {
+                n.first_prim
+            } else {
+                i as u32 + 1
+            },
+            exit_index: exit,
+            primitive_count: n.prim_count,
         };
         writer.write(&node).unwrap();
+    }
+    if bvh.nodes.is_empty() {
+        // a root that is never hit, see intersect_aabb
+        writer
+            .write(&GPUBlasBvhNode {
+                aabb: (&AABB::empty()).into(),
+                entry_index_or_first_primitive: 1,
+                exit_index: u32::max_value(),
+                primitive_count: 0,
+            })
+            .unwrap();
+    }
+    let aligned_size = align_to(serialized.len(), Std430GPUBlasBvhNode::ALIGNMENT);
+    serialized.resize(aligned_size, 0);
+    serialized
+}
+
+// Per-lane values of a wide node, unused lanes are padded.
+struct WideLanes {
+    min: [Vec<f32>; 3],
+    max: [Vec<f32>; 3],
+    child_index_or_first_primitive: Vec<u32>,
+    child_primitive_count: Vec<u32>,
+}
+
+impl WideLanes {
+    fn new(node: &wide::WideNode, width: usize) -> Self {
+        let mut lanes = WideLanes {
+            min: [
+                vec![f32::MAX; width],
+                vec![f32::MAX; width],
+                vec![f32::MAX; width],
+            ],
+            max: [
+                vec![f32::MIN; width],
+                vec![f32::MIN; width],
+                vec![f32::MIN; width],
+            ],
+            child_index_or_first_primitive: vec![WIDE_NODE_EMPTY_CHILD; width],
+            child_primitive_count: vec![0; width],
+        };
+        for (k, c) in node.children.iter().enumerate() {
+            for axis in 0..3 {
+                lanes.min[axis][k] = c.aabb.min[axis];
+                lanes.max[axis][k] = c.aabb.max[axis];
+            }
+            lanes.child_index_or_first_primitive[k] = c.index_or_first_prim;
+            lanes.child_primitive_count[k] = c.prim_count;
+        }
+        lanes
+    }
+}
+
+fn vec4<T: Copy>(lanes: &[T], first: usize) -> mint::Vector4<T> {
+    mint::Vector4::from([
+        lanes[first],
+        lanes[first + 1],
+        lanes[first + 2],
+        lanes[first + 3],
+    ])
+}
+
+fn serialize_blas_nodes_wide(bvh: &Bvh, format: NodeFormat) -> (Vec<u8>, u32) {
+    let width = match format {
+        NodeFormat::Wide4 => 4,
+        NodeFormat::Wide8 => 8,
+        _ => panic!("not a wide format"),
+    };
+    let mut nodes = wide::collapse(bvh, width);
+    if nodes.is_empty() {
+        // a root without children
+        nodes.push(wide::WideNode {
+            children: Vec::new(),
+        });
+    }
+    let mut serialized = Vec::with_capacity(nodes.len() * layout::blas_node_format_stride(format));
+    let mut writer = std430::Writer::new(&mut serialized);
+    for node in &nodes {
+        let l = WideLanes::new(node, width);
+        if width == 4 {
+            writer
+                .write(&GPUBlasBvhNodeWide4 {
+                    min_x: vec4(&l.min[0], 0),
+                    min_y: vec4(&l.min[1], 0),
+                    min_z: vec4(&l.min[2], 0),
+                    max_x: vec4(&l.max[0], 0),
+                    max_y: vec4(&l.max[1], 0),
+                    max_z: vec4(&l.max[2], 0),
+                    child_index_or_first_primitive: vec4(&l.child_index_or_first_primitive, 0),
+                    child_primitive_count: vec4(&l.child_primitive_count, 0),
+                })
+                .unwrap();
+        } else {
+            writer
+                .write(&GPUBlasBvhNodeWide8 {
+                    min_x_lo: vec4(&l.min[0], 0),
+                    min_x_hi: vec4(&l.min[0], 4),
+                    min_y_lo: vec4(&l.min[1], 0),
+                    min_y_hi: vec4(&l.min[1], 4),
+                    min_z_lo: vec4(&l.min[2], 0),
+                    min_z_hi: vec4(&l.min[2], 4),
+                    max_x_lo: vec4(&l.max[0], 0),
+                    max_x_hi: vec4(&l.max[0], 4),
+                    max_y_lo: vec4(&l.max[1], 0),
+                    max_y_hi: vec4(&l.max[1], 4),
+                    max_z_lo: vec4(&l.max[2], 0),
+                    max_z_hi: vec4(&l.max[2], 4),
+                    child_index_or_first_primitive_lo: vec4(&l.child_index_or_first_primitive, 0),
+                    child_index_or_first_primitive_hi: vec4(&l.child_index_or_first_primitive, 4),
+                    child_primitive_count_lo: vec4(&l.child_primitive_count, 0),
+                    child_primitive_count_hi: vec4(&l.child_primitive_count, 4),
+                })
+                .unwrap();
+        }
+    }
+    (serialized, nodes.len() as u32)
+}
+
+// leaves reference contiguous ranges of the reordered primitives
+fn serialize_blas_primitive_refs(bvh: &Bvh, primitives: &[Primitive]) -> Vec<u8> {
+    let mut serialized =
+        Vec::with_capacity(bvh.prim_indices.len() * std430_array_stride::<GPUBlasPrimitiveRef>());
+    let mut writer = std430::Writer::new(&mut serialized);
+    for &pi in &bvh.prim_indices {
+        let p = &primitives[pi as usize];
+        writer
+            .write(&GPUBlasPrimitiveRef {
+                geometry_id: p.blas_local_geometry_id as i32,
+                primitive_id: p.primitive_id, // local
+                flags: p.flags.bits(),
+            })
+            .unwrap();
+    }
+    let aligned_size = align_to(serialized.len(), Std430GPUBlasPrimitiveRef::ALIGNMENT);
+    serialized.resize(aligned_size, 0);
+    serialized
+}
+
+/// Throws a `BvhBuildError` message if the descriptor or a buffer it
+/// references is malformed.
+#[wasm_bindgen]
+pub fn build_blas(
+    blas_descriptor_buffer_id: u32,
+    options: &BuildOptions,
+) -> Result<BuiltBvh, JsValue> {
+    utils::set_panic_hook();
+    let blas = with_staging_buffers(|map| try_build_blas(map, blas_descriptor_buffer_id, options))?;
+    Ok(BuiltBvh::try_from(blas)?)
+}
+
+fn try_build_blas(
+    map: &StagingBufferMap,
+    blas_descriptor_buffer_id: u32,
+    options: &BuildOptions,
+) -> Result<BuiltBlas, BvhBuildError> {
+    options.validate()?;
+    let primitives = blas_primitives(map, blas_descriptor_buffer_id)?;
+    Ok(native::build_blas_primitives(&primitives, options))
+}
+
+/// BLAS descriptors built together by `build`, at the same time with the
+/// `parallel` feature.
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct BlasBatch {
+    blases: Vec<(u32, BuildOptions)>,
+}
+
+#[wasm_bindgen]
+impl BlasBatch {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, blas_descriptor_buffer_id: u32, options: &BuildOptions) {
+        self.blases.push((blas_descriptor_buffer_id, *options));
+    }
+
+    /// The BuiltBvhs in the order the descriptors were added, like
+    /// `build_blas` for each. Throws the `BvhBuildError` message of the first
+    /// descriptor that fails to build.
+    pub fn build(&self) -> Result<js_sys::Array, JsValue> {
+        utils::set_panic_hook();
+        let built = with_staging_buffers(|map| try_build_blases(map, &self.blases))?;
+        let array = js_sys::Array::new();
+        for blas in built {
+            array.push(&BuiltBvh::try_from(blas)?.into());
+        }
+        Ok(array)
+    }
+}
+
+fn try_build_blases(
+    map: &StagingBufferMap,
+    blases: &[(u32, BuildOptions)],
+) -> Result<Vec<BuiltBlas>, BvhBuildError> {
+    // builds only read the staging buffers, the registry is updated on this
+    // thread
+    let built: Vec<Result<BuiltBlas, BvhBuildError>> = parallel::map(blases, |(id, options)| {
+        options.validate()?;
+        let primitives = blas_primitives(map, *id)?;
+        Ok(native::build_blas_primitives(&primitives, options))
+    });
+    built.into_iter().collect()
+}
+
+/// Recomputes the node bounds of a BLAS built by `build_blas` from updated
+/// vertex positions (or AABBs), keeping the tree topology. The descriptor
+/// must reference the same geometries and primitive counts as the one used
+/// for building. Bounds are written in place into `built.serialized`.
+/// Throws for TLASes and compacted or wide BLASes.
+#[wasm_bindgen]
+pub fn refit_blas(built: &BuiltBvh, blas_descriptor_buffer_id: u32) -> Result<(), JsValue> {
+    utils::set_panic_hook();
+    // the nodes are out of the registry while the descriptor is read
+    built.serialized.with_buffer_mut(|nodes| {
+        with_staging_buffers(|map| try_refit_blas(map, built, nodes, blas_descriptor_buffer_id))
+    })?;
+    Ok(())
+}
+
+fn try_refit_blas(
+    map: &StagingBufferMap,
+    built: &BuiltBvh,
+    nodes: &mut [u8],
+    blas_descriptor_buffer_id: u32,
+) -> Result<(), BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    let refs_buffer = built
+        .primitive_refs
+        .as_ref()
+        .ok_or(BvhBuildError::NotABlas { at })?;
+    // only binary, uncompacted BLASes can be refitted
+    if built.format != NodeFormat::Full {
+        return Err(BvhBuildError::UnsupportedNodeFormat {
+            at,
+            format: built.format,
+        });
+    }
+    let primitives = blas_primitives(map, blas_descriptor_buffer_id)?;
+    if built.num_primitive_refs == 0 {
+        // the empty root has no children
+        return Ok(());
+    }
+    // primitives are in geometry order
+    let mut geometry_first_primitive = Vec::<u32>::new();
+    let mut geometry_num_primitives = Vec::<u32>::new();
+    for p in &primitives {
+        if p.blas_local_geometry_id as usize == geometry_first_primitive.len() {
+            geometry_first_primitive.push(p.within_blas_primitive_id);
+            geometry_num_primitives.push(0);
+        }
+        geometry_num_primitives[p.blas_local_geometry_id as usize] += 1;
+    }
+
+    let refs = &map[refs_buffer.id];
+    // the refs were built from another descriptor
+    for i in 0..built.num_primitive_refs {
+        let (geometry_id, primitive_id) = layout::read_primitive_ref(refs, i);
+        let at = DescriptorLocation::Geometry(geometry_id as u32);
+        let num_primitives = geometry_num_primitives.get(geometry_id as usize).ok_or(
+            BvhBuildError::IndexOutOfRange {
+                at,
+                index: geometry_id as usize,
+                len: geometry_num_primitives.len(),
+            },
+        )?;
+        if primitive_id >= *num_primitives {
+            return Err(BvhBuildError::IndexOutOfRange {
+                at,
+                index: primitive_id as usize,
+                len: *num_primitives as usize,
+            });
+        }
+    }
+    refit::refit_blas_nodes(nodes, built.num_nodes, refs, |geometry_id, primitive_id| {
+        primitives[(geometry_first_primitive[geometry_id as usize] + primitive_id) as usize].aabb()
+    });
+    Ok(())
+}
+
+/// Size in bytes of the node and primitive ref buffers of `built` after
+/// `compact_blas(built, format)`, like
+/// vkCmdWriteAccelerationStructuresPropertiesKHR. TLASes are not compacted,
+/// their current size is returned. Throws like `compact_blas` for BLASes.
+#[wasm_bindgen]
+pub fn compacted_size(built: &BuiltBvh, format: NodeFormat) -> Result<u32, JsValue> {
+    Ok(try_compacted_size(built, format)?)
+}
+
+fn try_compacted_size(built: &BuiltBvh, format: NodeFormat) -> Result<u32, BvhBuildError> {
+    let refs = match built.primitive_refs.as_ref() {
+        Some(refs) => refs,
+        None => return Ok(built.serialized.with_buffer(|nodes| nodes.len()) as u32),
     };
+    check_compaction_formats(built, format)?;
+    // compaction keeps the binary tree, node for node
+    let nodes_size = built.num_nodes as usize * layout::blas_node_format_stride(format);
+    Ok((nodes_size + refs.with_buffer(|refs| refs.len())) as u32)
+}
 
-    bvh.flatten_custom(&mut blas_node_ctor);
-    let aligned_size = align_to(staging_buffer_u8.len(), Std430GPUBlasBvhNode::ALIGNMENT);
-    if staging_buffer_u8.len() < aligned_size {
-        staging_buffer_u8.resize(aligned_size, 0);
+// Only binary, uncompacted BLASes are compacted, and not into wide formats,
+// which are built with BuildOptions::branching_factor.
+fn check_compaction_formats(built: &BuiltBvh, format: NodeFormat) -> Result<(), BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    if built.format != NodeFormat::Full {
+        return Err(BvhBuildError::UnsupportedNodeFormat {
+            at,
+            format: built.format,
+        });
     }
-    BuiltBvh {
-        serialized: staging_buffer,
-        num_nodes: num_bvh_nodes,
+    if format == NodeFormat::Wide4 || format == NodeFormat::Wide8 {
+        return Err(BvhBuildError::UnsupportedNodeFormat { at, format });
     }
+    Ok(())
+}
+
+/// Re-encodes the nodes of a BLAS built by `build_blas` with quantized
+/// bounds. The result owns new staging buffers, `built` can be freed
+/// afterwards. Compacted BLASes cannot be refitted. Throws for TLASes,
+/// compacted BLASes and wide formats, which are built with
+/// `BuildOptions::branching_factor`.
+#[wasm_bindgen]
+pub fn compact_blas(built: &BuiltBvh, format: NodeFormat) -> Result<BuiltBvh, JsValue> {
+    utils::set_panic_hook();
+    Ok(try_compact_blas(built, format)?)
+}
+
+fn try_compact_blas(built: &BuiltBvh, format: NodeFormat) -> Result<BuiltBvh, BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    let refs = built
+        .primitive_refs
+        .as_ref()
+        .ok_or(BvhBuildError::NotABlas { at })?
+        .with_buffer(Vec::clone);
+    check_compaction_formats(built, format)?;
+    let frame = compact::blas_quantization_frame(&built.aabb, built.num_primitive_refs);
+    let serialized = built.serialized.with_buffer(|nodes| {
+        if format == NodeFormat::Full {
+            nodes.clone()
+        } else {
+            compact::serialize_blas_nodes_quantized(nodes, built.num_nodes, &frame, format)
+        }
+    });
+    Ok(BuiltBvh {
+        serialized: StagingBuffer::from_existing_buffer(serialized)?,
+        num_nodes: built.num_nodes,
+        format,
+        aabb: if format == NodeFormat::Full {
+            built.aabb
+        } else {
+            frame
+        },
+        primitive_refs: Some(StagingBuffer::from_existing_buffer(refs)?),
+        num_primitive_refs: built.num_primitive_refs,
+        instance_leaf_nodes: None,
+        num_inactive: built.num_inactive,
+        num_degenerate: built.num_degenerate,
+        blas_bounds: Vec::new(),
+    })
 }
 
 // TODO: default as identity matrix
@@ -382,6 +1288,50 @@ impl From<&[f32; 12]> for Float12 {
     }
 }
 
+// motion keyframes, float[16] in common.glsl
+#[derive(Debug, AsStd430, Default)]
+struct Float16 {
+    f0: f32,
+    f1: f32,
+    f2: f32,
+    f3: f32,
+    f4: f32,
+    f5: f32,
+    f6: f32,
+    f7: f32,
+    f8: f32,
+    f9: f32,
+    f10: f32,
+    f11: f32,
+    f12: f32,
+    f13: f32,
+    f14: f32,
+    f15: f32,
+}
+
+impl From<&[f32; 16]> for Float16 {
+    fn from(k: &[f32; 16]) -> Self {
+        Float16 {
+            f0: k[0],
+            f1: k[1],
+            f2: k[2],
+            f3: k[3],
+            f4: k[4],
+            f5: k[5],
+            f6: k[6],
+            f7: k[7],
+            f8: k[8],
+            f9: k[9],
+            f10: k[10],
+            f11: k[11],
+            f12: k[12],
+            f13: k[13],
+            f14: k[14],
+            f15: k[15],
+        }
+    }
+}
+
 // TODO: deprecate this
 // https://bugs.chromium.org/p/tint/issues/detail?id=1049
 // column major
@@ -463,6 +1413,17 @@ struct GPUTlasBvhNode {
 
     // For traversal
     blas_geometry_id_offset: u32,
+    blas_primitive_ref_offset: u32,
+    // BLAS root bounds, the quantization frame of compacted BLASes
+    blas_aabb: GPUAabb,
+
+    // InstanceMotionType, transform_to_world is the transform at time 0
+    motion_type: u32,
+    // see InstanceMotion::keyframes
+    motion_start: Float16,
+    motion_end: Float16,
+    // TransformClass, over time with motion
+    transform_class: u32,
 }
 
 fn flat_bvh_nodes_to_u8_view<T>(nodes: Vec<T>) -> Vec<u8> {
@@ -508,109 +1469,290 @@ fn align_to(x: usize, to: usize) -> usize {
     (x + to - 1) / to * to
 }
 
-#[wasm_bindgen]
-pub fn build_tlas(tlas_descriptor_buffer_id: u32) -> BuiltBvh {
-    utils::set_panic_hook();
-    let map = staging_buffers_map();
-    // TODO: error handling
-    let buf = map.get(&tlas_descriptor_buffer_id).unwrap();
-    let buf_i32_le: &[i32] = unsafe { buf.align_to().1 };
-    let num_blases = buf_i32_le[0];
-    assert!(num_blases > 0);
-    let num_blases = num_blases as usize;
-    assert!(buf.len() == 1 * 4 + num_blases * mem::size_of::<TlasInstanceDescriptorJsInput>());
-
-    let buf_descriptors: &[TlasInstanceDescriptorJsInput] = unsafe { buf[1..].align_to().1 };
-    assert!(num_blases == buf_descriptors.len());
-
-    let mut instances = Vec::<TlasInstanceDescriptor>::with_capacity(num_blases);
-    for inst in buf_descriptors {
-        instances.push(TlasInstanceDescriptor {
-            mask: inst.mask,
+impl TlasInstanceDescriptor {
+    // Fails for invalid flags or motion of the instance at `index`.
+    fn new(index: u32, inst: &TlasInstance) -> Result<Self, BvhBuildError> {
+        let flags = InstanceFlags::from_bits(inst.flags).ok_or(BvhBuildError::InvalidFlags {
+            at: DescriptorLocation::Instance(index),
             flags: inst.flags,
+        })?;
+        let motion = InstanceMotion::new(index, inst)?;
+        let transform_to_world_4x3 = motion.start_transform(&inst.transform_to_world_4x3);
+        Ok(TlasInstanceDescriptor {
+            mask: inst.mask,
+            flags,
             instance_id: inst.instance_id,
             sbt_instance_offset: inst.sbt_instance_offset,
             instance_custom_index: inst.instance_custom_index,
             blas_entry_index: inst.blas_entry_index,
             blas_geometry_id_offset: inst.blas_geometry_id_offset,
-            aabb: transform_aabb(&inst.transform_to_world_4x3, &inst.blas_aabb),
-            transform_to_world_4x3: inst.transform_to_world_4x3,
+            blas_primitive_ref_offset: inst.blas_primitive_ref_offset,
+            blas_aabb: AABB::with_bounds(
+                Point3::new(inst.blas_aabb[0], inst.blas_aabb[1], inst.blas_aabb[2]),
+                Point3::new(inst.blas_aabb[3], inst.blas_aabb[4], inst.blas_aabb[5]),
+            ),
+            aabb: motion.aabb(&transform_to_world_4x3, &inst.blas_aabb),
+            transform_class: motion.transform_class(&transform_to_world_4x3),
+            transform_to_world_4x3,
+            motion,
         })
     }
 
-    log!("building from tlas instances: {:?}", instances);
-    let bvh = BVH::build(&mut instances);
-    let num_bvh_nodes = bvh.nodes.len() as u32;
-    log!("tlas bvh tree: {:?}", bvh.nodes);
-
-    let mut sizer = std430::Sizer::new();
-    sizer.add::<GPUTlasBvhNode>();
-    let node_array_stride = sizer.add::<GPUTlasBvhNode>();
-    let staging_buffer = StagingBuffer::new(num_bvh_nodes as usize * node_array_stride);
-    let staging_buffer_u8 = staging_buffer.buffer();
-    staging_buffer_u8.truncate(0); // !!
-    let mut writer = std430::Writer::new(staging_buffer_u8);
-    let mut tlas_node_ctor = |aabb: &AABB, entry, mut exit, instance_id| {
-        if exit >= num_bvh_nodes {
-            exit = u32::max_value();
+    // Bounds the instance by the transformed boxes of its BLAS instead of
+    // the BLAS root box. Instances of empty BLASes keep their bounds.
+    fn bound_by(&mut self, bounds: &BlasBounds) {
+        if bounds.boxes.is_empty() {
+            return;
         }
-        let node = if entry == u32::max_value() {
-            // leaf
-            let inst = &instances[instance_id as usize];
-            // currently leaf only contains single shape/primitive
-            GPUTlasBvhNode {
-                aabb: (&inst.aabb).into(), // this is the transformed aabb of the blas root aabb
-                entry_index: inst.blas_entry_index,
-                exit_index: exit,
-                is_leaf: 1,
-                // TODO: store leaf data in input instance
-                mask: inst.mask,
-                flags: inst.flags,
-                instance_id: inst.instance_id,
-                sbt_instance_offset: inst.sbt_instance_offset,
-                instance_custom_index: inst.instance_custom_index,
-                blas_geometry_id_offset: inst.blas_geometry_id_offset,
-                transform_to_world: (&inst.transform_to_world_4x3).into(),
-                transform_to_object: (&inv(&inst.transform_to_world_4x3)).into(),
-            }
+        let mut aabb = AABB::empty();
+        for b in &bounds.boxes {
+            let b = [b.min.x, b.min.y, b.min.z, b.max.x, b.max.y, b.max.z];
+            aabb.join_mut(&self.motion.aabb(&self.transform_to_world_4x3, &b));
+        }
+        self.aabb = aabb;
+    }
+}
+
+fn tlas_leaf_node(inst: &TlasInstanceDescriptor, exit: u32) -> GPUTlasBvhNode {
+    let (motion_start, motion_end) = inst.motion.keyframes(&inst.transform_to_world_4x3);
+    GPUTlasBvhNode {
+        aabb: (&inst.aabb).into(), // the transformed BLAS bounds, see InstanceBounds
+        entry_index: inst.blas_entry_index,
+        exit_index: exit,
+        is_leaf: 1,
+        // TODO: store leaf data in input instance
+        mask: inst.mask,
+        flags: inst.flags.bits(),
+        instance_id: inst.instance_id,
+        sbt_instance_offset: inst.sbt_instance_offset,
+        instance_custom_index: inst.instance_custom_index,
+        blas_geometry_id_offset: inst.blas_geometry_id_offset,
+        blas_primitive_ref_offset: inst.blas_primitive_ref_offset,
+        blas_aabb: (&inst.blas_aabb).into(),
+        transform_to_world: (&inst.transform_to_world_4x3).into(),
+        transform_to_object: match inst.transform_class {
+            // culled, see try_update_tlas
+            TransformClass::Singular => Mat4x3Workaround::default(),
+            _ => (&inv(&inst.transform_to_world_4x3)).into(),
+        },
+        motion_type: inst.motion.motion_type() as u32,
+        motion_start: (&motion_start).into(),
+        motion_end: (&motion_end).into(),
+        transform_class: inst.transform_class as u32,
+    }
+}
+
+fn tlas_interior_node(aabb: &AABB, entry: u32, exit: u32) -> GPUTlasBvhNode {
+    GPUTlasBvhNode {
+        aabb: aabb.into(),
+        entry_index: entry,
+        exit_index: exit,
+        is_leaf: 0,
+        mask: 0,
+        flags: 0,
+        instance_id: 0,
+        sbt_instance_offset: 0,
+        instance_custom_index: 0,
+        blas_geometry_id_offset: 0,
+        blas_primitive_ref_offset: 0,
+        blas_aabb: GPUAabb::default(),
+        transform_to_world: Mat4x3Workaround::default(),
+        transform_to_object: Mat4x3Workaround::default(),
+        motion_type: 0,
+        motion_start: Float16::default(),
+        motion_end: Float16::default(),
+        transform_class: 0,
+    }
+}
+
+fn serialize_tlas_nodes(bvh: &Bvh, instances: &[TlasInstanceDescriptor]) -> Vec<u8> {
+    let num_bvh_nodes = bvh.nodes.len() as u32;
+    let mut serialized =
+        Vec::with_capacity(num_bvh_nodes as usize * std430_array_stride::<GPUTlasBvhNode>());
+    let mut writer = std430::Writer::new(&mut serialized);
+    for (i, n) in bvh.nodes.iter().enumerate() {
+        let exit = if n.exit_index >= num_bvh_nodes {
+            u32::max_value()
         } else {
-            GPUTlasBvhNode {
-                aabb: aabb.into(),
-                entry_index: entry,
-                exit_index: exit,
-                is_leaf: 0,
-                mask: 0,
-                flags: 0,
-                instance_id: 0,
-                sbt_instance_offset: 0,
-                instance_custom_index: 0,
-                blas_geometry_id_offset: 0,
-                transform_to_world: Mat4x3Workaround::default(),
-                transform_to_object: Mat4x3Workaround::default(),
-            }
+            n.exit_index
+        };
+        let node = if n.is_leaf() {
+            tlas_leaf_node(&instances[bvh.leaf_prims(n)[0] as usize], exit)
+        } else {
+            tlas_interior_node(&n.aabb, i as u32 + 1, exit)
         };
         writer.write(&node).unwrap();
-    };
-    bvh.flatten_custom(&mut tlas_node_ctor);
-    let aligned_size = align_to(staging_buffer_u8.len(), Std430GPUTlasBvhNode::ALIGNMENT);
-    if staging_buffer_u8.len() < aligned_size {
-        staging_buffer_u8.resize(aligned_size, 0);
     }
-    BuiltBvh {
-        serialized: staging_buffer,
-        num_nodes: num_bvh_nodes,
+    if bvh.nodes.is_empty() {
+        // a root that is never hit, see intersect_aabb
+        writer
+            .write(&tlas_interior_node(&AABB::empty(), 1, u32::max_value()))
+            .unwrap();
+    }
+    let aligned_size = align_to(serialized.len(), Std430GPUTlasBvhNode::ALIGNMENT);
+    serialized.resize(aligned_size, 0);
+    serialized
+}
+
+// node index of the leaf of each instance, in instance order, u32::MAX for
+// instances left out of the tree
+fn tlas_instance_leaf_nodes(bvh: &Bvh, num_instances: usize) -> Vec<u32> {
+    let mut leaf_nodes = vec![u32::max_value(); num_instances];
+    for (i, n) in bvh.nodes.iter().enumerate() {
+        if n.is_leaf() {
+            leaf_nodes[bvh.leaf_prims(n)[0] as usize] = i as u32;
+        }
     }
+    leaf_nodes
 }
 
+// [count, T*], for TLAS instance descriptors and updates
+fn counted_staging_buffer<T>(
+    map: &StagingBufferMap,
+    buffer_id: u32,
+) -> Result<&[T], BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    let buf = staging_buffer(map, buffer_id, at)?;
+    let buf_i32_le: &[i32] = cast_staging_buffer(buf, buffer_id, at)?;
+    let count = *buf_i32_le.first().ok_or(BvhBuildError::LengthMismatch {
+        at,
+        expected: 4,
+        actual: buf.len(),
+    })?;
+    if count < 0 {
+        return Err(BvhBuildError::InvalidCount { at, count });
+    }
+    let expected_len = 4 + count as usize * mem::size_of::<T>();
+    if buf.len() != expected_len {
+        return Err(BvhBuildError::LengthMismatch {
+            at,
+            expected: expected_len,
+            actual: buf.len(),
+        });
+    }
+    cast_staging_buffer(&buf[4..], buffer_id, at)
+}
+
+/// Throws a `BvhBuildError` message if the descriptor is malformed.
+/// Instances are bounded by their transformed `blas_aabb`, whatever
+/// `options.instance_bounds` is, as the BLASes are not at hand. Use
+/// `SceneBatch` for tighter bounds.
+#[wasm_bindgen]
+pub fn build_tlas(
+    tlas_descriptor_buffer_id: u32,
+    options: &BuildOptions,
+) -> Result<BuiltBvh, JsValue> {
+    utils::set_panic_hook();
+    let tlas = with_staging_buffers(|map| try_build_tlas(map, tlas_descriptor_buffer_id, options))?;
+    Ok(BuiltBvh::try_from(tlas)?)
+}
+
+fn try_build_tlas(
+    map: &StagingBufferMap,
+    tlas_descriptor_buffer_id: u32,
+    options: &BuildOptions,
+) -> Result<BuiltTlas, BvhBuildError> {
+    // [num_instances, TlasInstance*]
+    let instances: &[TlasInstance] = counted_staging_buffer(map, tlas_descriptor_buffer_id)?;
+    native::build_tlas_from_instances(instances, options)
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct TlasInstanceUpdateJsInput {
+    instance_index: u32,
+    descriptor: TlasInstance,
+}
+
+/// Updates instances of a TLAS built by `build_tlas` in place, keeping the
+/// tree topology. Only the changed leaves and their ancestors are
+/// re-serialized, tree quality degrades if instances move far. Instances
+/// updated to a singular transform keep their leaf, but cannot be hit.
+///
+/// Returns the dirty byte ranges of `built.serialized` as sorted, disjoint
+/// `[begin, end)` pairs. Throws for BLASes.
+#[wasm_bindgen]
+pub fn update_tlas(built: &BuiltBvh, tlas_update_buffer_id: u32) -> Result<Vec<u32>, JsValue> {
+    utils::set_panic_hook();
+    // the nodes are out of the registry while the updates are read
+    let dirty = built.serialized.with_buffer_mut(|nodes| {
+        with_staging_buffers(|map| try_update_tlas(map, built, nodes, tlas_update_buffer_id))
+    })?;
+    Ok(dirty)
+}
+
+fn try_update_tlas(
+    map: &StagingBufferMap,
+    built: &BuiltBvh,
+    nodes: &mut [u8],
+    tlas_update_buffer_id: u32,
+) -> Result<Vec<u32>, BvhBuildError> {
+    // [num_updates, TlasInstanceUpdateJsInput*]
+    let updates: &[TlasInstanceUpdateJsInput] = counted_staging_buffer(map, tlas_update_buffer_id)?;
+
+    let leaf_nodes_buffer = built
+        .instance_leaf_nodes
+        .as_ref()
+        .ok_or(BvhBuildError::NotATlas {
+            at: DescriptorLocation::Descriptor,
+        })?;
+    let leaf_nodes: &[u32] = unsafe { map[leaf_nodes_buffer.id].align_to().1 };
+    let mut changed = Vec::<(u32, GPUTlasBvhNode)>::with_capacity(updates.len());
+    for (k, u) in updates.iter().enumerate() {
+        let leaf =
+            *leaf_nodes
+                .get(u.instance_index as usize)
+                .ok_or(BvhBuildError::IndexOutOfRange {
+                    at: DescriptorLocation::Instance(k as u32),
+                    index: u.instance_index as usize,
+                    len: leaf_nodes.len(),
+                })?;
+        // instances left out of the tree at build time stay out
+        if leaf == u32::max_value() {
+            continue;
+        }
+        let node = layout::read_tlas_node(nodes, leaf);
+        let mut descriptor = TlasInstanceDescriptor::new(k as u32, &u.descriptor)?;
+        // the bounds of the build hold while the instance keeps its BLAS
+        if let Some(bounds) = built.blas_bounds.get(u.instance_index as usize) {
+            if node.entry_index == u.descriptor.blas_entry_index {
+                descriptor.bound_by(bounds);
+            }
+        }
+        // the leaf stays in the tree, but cannot be hit
+        if descriptor.transform_class == TransformClass::Singular {
+            log!(
+                "culling instance {} with a singular transform",
+                u.instance_index
+            );
+            descriptor.mask = 0;
+            descriptor.aabb = node.aabb;
+        }
+        let exit = node.exit_index;
+        changed.push((leaf, tlas_leaf_node(&descriptor, exit)));
+    }
+    Ok(refit::update_tlas_nodes(nodes, built.num_nodes, &changed))
+}
+
+#[cfg(test)]
 mod tests {
-    use crate::{build_blas, staging_buffers_map, StagingBufferMap};
+    use crate::builder::Bvh;
+    use crate::VertexFormat;
+    use crate::{layout, NodeFormat};
+    use crate::{try_build_blas, try_build_tlas, TlasInstance};
+    use crate::{BuildOptions, StagingBufferMap};
+    use crate::{BvhBuildError, DescriptorLocation};
+    use crate::{GeometryDescriptorField, GeometryFlags, GeometryType};
+    use crate::{IndexFormat, Primitive, PrimitiveClass};
+    use bvh::aabb::Bounded;
+    use bvh::Point3;
 
     #[test]
     /// Verify contents of the bounding hierarchy for a fixed scene structure
     fn test_debug_bug() {
-        let map = staging_buffers_map();
+        let mut map = StagingBufferMap::new();
         {
-            map.insert(
+            map.insert_at(
                 11,
                 vec![
                     51, 51, 10, 68, 0, 0, 0, 0, 0, 0, 0, 0, 102, 102, 9, 68, 0, 0, 0, 0, 205, 204,
@@ -618,7 +1760,7 @@ mod tests {
                     68, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 9,
                 vec![
                     0, 0, 0, 0, 0, 0, 0, 0, 205, 204, 11, 68, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -626,7 +1768,7 @@ mod tests {
                     68,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 7,
                 vec![
                     51, 51, 10, 68, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
@@ -634,13 +1776,13 @@ mod tests {
                     11, 68,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 6,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 1,
                 vec![
                     0, 0, 11, 68, 51, 51, 9, 68, 0, 0, 0, 0, 0, 0, 11, 68, 51, 51, 9, 68, 205, 204,
@@ -650,7 +1792,7 @@ mod tests {
                     67, 51, 51, 9, 68, 0, 0, 99, 67,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 14,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0,
@@ -661,7 +1803,7 @@ mod tests {
                     0, 0, 0, 22, 0, 0, 0, 20, 0, 0, 0, 22, 0, 0, 0, 23, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 5,
                 vec![
                     102, 102, 9, 68, 0, 0, 0, 0, 205, 204, 11, 68, 0, 0, 0, 0, 0, 0, 0, 0, 205,
@@ -669,33 +1811,26 @@ mod tests {
                     9, 68, 205, 204, 11, 68,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 10,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 12,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
-                16,
-                vec![
-                    2, 0, 0, 0, 6, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0,
-                    0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 0, 0, 0,
-                ],
-            );
-            map.insert(
+            map.insert_at(
                 2,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 4, 0,
                     0, 0, 5, 0, 0, 0, 6, 0, 0, 0, 6, 0, 0, 0, 7, 0, 0, 0, 4, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 3,
                 vec![
                     0, 128, 171, 67, 51, 19, 9, 68, 0, 0, 99, 67, 0, 128, 171, 67, 51, 19, 9, 68,
@@ -703,26 +1838,26 @@ mod tests {
                     19, 9, 68, 0, 0, 99, 67,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 4,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 8,
                 vec![
                     0, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 0,
                 vec![
                     0, 0, 22, 67, 0, 0, 0, 0, 0, 0, 72, 66, 0, 0, 175, 67, 0, 0, 72, 67, 0, 0, 122,
                     67,
                 ],
             );
-            map.insert(
+            map.insert_at(
                 13,
                 vec![
                     0, 128, 211, 67, 0, 0, 165, 67, 0, 0, 119, 67, 0, 128, 132, 67, 0, 0, 165, 67,
@@ -743,7 +1878,191 @@ mod tests {
             );
         }
 
-        print!("{:?}", map);
-        build_blas(16);
+        // the recorded descriptor in the current layout: two indexed
+        // triangle geometries, (num_primitives, vbuf_id, ibuf_id) each
+        let num_fields = GeometryDescriptorField::NumFields as usize;
+        let mut words = vec![0i32; 2 + 2 * num_fields];
+        words[0] = 2;
+        words[1] = 6;
+        for (gi, &(np, vbuf_id, ibuf_id)) in [(4, 1, 2), (2, 3, 4)].iter().enumerate() {
+            let geom = &mut words[2 + gi * num_fields..2 + (gi + 1) * num_fields];
+            geom[GeometryDescriptorField::Type as usize] = GeometryType::Triangle as i32;
+            geom[GeometryDescriptorField::NumPrimitives as usize] = np;
+            geom[GeometryDescriptorField::VbufId as usize] = vbuf_id;
+            geom[GeometryDescriptorField::IbufId as usize] = ibuf_id;
+            geom[GeometryDescriptorField::VertexStride as usize] = 12;
+            geom[GeometryDescriptorField::EndVbufId as usize] = -1;
+        }
+        map.insert_at(16, words.iter().flat_map(|w| w.to_le_bytes()).collect());
+
+        let built = try_build_blas(&map, 16, &BuildOptions::default()).unwrap();
+        assert_eq!(
+            built.num_primitive_refs + built.num_inactive + built.num_degenerate,
+            6
+        );
+    }
+
+    #[test]
+    fn test_geometry_transform_applied_to_bounds() {
+        let vertices: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 2.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        // swap x and y, then translate by (10, 20, 30)
+        let transform = [
+            0.0f32, 1.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 10.0, 20.0, 30.0,
+        ];
+        let mut primitive = Primitive {
+            blas_local_geometry_id: 0,
+            within_blas_primitive_id: 0,
+            primitive_id: 0,
+            geometry_type: GeometryType::Triangle,
+            vbuf: &vertices,
+            end_vbuf: None,
+            vertex_format: VertexFormat::Float32x3,
+            vertex_stride: 12,
+            ibuf: None,
+            index_format: IndexFormat::Uint32,
+            transform: None,
+            flags: GeometryFlags::NONE,
+        };
+        let aabb = primitive.aabb();
+        assert_eq!(aabb.max, Point3::new(1.0, 2.0, 0.0));
+        primitive.transform = Some(&transform);
+        let aabb = primitive.aabb();
+        assert_eq!(aabb.min, Point3::new(10.0, 20.0, 30.0));
+        assert_eq!(aabb.max, Point3::new(12.0, 21.0, 30.0));
+    }
+
+    #[test]
+    fn test_malformed_descriptors_are_errors() {
+        let mut map = StagingBufferMap::new();
+        let options = BuildOptions::default();
+        assert_eq!(
+            try_build_blas(&map, 3, &options).err(),
+            Some(BvhBuildError::UnknownBufferId {
+                at: DescriptorLocation::Descriptor,
+                buffer_id: 3,
+            })
+        );
+        // a lone geometry count
+        map.insert_at(3, 1i32.to_le_bytes().to_vec());
+        assert_eq!(
+            try_build_blas(&map, 3, &options).err(),
+            Some(BvhBuildError::LengthMismatch {
+                at: DescriptorLocation::Descriptor,
+                expected: 2,
+                actual: 1,
+            })
+        );
+
+        let descriptor_size = std::mem::size_of::<TlasInstance>();
+        let mut tlas = 2i32.to_le_bytes().to_vec();
+        tlas.resize(4 + descriptor_size, 0);
+        map.insert_at(4, tlas);
+        assert_eq!(
+            try_build_tlas(&map, 4, &options).err(),
+            Some(BvhBuildError::LengthMismatch {
+                at: DescriptorLocation::Descriptor,
+                expected: 4 + 2 * descriptor_size,
+                actual: 4 + descriptor_size,
+            })
+        );
+        map.insert_at(4, (-1i32).to_le_bytes().to_vec());
+        assert_eq!(
+            try_build_tlas(&map, 4, &options).err(),
+            Some(BvhBuildError::InvalidCount {
+                at: DescriptorLocation::Descriptor,
+                count: -1,
+            })
+        );
+        // FORCE_OPAQUE | FORCE_NO_OPAQUE
+        let mut tlas = 1i32.to_le_bytes().to_vec();
+        tlas.resize(4 + descriptor_size, 0);
+        tlas[8..12].copy_from_slice(&12u32.to_le_bytes());
+        map.insert_at(4, tlas);
+        assert_eq!(
+            try_build_tlas(&map, 4, &options).err(),
+            Some(BvhBuildError::InvalidFlags {
+                at: DescriptorLocation::Instance(0),
+                flags: 12,
+            })
+        );
+    }
+
+    #[test]
+    fn test_inactive_and_degenerate_primitives() {
+        let bytes = |v: &[f32]| -> Vec<u8> { v.iter().flat_map(|f| f.to_le_bytes()).collect() };
+        let triangle = |vbuf| Primitive {
+            blas_local_geometry_id: 0,
+            within_blas_primitive_id: 0,
+            primitive_id: 0,
+            geometry_type: GeometryType::Triangle,
+            vbuf,
+            end_vbuf: None,
+            vertex_format: VertexFormat::Float32x3,
+            vertex_stride: 12,
+            ibuf: None,
+            index_format: IndexFormat::Uint32,
+            transform: None,
+            flags: GeometryFlags::NONE,
+        };
+        let nan = f32::NAN;
+        let active = bytes(&[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        let inactive = bytes(&[nan, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        let collinear = bytes(&[0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 2.0, 2.0, 2.0]);
+        let nan_y = bytes(&[0.0, nan, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]);
+        assert!(matches!(
+            triangle(&active).classify(),
+            PrimitiveClass::Active(_)
+        ));
+        assert!(matches!(
+            triangle(&inactive).classify(),
+            PrimitiveClass::Inactive
+        ));
+        assert!(matches!(
+            triangle(&collinear).classify(),
+            PrimitiveClass::Degenerate
+        ));
+        assert!(matches!(
+            triangle(&nan_y).classify(),
+            PrimitiveClass::Degenerate
+        ));
+
+        let aabb = |vbuf| Primitive {
+            geometry_type: GeometryType::Aabb,
+            ..triangle(vbuf)
+        };
+        let flat = bytes(&[0.0, 0.0, 0.0, 1.0, 0.0, 1.0]);
+        let inactive = bytes(&[nan, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        let inverted = bytes(&[0.0, 2.0, 0.0, 1.0, 1.0, 1.0]);
+        assert!(matches!(aabb(&flat).classify(), PrimitiveClass::Active(_)));
+        assert!(matches!(
+            aabb(&inactive).classify(),
+            PrimitiveClass::Inactive
+        ));
+        assert!(matches!(
+            aabb(&inverted).classify(),
+            PrimitiveClass::Degenerate
+        ));
+    }
+
+    #[test]
+    fn test_empty_bvh_has_an_empty_root() {
+        let bvh = Bvh::build(&[], &BuildOptions::default());
+        assert!(bvh.nodes.is_empty());
+        let bytes = crate::serialize_blas_nodes(&bvh);
+        assert_eq!(bytes.len(), layout::blas_node_stride());
+        let root = layout::read_blas_node(&bytes, 0);
+        assert!(root.aabb.min.x > root.aabb.max.x);
+        assert_eq!(root.primitive_count, 0);
+        assert_eq!(root.exit_index, u32::max_value());
+
+        let (bytes, num_nodes) = crate::serialize_blas_nodes_wide(&bvh, NodeFormat::Wide4);
+        assert_eq!(num_nodes, 1);
+        assert_eq!(
+            layout::read_u32(&bytes, 6 * 16),
+            crate::WIDE_NODE_EMPTY_CHILD
+        );
     }
 }
@@ -0,0 +1,420 @@
+// Motion blur, like VK_NV_ray_tracing_motion_blur. Instances move between
+// two transforms and triangle geometries between two vertex buffers over the
+// ray time interval [0, 1]. Trees are built over bounds covering the whole
+// interval, traversal interpolates at the time of each ray, see
+// traceRayMotionNV in trace.glsl. The arithmetic below is mirrored there.
+
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::layout::TlasNode;
+use crate::transform::{classify_transform, det_4x3, TransformClass};
+use crate::{transform_aabb, TlasInstance};
+use bvh::aabb::AABB;
+use bvh::Point3;
+
+/// How a TLAS instance moves, the `motion_type` of a `TlasInstance`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InstanceMotionType {
+    Static = 0,
+    /// The transform is interpolated linearly between two 4x3 matrices.
+    Matrix = 1,
+    /// Scale, rotation and translation are interpolated separately between
+    /// two `SrtTransform`s, rotations along the shortest arc.
+    Srt = 2,
+}
+
+impl TryFrom<u32> for InstanceMotionType {
+    type Error = ();
+
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(InstanceMotionType::Static),
+            1 => Ok(InstanceMotionType::Matrix),
+            2 => Ok(InstanceMotionType::Srt),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A transform as scale, rotation and translation, laid out like
+/// VkSRTDataNV: sx, a, b, pvx, sy, c, pvy, sz, pvz, qx, qy, qz, qw, tx, ty,
+/// tz. Points are scaled and sheared by [sx a b pvx; 0 sy c pvy; 0 0 sz pvz],
+/// rotated by the quaternion q and translated by t.
+pub type SrtTransform = [f32; 16];
+
+// The motion of a TlasInstanceDescriptor.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum InstanceMotion {
+    Static,
+    // transform_to_world at time 1
+    Matrix([f32; 12]),
+    // at time 0 and 1
+    Srt(SrtTransform, SrtTransform),
+}
+
+// Rotations of SRT motion are bounded piecewise, see srt_motion_aabb.
+const SRT_BOUND_SEGMENTS: u32 = 8;
+
+impl InstanceMotion {
+    // Fails for an unknown motion type of the instance at `index`.
+    pub(crate) fn new(index: u32, inst: &TlasInstance) -> Result<Self, BvhBuildError> {
+        let k = &inst.motion_keyframes;
+        let keyframe = |i: usize| -> [f32; 16] { k[16 * i..16 * (i + 1)].try_into().unwrap() };
+        match InstanceMotionType::try_from(inst.motion_type) {
+            Ok(InstanceMotionType::Static) => Ok(InstanceMotion::Static),
+            Ok(InstanceMotionType::Matrix) => {
+                Ok(InstanceMotion::Matrix(k[..12].try_into().unwrap()))
+            }
+            Ok(InstanceMotionType::Srt) => Ok(InstanceMotion::Srt(keyframe(0), keyframe(1))),
+            Err(()) => Err(BvhBuildError::UnknownMotionType {
+                at: DescriptorLocation::Instance(index),
+                motion_type: inst.motion_type,
+            }),
+        }
+    }
+
+    // The motion of a TLAS leaf written by tlas_leaf_node.
+    pub(crate) fn from_node(node: &TlasNode) -> Self {
+        let [start, end] = &node.motion_keyframes;
+        match node.motion_type {
+            InstanceMotionType::Static => InstanceMotion::Static,
+            InstanceMotionType::Matrix => InstanceMotion::Matrix(end[..12].try_into().unwrap()),
+            InstanceMotionType::Srt => InstanceMotion::Srt(*start, *end),
+        }
+    }
+
+    pub(crate) fn motion_type(&self) -> InstanceMotionType {
+        match self {
+            InstanceMotion::Static => InstanceMotionType::Static,
+            InstanceMotion::Matrix(_) => InstanceMotionType::Matrix,
+            InstanceMotion::Srt(..) => InstanceMotionType::Srt,
+        }
+    }
+
+    // The transform at time 0, `transform` is the one given with the
+    // instance, which SRT motion ignores.
+    pub(crate) fn start_transform(&self, transform: &[f32; 12]) -> [f32; 12] {
+        match self {
+            InstanceMotion::Srt(start, _) => srt_to_4x3(start),
+            _ => *transform,
+        }
+    }
+
+    // The transform at `time`, clamped to [0, 1]. `start` is the transform
+    // at time 0.
+    pub(crate) fn transform_at(&self, start: &[f32; 12], time: f32) -> [f32; 12] {
+        let time = time.clamp(0.0, 1.0);
+        match self {
+            InstanceMotion::Static => *start,
+            InstanceMotion::Matrix(end) => {
+                let mut m = [0f32; 12];
+                for (k, v) in m.iter_mut().enumerate() {
+                    *v = mix(start[k], end[k], time);
+                }
+                m
+            }
+            InstanceMotion::Srt(a, b) => srt_to_4x3(&mix_srt(a, b, time)),
+        }
+    }
+
+    // The class of the transforms over [0, 1], from those at the keyframes.
+    // `start` is the transform at time 0.
+    pub(crate) fn transform_class(&self, start: &[f32; 12]) -> TransformClass {
+        match self {
+            InstanceMotion::Static => classify_transform(start),
+            InstanceMotion::Matrix(end) => {
+                // the determinant changes continuously, it vanishes in
+                // between if its sign differs at the keyframes
+                if det_4x3(start) * det_4x3(end) <= 0.0 {
+                    return TransformClass::Singular;
+                }
+                // interpolated matrices are only rigid or uniformly scaled at
+                // the keyframes
+                match classify_transform(start).max(classify_transform(end)) {
+                    TransformClass::Rigid | TransformClass::UniformScale => TransformClass::Affine,
+                    class => class,
+                }
+            }
+            InstanceMotion::Srt(a, b) => {
+                // the diagonal of the upper triangular scale matrix moves
+                // linearly, it must not cross zero
+                if [0, 4, 7].iter().any(|&k| a[k] * b[k] <= 0.0) {
+                    return TransformClass::Singular;
+                }
+                classify_transform(start).max(classify_transform(&srt_to_4x3(b)))
+            }
+        }
+    }
+
+    // Bounds of `blas_aabb` transformed at any time in [0, 1].
+    pub(crate) fn aabb(&self, start: &[f32; 12], blas_aabb: &[f32; 6]) -> AABB {
+        match self {
+            InstanceMotion::Static => transform_aabb(start, blas_aabb),
+            // the corners move on straight lines
+            InstanceMotion::Matrix(end) => {
+                transform_aabb(start, blas_aabb).join(&transform_aabb(end, blas_aabb))
+            }
+            InstanceMotion::Srt(a, b) => srt_motion_aabb(a, b, blas_aabb),
+        }
+    }
+
+    // The keyframes of the TLAS leaf, see TlasBvhNode.motionStart.
+    pub(crate) fn keyframes(&self, start: &[f32; 12]) -> ([f32; 16], [f32; 16]) {
+        let pad = |m: &[f32; 12]| {
+            let mut k = [0f32; 16];
+            k[..12].copy_from_slice(m);
+            k
+        };
+        match self {
+            InstanceMotion::Static => ([0.0; 16], [0.0; 16]),
+            InstanceMotion::Matrix(end) => (pad(start), pad(end)),
+            InstanceMotion::Srt(a, b) => (*a, *b),
+        }
+    }
+}
+
+// GLSL mix
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+fn dot4(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
+}
+
+fn normalize4(q: [f32; 4]) -> [f32; 4] {
+    let s = 1.0 / dot4(&q, &q).sqrt();
+    q.map(|v| v * s)
+}
+
+// The unit quaternions of two SRTs, the second one negated if needed so that
+// they are less than half a turn apart. SRTs exactly half a turn apart may
+// turn either way, depending on rounding.
+fn srt_quats(a: &SrtTransform, b: &SrtTransform) -> ([f32; 4], [f32; 4]) {
+    let q0 = normalize4([a[9], a[10], a[11], a[12]]);
+    let mut q1 = normalize4([b[9], b[10], b[11], b[12]]);
+    if dot4(&q0, &q1) < 0.0 {
+        q1 = q1.map(|v| -v);
+    }
+    (q0, q1)
+}
+
+fn slerp(q0: &[f32; 4], q1: &[f32; 4], t: f32) -> [f32; 4] {
+    let d = dot4(q0, q1);
+    if d > 0.9995 {
+        // nearly parallel, sin(theta) would lose all precision
+        let mut q = [0f32; 4];
+        for (k, v) in q.iter_mut().enumerate() {
+            *v = mix(q0[k], q1[k], t);
+        }
+        return normalize4(q);
+    }
+    let theta = d.acos();
+    let (s0, s1) = (((1.0 - t) * theta).sin(), (t * theta).sin());
+    let inv_sin = 1.0 / theta.sin();
+    let mut q = [0f32; 4];
+    for (k, v) in q.iter_mut().enumerate() {
+        *v = (q0[k] * s0 + q1[k] * s1) * inv_sin;
+    }
+    q
+}
+
+// The SRT at `t` between `a` and `b`.
+pub(crate) fn mix_srt(a: &SrtTransform, b: &SrtTransform, t: f32) -> SrtTransform {
+    let mut srt = [0f32; 16];
+    for (k, v) in srt.iter_mut().enumerate() {
+        *v = mix(a[k], b[k], t);
+    }
+    let (q0, q1) = srt_quats(a, b);
+    srt[9..13].copy_from_slice(&slerp(&q0, &q1, t));
+    srt
+}
+
+// The scale, shear and pivot of an SRT as a 4x3 column major matrix.
+fn srt_scale_4x3(srt: &SrtTransform) -> [f32; 12] {
+    [
+        srt[0], 0.0, 0.0, // sx
+        srt[1], srt[4], 0.0, // a, sy
+        srt[2], srt[5], srt[7], // b, c, sz
+        srt[3], srt[6], srt[8], // pivot
+    ]
+}
+
+// The rotation of an SRT as a 4x3 column major matrix.
+fn srt_rotation_4x3(srt: &SrtTransform) -> [f32; 12] {
+    let [x, y, z, w] = normalize4([srt[9], srt[10], srt[11], srt[12]]);
+    [
+        1.0 - 2.0 * (y * y + z * z),
+        2.0 * (x * y + z * w),
+        2.0 * (x * z - y * w),
+        2.0 * (x * y - z * w),
+        1.0 - 2.0 * (x * x + z * z),
+        2.0 * (y * z + x * w),
+        2.0 * (x * z + y * w),
+        2.0 * (y * z - x * w),
+        1.0 - 2.0 * (x * x + y * y),
+        0.0,
+        0.0,
+        0.0,
+    ]
+}
+
+// a * b, 4x3 column major
+fn mul_4x3(a: &[f32; 12], b: &[f32; 12]) -> [f32; 12] {
+    let mut m = [0f32; 12];
+    for c in 0..4 {
+        for r in 0..3 {
+            m[3 * c + r] = a[r] * b[3 * c] + a[3 + r] * b[3 * c + 1] + a[6 + r] * b[3 * c + 2];
+        }
+    }
+    for r in 0..3 {
+        m[9 + r] += a[9 + r];
+    }
+    m
+}
+
+pub(crate) fn srt_to_4x3(srt: &SrtTransform) -> [f32; 12] {
+    let mut m = mul_4x3(&srt_rotation_4x3(srt), &srt_scale_4x3(srt));
+    m[9] += srt[13];
+    m[10] += srt[14];
+    m[11] += srt[15];
+    m
+}
+
+// Inverse of an affine 4x3 column major transform, from the cross products
+// of its columns like affineInverse in trace.glsl.
+pub(crate) fn inverse_4x3(m: &[f32; 12]) -> [f32; 12] {
+    let col = |c: usize| Point3::new(m[3 * c], m[3 * c + 1], m[3 * c + 2]);
+    let (c0, c1, c2, c3) = (col(0), col(1), col(2), col(3));
+    // rows of the inverse
+    let inv_det = 1.0 / c0.dot(c1.cross(c2));
+    let r0 = c1.cross(c2) * inv_det;
+    let r1 = c2.cross(c0) * inv_det;
+    let r2 = c0.cross(c1) * inv_det;
+    [
+        r0.x,
+        r1.x,
+        r2.x,
+        r0.y,
+        r1.y,
+        r2.y,
+        r0.z,
+        r1.z,
+        r2.z,
+        -r0.dot(c3),
+        -r1.dot(c3),
+        -r2.dot(c3),
+    ]
+}
+
+fn aabb_array(aabb: &AABB) -> [f32; 6] {
+    [
+        aabb.min.x, aabb.min.y, aabb.min.z, aabb.max.x, aabb.max.y, aabb.max.z,
+    ]
+}
+
+// Bounds of SRT motion over [0, 1], in segments. Within a segment, scale and
+// translation move linearly, so the scaled box stays within the bounds of
+// its keyframes, and so does the translation. The rotation turns about a
+// fixed axis, so points move on arcs, which bulge out of their chords by at
+// most radius * (1 - cos(angle / 2)).
+fn srt_motion_aabb(a: &SrtTransform, b: &SrtTransform, blas_aabb: &[f32; 6]) -> AABB {
+    let (q0, q1) = srt_quats(a, b);
+    let angle = 2.0 * dot4(&q0, &q1).min(1.0).acos();
+    let bulge = 1.0 - (0.5 * angle / SRT_BOUND_SEGMENTS as f32).cos();
+    let mut aabb = AABB::empty();
+    for s in 0..SRT_BOUND_SEGMENTS {
+        let k0 = mix_srt(a, b, s as f32 / SRT_BOUND_SEGMENTS as f32);
+        let k1 = mix_srt(a, b, (s + 1) as f32 / SRT_BOUND_SEGMENTS as f32);
+        let scaled = transform_aabb(&srt_scale_4x3(&k0), blas_aabb)
+            .join(&transform_aabb(&srt_scale_4x3(&k1), blas_aabb));
+        let scaled = aabb_array(&scaled);
+        let mut rotated = transform_aabb(&srt_rotation_4x3(&k0), &scaled)
+            .join(&transform_aabb(&srt_rotation_4x3(&k1), &scaled));
+        let radius = scaled[..3]
+            .iter()
+            .zip(&scaled[3..])
+            .map(|(lo, hi)| lo.abs().max(hi.abs()).powi(2))
+            .sum::<f32>()
+            .sqrt();
+        let pad = Point3::splat(radius * bulge);
+        rotated.min -= pad;
+        rotated.max += pad;
+        let t0 = Point3::new(k0[13], k0[14], k0[15]);
+        let t1 = Point3::new(k1[13], k1[14], k1[15]);
+        rotated.min += t0.min(t1);
+        rotated.max += t0.max(t1);
+        aabb.join_mut(&rotated);
+    }
+    aabb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{inverse_4x3, srt_to_4x3, InstanceMotion};
+    use crate::traverse::transform_4x3;
+    use bvh::Point3;
+
+    // a unit scale SRT rotating about z by `angle`, at `x`
+    fn srt(angle: f32, x: f32) -> [f32; 16] {
+        let (s, c) = (0.5 * angle).sin_cos();
+        [
+            1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, s, c, x, 0.0, 0.0,
+        ]
+    }
+
+    #[test]
+    fn test_srt_motion_bounds() {
+        let start = srt(0.0, 0.0);
+        let end = srt(std::f32::consts::FRAC_PI_2, 10.0);
+        let motion = InstanceMotion::Srt(start, end);
+        let transform = motion.start_transform(&[0.0; 12]);
+        assert_eq!(transform, srt_to_4x3(&start));
+        // a box sticking out along x, a quarter turn sweeps it through +y
+        let blas_aabb = [0.0, -0.5, -0.5, 2.0, 0.5, 0.5];
+        let aabb = motion.aabb(&transform, &blas_aabb);
+        for step in 0..=64 {
+            let time = step as f32 / 64.0;
+            let m = motion.transform_at(&transform, time);
+            for corner in 0..8 {
+                let p = Point3::new(
+                    blas_aabb[if corner & 1 == 0 { 0 } else { 3 }],
+                    blas_aabb[if corner & 2 == 0 { 1 } else { 4 }],
+                    blas_aabb[if corner & 4 == 0 { 2 } else { 5 }],
+                );
+                let p = transform_4x3(&m, p, 1.0);
+                assert!(
+                    aabb.contains(&p),
+                    "{:?} at time {} outside {:?}",
+                    p,
+                    time,
+                    aabb
+                );
+            }
+        }
+        // halfway, the box points along the diagonal and is translated by 5
+        let m = motion.transform_at(&transform, 0.5);
+        let p = transform_4x3(&m, Point3::new(2.0, 0.0, 0.0), 1.0);
+        let d = std::f32::consts::SQRT_2;
+        assert!((p - Point3::new(5.0 + d, d, 0.0)).length() < 1e-5);
+        // not much looser than the swept area
+        assert!(aabb.max.y < 2.3 && aabb.min.y > -0.8);
+
+        let inverse = inverse_4x3(&m);
+        let q = transform_4x3(&inverse, p, 1.0);
+        assert!((q - Point3::new(2.0, 0.0, 0.0)).length() < 1e-5);
+    }
+
+    #[test]
+    fn test_matrix_motion_bounds() {
+        let start = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+        let mut end = start;
+        end[9] = 4.0;
+        let motion = InstanceMotion::Matrix(end);
+        let aabb = motion.aabb(&start, &[0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+        assert_eq!(aabb.min, Point3::new(0.0, 0.0, 0.0));
+        assert_eq!(aabb.max, Point3::new(5.0, 1.0, 1.0));
+        assert_eq!(motion.transform_at(&start, 0.25)[9], 1.0);
+        // clamped
+        assert_eq!(motion.transform_at(&start, 2.0)[9], 4.0);
+    }
+}
@@ -0,0 +1,584 @@
+// The Rust API for building BVHs from typed geometries and instances,
+// without staging buffers. build_blas and build_tlas are adapters over it.
+
+use crate::builder::{BuildOptions, Bvh};
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::instance_bounds::{node_boxes, BlasBounds, InstanceBounds};
+use crate::layout::{read_blas_node, read_primitive_ref, read_tlas_node};
+use crate::layout::{BlasNode, TlasNode};
+use crate::parallel;
+use crate::vertex::{IndexFormat, VertexFormat};
+use crate::StagingBuffer;
+use crate::{serialize_blas_nodes, serialize_blas_nodes_wide, serialize_blas_primitive_refs};
+use crate::{serialize_tlas_nodes, tlas_instance_leaf_nodes};
+use crate::{BuiltBvh, GeometryFlags, GeometryType, NodeFormat, Primitive, PrimitiveClass};
+use crate::{TlasInstance, TlasInstanceDescriptor, TransformClass};
+use bvh::aabb::{Bounded, AABB};
+use std::mem;
+use std::sync::Arc;
+
+/// A BLAS geometry, borrowing its vertices and indices.
+#[derive(Debug, Clone, Copy)]
+pub enum Geometry<'a> {
+    /// A triangle list, of `indices` if given, else of `vertices`.
+    Triangles {
+        vertices: &'a [[f32; 3]],
+        /// The vertices at time 1 of a moving geometry, as many as
+        /// `vertices`, see `Ray::time`.
+        end_vertices: Option<&'a [[f32; 3]]>,
+        indices: Option<&'a [u32]>,
+        /// 4x3 column major, applied to the vertices.
+        transform: Option<&'a [f32; 12]>,
+        flags: GeometryFlags,
+    },
+    /// A single AABB primitive as min.xyz, max.xyz, like the AABB
+    /// geometries of wasm_bvh_builder.ts.
+    Aabb {
+        aabb: &'a [f32; 6],
+        flags: GeometryFlags,
+    },
+}
+
+/// A BLAS built by `build_blas_from_geometries`, the same buffers as the
+/// staging buffers of a `BuiltBvh`.
+#[derive(Debug, Clone)]
+pub struct BuiltBlas {
+    /// GPUBlasBvhNode, or the wide node struct of `format`.
+    pub serialized: Vec<u8>,
+    pub num_nodes: u32,
+    pub format: NodeFormat,
+    pub aabb: AABB,
+    /// GPUBlasPrimitiveRef
+    pub primitive_refs: Vec<u8>,
+    pub num_primitive_refs: u32,
+    pub num_inactive: u32,
+    pub num_degenerate: u32,
+    /// Boxes of up to 8 nodes near the root that cover the primitives, see
+    /// `InstanceBounds::NodeBoxes`.
+    pub node_boxes: Vec<AABB>,
+}
+
+/// A TLAS built by `build_tlas_from_instances`, see `BuiltBlas`.
+#[derive(Debug, Clone)]
+pub struct BuiltTlas {
+    /// GPUTlasBvhNode
+    pub serialized: Vec<u8>,
+    pub num_nodes: u32,
+    pub aabb: AABB,
+    /// The leaf node index of each instance, u32::MAX for instances left out
+    /// of the tree.
+    pub instance_leaf_nodes: Vec<u32>,
+    pub num_inactive: u32,
+    /// Instances with a singular transform or NaN bounds.
+    pub num_degenerate: u32,
+    /// The bounds of the BLAS of each instance the TLAS was built with, empty
+    /// for root box bounds. Kept for `update_tlas`.
+    pub blas_bounds: Vec<Arc<BlasBounds>>,
+}
+
+impl BuiltBlas {
+    /// The serialized nodes, in the stackless pre-order layout. Only for
+    /// binary BLASes, built with a branching factor of 2.
+    pub fn nodes(&self) -> Vec<BlasNode> {
+        assert!(self.format == NodeFormat::Full, "not a binary BLAS");
+        (0..self.num_nodes)
+            .map(|i| read_blas_node(&self.serialized, i))
+            .collect()
+    }
+
+    /// (geometry index, primitive index) of each primitive ref.
+    pub fn primitive_refs(&self) -> Vec<(i32, u32)> {
+        (0..self.num_primitive_refs)
+            .map(|i| read_primitive_ref(&self.primitive_refs, i))
+            .collect()
+    }
+}
+
+impl BuiltTlas {
+    /// The serialized nodes, in the stackless pre-order layout.
+    pub fn nodes(&self) -> Vec<TlasNode> {
+        (0..self.num_nodes)
+            .map(|i| read_tlas_node(&self.serialized, i))
+            .collect()
+    }
+}
+
+impl BlasBounds {
+    /// The bounds of `blas` for `mode`, `geometries` are the ones it was
+    /// built from. Fails like `build_blas_from_geometries`.
+    pub fn new(
+        mode: InstanceBounds,
+        blas: &BuiltBlas,
+        geometries: &[Geometry],
+    ) -> Result<Self, BvhBuildError> {
+        let primitives = match mode {
+            InstanceBounds::Vertices => geometries_primitives(geometries)?,
+            _ => Vec::new(),
+        };
+        Ok(Self::from_primitives(
+            mode,
+            &blas.aabb,
+            &blas.node_boxes,
+            &primitives,
+        ))
+    }
+}
+
+/// Builds a BLAS like `build_blas`, from typed geometries. Fails with the
+/// location of the first geometry with an index past its vertices, a vertex
+/// or index count that is not a multiple of 3, or end vertices that are not
+/// as many as its vertices, and for an unsupported branching factor.
+pub fn build_blas_from_geometries(
+    geometries: &[Geometry],
+    options: &BuildOptions,
+) -> Result<BuiltBlas, BvhBuildError> {
+    options.validate()?;
+    Ok(build_blas_primitives(
+        &geometries_primitives(geometries)?,
+        options,
+    ))
+}
+
+/// Builds independent BLASes, at the same time with the `parallel` feature.
+/// Fails with the error of the first BLAS that fails to build.
+pub fn build_blases_from_geometries(
+    blases: &[&[Geometry]],
+    options: &BuildOptions,
+) -> Result<Vec<BuiltBlas>, BvhBuildError> {
+    parallel::map(blases, |geometries| {
+        build_blas_from_geometries(geometries, options)
+    })
+    .into_iter()
+    .collect()
+}
+
+/// Builds a TLAS like `build_tlas`, from typed instances. Fails with the
+/// location of the first instance with invalid flags, and for an unsupported
+/// branching factor.
+pub fn build_tlas_from_instances(
+    instances: &[TlasInstance],
+    options: &BuildOptions,
+) -> Result<BuiltTlas, BvhBuildError> {
+    build_tlas_from_instances_with_bounds(instances, &[], options)
+}
+
+/// Builds a TLAS like `build_tlas_from_instances`, bounding each instance by
+/// the transformed `blas_bounds` of the BLAS it references instead of its
+/// `blas_aabb`. `blas_bounds` is empty or has one entry per instance,
+/// `options.instance_bounds` is not used.
+pub fn build_tlas_from_instances_with_bounds(
+    instances: &[TlasInstance],
+    blas_bounds: &[Arc<BlasBounds>],
+    options: &BuildOptions,
+) -> Result<BuiltTlas, BvhBuildError> {
+    options.validate()?;
+    if !blas_bounds.is_empty() && blas_bounds.len() != instances.len() {
+        return Err(BvhBuildError::LengthMismatch {
+            at: DescriptorLocation::Descriptor,
+            expected: instances.len(),
+            actual: blas_bounds.len(),
+        });
+    }
+    let mut instances = instances
+        .iter()
+        .enumerate()
+        .map(|(i, inst)| TlasInstanceDescriptor::new(i as u32, inst))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (inst, bounds) in instances.iter_mut().zip(blas_bounds) {
+        inst.bound_by(bounds);
+    }
+
+    log!("building from tlas instances: {:?}", instances);
+    // instances of empty BLASes are inactive, a singular transform (see
+    // TransformClass) or NaN bounds make an instance degenerate
+    let mut active = Vec::<u32>::with_capacity(instances.len());
+    let mut instance_aabbs = Vec::<AABB>::with_capacity(instances.len());
+    let mut num_inactive = 0;
+    let mut num_degenerate = 0;
+    for (i, inst) in instances.iter().enumerate() {
+        if !(inst.blas_aabb.min.x <= inst.blas_aabb.max.x) {
+            num_inactive += 1;
+        } else if inst.transform_class == TransformClass::Singular {
+            log!("culling instance {} with a singular transform", i);
+            num_degenerate += 1;
+        } else if !(0..3).all(|a| {
+            inst.aabb.min[a].is_finite()
+                && inst.aabb.max[a].is_finite()
+                && inst.aabb.min[a] <= inst.aabb.max[a]
+        }) {
+            num_degenerate += 1;
+        } else {
+            active.push(i as u32);
+            instance_aabbs.push(inst.aabb());
+        }
+    }
+    let mut bvh = Bvh::build(
+        &instance_aabbs,
+        &BuildOptions {
+            // a TLAS leaf references exactly one instance
+            max_leaf_size: 1,
+            branching_factor: 2,
+            // instances are not split, each has exactly one leaf
+            spatial_split_budget: 0.0,
+            ..*options
+        },
+    );
+    log!("tlas bvh tree: {:?}", bvh.nodes);
+    for prim in bvh.prim_indices.iter_mut() {
+        *prim = active[*prim as usize];
+    }
+
+    Ok(BuiltTlas {
+        serialized: serialize_tlas_nodes(&bvh, &instances),
+        num_nodes: bvh.nodes.len().max(1) as u32,
+        aabb: bvh.nodes.first().map_or(AABB::empty(), |n| n.aabb),
+        instance_leaf_nodes: tlas_instance_leaf_nodes(&bvh, instances.len()),
+        num_inactive,
+        num_degenerate,
+        blas_bounds: blas_bounds.to_vec(),
+    })
+}
+
+// The primitives of `geometries`, in BLAS order.
+fn geometries_primitives<'a>(
+    geometries: &[Geometry<'a>],
+) -> Result<Vec<Primitive<'a>>, BvhBuildError> {
+    let mut primitives = Vec::new();
+    for (gi, geometry) in geometries.iter().enumerate() {
+        let (template, num_primitives) = geometry_primitive(gi as u32, geometry)?;
+        for pi in 0..num_primitives {
+            primitives.push(Primitive {
+                primitive_id: pi,
+                within_blas_primitive_id: primitives.len() as u32,
+                ..template
+            });
+        }
+    }
+    Ok(primitives)
+}
+
+pub(crate) fn build_blas_primitives(primitives: &[Primitive], options: &BuildOptions) -> BuiltBlas {
+    // log!("building from primitives: {:?}", primitives);
+    // indices of the primitives that go into the tree
+    let mut active = Vec::<u32>::with_capacity(primitives.len());
+    let mut prim_aabbs = Vec::<AABB>::with_capacity(primitives.len());
+    let mut num_inactive = 0;
+    let mut num_degenerate = 0;
+    for (i, p) in primitives.iter().enumerate() {
+        match p.classify() {
+            PrimitiveClass::Active(aabb) => {
+                active.push(i as u32);
+                prim_aabbs.push(aabb);
+            }
+            PrimitiveClass::Inactive => num_inactive += 1,
+            PrimitiveClass::Degenerate => num_degenerate += 1,
+        }
+    }
+    // a primitive in several leaves could invoke the any-hit shader twice,
+    // and split bounds of a moving triangle only hold at the keyframes
+    let no_duplicates = primitives.iter().any(|p| {
+        p.flags
+            .contains(GeometryFlags::NO_DUPLICATE_ANY_HIT_INVOCATION)
+            || p.end_vbuf.is_some()
+    });
+    let mut bvh = if options.spatial_split_budget > 0.0 && !no_duplicates {
+        Bvh::build_spatial(&prim_aabbs, options, |prim, axis, pos| {
+            primitives[active[prim as usize] as usize].split_aabb(axis, pos)
+        })
+    } else {
+        Bvh::build(&prim_aabbs, options)
+    };
+    for prim in bvh.prim_indices.iter_mut() {
+        *prim = active[*prim as usize];
+    }
+    // log!("bvh tree: {:?}", bvh.nodes);
+
+    let (serialized, num_nodes, format) = match options.branching_factor {
+        2 => (
+            serialize_blas_nodes(&bvh),
+            bvh.nodes.len().max(1) as u32,
+            NodeFormat::Full,
+        ),
+        4 | 8 => {
+            let format = if options.branching_factor == 4 {
+                NodeFormat::Wide4
+            } else {
+                NodeFormat::Wide8
+            };
+            let (serialized, num_nodes) = serialize_blas_nodes_wide(&bvh, format);
+            (serialized, num_nodes, format)
+        }
+        b => unreachable!("branching factor {} not validated", b),
+    };
+    BuiltBlas {
+        serialized,
+        num_nodes,
+        format,
+        aabb: bvh.nodes.first().map_or(AABB::empty(), |n| n.aabb),
+        primitive_refs: serialize_blas_primitive_refs(&bvh, primitives),
+        num_primitive_refs: bvh.prim_indices.len() as u32,
+        num_inactive,
+        num_degenerate,
+        node_boxes: node_boxes(&bvh.nodes),
+    }
+}
+
+// The first primitive of a geometry and its primitive count, like
+// blas_geometries.
+fn geometry_primitive<'a>(
+    gi: u32,
+    geometry: &Geometry<'a>,
+) -> Result<(Primitive<'a>, u32), BvhBuildError> {
+    let at = DescriptorLocation::Geometry(gi);
+    let primitive =
+        |geometry_type, vbuf, ibuf, transform: Option<&'a [f32; 12]>, flags| Primitive {
+            blas_local_geometry_id: gi,
+            within_blas_primitive_id: 0,
+            primitive_id: 0,
+            geometry_type,
+            vbuf,
+            end_vbuf: None,
+            vertex_format: VertexFormat::Float32x3,
+            vertex_stride: mem::size_of::<[f32; 3]>(),
+            ibuf,
+            index_format: IndexFormat::Uint32,
+            transform: transform.map(|t| &t[..]),
+            flags,
+        };
+    match *geometry {
+        Geometry::Triangles {
+            vertices,
+            end_vertices,
+            indices,
+            transform,
+            flags,
+        } => {
+            if let Some(end) = end_vertices.filter(|end| end.len() != vertices.len()) {
+                return Err(BvhBuildError::LengthMismatch {
+                    at,
+                    expected: vertices.len(),
+                    actual: end.len(),
+                });
+            }
+            let num_indices = indices.map_or(vertices.len(), |i| i.len());
+            if num_indices % 3 != 0 {
+                return Err(BvhBuildError::LengthMismatch {
+                    at,
+                    expected: num_indices - num_indices % 3,
+                    actual: num_indices,
+                });
+            }
+            if let Some(&index) =
+                indices.and_then(|i| i.iter().find(|&&i| i as usize >= vertices.len()))
+            {
+                return Err(BvhBuildError::IndexOutOfRange {
+                    at,
+                    index: index as usize,
+                    len: vertices.len(),
+                });
+            }
+            Ok((
+                Primitive {
+                    end_vbuf: end_vertices.map(as_bytes),
+                    ..primitive(
+                        GeometryType::Triangle,
+                        as_bytes(vertices),
+                        indices.map(as_bytes),
+                        transform,
+                        flags,
+                    )
+                },
+                (num_indices / 3) as u32,
+            ))
+        }
+        Geometry::Aabb { aabb, flags } => Ok((
+            primitive(GeometryType::Aabb, as_bytes(&aabb[..]), None, None, flags),
+            1,
+        )),
+    }
+}
+
+// Staging buffers hold little endian bytes, like the target.
+fn as_bytes<T: Copy>(v: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, mem::size_of_val(v)) }
+}
+
+// Moves the buffers into staging buffers, fails when no id is left.
+impl TryFrom<BuiltBlas> for BuiltBvh {
+    type Error = BvhBuildError;
+
+    fn try_from(blas: BuiltBlas) -> Result<Self, BvhBuildError> {
+        Ok(BuiltBvh {
+            serialized: StagingBuffer::from_existing_buffer(blas.serialized)?,
+            num_nodes: blas.num_nodes,
+            format: blas.format,
+            aabb: blas.aabb,
+            primitive_refs: Some(StagingBuffer::from_existing_buffer(blas.primitive_refs)?),
+            num_primitive_refs: blas.num_primitive_refs,
+            instance_leaf_nodes: None,
+            num_inactive: blas.num_inactive,
+            num_degenerate: blas.num_degenerate,
+            blas_bounds: Vec::new(),
+        })
+    }
+}
+
+impl TryFrom<BuiltTlas> for BuiltBvh {
+    type Error = BvhBuildError;
+
+    fn try_from(tlas: BuiltTlas) -> Result<Self, BvhBuildError> {
+        let leaf_nodes: Vec<u8> = tlas
+            .instance_leaf_nodes
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        Ok(BuiltBvh {
+            serialized: StagingBuffer::from_existing_buffer(tlas.serialized)?,
+            num_nodes: tlas.num_nodes,
+            format: NodeFormat::Full,
+            aabb: tlas.aabb,
+            primitive_refs: None,
+            num_primitive_refs: 0,
+            instance_leaf_nodes: Some(StagingBuffer::from_existing_buffer(leaf_nodes)?),
+            num_inactive: tlas.num_inactive,
+            num_degenerate: tlas.num_degenerate,
+            blas_bounds: tlas.blas_bounds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_blas_from_geometries, build_blases_from_geometries};
+    use super::{build_tlas_from_instances, Geometry};
+    use crate::builder::BuildOptions;
+    use crate::error::{BvhBuildError, DescriptorLocation};
+    use crate::{GeometryFlags, InstanceFlags, NodeFormat, TlasInstance, TransformClass};
+
+    #[test]
+    fn test_build_from_typed_geometries() {
+        let vertices = [
+            [0.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+        ];
+        let indices = [0, 1, 2, 0, 2, 3];
+        let unit_box = [2.0, 0.0, 0.0, 3.0, 1.0, 1.0];
+        let geometries = [
+            Geometry::Triangles {
+                vertices: &vertices,
+                end_vertices: None,
+                indices: Some(&indices),
+                transform: None,
+                flags: GeometryFlags::NONE,
+            },
+            Geometry::Aabb {
+                aabb: &unit_box,
+                flags: GeometryFlags::OPAQUE,
+            },
+        ];
+        let blas = build_blas_from_geometries(&geometries, &BuildOptions::default()).unwrap();
+        assert_eq!(blas.format, NodeFormat::Full);
+        assert_eq!(blas.num_primitive_refs, 3);
+        let mut refs = blas.primitive_refs();
+        refs.sort();
+        assert_eq!(refs, vec![(0, 0), (0, 1), (1, 0)]);
+        let nodes = blas.nodes();
+        assert_eq!(nodes.len() as u32, blas.num_nodes);
+        assert_eq!(nodes[0].aabb.max.x, 3.0);
+        assert_eq!(nodes.iter().map(|n| n.primitive_count).sum::<u32>(), 3);
+
+        let blases = build_blases_from_geometries(
+            &[&geometries, &geometries[1..]],
+            &BuildOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(blases[0].serialized, blas.serialized);
+        assert_eq!(blases[0].primitive_refs, blas.primitive_refs);
+        assert_eq!(blases[1].num_primitive_refs, 1);
+
+        let out_of_range = [0, 1, 4];
+        let err = build_blas_from_geometries(
+            &[
+                geometries[1],
+                Geometry::Triangles {
+                    vertices: &vertices,
+                    end_vertices: None,
+                    indices: Some(&out_of_range),
+                    transform: None,
+                    flags: GeometryFlags::NONE,
+                },
+            ],
+            &BuildOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            BvhBuildError::IndexOutOfRange {
+                at: DescriptorLocation::Geometry(1),
+                index: 4,
+                len: 4
+            }
+        );
+        let options = BuildOptions {
+            branching_factor: 3,
+            ..BuildOptions::default()
+        };
+        let err = build_blas_from_geometries(&geometries, &options).unwrap_err();
+        assert_eq!(
+            err,
+            BvhBuildError::UnsupportedBranchingFactor {
+                at: DescriptorLocation::Descriptor,
+                branching_factor: 3
+            }
+        );
+
+        let instance = |instance_id, x| TlasInstance {
+            mask: 0xff,
+            flags: InstanceFlags::NONE.bits(),
+            instance_id,
+            sbt_instance_offset: 0,
+            instance_custom_index: 0,
+            blas_entry_index: 0,
+            blas_geometry_id_offset: 0,
+            blas_primitive_ref_offset: 0,
+            blas_aabb: [0.0, 0.0, 0.0, 3.0, 1.0, 1.0],
+            transform_to_world_4x3: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, x, 0.0, 0.0],
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        };
+        let tlas = build_tlas_from_instances(
+            &[instance(0, 0.0), instance(1, 10.0)],
+            &BuildOptions::default(),
+        )
+        .unwrap();
+        let nodes = tlas.nodes();
+        assert_eq!(nodes.len(), 3);
+        assert_eq!(nodes[0].aabb.max.x, 13.0);
+        for (i, &leaf) in tlas.instance_leaf_nodes.iter().enumerate() {
+            let node = &nodes[leaf as usize];
+            assert!(node.is_leaf);
+            assert_eq!(node.instance_id, i as u32);
+        }
+        let identity = nodes[tlas.instance_leaf_nodes[0] as usize].transform_class;
+        assert_eq!(identity, TransformClass::Identity);
+        let translated = nodes[tlas.instance_leaf_nodes[1] as usize].transform_class;
+        assert_eq!(translated, TransformClass::Rigid);
+
+        // a zero scale leaves the instance out instead of a NaN inverse
+        let mut flat = instance(2, 20.0);
+        flat.transform_to_world_4x3[8] = 0.0;
+        let tlas = build_tlas_from_instances(
+            &[instance(0, 0.0), instance(1, 10.0), flat],
+            &BuildOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(tlas.num_degenerate, 1);
+        assert_eq!(tlas.instance_leaf_nodes[2], u32::MAX);
+        assert!(tlas
+            .nodes()
+            .iter()
+            .all(|n| n.transform_to_object.iter().all(|v| v.is_finite())));
+    }
+}
@@ -0,0 +1,57 @@
+// Threading of BVH builds. With the `parallel` feature work is spread over
+// the rayon thread pool, on wasm the one started by `initThreadPool`.
+// Results are put together in input order, so builds are identical to
+// single threaded ones.
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+/// Nodes with fewer references are built on the current thread, splitting
+/// them up costs more than it saves.
+#[cfg(feature = "parallel")]
+pub const MIN_PARALLEL_REFS: usize = 4096;
+#[cfg(not(feature = "parallel"))]
+pub const MIN_PARALLEL_REFS: usize = usize::MAX;
+
+#[cfg(feature = "parallel")]
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    rayon::join(a, b)
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn join<A, B, RA, RB>(a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    (a(), b())
+}
+
+/// `items.iter().map(f).collect()`, mapping items at the same time.
+#[cfg(feature = "parallel")]
+pub fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    items.par_iter().map(f).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+pub fn map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync + Send,
+{
+    items.iter().map(f).collect()
+}
@@ -0,0 +1,382 @@
+// Overlap and nearest point queries on the same TLAS and BLASes as
+// traverse.rs. Queries are in world space, BLAS nodes and primitives are
+// transformed with the world transform of their instance. AABB primitives are
+// tested with their transformed bounds.
+
+use crate::layout::{read_tlas_node, TlasNode};
+use crate::transform_aabb;
+use crate::traverse::{transform_4x3, BlasBuffers};
+use crate::{BuiltBvh, GeometryType};
+use bvh::aabb::{Bounded, AABB};
+use bvh::Point3;
+use wasm_bindgen::prelude::*;
+
+const TRAVERSE_MAX_INT: u32 = u32::MAX;
+
+/// A world space query volume.
+#[derive(Debug, Clone, Copy)]
+pub enum Volume {
+    Aabb(AABB),
+    Sphere {
+        center: Point3,
+        radius: f32,
+    },
+    /// Planes as (normal.xyz, d), points with dot(normal, p) + d >= 0 are
+    /// inside.
+    Frustum([[f32; 4]; 6]),
+}
+
+/// A primitive overlapping a query volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Overlap {
+    pub instance_id: u32,
+    /// Within the BLAS.
+    pub geometry_id: i32,
+    pub primitive_id: u32,
+}
+
+/// The primitive nearest to a query point.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClosestPoint {
+    pub instance_id: u32,
+    pub geometry_index: i32,
+    pub primitive_id: u32,
+    pub distance: f32,
+    point: Point3,
+}
+
+#[wasm_bindgen]
+impl ClosestPoint {
+    /// The nearest point on the primitive, in world space.
+    pub fn point(&self) -> Vec<f32> {
+        vec![self.point.x, self.point.y, self.point.z]
+    }
+}
+
+/// Primitives of instances matching `cull_mask` that overlap `volume`.
+pub fn overlaps(
+    tlas: &BuiltBvh,
+    blases: &BlasBuffers,
+    volume: &Volume,
+    cull_mask: u32,
+) -> Vec<Overlap> {
+    assert!(tlas.primitive_refs.is_none(), "not a TLAS");
+    tlas.serialized
+        .with_buffer(|nodes| overlaps_nodes(nodes, blases, volume, cull_mask))
+}
+
+/// The primitive of an instance matching `cull_mask` nearest to `point`,
+/// if there is one within `max_distance`.
+pub fn closest_point(
+    tlas: &BuiltBvh,
+    blases: &BlasBuffers,
+    point: Point3,
+    max_distance: f32,
+    cull_mask: u32,
+) -> Option<ClosestPoint> {
+    assert!(tlas.primitive_refs.is_none(), "not a TLAS");
+    tlas.serialized
+        .with_buffer(|nodes| closest_point_nodes(nodes, blases, point, max_distance, cull_mask))
+}
+
+pub(crate) fn overlaps_nodes(
+    tlas_nodes: &[u8],
+    blases: &BlasBuffers,
+    volume: &Volume,
+    cull_mask: u32,
+) -> Vec<Overlap> {
+    let mut overlaps = Vec::new();
+    walk(
+        tlas_nodes,
+        blases,
+        cull_mask,
+        |aabb| volume.overlaps_aabb(aabb),
+        |instance, geometry_id, primitive_id, shape| {
+            if volume.overlaps_shape(shape) {
+                overlaps.push(Overlap {
+                    instance_id: instance.instance_id,
+                    geometry_id,
+                    primitive_id,
+                });
+            }
+        },
+    );
+    overlaps
+}
+
+pub(crate) fn closest_point_nodes(
+    tlas_nodes: &[u8],
+    blases: &BlasBuffers,
+    point: Point3,
+    max_distance: f32,
+    cull_mask: u32,
+) -> Option<ClosestPoint> {
+    // squared distance bound, shrinks as primitives are found
+    let bound = std::cell::Cell::new(max_distance * max_distance);
+    let mut closest = None;
+    walk(
+        tlas_nodes,
+        blases,
+        cull_mask,
+        |aabb| (clamp_to_aabb(point, aabb) - point).length_squared() <= bound.get(),
+        |instance, geometry_id, primitive_id, shape| {
+            let p = match shape {
+                Shape::Triangle(v) => closest_point_on_triangle(point, v),
+                Shape::Aabb(aabb) => clamp_to_aabb(point, aabb),
+            };
+            let d2 = (p - point).length_squared();
+            if d2 <= bound.get() {
+                bound.set(d2);
+                closest = Some(ClosestPoint {
+                    instance_id: instance.instance_id,
+                    geometry_index: geometry_id,
+                    primitive_id,
+                    distance: d2.sqrt(),
+                    point: p,
+                });
+            }
+        },
+    );
+    closest
+}
+
+// A primitive in world space.
+pub(crate) enum Shape {
+    Triangle([Point3; 3]),
+    Aabb(AABB),
+}
+
+// Visits the primitives of the instances matching `cull_mask` in subtrees
+// whose world space bounds pass `enter`, with the same stackless order as
+// trace_ray.
+fn walk(
+    tlas_nodes: &[u8],
+    blases: &BlasBuffers,
+    cull_mask: u32,
+    enter: impl Fn(&AABB) -> bool,
+    mut visit: impl FnMut(&TlasNode, i32, u32, &Shape),
+) {
+    let mut cur = 0u32;
+    while cur < TRAVERSE_MAX_INT {
+        let node = read_tlas_node(tlas_nodes, cur);
+        if (node.is_leaf && (node.mask & cull_mask) == 0)
+            || is_empty(&node.aabb)
+            || !enter(&node.aabb)
+        {
+            cur = node.exit_index;
+            continue;
+        }
+        if !node.is_leaf {
+            cur = node.entry_index;
+            continue;
+        }
+
+        let world = &node.transform_to_world;
+        let blas_index_offset = node.entry_index;
+        let mut blas_cur = blas_index_offset;
+        loop {
+            let blas_node = blases.node(blas_cur, &node.blas_aabb);
+            let entered = !is_empty(&blas_node.aabb) && enter(&to_world(world, &blas_node.aabb));
+            if entered && blas_node.primitive_count == 0 {
+                blas_cur = blas_node.entry_index_or_first_primitive + blas_index_offset;
+                continue;
+            }
+            if entered {
+                for k in 0..blas_node.primitive_count {
+                    let (geometry_id, primitive) =
+                        blases.primitive(&node, blas_node.entry_index_or_first_primitive + k);
+                    let shape = if primitive.geometry_type == GeometryType::Triangle {
+                        Shape::Triangle(
+                            primitive
+                                .triangle_vertices()
+                                .map(|v| transform_4x3(world, v, 1.0)),
+                        )
+                    } else {
+                        Shape::Aabb(to_world(world, &primitive.aabb()))
+                    };
+                    visit(&node, geometry_id, primitive.primitive_id, &shape);
+                }
+            }
+            if blas_node.exit_index == TRAVERSE_MAX_INT {
+                break;
+            }
+            blas_cur = blas_node.exit_index + blas_index_offset;
+        }
+        cur = node.exit_index;
+    }
+}
+
+// empty roots of empty BLASes and TLASes are inverted
+fn is_empty(aabb: &AABB) -> bool {
+    aabb.min.x > aabb.max.x
+}
+
+fn to_world(world: &[f32; 12], aabb: &AABB) -> AABB {
+    transform_aabb(
+        world,
+        &[
+            aabb.min.x, aabb.min.y, aabb.min.z, aabb.max.x, aabb.max.y, aabb.max.z,
+        ],
+    )
+}
+
+impl Volume {
+    fn overlaps_aabb(&self, aabb: &AABB) -> bool {
+        match self {
+            Volume::Aabb(b) => (0..3).all(|a| b.min[a] <= aabb.max[a] && aabb.min[a] <= b.max[a]),
+            Volume::Sphere { center, radius } => {
+                (clamp_to_aabb(*center, aabb) - *center).length_squared() <= radius * radius
+            }
+            // conservative, the box is only rejected if it is outside of a
+            // single plane
+            Volume::Frustum(planes) => planes.iter().all(|p| {
+                let n = Point3::new(p[0], p[1], p[2]);
+                let farthest = Point3::select(n.cmpge(Point3::ZERO), aabb.max, aabb.min);
+                n.dot(farthest) + p[3] >= 0.0
+            }),
+        }
+    }
+
+    fn overlaps_shape(&self, shape: &Shape) -> bool {
+        match shape {
+            Shape::Aabb(aabb) => self.overlaps_aabb(aabb),
+            Shape::Triangle(v) => match self {
+                Volume::Aabb(b) => triangle_overlaps_aabb(v, b),
+                Volume::Sphere { center, radius } => {
+                    (closest_point_on_triangle(*center, v) - *center).length_squared()
+                        <= radius * radius
+                }
+                Volume::Frustum(planes) => planes.iter().all(|p| {
+                    let n = Point3::new(p[0], p[1], p[2]);
+                    v.iter().any(|v| n.dot(*v) + p[3] >= 0.0)
+                }),
+            },
+        }
+    }
+}
+
+fn clamp_to_aabb(p: Point3, aabb: &AABB) -> Point3 {
+    p.max(aabb.min).min(aabb.max)
+}
+
+// Separating axis test of Akenine-Möller, "Fast 3D Triangle-Box Overlap
+// Testing".
+fn triangle_overlaps_aabb(v: &[Point3; 3], aabb: &AABB) -> bool {
+    let center = (aabb.min + aabb.max) * 0.5;
+    let half = (aabb.max - aabb.min) * 0.5;
+    let v = v.map(|v| v - center);
+    let separated = |axis: Point3| {
+        let p = v.map(|v| v.dot(axis));
+        let r = half.dot(axis.abs());
+        p[0].min(p[1]).min(p[2]) > r || p[0].max(p[1]).max(p[2]) < -r
+    };
+    let box_axes = [
+        Point3::new(1.0, 0.0, 0.0),
+        Point3::new(0.0, 1.0, 0.0),
+        Point3::new(0.0, 0.0, 1.0),
+    ];
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+    if box_axes.iter().any(|a| separated(*a)) || separated(edges[0].cross(edges[1])) {
+        return false;
+    }
+    !box_axes
+        .iter()
+        .any(|a| edges.iter().any(|e| separated(a.cross(*e))))
+}
+
+// Ericson, Real-Time Collision Detection, 5.1.5.
+fn closest_point_on_triangle(p: Point3, [a, b, c]: &[Point3; 3]) -> Point3 {
+    let (a, b, c) = (*a, *b, *c);
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+    let denom = 1.0 / (va + vb + vc);
+    a + ab * (vb * denom) + ac * (vc * denom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{closest_point_nodes, overlaps_nodes, Overlap, Volume};
+    use crate::traverse::tests::{quad_and_box_scene, quad_vertices, unit_box};
+    use crate::traverse::BlasBuffers;
+    use crate::NodeFormat;
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_overlap_and_closest_point_queries() {
+        let (quad, unit_box) = (quad_vertices(), unit_box());
+        let mut blases = BlasBuffers::new(NodeFormat::Full).unwrap();
+        let tlas_nodes = quad_and_box_scene(&mut blases, &quad, &unit_box);
+        let query = |volume: Volume, cull_mask| {
+            let mut overlaps: Vec<(u32, i32, u32)> =
+                overlaps_nodes(&tlas_nodes, &blases, &volume, cull_mask)
+                    .iter()
+                    .map(|o: &Overlap| (o.instance_id, o.geometry_id, o.primitive_id))
+                    .collect();
+            overlaps.sort();
+            overlaps
+        };
+
+        // above the diagonal of the quad, only its second triangle
+        let small_box = AABB::with_bounds(Point3::new(0.2, 0.6, -0.1), Point3::new(0.3, 0.7, 0.1));
+        assert_eq!(query(Volume::Aabb(small_box), 0xff), vec![(0, 0, 1)]);
+
+        let sphere = Volume::Sphere {
+            center: Point3::new(5.5, 0.5, 0.5),
+            radius: 0.6,
+        };
+        assert_eq!(query(sphere, 0xff), vec![(1, 0, 0), (1, 0, 1)]);
+        assert_eq!(query(sphere, 0x1), vec![]);
+
+        // z >= 2.5 within a large box, only the box instance
+        let frustum = Volume::Frustum([
+            [1.0, 0.0, 0.0, 10.0],
+            [-1.0, 0.0, 0.0, 10.0],
+            [0.0, 1.0, 0.0, 10.0],
+            [0.0, -1.0, 0.0, 10.0],
+            [0.0, 0.0, 1.0, -2.5],
+            [0.0, 0.0, -1.0, 100.0],
+        ]);
+        assert_eq!(query(frustum, 0xff), vec![(2, 0, 0)]);
+
+        let p = Point3::new(0.5, 0.5, 2.0);
+        let closest = closest_point_nodes(&tlas_nodes, &blases, p, 10.0, 0xff).unwrap();
+        assert_eq!((closest.instance_id, closest.distance), (2, 1.0));
+        assert_eq!(closest.point(), vec![0.5, 0.5, 3.0]);
+        assert!(closest_point_nodes(&tlas_nodes, &blases, p, 0.5, 0xff).is_none());
+        let closest = closest_point_nodes(&tlas_nodes, &blases, p, 10.0, 0x2).unwrap();
+        assert_eq!(closest.instance_id, 1);
+        assert_eq!(closest.point(), vec![5.0, 0.5, 0.0]);
+    }
+}
@@ -0,0 +1,197 @@
+use crate::layout::{
+    read_blas_node, read_primitive_ref, read_tlas_node, tlas_node_stride, write_blas_node_aabb,
+    write_tlas_node, write_tlas_node_aabb,
+};
+use crate::GPUTlasBvhNode;
+use bvh::aabb::AABB;
+use std::collections::BTreeSet;
+
+// Recomputes bounds bottom-up. Children are always stored after their parent,
+// so a reverse sweep visits both children before the parent.
+pub(crate) fn refit_blas_nodes<F: Fn(i32, u32) -> AABB>(
+    nodes: &mut [u8],
+    num_nodes: u32,
+    refs: &[u8],
+    primitive_aabb: F,
+) {
+    for i in (0..num_nodes).rev() {
+        let node = read_blas_node(nodes, i);
+        let mut aabb = AABB::empty();
+        if node.is_leaf() {
+            let first = node.entry_index_or_first_primitive;
+            for r in first..first + node.primitive_count {
+                let (geometry_id, primitive_id) = read_primitive_ref(refs, r);
+                aabb.join_mut(&primitive_aabb(geometry_id, primitive_id));
+            }
+        } else {
+            let left = read_blas_node(nodes, i + 1);
+            let right = read_blas_node(nodes, left.exit_index);
+            aabb.join_mut(&left.aabb);
+            aabb.join_mut(&right.aabb);
+        }
+        write_blas_node_aabb(nodes, i, &aabb);
+    }
+}
+
+// Interior nodes on the path from the root to `leaf`.
+fn tlas_ancestors(nodes: &[u8], num_nodes: u32, leaf: u32) -> Vec<u32> {
+    let mut ancestors = Vec::new();
+    let mut i = 0;
+    while i != leaf {
+        ancestors.push(i);
+        let left = i + 1;
+        let right = read_tlas_node(nodes, left).exit_index.min(num_nodes);
+        i = if leaf < right { left } else { right };
+    }
+    ancestors
+}
+
+// Overwrites the changed leaves, refits their ancestors and returns the dirty
+// byte ranges.
+pub(crate) fn update_tlas_nodes(
+    nodes: &mut [u8],
+    num_nodes: u32,
+    changed_leaves: &[(u32, GPUTlasBvhNode)],
+) -> Vec<u32> {
+    let mut dirty = BTreeSet::new();
+    let mut ancestors = BTreeSet::new();
+    for (leaf, node) in changed_leaves {
+        write_tlas_node(nodes, *leaf, node);
+        dirty.insert(*leaf);
+        ancestors.extend(tlas_ancestors(nodes, num_nodes, *leaf));
+    }
+    // deepest first
+    for &i in ancestors.iter().rev() {
+        let left = read_tlas_node(nodes, i + 1);
+        let right = read_tlas_node(nodes, left.exit_index);
+        write_tlas_node_aabb(nodes, i, &left.aabb.join(&right.aabb));
+    }
+    dirty.extend(ancestors);
+
+    let stride = tlas_node_stride() as u32;
+    let mut ranges: Vec<u32> = Vec::new();
+    for i in dirty {
+        let n = ranges.len();
+        if n > 0 && ranges[n - 1] == i * stride {
+            ranges[n - 1] += stride;
+        } else {
+            ranges.push(i * stride);
+            ranges.push((i + 1) * stride);
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{refit_blas_nodes, update_tlas_nodes};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::layout::{primitive_ref_stride, read_blas_node, read_tlas_node, tlas_node_stride};
+    use crate::{serialize_tlas_nodes, tlas_instance_leaf_nodes, tlas_leaf_node};
+    use crate::{TlasInstance, TlasInstanceDescriptor};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_refit_matches_translated_bounds() {
+        let boxes: Vec<AABB> = (0..20)
+            .map(|i| {
+                let x = (i * 3 % 20) as f32;
+                AABB::with_bounds(Point3::new(x, 0.0, 0.0), Point3::new(x + 0.5, 1.0, 1.0))
+            })
+            .collect();
+        let bvh = Bvh::build(&boxes, &BuildOptions::default());
+        let mut nodes = crate::serialize_blas_nodes(&bvh);
+        // geometry 0 without flags, primitive ids are the box indices
+        let mut refs = Vec::new();
+        for &pi in &bvh.prim_indices {
+            refs.extend_from_slice(&0i32.to_le_bytes());
+            refs.extend_from_slice(&pi.to_le_bytes());
+            refs.extend_from_slice(&0u32.to_le_bytes());
+        }
+        assert_eq!(refs.len(), bvh.prim_indices.len() * primitive_ref_stride());
+
+        let offset = Point3::new(1.0, 2.0, 3.0);
+        let moved = |_geometry_id: i32, primitive_id: u32| {
+            let b = &boxes[primitive_id as usize];
+            AABB::with_bounds(b.min + offset, b.max + offset)
+        };
+        refit_blas_nodes(&mut nodes, bvh.nodes.len() as u32, &refs, moved);
+
+        for (i, n) in bvh.nodes.iter().enumerate() {
+            let node = read_blas_node(&nodes, i as u32);
+            assert_eq!(node.aabb.min, n.aabb.min + offset);
+            assert_eq!(node.aabb.max, n.aabb.max + offset);
+        }
+    }
+
+    fn instance_at(x: f32) -> TlasInstance {
+        TlasInstance {
+            mask: 0xff,
+            flags: 0,
+            instance_id: 0,
+            sbt_instance_offset: 0,
+            instance_custom_index: -1,
+            blas_entry_index: 0,
+            blas_geometry_id_offset: 0,
+            blas_primitive_ref_offset: 0,
+            blas_aabb: [0.0, 0.0, 0.0, 1.0, 1.0, 1.0],
+            transform_to_world_4x3: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, x, 0.0, 0.0],
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        }
+    }
+
+    #[test]
+    fn test_update_tlas_refits_ancestors_only() {
+        let mut instances: Vec<TlasInstanceDescriptor> = (0..16)
+            .map(|i| TlasInstanceDescriptor::new(i, &instance_at(2.0 * i as f32)).unwrap())
+            .collect();
+        let aabbs: Vec<AABB> = instances.iter().map(|inst| inst.aabb).collect();
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&aabbs, &options);
+        let mut nodes = serialize_tlas_nodes(&bvh, &instances);
+        let num_nodes = bvh.nodes.len() as u32;
+        let leaf_nodes = tlas_instance_leaf_nodes(&bvh, instances.len());
+
+        let moved = 5;
+        instances[moved] = TlasInstanceDescriptor::new(moved as u32, &instance_at(100.0)).unwrap();
+        let leaf = leaf_nodes[moved];
+        let exit = read_tlas_node(&nodes, leaf).exit_index;
+        let ranges = update_tlas_nodes(
+            &mut nodes,
+            num_nodes,
+            &[(leaf, tlas_leaf_node(&instances[moved], exit))],
+        );
+
+        // the root is dirty and covers the moved instance
+        assert_eq!(ranges[0], 0);
+        let root = read_tlas_node(&nodes, 0);
+        assert_eq!(root.aabb.max.x, 101.0);
+        assert_eq!(read_tlas_node(&nodes, leaf).aabb.min.x, 100.0);
+        // only the root-to-leaf path is dirty
+        let stride = tlas_node_stride() as u32;
+        let num_dirty: u32 = ranges.chunks(2).map(|r| (r[1] - r[0]) / stride).sum();
+        assert!(num_dirty < num_nodes);
+        assert!(ranges
+            .chunks(2)
+            .any(|r| r[0] <= leaf * stride && (leaf + 1) * stride <= r[1]));
+
+        // leaves keep their instances, interior nodes bound their children
+        for (i, n) in bvh.nodes.iter().enumerate() {
+            let node = read_tlas_node(&nodes, i as u32);
+            let aabb = if n.is_leaf() {
+                instances[bvh.leaf_prims(n)[0] as usize].aabb
+            } else {
+                let left = read_tlas_node(&nodes, i as u32 + 1);
+                let right = read_tlas_node(&nodes, left.exit_index);
+                left.aabb.join(&right.aabb)
+            };
+            assert_eq!(node.aabb.min, aabb.min);
+            assert_eq!(node.aabb.max, aabb.max);
+        }
+    }
+}
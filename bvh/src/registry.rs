@@ -0,0 +1,212 @@
+// Staging buffers by id. Ids are generation tagged, a freed id stays invalid
+// when its slot is reused, so stale ids are errors instead of aliasing a
+// newer buffer. Slots whose generation is used up are retired instead of
+// wrapping around to ids that were handed out before.
+
+use crate::error::{BvhBuildError, DescriptorLocation};
+use wasm_bindgen::prelude::*;
+
+// Ids are written into i32 descriptor fields, where -1 means no buffer, so
+// they must stay positive.
+const INDEX_BITS: u32 = 20;
+const INDEX_MASK: u32 = (1 << INDEX_BITS) - 1;
+const GENERATION_MASK: u32 = (1 << (31 - INDEX_BITS)) - 1;
+
+#[derive(Debug, Default)]
+struct Slot {
+    generation: u32,
+    buffer: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct StagingBufferMap {
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+}
+
+impl StagingBufferMap {
+    pub const fn new() -> Self {
+        StagingBufferMap {
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, buffer: Vec<u8>) -> Result<u32, BvhBuildError> {
+        let index = match self.free_slots.pop() {
+            Some(index) => index,
+            None => {
+                if self.slots.len() > INDEX_MASK as usize {
+                    return Err(BvhBuildError::StagingBuffersExhausted {
+                        at: DescriptorLocation::Descriptor,
+                    });
+                }
+                self.slots.push(Slot::default());
+                self.slots.len() as u32 - 1
+            }
+        };
+        let slot = &mut self.slots[index as usize];
+        slot.buffer = Some(buffer);
+        Ok(slot.generation << INDEX_BITS | index)
+    }
+
+    // Inserts under a fixed id, for tests with recorded descriptors.
+    #[cfg(test)]
+    pub fn insert_at(&mut self, id: u32, buffer: Vec<u8>) {
+        let index = (id & INDEX_MASK) as usize;
+        if self.slots.len() <= index {
+            self.slots.resize_with(index + 1, Slot::default);
+        }
+        self.slots[index] = Slot {
+            generation: id >> INDEX_BITS,
+            buffer: Some(buffer),
+        };
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Vec<u8>> {
+        let slot = self.slots.get((id & INDEX_MASK) as usize)?;
+        if slot.generation != id >> INDEX_BITS {
+            return None;
+        }
+        slot.buffer.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Vec<u8>> {
+        let slot = self.slots.get_mut((id & INDEX_MASK) as usize)?;
+        if slot.generation != id >> INDEX_BITS {
+            return None;
+        }
+        slot.buffer.as_mut()
+    }
+
+    // Whether `id` was valid once and has been freed since.
+    pub fn is_stale(&self, id: u32) -> bool {
+        self.slots
+            .get((id & INDEX_MASK) as usize)
+            .map_or(false, |slot| {
+                let generation = id >> INDEX_BITS;
+                generation < slot.generation
+                    || (generation == slot.generation && slot.buffer.is_none())
+            })
+    }
+
+    /// Frees the buffer of `id`, does nothing for stale ids.
+    pub fn remove(&mut self, id: u32) -> Option<Vec<u8>> {
+        let index = id & INDEX_MASK;
+        let slot = self.slots.get_mut(index as usize)?;
+        if slot.generation != id >> INDEX_BITS || slot.buffer.is_none() {
+            return None;
+        }
+        let buffer = slot.buffer.take();
+        // after 2^11 uses a slot is retired, it keeps its last generation so
+        // its ids stay stale
+        if slot.generation < GENERATION_MASK {
+            slot.generation += 1;
+            self.free_slots.push(index);
+        }
+        buffer
+    }
+
+    pub fn live_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.buffer
+                .as_ref()
+                .map(|_| slot.generation << INDEX_BITS | index as u32)
+        })
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.slots
+            .iter()
+            .filter_map(|slot| slot.buffer.as_ref())
+            .map(|buffer| buffer.len())
+            .sum()
+    }
+}
+
+impl std::ops::Index<u32> for StagingBufferMap {
+    type Output = Vec<u8>;
+
+    fn index(&self, id: u32) -> &Vec<u8> {
+        self.get(id).expect("stale or unknown staging buffer id")
+    }
+}
+
+/// Staging buffers that are allocated and not freed yet, to find leaks.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy)]
+pub struct StagingBufferStats {
+    pub live_buffers: u32,
+    pub live_bytes: usize,
+}
+
+#[wasm_bindgen]
+pub fn staging_buffer_stats() -> StagingBufferStats {
+    crate::with_staging_buffers(|map| StagingBufferStats {
+        live_buffers: map.live_ids().count() as u32,
+        live_bytes: map.live_bytes(),
+    })
+}
+
+/// Ids of the live staging buffers, oldest slots first.
+#[wasm_bindgen]
+pub fn live_staging_buffer_ids() -> Vec<u32> {
+    crate::with_staging_buffers(|map| map.live_ids().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StagingBufferMap, GENERATION_MASK, INDEX_BITS, INDEX_MASK};
+    use crate::error::{BvhBuildError, DescriptorLocation};
+
+    #[test]
+    fn test_stale_ids_are_rejected() {
+        let mut map = StagingBufferMap::new();
+        let a = map.insert(vec![1; 4]).unwrap();
+        let b = map.insert(vec![2; 8]).unwrap();
+        assert_eq!(map.live_bytes(), 12);
+        assert_eq!(map.remove(a), Some(vec![1; 4]));
+        assert!(map.get(a).is_none() && map.is_stale(a));
+        assert_eq!(map.remove(a), None);
+
+        // reuses the slot of `a` with a new generation
+        let c = map.insert(vec![3; 2]).unwrap();
+        assert_ne!(a, c);
+        assert!(map.get(a).is_none() && map.is_stale(a));
+        assert_eq!(map[c], vec![3; 2]);
+        assert_eq!(map.live_ids().collect::<Vec<_>>(), vec![c, b]);
+        assert_eq!(map.live_bytes(), 10);
+        assert!(!map.is_stale(12345) && map.get(12345).is_none());
+        assert!(c as i32 > 0);
+    }
+
+    #[test]
+    fn test_used_up_slots_are_retired() {
+        let mut map = StagingBufferMap::new();
+        let last = GENERATION_MASK << INDEX_BITS;
+        map.insert_at(last, vec![1; 4]);
+        assert!(last as i32 > 0);
+        assert_eq!(map.remove(last), Some(vec![1; 4]));
+        assert!(map.is_stale(last));
+        // ids of the first generation of the slot are not handed out again
+        let a = map.insert(vec![2; 4]).unwrap();
+        assert_eq!(a, 1);
+        assert!(map.get(0).is_none() && map.is_stale(0));
+        assert!(map.get(last).is_none() && map.is_stale(last));
+    }
+
+    #[test]
+    fn test_insert_fails_when_ids_run_out() {
+        let mut map = StagingBufferMap::new();
+        map.insert_at(INDEX_MASK, vec![1; 4]);
+        assert_eq!(
+            map.insert(vec![2; 4]),
+            Err(BvhBuildError::StagingBuffersExhausted {
+                at: DescriptorLocation::Descriptor
+            })
+        );
+        // freed slots are still reused
+        assert_eq!(map.remove(INDEX_MASK), Some(vec![1; 4]));
+        assert!(map.insert(vec![2; 4]).is_ok());
+    }
+}
@@ -0,0 +1,434 @@
+// Host-side ray queries against the same TLAS and BLASes that are uploaded
+// for GPU tracing, e.g. for picking.
+
+use crate::builder::Bvh;
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::layout::{read_i32, write_i32};
+use crate::query::{closest_point_nodes, overlaps_nodes, ClosestPoint, Volume};
+use crate::traverse::{trace_ray_nodes, BlasBuffers, Hit, HitReport, Ray, Trace};
+use crate::{blas_geometries, serialize_tlas_nodes, utils, with_staging_buffers};
+use crate::{BuiltBvh, GeometryDescriptorField, GeometryType, NodeFormat, StagingBufferMap};
+use bvh::aabb::AABB;
+use bvh::Point3;
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+// origin.xyz, tmin, direction.xyz, tmax, like the traceRayEXT arguments
+const RAY_NUM_F32: usize = 8;
+
+/// A TLAS and the BLASes it references, for ray queries on the CPU.
+#[wasm_bindgen]
+pub struct BvhScene {
+    blas_node_format: NodeFormat,
+    tlas_nodes: Vec<u8>,
+    blas_nodes: Vec<u8>,
+    blas_primitive_refs: Vec<u8>,
+    num_blas_nodes: u32,
+    num_blas_geometries: u32,
+    num_blas_primitive_refs: u32,
+    // copies of the BLAS descriptors and the vertex and index buffers they
+    // reference, the geometries are read from them for each query
+    buffers: StagingBufferMap,
+    blas_descriptor_ids: Vec<u32>,
+}
+
+#[wasm_bindgen]
+impl BvhScene {
+    /// `blas_node_format` is the format of all BLASes added, Full or
+    /// Quantized16. Queries miss until a TLAS is set.
+    #[wasm_bindgen(constructor)]
+    pub fn new(blas_node_format: NodeFormat) -> Result<BvhScene, JsValue> {
+        utils::set_panic_hook();
+        // rejects the wide formats
+        BlasBuffers::new(blas_node_format)?;
+        let empty_tlas = Bvh {
+            nodes: Vec::new(),
+            prim_indices: Vec::new(),
+        };
+        Ok(BvhScene {
+            blas_node_format,
+            tlas_nodes: serialize_tlas_nodes(&empty_tlas, &[]),
+            blas_nodes: Vec::new(),
+            blas_primitive_refs: Vec::new(),
+            num_blas_nodes: 0,
+            num_blas_geometries: 0,
+            num_blas_primitive_refs: 0,
+            buffers: StagingBufferMap::new(),
+            blas_descriptor_ids: Vec::new(),
+        })
+    }
+
+    /// Copies the nodes and primitive refs of a BLAS built from the
+    /// descriptor in `blas_descriptor_buffer_id`, along with the descriptor
+    /// and the vertex and index buffers it references. They can be freed or
+    /// reused afterwards. BLASes are added in the order of
+    /// wasm_bvh_builder.ts, returns the [blas_entry_index,
+    /// blas_geometry_id_offset, blas_primitive_ref_offset] of the instances
+    /// referencing this BLAS.
+    pub fn add_blas(
+        &mut self,
+        built: &BuiltBvh,
+        blas_descriptor_buffer_id: u32,
+    ) -> Result<Vec<u32>, JsValue> {
+        utils::set_panic_hook();
+        let offsets =
+            with_staging_buffers(|map| self.try_add_blas(map, built, blas_descriptor_buffer_id))?;
+        Ok(offsets.to_vec())
+    }
+
+    /// Copies the nodes of a TLAS built by `build_tlas` over the BLASes
+    /// added so far, replacing the previous TLAS.
+    pub fn set_tlas(&mut self, built: &BuiltBvh) -> Result<(), JsValue> {
+        if built.primitive_refs.is_some() {
+            let at = DescriptorLocation::Descriptor;
+            return Err(BvhBuildError::NotATlas { at }.into());
+        }
+        self.tlas_nodes = built.serialized.with_buffer(Vec::clone);
+        Ok(())
+    }
+
+    /// Closest hits of a batch of rays, 8 floats per ray: origin.xyz, tmin,
+    /// direction.xyz, tmax, and one cull mask per ray.
+    pub fn intersect_closest(&self, rays: &[f32], cull_masks: &[u32]) -> Result<RayHits, JsValue> {
+        Ok(self.intersect(rays, cull_masks, HitReport::Confirm)?)
+    }
+
+    /// Any hit of each ray of a batch, for visibility tests, see
+    /// `intersect_closest`.
+    pub fn intersect_any(&self, rays: &[f32], cull_masks: &[u32]) -> Result<RayHits, JsValue> {
+        Ok(self.intersect(rays, cull_masks, HitReport::Terminate)?)
+    }
+
+    /// Primitives overlapping the box min.xyz, max.xyz, as [instance_id,
+    /// geometry_index, primitive_id] triples.
+    pub fn overlap_aabb(&self, min_max: &[f32], cull_mask: u32) -> Result<Vec<u32>, JsValue> {
+        check_len(min_max, 6)?;
+        let aabb = AABB::with_bounds(
+            Point3::new(min_max[0], min_max[1], min_max[2]),
+            Point3::new(min_max[3], min_max[4], min_max[5]),
+        );
+        Ok(self.overlaps(&Volume::Aabb(aabb), cull_mask)?)
+    }
+
+    /// Primitives overlapping a sphere, see `overlap_aabb`.
+    pub fn overlap_sphere(
+        &self,
+        center: &[f32],
+        radius: f32,
+        cull_mask: u32,
+    ) -> Result<Vec<u32>, JsValue> {
+        check_len(center, 3)?;
+        let center = Point3::new(center[0], center[1], center[2]);
+        Ok(self.overlaps(&Volume::Sphere { center, radius }, cull_mask)?)
+    }
+
+    /// Primitives inside or crossing a frustum of 6 planes, 4 floats per
+    /// plane: normal.xyz pointing inside and d, see `overlap_aabb`. Like
+    /// usual frustum culling it may report primitives outside of the
+    /// frustum near its corners.
+    pub fn frustum_cull(&self, planes: &[f32], cull_mask: u32) -> Result<Vec<u32>, JsValue> {
+        check_len(planes, 24)?;
+        let mut frustum = [[0.0; 4]; 6];
+        for (plane, p) in frustum.iter_mut().zip(planes.chunks(4)) {
+            plane.copy_from_slice(p);
+        }
+        Ok(self.overlaps(&Volume::Frustum(frustum), cull_mask)?)
+    }
+
+    /// The nearest point on any primitive within `max_distance` of `point`.
+    pub fn closest_point(
+        &self,
+        point: &[f32],
+        max_distance: f32,
+        cull_mask: u32,
+    ) -> Result<Option<ClosestPoint>, JsValue> {
+        check_len(point, 3)?;
+        Ok(closest_point_nodes(
+            &self.tlas_nodes,
+            &self.blases()?,
+            Point3::new(point[0], point[1], point[2]),
+            max_distance,
+            cull_mask,
+        ))
+    }
+}
+
+impl BvhScene {
+    fn try_add_blas(
+        &mut self,
+        map: &StagingBufferMap,
+        built: &BuiltBvh,
+        blas_descriptor_buffer_id: u32,
+    ) -> Result<[u32; 3], BvhBuildError> {
+        let at = DescriptorLocation::Descriptor;
+        let primitive_refs = built
+            .primitive_refs
+            .as_ref()
+            .ok_or(BvhBuildError::NotABlas { at })?;
+        if built.format != self.blas_node_format {
+            return Err(BvhBuildError::UnsupportedNodeFormat {
+                at,
+                format: built.format,
+            });
+        }
+        let num_geometries = blas_geometries(map, blas_descriptor_buffer_id)?.len() as u32;
+        let descriptor_id =
+            copy_blas_descriptor(map, &mut self.buffers, blas_descriptor_buffer_id)?;
+        let offsets = [
+            self.num_blas_nodes,
+            self.num_blas_geometries,
+            self.num_blas_primitive_refs,
+        ];
+        self.blas_nodes.extend_from_slice(&map[built.serialized.id]);
+        self.blas_primitive_refs
+            .extend_from_slice(&map[primitive_refs.id]);
+        self.num_blas_nodes += built.num_nodes;
+        self.num_blas_geometries += num_geometries;
+        self.num_blas_primitive_refs += built.num_primitive_refs;
+        self.blas_descriptor_ids.push(descriptor_id);
+        Ok(offsets)
+    }
+
+    // The BLASes added so far, borrowing the scene's copies.
+    fn blases(&self) -> Result<BlasBuffers<'_>, BvhBuildError> {
+        let mut geometries = Vec::with_capacity(self.num_blas_geometries as usize);
+        for &id in &self.blas_descriptor_ids {
+            geometries.extend(
+                blas_geometries(&self.buffers, id)?
+                    .into_iter()
+                    .map(|(g, _)| g),
+            );
+        }
+        BlasBuffers::from_parts(
+            self.blas_node_format,
+            &self.blas_nodes,
+            self.num_blas_nodes,
+            &self.blas_primitive_refs,
+            self.num_blas_primitive_refs,
+            geometries,
+        )
+    }
+
+    fn intersect(
+        &self,
+        rays: &[f32],
+        cull_masks: &[u32],
+        report: HitReport,
+    ) -> Result<RayHits, BvhBuildError> {
+        intersect_rays(&self.tlas_nodes, &self.blases()?, rays, cull_masks, report)
+    }
+
+    fn overlaps(&self, volume: &Volume, cull_mask: u32) -> Result<Vec<u32>, BvhBuildError> {
+        Ok(
+            overlaps_nodes(&self.tlas_nodes, &self.blases()?, volume, cull_mask)
+                .iter()
+                .flat_map(|o| [o.instance_id, o.geometry_id as u32, o.primitive_id])
+                .collect(),
+        )
+    }
+}
+
+fn check_len(v: &[f32], expected: usize) -> Result<(), BvhBuildError> {
+    if v.len() != expected {
+        return Err(BvhBuildError::LengthMismatch {
+            at: DescriptorLocation::Descriptor,
+            expected,
+            actual: v.len(),
+        });
+    }
+    Ok(())
+}
+
+// Traces each ray, reporting every hit to the any-hit stage with `report`.
+fn intersect_rays(
+    tlas_nodes: &[u8],
+    blases: &BlasBuffers,
+    rays: &[f32],
+    cull_masks: &[u32],
+    report: HitReport,
+) -> Result<RayHits, BvhBuildError> {
+    check_len(rays, cull_masks.len() * RAY_NUM_F32)?;
+    let mut hits = RayHits::with_capacity(cull_masks.len());
+    for (r, &cull_mask) in rays.chunks(RAY_NUM_F32).zip(cull_masks) {
+        let ray = Ray {
+            origin: Point3::new(r[0], r[1], r[2]),
+            tmin: r[3],
+            direction: Point3::new(r[4], r[5], r[6]),
+            tmax: r[7],
+            time: 0.0,
+        };
+        match trace_ray_nodes(tlas_nodes, blases, &ray, cull_mask, |_| report) {
+            Trace::ClosestHit(hit) | Trace::Terminated(hit) => hits.push(&hit),
+            Trace::Miss => hits.push_miss(ray.tmax),
+        }
+    }
+    Ok(hits)
+}
+
+/// Per ray results of `BvhScene` queries. Misses have a geometry index of
+/// -1 and t = tmax.
+#[wasm_bindgen]
+pub struct RayHits {
+    t: Vec<f32>,
+    instance_id: Vec<u32>,
+    instance_custom_index: Vec<i32>,
+    geometry_index: Vec<i32>,
+    primitive_id: Vec<u32>,
+    barycentrics: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl RayHits {
+    pub fn t(&self) -> Vec<f32> {
+        self.t.clone()
+    }
+
+    pub fn instance_id(&self) -> Vec<u32> {
+        self.instance_id.clone()
+    }
+
+    pub fn instance_custom_index(&self) -> Vec<i32> {
+        self.instance_custom_index.clone()
+    }
+
+    pub fn geometry_index(&self) -> Vec<i32> {
+        self.geometry_index.clone()
+    }
+
+    pub fn primitive_id(&self) -> Vec<u32> {
+        self.primitive_id.clone()
+    }
+
+    /// Two per ray, 0 for AABB hits.
+    pub fn barycentrics(&self) -> Vec<f32> {
+        self.barycentrics.clone()
+    }
+}
+
+impl RayHits {
+    fn with_capacity(n: usize) -> Self {
+        RayHits {
+            t: Vec::with_capacity(n),
+            instance_id: Vec::with_capacity(n),
+            instance_custom_index: Vec::with_capacity(n),
+            geometry_index: Vec::with_capacity(n),
+            primitive_id: Vec::with_capacity(n),
+            barycentrics: Vec::with_capacity(2 * n),
+        }
+    }
+
+    fn push(&mut self, hit: &Hit) {
+        self.t.push(hit.t);
+        self.instance_id.push(hit.instance_id);
+        self.instance_custom_index.push(hit.instance_custom_index);
+        self.geometry_index.push(hit.geometry_id);
+        self.primitive_id.push(hit.primitive_id);
+        self.barycentrics
+            .extend_from_slice(&[hit.attributes[0], hit.attributes[1]]);
+    }
+
+    fn push_miss(&mut self, tmax: f32) {
+        self.t.push(tmax);
+        self.instance_id.push(0);
+        self.instance_custom_index.push(0);
+        self.geometry_index.push(-1);
+        self.primitive_id.push(0);
+        self.barycentrics.extend_from_slice(&[0.0, 0.0]);
+    }
+}
+
+// Copies the descriptor and the vertex, end vertex and index buffers its
+// geometries reference into `buffers` under new ids, for a descriptor
+// validated by blas_geometries. Returns the id of the copied descriptor.
+fn copy_blas_descriptor(
+    map: &StagingBufferMap,
+    buffers: &mut StagingBufferMap,
+    blas_descriptor_buffer_id: u32,
+) -> Result<u32, BvhBuildError> {
+    let mut descriptor = map[blas_descriptor_buffer_id].clone();
+    let num_geoms = read_i32(&descriptor, 0) as usize;
+    // buffers shared by several geometries are copied once
+    let mut copies = HashMap::<i32, i32>::new();
+    for gi in 0..num_geoms {
+        let offset = |f: GeometryDescriptorField| {
+            4 * (2 + gi * GeometryDescriptorField::NumFields as usize + f as usize)
+        };
+        let mut fields = vec![GeometryDescriptorField::VbufId];
+        if read_i32(&descriptor, offset(GeometryDescriptorField::Type))
+            == GeometryType::Triangle as i32
+        {
+            fields.extend([
+                GeometryDescriptorField::IbufId,
+                GeometryDescriptorField::EndVbufId,
+            ]);
+        }
+        for f in fields {
+            let id = read_i32(&descriptor, offset(f));
+            if id < 0 {
+                continue;
+            }
+            let copy = match copies.get(&id) {
+                Some(&copy) => copy,
+                None => buffers.insert(map[id as u32].clone())? as i32,
+            };
+            copies.insert(id, copy);
+            write_i32(&mut descriptor, offset(f), copy);
+        }
+    }
+    buffers.insert(descriptor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::intersect_rays;
+    use crate::traverse::tests::{quad_and_box_scene, quad_vertices, unit_box};
+    use crate::traverse::{BlasBuffers, HitReport};
+    use crate::NodeFormat;
+
+    #[test]
+    fn test_batched_ray_queries() {
+        let (quad, unit_box) = (quad_vertices(), unit_box());
+        let mut blases = BlasBuffers::new(NodeFormat::Full).unwrap();
+        let tlas_nodes = quad_and_box_scene(&mut blases, &quad, &unit_box);
+
+        #[rustfmt::skip]
+        let rays = [
+            // the quad from below
+            0.75, 0.25, -1.0, 0.0, 0.0, 0.0, 1.0, 1e38,
+            // the box from above, then the quad
+            0.5, 0.5, 10.0, 0.0, 0.0, 0.0, -1.0, 1e38,
+            // masked out translated quad
+            5.25, 0.75, -1.0, 0.0, 0.0, 0.0, 1.0, 1e38,
+        ];
+        let cull_masks = [0xff, 0xff, 0x1];
+        let hits =
+            intersect_rays(&tlas_nodes, &blases, &rays, &cull_masks, HitReport::Confirm).unwrap();
+        assert_eq!(hits.t(), vec![1.0, 6.0, 1e38]);
+        assert_eq!(hits.instance_id(), vec![0, 2, 0]);
+        assert_eq!(hits.instance_custom_index(), vec![10, 12, 0]);
+        assert_eq!(hits.geometry_index(), vec![0, 0, -1]);
+        assert_eq!(hits.primitive_id(), vec![0, 0, 0]);
+        let b = hits.barycentrics();
+        assert_eq!(b.len(), 6);
+        assert!(b[0] > 0.0 && b[1] > 0.0 && b[0] + b[1] <= 1.0);
+
+        let hits = intersect_rays(
+            &tlas_nodes,
+            &blases,
+            &rays,
+            &cull_masks,
+            HitReport::Terminate,
+        )
+        .unwrap();
+        assert_eq!(hits.geometry_index(), vec![0, 0, -1]);
+        assert!(intersect_rays(
+            &tlas_nodes,
+            &blases,
+            &rays[..8],
+            &cull_masks,
+            HitReport::Confirm
+        )
+        .is_err());
+        assert_eq!(hits.t()[0], 1.0);
+    }
+}
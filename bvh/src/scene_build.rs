@@ -0,0 +1,670 @@
+// Builds all BLASes and the TLAS of a scene in one call. The blas_* fields
+// of the instances and the geometry table of geom.glsl
+// (bvhReferencedGeomBuffer) are computed here, wasm_bvh_builder.ts only
+// uploads the buffers.
+//
+// BLAS descriptors with the same geometries and build options are built
+// once, their instances share the nodes, primitive refs and geometry table
+// entries.
+
+use crate::archive::blas_geometry_hash;
+use crate::builder::BuildOptions;
+use crate::compact::{blas_quantization_frame, serialize_blas_nodes_quantized};
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::instance_bounds::{BlasBounds, InstanceBounds};
+use crate::native::{build_blas_primitives, build_tlas_from_instances_with_bounds};
+use crate::native::{BuiltBlas, BuiltTlas};
+use crate::parallel;
+use crate::registry::StagingBufferMap;
+use crate::{blas_geometries, cast_staging_buffer, geometry_primitives, staging_buffer};
+use crate::{utils, with_staging_buffers, BuiltBvh, GeometryDescriptorField, GeometryType};
+use crate::{NodeFormat, Primitive, StagingBuffer, TlasInstance};
+use bvh::aabb::AABB;
+use std::collections::HashMap;
+use std::sync::Arc;
+use wasm_bindgen::prelude::*;
+
+const IDENTITY_4X3: [f32; 12] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+
+/// BLAS descriptors and the instances referencing them, built together by
+/// `build`.
+#[wasm_bindgen]
+#[derive(Debug, Default)]
+pub struct SceneBatch {
+    blases: Vec<(u32, BuildOptions)>,
+    instances: Vec<SceneInstance>,
+}
+
+// A TLAS instance of a BLAS of the batch, the build fills in the rest of its
+// TlasInstance.
+#[derive(Debug, Clone, Copy)]
+struct SceneInstance {
+    blas_index: u32,
+    mask: u32,
+    flags: u32,
+    sbt_instance_offset: u32,
+    instance_custom_index: i32,
+    transform_to_world_4x3: [f32; 12],
+    motion_type: u32,
+    motion_keyframes: [f32; 32],
+}
+
+#[wasm_bindgen]
+impl SceneBatch {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the BLAS descriptor in `blas_descriptor_buffer_id`, which must
+    /// stay alive until `build`, and returns its BLAS index. The branching
+    /// factor of `options` is ignored, see `build`.
+    pub fn add_blas(&mut self, blas_descriptor_buffer_id: u32, options: &BuildOptions) -> u32 {
+        self.blases.push((blas_descriptor_buffer_id, *options));
+        self.blases.len() as u32 - 1
+    }
+
+    /// Adds an instance of the BLAS at `blas_index`, its instance id is the
+    /// number of instances added before it. `transform_to_world_4x3` is 4x3
+    /// column major.
+    pub fn add_instance(
+        &mut self,
+        blas_index: u32,
+        mask: u32,
+        flags: u32,
+        sbt_instance_offset: u32,
+        instance_custom_index: i32,
+        transform_to_world_4x3: &[f32],
+    ) {
+        let mut transform = [0.0; 12];
+        transform.copy_from_slice(transform_to_world_4x3);
+        self.instances.push(SceneInstance {
+            blas_index,
+            mask,
+            flags,
+            sbt_instance_offset,
+            instance_custom_index,
+            transform_to_world_4x3: transform,
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        });
+    }
+
+    /// Makes instance `instance_id` move, see `TlasInstance::motion_type`
+    /// and `TlasInstance::motion_keyframes`. `motion_keyframes` is padded
+    /// with zeros to 32 words.
+    pub fn set_instance_motion(
+        &mut self,
+        instance_id: u32,
+        motion_type: u32,
+        motion_keyframes: &[f32],
+    ) -> Result<(), JsValue> {
+        let at = DescriptorLocation::Instance(instance_id);
+        let len = self.instances.len();
+        let inst =
+            self.instances
+                .get_mut(instance_id as usize)
+                .ok_or(BvhBuildError::IndexOutOfRange {
+                    at,
+                    index: instance_id as usize,
+                    len,
+                })?;
+        if motion_keyframes.len() > inst.motion_keyframes.len() {
+            return Err(BvhBuildError::LengthMismatch {
+                at,
+                expected: inst.motion_keyframes.len(),
+                actual: motion_keyframes.len(),
+            }
+            .into());
+        }
+        inst.motion_type = motion_type;
+        inst.motion_keyframes = [0.0; 32];
+        inst.motion_keyframes[..motion_keyframes.len()].copy_from_slice(motion_keyframes);
+        Ok(())
+    }
+
+    /// Builds the BLASes, at the same time with the `parallel` feature, and
+    /// the TLAS over the instances. BLASes are built with the branching
+    /// factor of `blas_node_format` and compacted to it. Instances are bounded
+    /// as selected by `tlas_options.instance_bounds`. Throws the
+    /// `BvhBuildError` message of the first BLAS descriptor or instance that
+    /// fails to build.
+    pub fn build(
+        &self,
+        blas_node_format: NodeFormat,
+        tlas_options: &BuildOptions,
+    ) -> Result<BuiltScene, JsValue> {
+        utils::set_panic_hook();
+        let scene = with_staging_buffers(|map| {
+            try_build_scene(
+                map,
+                &self.blases,
+                &self.instances,
+                blas_node_format,
+                tlas_options,
+            )
+        })?;
+        Ok(BuiltScene::try_from(scene)?)
+    }
+}
+
+/// An entry of the geometry table, a BvhGeometryDescriptor of geom.glsl.
+/// Buffers are indices into `BuiltScene::geometry_buffer_ids`.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SceneGeometry {
+    /// GeometryType, 0 for triangles and 1 for AABBs.
+    pub geometry_type: u32,
+    pub flags: u32,
+    pub vbuf_index: u32,
+    pub vbuf_byte_offset: u32,
+    /// AABBs are read as two float32x3 vertices, min and max.
+    pub vertex_stride: u32,
+    pub vertex_format: u32,
+    /// -1 for AABBs and triangles without indices.
+    pub ibuf_index: i32,
+    pub ibuf_byte_offset: u32,
+    /// Index bytes per triangle.
+    pub index_stride: u32,
+    pub index_format: u32,
+    /// Triangles only, -1 for geometries that do not move.
+    pub end_vbuf_index: i32,
+    pub end_vbuf_byte_offset: u32,
+    transform_4x3: [f32; 12],
+}
+
+#[wasm_bindgen]
+impl SceneGeometry {
+    /// 4x3 column major, identity for geometries without a transform.
+    pub fn transform_4x3(&self) -> Vec<f32> {
+        self.transform_4x3.to_vec()
+    }
+}
+
+/// The buffers of a scene built by `SceneBatch::build`. Owns its staging
+/// buffers, they are freed with it.
+#[wasm_bindgen]
+pub struct BuiltScene {
+    blas_nodes: StagingBuffer,
+    blas_primitive_refs: StagingBuffer,
+    pub blas_node_format: NodeFormat,
+    // [blas_entry_index, blas_geometry_id_offset, blas_primitive_ref_offset]
+    // and blas_aabb of each BLAS added to the batch
+    blas_offsets: Vec<[u32; 3]>,
+    blas_aabbs: Vec<AABB>,
+    geometries: Vec<SceneGeometry>,
+    geometry_buffer_ids: Vec<u32>,
+    tlas: BuiltBvh,
+    /// BLASes built, those added to the batch less the duplicates.
+    pub num_unique_blases: u32,
+    // primitives of the built BLASes left out of the trees, see
+    // PrimitiveClass
+    pub num_inactive_primitives: u32,
+    pub num_degenerate_primitives: u32,
+}
+
+#[wasm_bindgen]
+impl BuiltScene {
+    /// The nodes of all BLASes back to back, in `blas_node_format`.
+    pub fn blas_nodes_view(&self) -> JsValue {
+        self.blas_nodes.u8_view()
+    }
+
+    /// The primitive refs of all BLASes back to back.
+    pub fn blas_primitive_refs_view(&self) -> JsValue {
+        self.blas_primitive_refs.u8_view()
+    }
+
+    /// [blas_entry_index, blas_geometry_id_offset, blas_primitive_ref_offset]
+    /// of the instances of the BLAS at `blas_index`. Throws for indices past
+    /// the BLASes added to the batch.
+    pub fn blas_offsets(&self, blas_index: u32) -> Result<Vec<u32>, JsValue> {
+        let offsets = scene_entry(
+            &self.blas_offsets,
+            blas_index,
+            DescriptorLocation::Descriptor,
+        )?;
+        Ok(offsets.to_vec())
+    }
+
+    /// The blas_aabb of the instances of the BLAS at `blas_index`, see
+    /// `BuiltBvh::aabb`. Throws like `blas_offsets`.
+    pub fn blas_aabb(&self, blas_index: u32) -> Result<Vec<f32>, JsValue> {
+        let aabb = scene_entry(&self.blas_aabbs, blas_index, DescriptorLocation::Descriptor)?;
+        Ok(vec![
+            aabb.min.x, aabb.min.y, aabb.min.z, aabb.max.x, aabb.max.y, aabb.max.z,
+        ])
+    }
+
+    pub fn num_geometries(&self) -> u32 {
+        self.geometries.len() as u32
+    }
+
+    /// Entry `index` of the geometry table, indexed by
+    /// blas_geometry_id_offset + geometry index. Throws for indices past
+    /// `num_geometries`.
+    pub fn geometry(&self, index: u32) -> Result<SceneGeometry, JsValue> {
+        Ok(*scene_entry(
+            &self.geometries,
+            index,
+            DescriptorLocation::Geometry(index),
+        )?)
+    }
+
+    /// The staging buffers referenced by the geometry table, in the order of
+    /// their indices.
+    pub fn geometry_buffer_ids(&self) -> Vec<u32> {
+        self.geometry_buffer_ids.clone()
+    }
+
+    /// The TLAS, for `update_tlas`. Frees the BLAS buffers.
+    pub fn into_tlas(self) -> BuiltBvh {
+        self.tlas
+    }
+}
+
+// The buffers of BuiltScene, before they are moved into staging buffers.
+#[derive(Debug)]
+struct Scene {
+    blas_nodes: Vec<u8>,
+    blas_primitive_refs: Vec<u8>,
+    blas_node_format: NodeFormat,
+    blas_offsets: Vec<[u32; 3]>,
+    blas_aabbs: Vec<AABB>,
+    geometries: Vec<SceneGeometry>,
+    geometry_buffer_ids: Vec<u32>,
+    tlas: BuiltTlas,
+    num_unique_blases: u32,
+    num_inactive_primitives: u32,
+    num_degenerate_primitives: u32,
+}
+
+impl TryFrom<Scene> for BuiltScene {
+    type Error = BvhBuildError;
+
+    fn try_from(scene: Scene) -> Result<Self, BvhBuildError> {
+        Ok(BuiltScene {
+            blas_nodes: StagingBuffer::from_existing_buffer(scene.blas_nodes)?,
+            blas_primitive_refs: StagingBuffer::from_existing_buffer(scene.blas_primitive_refs)?,
+            blas_node_format: scene.blas_node_format,
+            blas_offsets: scene.blas_offsets,
+            blas_aabbs: scene.blas_aabbs,
+            geometries: scene.geometries,
+            geometry_buffer_ids: scene.geometry_buffer_ids,
+            tlas: scene.tlas.try_into()?,
+            num_unique_blases: scene.num_unique_blases,
+            num_inactive_primitives: scene.num_inactive_primitives,
+            num_degenerate_primitives: scene.num_degenerate_primitives,
+        })
+    }
+}
+
+fn try_build_scene(
+    map: &StagingBufferMap,
+    blases: &[(u32, BuildOptions)],
+    instances: &[SceneInstance],
+    blas_node_format: NodeFormat,
+    tlas_options: &BuildOptions,
+) -> Result<Scene, BvhBuildError> {
+    let branching_factor = match blas_node_format {
+        NodeFormat::Wide4 => 4,
+        NodeFormat::Wide8 => 8,
+        _ => 2,
+    };
+    // the first BLAS of each set of equal ones, and which of them each BLAS
+    // is built as
+    let mut unique = Vec::<usize>::new();
+    let mut unique_index = Vec::<usize>::with_capacity(blases.len());
+    let mut geometries = Vec::<Vec<(Primitive, u32)>>::with_capacity(blases.len());
+    let mut unique_by_hash = HashMap::<u64, Vec<usize>>::new();
+    for (i, (id, options)) in blases.iter().enumerate() {
+        let g = blas_geometries(map, *id)?;
+        let candidates = unique_by_hash.entry(blas_geometry_hash(&g)).or_default();
+        let k = match candidates.iter().find(|&&k| {
+            let j = unique[k];
+            blases[j].1 == *options && same_geometries(&geometries[j], &g)
+        }) {
+            Some(&k) => k,
+            None => {
+                candidates.push(unique.len());
+                unique.push(i);
+                unique.len() - 1
+            }
+        };
+        unique_index.push(k);
+        geometries.push(g);
+    }
+
+    // builds only read the staging buffers. Instance bounds other than the
+    // root box are computed from each BLAS while its primitives are at hand.
+    let tight_bounds = tlas_options.instance_bounds != InstanceBounds::RootBox;
+    let built: Vec<(BuiltBlas, Arc<BlasBounds>)> = parallel::map(&unique, |&i| {
+        let options = BuildOptions {
+            branching_factor,
+            ..blases[i].1
+        };
+        let primitives = geometry_primitives(&geometries[i]);
+        let blas = build_blas_primitives(&primitives, &options);
+        let bounds = if tight_bounds {
+            BlasBounds::from_primitives(
+                tlas_options.instance_bounds,
+                &blas.aabb,
+                &blas.node_boxes,
+                &primitives,
+            )
+        } else {
+            BlasBounds::default()
+        };
+        (blas, Arc::new(bounds))
+    });
+
+    let mut blas_nodes = Vec::new();
+    let mut blas_primitive_refs = Vec::new();
+    let mut unique_offsets = Vec::<([u32; 3], AABB)>::with_capacity(built.len());
+    let mut unique_bounds = Vec::with_capacity(built.len());
+    let mut table = Vec::new();
+    let mut buffer_indices = HashMap::<u32, u32>::new();
+    let mut geometry_buffer_ids = Vec::new();
+    let mut buffer_index = |id: u32| {
+        *buffer_indices.entry(id).or_insert_with(|| {
+            geometry_buffer_ids.push(id);
+            geometry_buffer_ids.len() as u32 - 1
+        })
+    };
+    let (mut num_nodes, mut num_primitive_refs) = (0, 0);
+    let (mut num_inactive_primitives, mut num_degenerate_primitives) = (0, 0);
+    for (&i, (blas, bounds)) in unique.iter().zip(built) {
+        unique_bounds.push(bounds);
+        // for quantized formats the blas_aabb is the quantization frame
+        let (nodes, aabb) = match blas_node_format {
+            NodeFormat::Quantized16 => {
+                let frame = blas_quantization_frame(&blas.aabb, blas.num_primitive_refs);
+                let nodes = serialize_blas_nodes_quantized(
+                    &blas.serialized,
+                    blas.num_nodes,
+                    &frame,
+                    blas_node_format,
+                );
+                (nodes, frame)
+            }
+            _ => (blas.serialized, blas.aabb),
+        };
+        unique_offsets.push(([num_nodes, table.len() as u32, num_primitive_refs], aabb));
+        blas_nodes.extend_from_slice(&nodes);
+        blas_primitive_refs.extend_from_slice(&blas.primitive_refs);
+        num_nodes += blas.num_nodes;
+        num_primitive_refs += blas.num_primitive_refs;
+        num_inactive_primitives += blas.num_inactive;
+        num_degenerate_primitives += blas.num_degenerate;
+        for (gi, (g, _)) in geometries[i].iter().enumerate() {
+            table.push(scene_geometry(map, blases[i].0, gi, g, &mut buffer_index)?);
+        }
+    }
+
+    let tlas_instances = instances
+        .iter()
+        .enumerate()
+        .map(|(i, inst)| {
+            let (offsets, aabb) = unique_index
+                .get(inst.blas_index as usize)
+                .map(|&k| unique_offsets[k])
+                .ok_or(BvhBuildError::IndexOutOfRange {
+                    at: DescriptorLocation::Instance(i as u32),
+                    index: inst.blas_index as usize,
+                    len: blases.len(),
+                })?;
+            Ok(TlasInstance {
+                mask: inst.mask,
+                flags: inst.flags,
+                instance_id: i as u32,
+                sbt_instance_offset: inst.sbt_instance_offset,
+                instance_custom_index: inst.instance_custom_index,
+                blas_entry_index: offsets[0],
+                blas_geometry_id_offset: offsets[1],
+                blas_primitive_ref_offset: offsets[2],
+                blas_aabb: [
+                    aabb.min.x, aabb.min.y, aabb.min.z, aabb.max.x, aabb.max.y, aabb.max.z,
+                ],
+                transform_to_world_4x3: inst.transform_to_world_4x3,
+                motion_type: inst.motion_type,
+                motion_keyframes: inst.motion_keyframes,
+            })
+        })
+        .collect::<Result<Vec<_>, BvhBuildError>>()?;
+    // indices were checked above
+    let instance_bounds: Vec<Arc<BlasBounds>> = if tight_bounds {
+        instances
+            .iter()
+            .map(|inst| unique_bounds[unique_index[inst.blas_index as usize]].clone())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let tlas =
+        build_tlas_from_instances_with_bounds(&tlas_instances, &instance_bounds, tlas_options)?;
+
+    Ok(Scene {
+        blas_nodes,
+        blas_primitive_refs,
+        blas_node_format,
+        blas_offsets: unique_index.iter().map(|&k| unique_offsets[k].0).collect(),
+        blas_aabbs: unique_index.iter().map(|&k| unique_offsets[k].1).collect(),
+        geometries: table,
+        geometry_buffer_ids,
+        tlas,
+        num_unique_blases: unique.len() as u32,
+        num_inactive_primitives,
+        num_degenerate_primitives,
+    })
+}
+
+// Whether two BLAS descriptors describe the same primitives, as hashed by
+// blas_geometry_hash.
+fn same_geometries(a: &[(Primitive, u32)], b: &[(Primitive, u32)]) -> bool {
+    a.len() == b.len()
+        && a.iter().zip(b).all(|((ga, na), (gb, nb))| {
+            na == nb
+                && ga.geometry_type == gb.geometry_type
+                && ga.flags == gb.flags
+                && ga.vertex_format == gb.vertex_format
+                && ga.vertex_stride == gb.vertex_stride
+                && ga.index_format == gb.index_format
+                && ga.ibuf == gb.ibuf
+                && ga.transform == gb.transform
+                && ga.vbuf == gb.vbuf
+                && ga.end_vbuf == gb.end_vbuf
+        })
+}
+
+// Entry `index` of a table of a BuiltScene, with the index passed from JS.
+fn scene_entry<T>(entries: &[T], index: u32, at: DescriptorLocation) -> Result<&T, BvhBuildError> {
+    entries
+        .get(index as usize)
+        .ok_or(BvhBuildError::IndexOutOfRange {
+            at,
+            index: index as usize,
+            len: entries.len(),
+        })
+}
+
+// The table entry of geometry `gi` of a descriptor accepted by
+// blas_geometries, `g` is its first primitive.
+fn scene_geometry(
+    map: &StagingBufferMap,
+    blas_descriptor_buffer_id: u32,
+    gi: usize,
+    g: &Primitive,
+    buffer_index: &mut impl FnMut(u32) -> u32,
+) -> Result<SceneGeometry, BvhBuildError> {
+    let at = DescriptorLocation::Descriptor;
+    let buf = staging_buffer(map, blas_descriptor_buffer_id, at)?;
+    let words: &[i32] = cast_staging_buffer(buf, blas_descriptor_buffer_id, at)?;
+    let num_fields = GeometryDescriptorField::NumFields as usize;
+    let geom = &words[2 + gi * num_fields..2 + (gi + 1) * num_fields];
+    let word = |field: GeometryDescriptorField| geom[field as usize];
+
+    let vbuf_index = buffer_index(word(GeometryDescriptorField::VbufId) as u32);
+    let ibuf_id = word(GeometryDescriptorField::IbufId);
+    let indexed = g.geometry_type == GeometryType::Triangle && ibuf_id >= 0;
+    let end_vbuf_id = word(GeometryDescriptorField::EndVbufId);
+    let moving = g.geometry_type == GeometryType::Triangle && end_vbuf_id >= 0;
+    Ok(SceneGeometry {
+        geometry_type: g.geometry_type as u32,
+        flags: g.flags.bits(),
+        vbuf_index,
+        vbuf_byte_offset: word(GeometryDescriptorField::VbufByteOffset) as u32,
+        vertex_stride: match g.geometry_type {
+            GeometryType::Triangle => g.vertex_stride as u32,
+            GeometryType::Aabb => 12,
+        },
+        vertex_format: g.vertex_format as u32,
+        ibuf_index: if indexed {
+            buffer_index(ibuf_id as u32) as i32
+        } else {
+            -1
+        },
+        ibuf_byte_offset: if indexed {
+            word(GeometryDescriptorField::IbufByteOffset) as u32
+        } else {
+            0
+        },
+        index_stride: if indexed {
+            3 * g.index_format.byte_size() as u32
+        } else {
+            0
+        },
+        index_format: g.index_format as u32,
+        end_vbuf_index: if moving {
+            buffer_index(end_vbuf_id as u32) as i32
+        } else {
+            -1
+        },
+        end_vbuf_byte_offset: if moving {
+            word(GeometryDescriptorField::EndVbufByteOffset) as u32
+        } else {
+            0
+        },
+        transform_4x3: match g.transform {
+            Some(t) => t.try_into().unwrap(),
+            None => IDENTITY_4X3,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{scene_entry, try_build_scene, SceneInstance, IDENTITY_4X3};
+    use crate::builder::BuildOptions;
+    use crate::error::{BvhBuildError, DescriptorLocation};
+    use crate::layout::read_tlas_node;
+    use crate::registry::StagingBufferMap;
+    use crate::{GeometryDescriptorField, GeometryType, NodeFormat};
+
+    // A BLAS descriptor of triangle geometries, (vbuf_id, num_primitives)
+    // each.
+    fn descriptor(geometries: &[(u32, i32)]) -> Vec<u8> {
+        let num_fields = GeometryDescriptorField::NumFields as usize;
+        let mut words = vec![0i32; 2 + geometries.len() * num_fields];
+        words[0] = geometries.len() as i32;
+        words[1] = geometries.iter().map(|(_, np)| np).sum();
+        for (gi, &(vbuf_id, np)) in geometries.iter().enumerate() {
+            let geom = &mut words[2 + gi * num_fields..2 + (gi + 1) * num_fields];
+            geom[GeometryDescriptorField::Type as usize] = GeometryType::Triangle as i32;
+            geom[GeometryDescriptorField::NumPrimitives as usize] = np;
+            geom[GeometryDescriptorField::VbufId as usize] = vbuf_id as i32;
+            geom[GeometryDescriptorField::IbufId as usize] = -1;
+            geom[GeometryDescriptorField::VertexStride as usize] = 12;
+            geom[GeometryDescriptorField::EndVbufId as usize] = -1;
+        }
+        words.iter().flat_map(|w| w.to_le_bytes()).collect()
+    }
+
+    fn instance(blas_index: u32, x: f32) -> SceneInstance {
+        let mut transform = IDENTITY_4X3;
+        transform[9] = x;
+        SceneInstance {
+            blas_index,
+            mask: 0xff,
+            flags: 0,
+            sbt_instance_offset: 0,
+            instance_custom_index: -1,
+            transform_to_world_4x3: transform,
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        }
+    }
+
+    #[test]
+    fn test_scene_shares_equal_blases() {
+        let triangle: Vec<u8> = [0.0f32, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect();
+        let mut map = StagingBufferMap::new();
+        // the same vertices in two buffers, and a second triangle
+        map.insert_at(1, triangle.clone());
+        map.insert_at(2, triangle.clone());
+        map.insert_at(3, [&triangle[..], &triangle[..]].concat());
+        map.insert_at(10, descriptor(&[(1, 1)]));
+        map.insert_at(11, descriptor(&[(2, 1)]));
+        map.insert_at(12, descriptor(&[(1, 1), (3, 2)]));
+        let options = BuildOptions::default();
+        let blases = [(10, options), (11, options), (12, options)];
+        let instances = [instance(0, 0.0), instance(1, 5.0), instance(2, 10.0)];
+        let scene = try_build_scene(&map, &blases, &instances, NodeFormat::Full, &options).unwrap();
+
+        assert_eq!(scene.num_unique_blases, 2);
+        assert_eq!(scene.blas_offsets[0], scene.blas_offsets[1]);
+        let [entry, geometry_offset, ref_offset] = scene.blas_offsets[2];
+        assert_eq!((entry, geometry_offset, ref_offset), (1, 1, 1));
+        // one entry per geometry of each unique BLAS
+        assert_eq!(scene.geometries.len(), 3);
+        assert_eq!(scene.geometry_buffer_ids, vec![1, 3]);
+        let g = scene.geometries[2];
+        assert_eq!((g.vbuf_index, g.ibuf_index, g.vertex_stride), (1, -1, 12));
+        assert_eq!(scene.blas_aabbs[2].max.x, 1.0);
+        assert_eq!(
+            scene_entry(&scene.geometries, 3, DescriptorLocation::Geometry(3)).unwrap_err(),
+            BvhBuildError::IndexOutOfRange {
+                at: DescriptorLocation::Geometry(3),
+                index: 3,
+                len: 3
+            }
+        );
+
+        assert_eq!(scene.tlas.instance_leaf_nodes.len(), 3);
+        for (i, &leaf) in scene.tlas.instance_leaf_nodes.iter().enumerate() {
+            let node = read_tlas_node(&scene.tlas.serialized, leaf);
+            let blas_index = instances[i].blas_index as usize;
+            assert_eq!(node.instance_id, i as u32);
+            assert_eq!(node.entry_index, scene.blas_offsets[blas_index][0]);
+            assert_eq!(
+                node.blas_geometry_id_offset,
+                scene.blas_offsets[blas_index][1]
+            );
+        }
+
+        let quantized =
+            try_build_scene(&map, &blases, &instances, NodeFormat::Quantized16, &options).unwrap();
+        assert_eq!(quantized.blas_offsets, scene.blas_offsets);
+        assert!(quantized.blas_nodes.len() < scene.blas_nodes.len());
+        assert!(quantized.blas_aabbs[0].max.x > 1.0);
+
+        let err = try_build_scene(
+            &map,
+            &blases,
+            &[instance(3, 0.0)],
+            NodeFormat::Full,
+            &options,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            BvhBuildError::IndexOutOfRange {
+                at: DescriptorLocation::Instance(0),
+                index: 3,
+                len: 3
+            }
+        );
+    }
+}
@@ -0,0 +1,205 @@
+// Quality statistics of a built BLAS or TLAS, read back from its serialized
+// nodes.
+
+use crate::builder::{half_area, BuildOptions};
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::layout::{blas_node_stride, read_blas_node, read_tlas_node, tlas_node_stride};
+use crate::{BuiltBvh, NodeFormat};
+use bvh::aabb::AABB;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default)]
+pub struct BvhStats {
+    pub num_nodes: u32,
+    pub num_leaves: u32,
+    /// Depth of the deepest leaf, the root is at depth 0.
+    pub max_depth: u32,
+    /// Mean depth of the leaves.
+    pub average_depth: f32,
+    /// Surface area heuristic cost of the tree, relative to the root area,
+    /// with the traversal and intersection costs of the given BuildOptions.
+    pub sah_cost: f32,
+    /// Primitives (BLAS) or instances (TLAS) per leaf.
+    pub average_primitives_per_leaf: f32,
+    /// Size of the serialized nodes, plus the primitive refs of a BLAS.
+    pub serialized_byte_size: u32,
+    surface_area_per_level: Vec<f32>,
+    overlap_area_per_level: Vec<f32>,
+}
+
+#[wasm_bindgen]
+impl BvhStats {
+    /// Total surface area of the nodes at each depth.
+    pub fn surface_area_per_level(&self) -> Vec<f32> {
+        self.surface_area_per_level.clone()
+    }
+
+    /// Total surface area shared by sibling nodes at each depth, 0 at the
+    /// root.
+    pub fn overlap_area_per_level(&self) -> Vec<f32> {
+        self.overlap_area_per_level.clone()
+    }
+}
+
+/// Statistics of a BLAS or TLAS built by `build_blas` or `build_tlas`.
+/// Throws for compacted and wide BLASes, take the stats before compacting.
+#[wasm_bindgen]
+pub fn bvh_stats(built: &BuiltBvh, options: &BuildOptions) -> Result<BvhStats, JsValue> {
+    Ok(try_bvh_stats(built, options)?)
+}
+
+fn try_bvh_stats(built: &BuiltBvh, options: &BuildOptions) -> Result<BvhStats, BvhBuildError> {
+    // only binary, uncompacted BVHs have stats
+    if built.format != NodeFormat::Full {
+        return Err(BvhBuildError::UnsupportedNodeFormat {
+            at: DescriptorLocation::Descriptor,
+            format: built.format,
+        });
+    }
+    Ok(built.serialized.with_buffer(|nodes| {
+        let mut stats = match &built.primitive_refs {
+            Some(refs) => {
+                let mut stats = blas_stats(nodes, built.num_nodes, options);
+                stats.serialized_byte_size += refs.with_buffer(|refs| refs.len()) as u32;
+                stats
+            }
+            None => tlas_stats(nodes, built.num_nodes, options),
+        };
+        stats.serialized_byte_size += nodes.len() as u32;
+        stats
+    }))
+}
+
+// The parts of a GPUBlasBvhNode or GPUTlasBvhNode the stats need.
+struct StatsNode {
+    aabb: AABB,
+    exit_index: u32,
+    // 0 for interior nodes
+    num_primitives: u32,
+}
+
+pub(crate) fn blas_stats(nodes: &[u8], num_nodes: u32, options: &BuildOptions) -> BvhStats {
+    debug_assert!(nodes.len() >= num_nodes as usize * blas_node_stride());
+    tree_stats(num_nodes, options, |i| {
+        let n = read_blas_node(nodes, i);
+        StatsNode {
+            aabb: n.aabb,
+            exit_index: n.exit_index,
+            num_primitives: n.primitive_count,
+        }
+    })
+}
+
+pub(crate) fn tlas_stats(nodes: &[u8], num_nodes: u32, options: &BuildOptions) -> BvhStats {
+    debug_assert!(nodes.len() >= num_nodes as usize * tlas_node_stride());
+    tree_stats(num_nodes, options, |i| {
+        let n = read_tlas_node(nodes, i);
+        StatsNode {
+            aabb: n.aabb,
+            exit_index: n.exit_index,
+            num_primitives: n.is_leaf as u32,
+        }
+    })
+}
+
+// Walks a binary tree in the stackless pre-order layout of build_blas and
+// build_tlas: the first child of an interior node at `i` is at `i + 1`, the
+// second child is at the exit index of the first child.
+fn tree_stats(num_nodes: u32, options: &BuildOptions, node: impl Fn(u32) -> StatsNode) -> BvhStats {
+    let mut stats = BvhStats {
+        num_nodes,
+        ..BvhStats::default()
+    };
+    let root = node(0);
+    if root.num_primitives == 0 && num_nodes < 3 {
+        // the empty root of an empty BVH
+        return stats;
+    }
+    let root_area = half_area(&root.aabb);
+    let mut depth_sum = 0u64;
+    let mut num_primitives = 0u64;
+    let mut stack = vec![(0u32, 0usize)];
+    while let Some((i, depth)) = stack.pop() {
+        let n = node(i);
+        if stats.surface_area_per_level.len() <= depth {
+            stats.surface_area_per_level.resize(depth + 1, 0.0);
+            stats.overlap_area_per_level.resize(depth + 1, 0.0);
+        }
+        let area = half_area(&n.aabb);
+        stats.surface_area_per_level[depth] += 2.0 * area;
+        let relative_area = if root_area > 0.0 {
+            area / root_area
+        } else {
+            1.0
+        };
+        if n.num_primitives > 0 {
+            stats.num_leaves += 1;
+            stats.max_depth = stats.max_depth.max(depth as u32);
+            depth_sum += depth as u64;
+            num_primitives += n.num_primitives as u64;
+            stats.sah_cost += options.intersection_cost * n.num_primitives as f32 * relative_area;
+        } else {
+            stats.sah_cost += options.traversal_cost * relative_area;
+            let left = i + 1;
+            let right = node(left).exit_index;
+            let (a, b) = (node(left).aabb, node(right).aabb);
+            let overlap = AABB::with_bounds(a.min.max(b.min), a.max.min(b.max));
+            if stats.overlap_area_per_level.len() <= depth + 1 {
+                stats.surface_area_per_level.resize(depth + 2, 0.0);
+                stats.overlap_area_per_level.resize(depth + 2, 0.0);
+            }
+            stats.overlap_area_per_level[depth + 1] += 2.0 * half_area(&overlap);
+            stack.push((right, depth + 1));
+            stack.push((left, depth + 1));
+        }
+    }
+    if stats.num_leaves > 0 {
+        stats.average_depth = depth_sum as f32 / stats.num_leaves as f32;
+        stats.average_primitives_per_leaf = num_primitives as f32 / stats.num_leaves as f32;
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::blas_stats;
+    use crate::builder::{BuildOptions, Bvh};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    #[test]
+    fn test_blas_stats() {
+        // two overlapping unit cubes and one far away
+        let boxes = vec![
+            AABB::with_bounds(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0)),
+            AABB::with_bounds(Point3::new(0.5, 0.0, 0.0), Point3::new(1.5, 1.0, 1.0)),
+            AABB::with_bounds(Point3::new(10.0, 0.0, 0.0), Point3::new(11.0, 1.0, 1.0)),
+        ];
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&boxes, &options);
+        let bytes = crate::serialize_blas_nodes(&bvh);
+        let stats = blas_stats(&bytes, bvh.nodes.len() as u32, &options);
+        assert_eq!(stats.num_nodes, 5);
+        assert_eq!(stats.num_leaves, 3);
+        assert_eq!(stats.max_depth, 2);
+        assert!((stats.average_depth - 5.0 / 3.0).abs() < 1e-6);
+        assert_eq!(stats.average_primitives_per_leaf, 1.0);
+        assert_eq!(stats.surface_area_per_level.len(), 3);
+        // root: 11 x 1 x 1
+        assert_eq!(stats.surface_area_per_level[0], 2.0 * 23.0);
+        assert_eq!(stats.overlap_area_per_level[0], 0.0);
+        assert_eq!(stats.overlap_area_per_level[1], 0.0);
+        // the two cubes share a 0.5 x 1 x 1 box
+        assert_eq!(stats.overlap_area_per_level[2], 2.0 * 2.0);
+        assert!(stats.sah_cost > 1.0);
+
+        let empty = crate::serialize_blas_nodes(&Bvh::build(&[], &options));
+        let stats = blas_stats(&empty, 1, &options);
+        assert_eq!((stats.num_nodes, stats.num_leaves), (1, 0));
+        assert_eq!(stats.sah_cost, 0.0);
+    }
+}
@@ -0,0 +1,165 @@
+// Classification of instance transforms. Instances with a singular transform
+// have no world to object transform, so they are left out of the TLAS like
+// degenerate instances. Identity instances are marked in their TLAS leaves,
+// traversal uses the world space ray for them, see traceRayMotionNV in
+// trace.glsl.
+
+use crate::motion::inverse_4x3;
+use bvh::Point3;
+
+const IDENTITY_4X3: [f32; 12] = [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+
+// Column lengths and dot products within this relative tolerance are equal.
+const TOLERANCE: f32 = 1e-5;
+// Transforms scaling volumes by less than this, relative to the lengths of
+// their columns, are singular.
+const SINGULAR_TOLERANCE: f32 = 1e-6;
+
+/// What an instance transform does, from the most specific to the most
+/// general, the `transform_class` of a `TlasNode`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TransformClass {
+    /// Exactly the identity.
+    Identity = 0,
+    /// A rotation and a translation.
+    Rigid = 1,
+    /// A rotation, a uniform scale and a translation.
+    UniformScale = 2,
+    /// Any other invertible affine transform, e.g. with a reflection, a
+    /// shear or a non-uniform scale.
+    Affine = 3,
+    /// Not invertible, with a zero scale or collinear columns, or not
+    /// finite.
+    Singular = 4,
+}
+
+impl TryFrom<u32> for TransformClass {
+    type Error = ();
+
+    fn try_from(v: u32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(TransformClass::Identity),
+            1 => Ok(TransformClass::Rigid),
+            2 => Ok(TransformClass::UniformScale),
+            3 => Ok(TransformClass::Affine),
+            4 => Ok(TransformClass::Singular),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Classifies a 4x3 column major transform.
+pub fn classify_transform(m: &[f32; 12]) -> TransformClass {
+    if !m.iter().all(|v| v.is_finite()) {
+        return TransformClass::Singular;
+    }
+    if *m == IDENTITY_4X3 {
+        return TransformClass::Identity;
+    }
+    let col = |c: usize| Point3::new(m[3 * c], m[3 * c + 1], m[3 * c + 2]);
+    let (c0, c1, c2) = (col(0), col(1), col(2));
+    let (l0, l1, l2) = (c0.length(), c1.length(), c2.length());
+    let det = det_4x3(m);
+    // the inverse may overflow for tiny scales even if the volume does not
+    // vanish relative to them
+    if !(det.abs() > SINGULAR_TOLERANCE * l0 * l1 * l2)
+        || !inverse_4x3(m).iter().all(|v| v.is_finite())
+    {
+        return TransformClass::Singular;
+    }
+    let orthogonal = c0.dot(c1).abs() <= TOLERANCE * l0 * l1
+        && c1.dot(c2).abs() <= TOLERANCE * l1 * l2
+        && c2.dot(c0).abs() <= TOLERANCE * l2 * l0;
+    let uniform = (l1 - l0).abs() <= TOLERANCE * l0 && (l2 - l0).abs() <= TOLERANCE * l0;
+    if !(orthogonal && uniform && det > 0.0) {
+        TransformClass::Affine
+    } else if (l0 - 1.0).abs() <= TOLERANCE {
+        TransformClass::Rigid
+    } else {
+        TransformClass::UniformScale
+    }
+}
+
+// The determinant of the linear part of a 4x3 column major transform.
+pub(crate) fn det_4x3(m: &[f32; 12]) -> f32 {
+    let col = |c: usize| Point3::new(m[3 * c], m[3 * c + 1], m[3 * c + 2]);
+    col(0).dot(col(1).cross(col(2)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify_transform, TransformClass};
+    use crate::motion::InstanceMotion;
+
+    #[test]
+    fn test_classify_transform() {
+        let (s, c) = 0.5f32.sin_cos();
+        let rotation = [c, s, 0.0, -s, c, 0.0, 0.0, 0.0, 1.0, 1.0, 2.0, 3.0];
+        let scaled = rotation.map(|v| 2.0 * v);
+        let cases = [
+            (
+                [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+                TransformClass::Identity,
+            ),
+            (
+                [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 5.0, 0.0, 0.0],
+                TransformClass::Rigid,
+            ),
+            (rotation, TransformClass::Rigid),
+            (scaled, TransformClass::UniformScale),
+            // a reflection
+            (
+                [-1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+                TransformClass::Affine,
+            ),
+            (
+                [1.0, 0.0, 0.0, 0.5, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+                TransformClass::Affine,
+            ),
+            (
+                [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+                TransformClass::Singular,
+            ),
+            (
+                [1.0, 1.0, 0.0, 2.0, 2.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+                TransformClass::Singular,
+            ),
+            (
+                [
+                    1e-30, 0.0, 0.0, 0.0, 1e-30, 0.0, 0.0, 0.0, 1e-30, 0.0, 0.0, 0.0,
+                ],
+                TransformClass::Singular,
+            ),
+            (
+                [
+                    f32::NAN,
+                    0.0,
+                    0.0,
+                    0.0,
+                    1.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                    1.0,
+                    0.0,
+                    0.0,
+                    0.0,
+                ],
+                TransformClass::Singular,
+            ),
+        ];
+        for (m, class) in cases {
+            assert_eq!(classify_transform(&m), class, "{:?}", m);
+        }
+
+        // mirrored at time 1, singular in between
+        let identity = cases[0].0;
+        let mirrored = cases[4].0;
+        let motion = InstanceMotion::Matrix(mirrored);
+        assert_eq!(motion.transform_class(&identity), TransformClass::Singular);
+        let motion = InstanceMotion::Matrix(rotation);
+        assert_eq!(motion.transform_class(&identity), TransformClass::Affine);
+        let motion = InstanceMotion::Matrix(identity);
+        assert_eq!(motion.transform_class(&identity), TransformClass::Identity);
+    }
+}
@@ -0,0 +1,783 @@
+// CPU reference of the stackless traversal in trace.glsl, over the same
+// serialized TLAS and BLAS buffers. Floating point expressions follow the
+// shader term by term so that hits can be compared with the GPU exactly.
+
+use crate::compact::read_blas_node_quantized;
+use crate::error::{BvhBuildError, DescriptorLocation};
+use crate::layout::{read_primitive_ref, read_primitive_ref_flags, read_tlas_node};
+use crate::layout::{BlasNode, TlasNode};
+use crate::motion::{inverse_4x3, InstanceMotion};
+use crate::{BuiltBvh, GeometryType, NodeFormat, Primitive};
+use crate::{InstanceFlags, InstanceMotionType, TransformClass};
+use bvh::aabb::{Bounded, AABB};
+use bvh::Point3;
+use std::borrow::Cow;
+
+const TRAVERSE_MAX_INT: u32 = u32::MAX;
+
+/// gl_HitKindFrontFacingTriangleEXT
+pub const HIT_KIND_FRONT_FACING_TRIANGLE: u32 = 0xfe;
+/// gl_HitKindBackFacingTriangleEXT
+pub const HIT_KIND_BACK_FACING_TRIANGLE: u32 = 0xff;
+
+/// The BLASes of a scene back to back, like the BLAS node, primitive ref and
+/// geometry buffers bound by wasm_bvh_builder.ts. TLAS leaves index them
+/// with blas_entry_index, blas_primitive_ref_offset and
+/// blas_geometry_id_offset.
+pub struct BlasBuffers<'a> {
+    format: NodeFormat,
+    nodes: Cow<'a, [u8]>,
+    primitive_refs: Cow<'a, [u8]>,
+    // indexed by blas_geometry_id_offset + geometryId, see
+    // bvhReferencedGeomBuffer
+    geometries: Vec<Primitive<'a>>,
+    num_nodes: u32,
+    num_primitive_refs: u32,
+}
+
+impl<'a> BlasBuffers<'a> {
+    /// `format` is the node format of all BLASes, wide BLASes are traversed
+    /// with a stack and not supported.
+    pub fn new(format: NodeFormat) -> Result<Self, BvhBuildError> {
+        Self::from_parts(format, &[], 0, &[], 0, Vec::new())
+    }
+
+    // BLASes already back to back, borrowed instead of copied.
+    pub(crate) fn from_parts(
+        format: NodeFormat,
+        nodes: &'a [u8],
+        num_nodes: u32,
+        primitive_refs: &'a [u8],
+        num_primitive_refs: u32,
+        geometries: Vec<Primitive<'a>>,
+    ) -> Result<Self, BvhBuildError> {
+        if format == NodeFormat::Wide4 || format == NodeFormat::Wide8 {
+            return Err(BvhBuildError::UnsupportedNodeFormat {
+                at: DescriptorLocation::Descriptor,
+                format,
+            });
+        }
+        Ok(BlasBuffers {
+            format,
+            nodes: Cow::Borrowed(nodes),
+            primitive_refs: Cow::Borrowed(primitive_refs),
+            geometries,
+            num_nodes,
+            num_primitive_refs,
+        })
+    }
+
+    pub(crate) fn push_buffers(
+        &mut self,
+        nodes: &[u8],
+        num_nodes: u32,
+        primitive_refs: &[u8],
+        num_primitive_refs: u32,
+        geometries: Vec<Primitive<'a>>,
+    ) -> [u32; 3] {
+        let offsets = [
+            self.num_nodes,
+            self.geometries.len() as u32,
+            self.num_primitive_refs,
+        ];
+        self.nodes.to_mut().extend_from_slice(nodes);
+        self.primitive_refs
+            .to_mut()
+            .extend_from_slice(primitive_refs);
+        self.geometries.extend(geometries);
+        self.num_nodes += num_nodes;
+        self.num_primitive_refs += num_primitive_refs;
+        offsets
+    }
+
+    // Node `index` of the BLASes back to back, `frame` is the blas_aabb of
+    // the instance.
+    pub(crate) fn node(&self, index: u32, frame: &AABB) -> BlasNode {
+        read_blas_node_quantized(&self.nodes, index, frame, self.format)
+    }
+
+    // The BLAS local geometry id and the primitive of a leaf's primitive ref,
+    // `index` is relative to the instance's blas_primitive_ref_offset. The
+    // primitive's flags are those stored in the ref.
+    pub(crate) fn primitive(&self, instance: &TlasNode, index: u32) -> (i32, Primitive<'a>) {
+        let index = instance.blas_primitive_ref_offset + index;
+        let (geometry_id, primitive_id) = read_primitive_ref(&self.primitive_refs, index);
+        let flags = read_primitive_ref_flags(&self.primitive_refs, index);
+        let g = &self.geometries[(geometry_id + instance.blas_geometry_id_offset as i32) as usize];
+        (
+            geometry_id,
+            Primitive {
+                primitive_id,
+                flags,
+                ..*g
+            },
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Ray {
+    pub origin: Point3,
+    pub direction: Point3,
+    pub tmin: f32,
+    pub tmax: f32,
+    /// The time of motion blurred rays, clamped to [0, 1]. Instances and
+    /// geometries that do not move are hit the same at any time.
+    pub time: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    pub t: f32,
+    pub instance_id: u32,
+    pub instance_custom_index: i32,
+    pub sbt_instance_offset: u32,
+    /// Within the BLAS, gl_GeometryIndexEXT.
+    pub geometry_id: i32,
+    pub primitive_id: u32,
+    pub hit_kind: u32,
+    /// Barycentrics and world space normal of triangle hits, see
+    /// buf_hitAttributes. Zero for AABB hits.
+    pub attributes: [f32; 5],
+}
+
+/// What the any-hit shader does with a hit, see _CRT_HIT_REPORT_*.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HitReport {
+    Ignore,
+    Confirm,
+    Terminate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Trace {
+    Miss,
+    ClosestHit(Hit),
+    /// The any-hit shader terminated the ray at this hit. trace.glsl then
+    /// returns without invoking the closest hit or miss shader.
+    Terminated(Hit),
+}
+
+/// Traces `ray` through a TLAS built by `build_tlas` over the BLASes in
+/// `blases`. `any_hit` stands in for the any-hit shader, confirm every hit
+/// for closest-hit queries and terminate on the first one for any-hit
+/// queries. It is not invoked for opaque primitives, see
+/// `InstanceFlags::is_opaque`, whose hits are confirmed. AABB primitives are hit where the ray enters their box, in place
+/// of an intersection shader.
+pub fn trace_ray(
+    tlas: &BuiltBvh,
+    blases: &BlasBuffers,
+    ray: &Ray,
+    cull_mask: u32,
+    any_hit: impl FnMut(&Hit) -> HitReport,
+) -> Trace {
+    assert!(tlas.primitive_refs.is_none(), "not a TLAS");
+    tlas.serialized
+        .with_buffer(|nodes| trace_ray_nodes(nodes, blases, ray, cull_mask, any_hit))
+}
+
+// Same as traceRayMotionNV in trace.glsl for stackless BLAS formats.
+pub(crate) fn trace_ray_nodes(
+    tlas_nodes: &[u8],
+    blases: &BlasBuffers,
+    ray: &Ray,
+    cull_mask: u32,
+    mut any_hit: impl FnMut(&Hit) -> HitReport,
+) -> Trace {
+    let inv_world_ray_dir = Point3::ONE / ray.direction;
+    let time = ray.time.clamp(0.0, 1.0);
+    let mut ray_tmax = ray.tmax;
+    let mut closest = None;
+    let mut cur = 0u32;
+    while cur < TRAVERSE_MAX_INT {
+        let node = read_tlas_node(tlas_nodes, cur);
+        if (node.is_leaf && (node.mask & cull_mask) == 0)
+            || !intersect_aabb(
+                ray.origin,
+                inv_world_ray_dir,
+                ray.tmin,
+                ray_tmax,
+                &node.aabb,
+            )
+        {
+            cur = node.exit_index;
+            continue;
+        }
+        if !node.is_leaf {
+            cur = node.entry_index;
+            continue;
+        }
+
+        // TLAS leaf, identity instances use the world space ray
+        let identity = node.transform_class == TransformClass::Identity;
+        // the identity at all times for identity instances
+        let world_to_object = if identity || node.motion_type == InstanceMotionType::Static {
+            node.transform_to_object
+        } else {
+            inverse_4x3(
+                &InstanceMotion::from_node(&node).transform_at(&node.transform_to_world, time),
+            )
+        };
+        let (object_ray_origin, object_ray_direction) = if identity {
+            (ray.origin, ray.direction)
+        } else {
+            (
+                transform_4x3(&world_to_object, ray.origin, 1.0),
+                transform_4x3(&world_to_object, ray.direction, 0.0),
+            )
+        };
+        let inv_object_ray_dir = Point3::ONE / object_ray_direction;
+
+        // entering blas tree
+        cur = node.entry_index;
+        let blas_index_offset = cur;
+        let instance_exit_index = node.exit_index;
+        while cur < TRAVERSE_MAX_INT {
+            let blas_node = blases.node(cur, &node.blas_aabb);
+            // the ray of an identity instance hit the TLAS leaf bounds, which
+            // are within the BLAS root bounds unless the root is the inverted
+            // one of an empty BLAS
+            let skip_aabb = identity
+                && cur == blas_index_offset
+                && blas_node.aabb.min.x <= blas_node.aabb.max.x;
+            if !skip_aabb
+                && !intersect_aabb(
+                    object_ray_origin,
+                    inv_object_ray_dir,
+                    ray.tmin,
+                    ray_tmax,
+                    &blas_node.aabb,
+                )
+            {
+                if blas_node.exit_index == TRAVERSE_MAX_INT {
+                    // leaving blas into tlas tree
+                    cur = instance_exit_index;
+                    break;
+                }
+                cur = blas_node.exit_index + blas_index_offset;
+                continue;
+            } else if blas_node.primitive_count == 0 {
+                // interior node
+                cur = blas_node.entry_index_or_first_primitive + blas_index_offset;
+                continue;
+            }
+
+            for k in 0..blas_node.primitive_count {
+                let (geometry_id, primitive) =
+                    blases.primitive(&node, blas_node.entry_index_or_first_primitive + k);
+                let primitive_id = primitive.primitive_id;
+                let mut hit = Hit {
+                    t: ray.tmin - 1.0,
+                    instance_id: node.instance_id,
+                    instance_custom_index: node.instance_custom_index,
+                    sbt_instance_offset: node.sbt_instance_offset,
+                    geometry_id,
+                    primitive_id,
+                    hit_kind: 0,
+                    attributes: [0.0; 5],
+                };
+                let is_hit = if primitive.geometry_type == GeometryType::Triangle {
+                    let p = primitive.triangle_vertices_at(time);
+                    match intersect_triangle_branchless(
+                        object_ray_origin,
+                        ray.tmin,
+                        object_ray_direction,
+                        ray_tmax,
+                        [p[0], p[1], p[2]],
+                    ) {
+                        Some((n, t, beta, gamma)) => {
+                            // n * _crt_WorldToObjectEXT
+                            let column = |c: usize| {
+                                Point3::new(
+                                    world_to_object[3 * c],
+                                    world_to_object[3 * c + 1],
+                                    world_to_object[3 * c + 2],
+                                )
+                            };
+                            let n =
+                                Point3::new(n.dot(column(0)), n.dot(column(1)), n.dot(column(2)))
+                                    .normalize();
+                            hit.t = t;
+                            hit.attributes = [beta, gamma, n.x, n.y, n.z];
+                            let mut front_facing = n.dot(ray.direction) > 0.0;
+                            if node
+                                .flags
+                                .contains(InstanceFlags::TRIANGLE_FRONT_COUNTERCLOCKWISE)
+                            {
+                                front_facing = !front_facing;
+                            }
+                            hit.hit_kind = if front_facing {
+                                HIT_KIND_FRONT_FACING_TRIANGLE
+                            } else {
+                                HIT_KIND_BACK_FACING_TRIANGLE
+                            };
+                            true
+                        }
+                        None => false,
+                    }
+                } else {
+                    match aabb_entry(
+                        object_ray_origin,
+                        inv_object_ray_dir,
+                        ray.tmin,
+                        ray_tmax,
+                        &primitive.aabb(),
+                    ) {
+                        Some(t) => {
+                            hit.t = t;
+                            true
+                        }
+                        None => false,
+                    }
+                };
+                if !is_hit {
+                    continue;
+                }
+                let report = if node.flags.is_opaque(primitive.flags) {
+                    HitReport::Confirm
+                } else {
+                    any_hit(&hit)
+                };
+                match report {
+                    HitReport::Terminate => return Trace::Terminated(hit),
+                    HitReport::Confirm => {
+                        ray_tmax = hit.t;
+                        closest = Some(hit);
+                    }
+                    HitReport::Ignore => {}
+                }
+            }
+
+            if blas_node.exit_index == TRAVERSE_MAX_INT {
+                // leaving blas into tlas tree
+                cur = instance_exit_index;
+                break;
+            }
+            cur = blas_node.exit_index + blas_index_offset;
+        }
+    }
+    match closest {
+        Some(hit) => Trace::ClosestHit(hit),
+        None => Trace::Miss,
+    }
+}
+
+// mat4x3 * vec4(v, w), column major
+pub(crate) fn transform_4x3(m: &[f32; 12], v: Point3, w: f32) -> Point3 {
+    let row = |r: usize| m[r] * v.x + m[3 + r] * v.y + m[6 + r] * v.z + m[9 + r] * w;
+    Point3::new(row(0), row(1), row(2))
+}
+
+// Where the ray enters `aabb`, clamped to ray_tmin, see intersect_aabb in
+// intersect.glsl.
+fn aabb_entry(
+    ray_origin: Point3,
+    inv_ray_dir: Point3,
+    ray_tmin: f32,
+    ray_tmax: f32,
+    aabb: &AABB,
+) -> Option<f32> {
+    // empty roots of empty BLASes and TLASes are inverted, min > max
+    if aabb.min.x > aabb.max.x {
+        return None;
+    }
+    let t0 = (aabb.min - ray_origin) * inv_ray_dir;
+    let t1 = (aabb.max - ray_origin) * inv_ray_dir;
+    let tmin = t0.min(t1);
+    let tmax = t0.max(t1);
+    let entry = ray_tmin.max(tmin.x.max(tmin.y).max(tmin.z));
+    let exit = ray_tmax.min(tmax.x.min(tmax.y).min(tmax.z));
+    if entry <= exit {
+        Some(entry)
+    } else {
+        None
+    }
+}
+
+fn intersect_aabb(
+    ray_origin: Point3,
+    inv_ray_dir: Point3,
+    ray_tmin: f32,
+    ray_tmax: f32,
+    aabb: &AABB,
+) -> bool {
+    aabb_entry(ray_origin, inv_ray_dir, ray_tmin, ray_tmax, aabb).is_some()
+}
+
+// (n, t, beta, gamma) of a hit, see intersect_triangle_branchless in
+// intersect.glsl.
+fn intersect_triangle_branchless(
+    ray_origin: Point3,
+    ray_tmin: f32,
+    ray_dir: Point3,
+    ray_tmax: f32,
+    [p0, p1, p2]: [Point3; 3],
+) -> Option<(Point3, f32, f32, f32)> {
+    let e0 = p1 - p0;
+    let e1 = p0 - p2;
+    let n = e1.cross(e0);
+
+    let e2 = (1.0 / n.dot(ray_dir)) * (p0 - ray_origin);
+    let i = ray_dir.cross(e2);
+
+    let beta = i.dot(e1);
+    let gamma = i.dot(e0);
+    let t = n.dot(e2);
+
+    if t < ray_tmax && t > ray_tmin && beta >= 0.0 && gamma >= 0.0 && beta + gamma <= 1.0 {
+        Some((n, t, beta, gamma))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{trace_ray_nodes, BlasBuffers, HitReport, Ray, Trace};
+    use super::{HIT_KIND_BACK_FACING_TRIANGLE, HIT_KIND_FRONT_FACING_TRIANGLE};
+    use crate::builder::{BuildOptions, Bvh};
+    use crate::compact::{quantization_frame, serialize_blas_nodes_quantized};
+    use crate::layout::read_tlas_node;
+    use crate::{serialize_blas_nodes, serialize_blas_primitive_refs, serialize_tlas_nodes};
+    use crate::{tlas_instance_leaf_nodes, GeometryFlags, InstanceFlags};
+    use crate::{GeometryType, IndexFormat, NodeFormat, Primitive, VertexFormat};
+    use crate::{InstanceMotionType, TlasInstance, TlasInstanceDescriptor};
+    use bvh::aabb::{Bounded, AABB};
+    use bvh::Point3;
+
+    fn bytes(v: &[f32]) -> Vec<u8> {
+        v.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+
+    pub(crate) fn geometry(geometry_type: GeometryType, vbuf: &[u8]) -> Primitive<'_> {
+        Primitive {
+            blas_local_geometry_id: 0,
+            within_blas_primitive_id: 0,
+            primitive_id: 0,
+            geometry_type,
+            vbuf,
+            end_vbuf: None,
+            vertex_format: VertexFormat::Float32x3,
+            vertex_stride: 12,
+            ibuf: None,
+            index_format: IndexFormat::Uint32,
+            transform: None,
+            flags: GeometryFlags::NONE,
+        }
+    }
+
+    // Builds a BLAS of one geometry and appends it, returns the blas_*
+    // offsets and the root bounds for its instances.
+    fn push_blas<'a>(
+        blases: &mut BlasBuffers<'a>,
+        g: Primitive<'a>,
+        num_primitives: u32,
+    ) -> ([u32; 3], AABB) {
+        let primitives: Vec<Primitive> = (0..num_primitives)
+            .map(|i| Primitive {
+                primitive_id: i,
+                within_blas_primitive_id: i,
+                ..g
+            })
+            .collect();
+        let aabbs: Vec<AABB> = primitives.iter().map(|p| p.aabb()).collect();
+        let options = BuildOptions {
+            max_leaf_size: 1,
+            ..BuildOptions::default()
+        };
+        let bvh = Bvh::build(&aabbs, &options);
+        let root = bvh.nodes[0].aabb;
+        let mut nodes = serialize_blas_nodes(&bvh);
+        let mut frame = root;
+        if blases.format != NodeFormat::Full {
+            frame = quantization_frame(&root);
+            nodes = serialize_blas_nodes_quantized(
+                &nodes,
+                bvh.nodes.len() as u32,
+                &frame,
+                blases.format,
+            );
+        }
+        let refs = serialize_blas_primitive_refs(&bvh, &primitives);
+        let offsets = blases.push_buffers(
+            &nodes,
+            bvh.nodes.len() as u32,
+            &refs,
+            num_primitives,
+            vec![g],
+        );
+        (offsets, frame)
+    }
+
+    fn instance(
+        instance_id: u32,
+        mask: u32,
+        flags: InstanceFlags,
+        blas: ([u32; 3], AABB),
+        translation: [f32; 3],
+    ) -> TlasInstanceDescriptor {
+        let instance = tlas_instance(instance_id, mask, flags, blas, translation);
+        TlasInstanceDescriptor::new(instance_id, &instance).unwrap()
+    }
+
+    fn tlas_instance(
+        instance_id: u32,
+        mask: u32,
+        flags: InstanceFlags,
+        (offsets, blas_aabb): ([u32; 3], AABB),
+        translation: [f32; 3],
+    ) -> TlasInstance {
+        let [x, y, z] = translation;
+        TlasInstance {
+            mask,
+            flags: flags.bits(),
+            instance_id,
+            sbt_instance_offset: 0,
+            instance_custom_index: 10 + instance_id as i32,
+            blas_entry_index: offsets[0],
+            blas_geometry_id_offset: offsets[1],
+            blas_primitive_ref_offset: offsets[2],
+            blas_aabb: [
+                blas_aabb.min.x,
+                blas_aabb.min.y,
+                blas_aabb.min.z,
+                blas_aabb.max.x,
+                blas_aabb.max.y,
+                blas_aabb.max.z,
+            ],
+            transform_to_world_4x3: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, x, y, z],
+            motion_type: 0,
+            motion_keyframes: [0.0; 32],
+        }
+    }
+
+    // A unit quad of two triangles at z = 0
+    pub(crate) fn quad_vertices() -> Vec<u8> {
+        bytes(&[
+            0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, //
+            0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 1.0, 0.0,
+        ])
+    }
+
+    pub(crate) fn unit_box() -> Vec<u8> {
+        bytes(&[0.0, 0.0, 0.0, 1.0, 1.0, 1.0])
+    }
+
+    // The quad as instance 0 (mask 0x1) and 1 (mask 0x2, at x = 5), the box
+    // as instance 2 (mask 0x1, at z = 3). Returns the TLAS nodes.
+    pub(crate) fn quad_and_box_scene<'a>(
+        blases: &mut BlasBuffers<'a>,
+        quad: &'a [u8],
+        unit_box: &'a [u8],
+    ) -> Vec<u8> {
+        let quad_blas = push_blas(blases, geometry(GeometryType::Triangle, quad), 2);
+        let box_blas = push_blas(blases, geometry(GeometryType::Aabb, unit_box), 1);
+        let instances = vec![
+            instance(0, 0x1, InstanceFlags::NONE, quad_blas, [0.0, 0.0, 0.0]),
+            instance(1, 0x2, InstanceFlags::NONE, quad_blas, [5.0, 0.0, 0.0]),
+            instance(2, 0x1, InstanceFlags::NONE, box_blas, [0.0, 0.0, 3.0]),
+        ];
+        let aabbs: Vec<AABB> = instances.iter().map(|i| i.aabb).collect();
+        let tlas = Bvh::build(
+            &aabbs,
+            &BuildOptions {
+                max_leaf_size: 1,
+                ..BuildOptions::default()
+            },
+        );
+        serialize_tlas_nodes(&tlas, &instances)
+    }
+
+    #[test]
+    fn test_trace_ray_matches_scene() {
+        let (quad, unit_box) = (quad_vertices(), unit_box());
+        for format in [NodeFormat::Full, NodeFormat::Quantized16] {
+            let mut blases = BlasBuffers::new(format).unwrap();
+            let tlas_nodes = quad_and_box_scene(&mut blases, &quad, &unit_box);
+
+            let ray = |origin: [f32; 3], direction: [f32; 3]| Ray {
+                origin: Point3::from(origin),
+                direction: Point3::from(direction),
+                tmin: 0.0,
+                tmax: 1e38,
+                time: 0.0,
+            };
+            let closest = |r: &Ray, cull_mask| {
+                trace_ray_nodes(&tlas_nodes, &blases, r, cull_mask, |_| HitReport::Confirm)
+            };
+
+            let up = ray([0.75, 0.25, -1.0], [0.0, 0.0, 1.0]);
+            match closest(&up, 0xff) {
+                Trace::ClosestHit(hit) => {
+                    assert_eq!(hit.t, 1.0);
+                    assert_eq!((hit.instance_id, hit.instance_custom_index), (0, 10));
+                    assert_eq!((hit.geometry_id, hit.primitive_id), (0, 0));
+                    assert_eq!(&hit.attributes[2..], &[0.0, 0.0, 1.0]);
+                    assert_eq!(hit.hit_kind, HIT_KIND_FRONT_FACING_TRIANGLE);
+                }
+                t => panic!("{:?}", t),
+            }
+            // second triangle of the translated quad, masked out by 0x1
+            let shifted = ray([5.25, 0.75, -1.0], [0.0, 0.0, 1.0]);
+            match closest(&shifted, 0x2) {
+                Trace::ClosestHit(hit) => {
+                    assert_eq!((hit.instance_id, hit.primitive_id), (1, 1));
+                }
+                t => panic!("{:?}", t),
+            }
+            assert_eq!(closest(&shifted, 0x1), Trace::Miss);
+
+            // the box at z = [3, 4] is in front of the quad
+            let down = ray([0.5, 0.5, 10.0], [0.0, 0.0, -1.0]);
+            match closest(&down, 0xff) {
+                Trace::ClosestHit(hit) => {
+                    assert_eq!(hit.t, 6.0);
+                    assert_eq!((hit.instance_id, hit.hit_kind), (2, 0));
+                }
+                t => panic!("{:?}", t),
+            }
+            // ignoring AABB hits reveals the back face of the quad
+            let mut num_any_hits = 0;
+            let trace = trace_ray_nodes(&tlas_nodes, &blases, &down, 0xff, |hit| {
+                num_any_hits += 1;
+                if hit.hit_kind == 0 {
+                    HitReport::Ignore
+                } else {
+                    HitReport::Confirm
+                }
+            });
+            match trace {
+                Trace::ClosestHit(hit) => {
+                    assert_eq!(hit.t, 10.0);
+                    assert_eq!(hit.hit_kind, HIT_KIND_BACK_FACING_TRIANGLE);
+                }
+                t => panic!("{:?}", t),
+            }
+            assert!(num_any_hits >= 2);
+            // any-hit queries stop at the first hit
+            let trace =
+                trace_ray_nodes(&tlas_nodes, &blases, &down, 0xff, |_| HitReport::Terminate);
+            assert!(matches!(trace, Trace::Terminated(_)));
+
+            let past = Ray { tmax: 5.0, ..down };
+            assert_eq!(closest(&past, 0xff), Trace::Miss);
+        }
+    }
+
+    #[test]
+    fn test_trace_ray_instance_flags() {
+        let (quad, unit_box) = (quad_vertices(), unit_box());
+        let mut blases = BlasBuffers::new(NodeFormat::Full).unwrap();
+        let quad_blas = push_blas(&mut blases, geometry(GeometryType::Triangle, &quad), 2);
+        let opaque_box = Primitive {
+            flags: GeometryFlags::OPAQUE,
+            ..geometry(GeometryType::Aabb, &unit_box)
+        };
+        let box_blas = push_blas(&mut blases, opaque_box, 1);
+        let flags = InstanceFlags::TRIANGLE_FRONT_COUNTERCLOCKWISE | InstanceFlags::FORCE_OPAQUE;
+        let instances = vec![
+            instance(0, 0x1, flags, quad_blas, [0.0, 0.0, 0.0]),
+            instance(1, 0x1, InstanceFlags::NONE, box_blas, [0.0, 0.0, 3.0]),
+        ];
+        let aabbs: Vec<AABB> = instances.iter().map(|i| i.aabb).collect();
+        let tlas = Bvh::build(
+            &aabbs,
+            &BuildOptions {
+                max_leaf_size: 1,
+                ..BuildOptions::default()
+            },
+        );
+        let tlas_nodes = serialize_tlas_nodes(&tlas, &instances);
+        let leaf = tlas_instance_leaf_nodes(&tlas, instances.len())[0];
+        assert_eq!(read_tlas_node(&tlas_nodes, leaf).flags, flags);
+
+        // opaque primitives are confirmed without the any-hit shader
+        let down = Ray {
+            origin: Point3::new(0.5, 0.5, 10.0),
+            direction: Point3::new(0.0, 0.0, -1.0),
+            tmin: 0.0,
+            tmax: 1e38,
+            time: 0.0,
+        };
+        let mut num_any_hits = 0;
+        let trace = trace_ray_nodes(&tlas_nodes, &blases, &down, 0xff, |_| {
+            num_any_hits += 1;
+            HitReport::Ignore
+        });
+        match trace {
+            Trace::ClosestHit(hit) => assert_eq!((hit.instance_id, hit.t), (1, 6.0)),
+            t => panic!("{:?}", t),
+        }
+        assert_eq!(num_any_hits, 0);
+
+        // counterclockwise front faces swap the hit kind
+        let up = Ray {
+            origin: Point3::new(0.75, 0.25, -1.0),
+            direction: Point3::new(0.0, 0.0, 1.0),
+            tmax: 2.0,
+            ..down
+        };
+        match trace_ray_nodes(&tlas_nodes, &blases, &up, 0xff, |_| HitReport::Ignore) {
+            Trace::ClosestHit(hit) => {
+                assert_eq!(hit.instance_id, 0);
+                assert_eq!(hit.hit_kind, HIT_KIND_BACK_FACING_TRIANGLE);
+            }
+            t => panic!("{:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_trace_ray_motion() {
+        let quad = quad_vertices();
+        // the quad moves by 2 along x
+        let end_quad = bytes(&[
+            2.0, 0.0, 0.0, 3.0, 0.0, 0.0, 3.0, 1.0, 0.0, //
+            2.0, 0.0, 0.0, 3.0, 1.0, 0.0, 2.0, 1.0, 0.0,
+        ]);
+        let mut blases = BlasBuffers::new(NodeFormat::Full).unwrap();
+        let moving_quad = Primitive {
+            end_vbuf: Some(&end_quad),
+            ..geometry(GeometryType::Triangle, &quad)
+        };
+        let moving_blas = push_blas(&mut blases, moving_quad, 2);
+        let quad_blas = push_blas(&mut blases, geometry(GeometryType::Triangle, &quad), 2);
+        // the static quad at y = 5, moving by 10 along x
+        let mut matrix = tlas_instance(1, 0x2, InstanceFlags::NONE, quad_blas, [0.0, 5.0, 0.0]);
+        matrix.motion_type = InstanceMotionType::Matrix as u32;
+        matrix.motion_keyframes[..12]
+            .copy_from_slice(&[1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 10.0, 5.0, 0.0]);
+        let instances = vec![
+            instance(0, 0x1, InstanceFlags::NONE, moving_blas, [0.0, 0.0, 0.0]),
+            TlasInstanceDescriptor::new(1, &matrix).unwrap(),
+        ];
+        assert_eq!(instances[1].aabb.max.x, 11.0);
+        let aabbs: Vec<AABB> = instances.iter().map(|i| i.aabb).collect();
+        let tlas = Bvh::build(&aabbs, &BuildOptions::default());
+        let tlas_nodes = serialize_tlas_nodes(&tlas, &instances);
+
+        let hit_at = |x: f32, y: f32, time: f32| {
+            let up = Ray {
+                origin: Point3::new(x, y, -1.0),
+                direction: Point3::new(0.0, 0.0, 1.0),
+                tmin: 0.0,
+                tmax: 1e38,
+                time,
+            };
+            match trace_ray_nodes(&tlas_nodes, &blases, &up, 0xff, |_| HitReport::Confirm) {
+                Trace::ClosestHit(hit) => Some(hit.instance_id),
+                _ => None,
+            }
+        };
+        // vertices are interpolated
+        assert_eq!(hit_at(2.5, 0.25, 0.0), None);
+        assert_eq!(hit_at(2.5, 0.25, 1.0), Some(0));
+        assert_eq!(hit_at(1.25, 0.25, 0.5), Some(0));
+        assert_eq!(hit_at(0.25, 0.25, 0.5), None);
+        // and so are transforms
+        assert_eq!(hit_at(5.5, 5.25, 0.0), None);
+        assert_eq!(hit_at(5.5, 5.25, 0.5), Some(1));
+        assert_eq!(hit_at(0.5, 5.25, 0.5), None);
+        // times are clamped to [0, 1]
+        assert_eq!(hit_at(10.5, 5.25, 2.0), Some(1));
+    }
+}
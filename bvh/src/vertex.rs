@@ -0,0 +1,203 @@
+// Vertex position and index decoding for interleaved vertex buffers, see
+// getTriVertPosition and getTriVertIndices in geom.glsl.
+
+use crate::layout::{read_f32, read_u32};
+use bvh::Point3;
+use std::convert::{TryFrom, TryInto};
+
+// GPUVertexFormat of the position attribute, see VertexFormat in
+// wasm_bvh_builder.ts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum VertexFormat {
+    Float32x3 = 0,
+    // z = 0
+    Float32x2 = 1,
+    // w is ignored
+    Float16x4 = 2,
+    Snorm16x4 = 3,
+    Unorm16x4 = 4,
+}
+
+impl TryFrom<i32> for VertexFormat {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(VertexFormat::Float32x3),
+            1 => Ok(VertexFormat::Float32x2),
+            2 => Ok(VertexFormat::Float16x4),
+            3 => Ok(VertexFormat::Snorm16x4),
+            4 => Ok(VertexFormat::Unorm16x4),
+            _ => Err(()),
+        }
+    }
+}
+
+impl VertexFormat {
+    // bytes read from each vertex
+    pub fn byte_size(self) -> usize {
+        match self {
+            VertexFormat::Float32x3 => 12,
+            _ => 8,
+        }
+    }
+
+    pub fn decode_position(self, bytes: &[u8], offset: usize) -> Point3 {
+        let u16_at = |k: usize| {
+            u16::from_le_bytes(
+                bytes[offset + 2 * k..offset + 2 * k + 2]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        match self {
+            VertexFormat::Float32x3 => Point3::new(
+                read_f32(bytes, offset),
+                read_f32(bytes, offset + 4),
+                read_f32(bytes, offset + 8),
+            ),
+            VertexFormat::Float32x2 => {
+                Point3::new(read_f32(bytes, offset), read_f32(bytes, offset + 4), 0.0)
+            }
+            VertexFormat::Float16x4 => Point3::new(
+                f16_to_f32(u16_at(0)),
+                f16_to_f32(u16_at(1)),
+                f16_to_f32(u16_at(2)),
+            ),
+            // same as unpackSnorm2x16
+            VertexFormat::Snorm16x4 => {
+                let snorm = |k: usize| (u16_at(k) as i16 as f32 / 32767.0).max(-1.0);
+                Point3::new(snorm(0), snorm(1), snorm(2))
+            }
+            VertexFormat::Unorm16x4 => {
+                let unorm = |k: usize| u16_at(k) as f32 / 65535.0;
+                Point3::new(unorm(0), unorm(1), unorm(2))
+            }
+        }
+    }
+}
+
+// GPUIndexFormat, see IndexFormat in wasm_bvh_builder.ts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum IndexFormat {
+    Uint32 = 0,
+    Uint16 = 1,
+}
+
+impl TryFrom<i32> for IndexFormat {
+    type Error = ();
+
+    fn try_from(v: i32) -> Result<Self, Self::Error> {
+        match v {
+            0 => Ok(IndexFormat::Uint32),
+            1 => Ok(IndexFormat::Uint16),
+            _ => Err(()),
+        }
+    }
+}
+
+impl IndexFormat {
+    pub fn byte_size(self) -> usize {
+        match self {
+            IndexFormat::Uint32 => 4,
+            IndexFormat::Uint16 => 2,
+        }
+    }
+
+    // `i`th index of a tightly packed index buffer
+    pub fn read_index(self, bytes: &[u8], i: usize) -> usize {
+        match self {
+            IndexFormat::Uint32 => read_u32(bytes, 4 * i) as usize,
+            IndexFormat::Uint16 => {
+                u16::from_le_bytes(bytes[2 * i..2 * i + 2].try_into().unwrap()) as usize
+            }
+        }
+    }
+}
+
+// IEEE 754 binary16 to binary32, same as unpackHalf2x16.
+fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exponent = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    let bits = match exponent {
+        0 if mantissa == 0 => sign,
+        // subnormal, exact in f32
+        0 => {
+            let v = mantissa as f32 * (1.0 / (1u32 << 24) as f32);
+            return if sign != 0 { -v } else { v };
+        }
+        // inf, nan
+        0x1f => sign | 0x7f80_0000 | mantissa << 13,
+        _ => sign | (exponent + 127 - 15) << 23 | mantissa << 13,
+    };
+    f32::from_bits(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{f16_to_f32, IndexFormat, VertexFormat};
+    use bvh::Point3;
+
+    #[test]
+    fn test_decode_interleaved_positions() {
+        // two vertices of [position, 4 bytes of padding], stride 16
+        let stride = 16;
+        let mut f32x3 = vec![0u8; 2 * stride];
+        for (k, v) in [1.5f32, -2.0, 3.25].iter().enumerate() {
+            f32x3[stride + 4 * k..stride + 4 * k + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        assert_eq!(
+            VertexFormat::Float32x3.decode_position(&f32x3, stride),
+            Point3::new(1.5, -2.0, 3.25)
+        );
+        assert_eq!(
+            VertexFormat::Float32x2.decode_position(&f32x3, stride),
+            Point3::new(1.5, -2.0, 0.0)
+        );
+
+        let pack = |words: [u16; 4]| -> Vec<u8> {
+            let mut bytes = vec![0u8; stride];
+            for (k, w) in words.iter().enumerate() {
+                bytes[2 * k..2 * k + 2].copy_from_slice(&w.to_le_bytes());
+            }
+            bytes
+        };
+        // 1.0, -2.5, 0.0, w
+        let half = pack([0x3c00, 0xc100, 0x0000, 0x3c00]);
+        assert_eq!(
+            VertexFormat::Float16x4.decode_position(&half, 0),
+            Point3::new(1.0, -2.5, 0.0)
+        );
+        let snorm = pack([0x7fff, 0x8000, 0x8001, 0]);
+        assert_eq!(
+            VertexFormat::Snorm16x4.decode_position(&snorm, 0),
+            Point3::new(1.0, -1.0, -1.0)
+        );
+        let unorm = pack([0xffff, 0, 0x8000, 0]);
+        let p = VertexFormat::Unorm16x4.decode_position(&unorm, 0);
+        assert_eq!((p.x, p.y), (1.0, 0.0));
+        assert!((p.z - 0.5).abs() < 1e-4);
+
+        assert_eq!(f16_to_f32(0x0001), 2f32.powi(-24));
+        assert_eq!(f16_to_f32(0x7bff), 65504.0);
+        assert_eq!(f16_to_f32(0xfc00), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_read_indices() {
+        let u16s: Vec<u8> = [7u16, 0xffff, 3]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let u32s: Vec<u8> = [7u32, 0x10000, 3]
+            .iter()
+            .flat_map(|i| i.to_le_bytes())
+            .collect();
+        let read = |format: IndexFormat, bytes: &[u8]| -> Vec<usize> {
+            (0..3).map(|i| format.read_index(bytes, i)).collect()
+        };
+        assert_eq!(read(IndexFormat::Uint16, &u16s), vec![7, 0xffff, 3]);
+        assert_eq!(read(IndexFormat::Uint32, &u32s), vec![7, 0x10000, 3]);
+    }
+}
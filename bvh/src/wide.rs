@@ -0,0 +1,165 @@
+// Collapses a binary Bvh into a 4- or 8-wide BVH, see GPUBlasBvhNodeWide4/8
+// and common.glsl.
+
+use crate::builder::{half_area, Bvh};
+use bvh::aabb::AABB;
+
+#[derive(Debug, Clone, Copy)]
+pub struct WideChild {
+    pub aabb: AABB,
+    // interior: index of the child WideNode
+    // leaf: offset of the first primitive in Bvh::prim_indices
+    pub index_or_first_prim: u32,
+    // 0 for interior children
+    pub prim_count: u32,
+}
+
+#[derive(Debug)]
+pub struct WideNode {
+    // at most `width` children
+    pub children: Vec<WideChild>,
+}
+
+// Nodes are in depth-first pre-order, the root is at 0.
+pub fn collapse(bvh: &Bvh, width: usize) -> Vec<WideNode> {
+    assert!(width >= 2);
+    let mut nodes = Vec::new();
+    if bvh.nodes.is_empty() {
+        return nodes;
+    }
+    if bvh.nodes[0].is_leaf() {
+        let root = &bvh.nodes[0];
+        nodes.push(WideNode {
+            children: vec![WideChild {
+                aabb: root.aabb,
+                index_or_first_prim: root.first_prim,
+                prim_count: root.prim_count,
+            }],
+        });
+        return nodes;
+    }
+    collapse_recursive(bvh, 0, width, &mut nodes);
+    nodes
+}
+
+// Binary children of the interior node at `i`.
+fn binary_children(bvh: &Bvh, i: usize) -> [usize; 2] {
+    let left = i + 1;
+    [left, bvh.nodes[left].exit_index as usize]
+}
+
+fn collapse_recursive(
+    bvh: &Bvh,
+    binary_index: usize,
+    width: usize,
+    nodes: &mut Vec<WideNode>,
+) -> u32 {
+    // greedily open the largest interior child until the node is full
+    let mut open: Vec<usize> = binary_children(bvh, binary_index).to_vec();
+    while open.len() < width {
+        let largest = open
+            .iter()
+            .enumerate()
+            .filter(|(_, &c)| !bvh.nodes[c].is_leaf())
+            .max_by(|(_, &a), (_, &b)| {
+                half_area(&bvh.nodes[a].aabb).total_cmp(&half_area(&bvh.nodes[b].aabb))
+            })
+            .map(|(k, _)| k);
+        match largest {
+            Some(k) => {
+                let c = open.swap_remove(k);
+                open.extend_from_slice(&binary_children(bvh, c));
+            }
+            None => break,
+        }
+    }
+    // keep the pre-order of the binary tree among siblings
+    open.sort_unstable();
+
+    let wide_index = nodes.len();
+    nodes.push(WideNode {
+        children: Vec::with_capacity(open.len()),
+    });
+    for c in open {
+        let n = &bvh.nodes[c];
+        let child = if n.is_leaf() {
+            WideChild {
+                aabb: n.aabb,
+                index_or_first_prim: n.first_prim,
+                prim_count: n.prim_count,
+            }
+        } else {
+            WideChild {
+                aabb: n.aabb,
+                index_or_first_prim: collapse_recursive(bvh, c, width, nodes),
+                prim_count: 0,
+            }
+        };
+        nodes[wide_index].children.push(child);
+    }
+    wide_index as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collapse, WideNode};
+    use crate::builder::{BuildOptions, Bvh};
+    use bvh::aabb::AABB;
+    use bvh::Point3;
+
+    fn contains(outer: &AABB, inner: &AABB) -> bool {
+        (0..3).all(|a| outer.min[a] <= inner.min[a] && outer.max[a] >= inner.max[a])
+    }
+
+    fn check_subtree(
+        nodes: &[WideNode],
+        index: u32,
+        width: usize,
+        seen: &mut Vec<bool>,
+        bvh: &Bvh,
+    ) {
+        let node = &nodes[index as usize];
+        assert!(!node.children.is_empty() && node.children.len() <= width);
+        for child in &node.children {
+            if child.prim_count > 0 {
+                let first = child.index_or_first_prim as usize;
+                for &pi in &bvh.prim_indices[first..first + child.prim_count as usize] {
+                    assert!(!seen[pi as usize]);
+                    seen[pi as usize] = true;
+                }
+            } else {
+                assert!(child.index_or_first_prim > index);
+                for grandchild in &nodes[child.index_or_first_prim as usize].children {
+                    assert!(contains(&child.aabb, &grandchild.aabb));
+                }
+                check_subtree(nodes, child.index_or_first_prim, width, seen, bvh);
+            }
+        }
+    }
+
+    #[test]
+    fn test_collapse_covers_all_primitives() {
+        let boxes: Vec<AABB> = (0..300)
+            .map(|i| {
+                let x = (i * 37 % 300) as f32;
+                let y = (i % 7) as f32;
+                AABB::with_bounds(Point3::new(x, y, 0.0), Point3::new(x + 1.5, y + 1.0, 1.0))
+            })
+            .collect();
+        let bvh = Bvh::build(&boxes, &BuildOptions::default());
+        for width in [4, 8] {
+            let nodes = collapse(&bvh, width);
+            let mut seen = vec![false; boxes.len()];
+            check_subtree(&nodes, 0, width, &mut seen, &bvh);
+            assert!(seen.iter().all(|&s| s));
+            // every wide node replaces at least one binary interior node
+            let num_interior = bvh.nodes.iter().filter(|n| !n.is_leaf()).count();
+            assert!(nodes.len() < num_interior);
+        }
+
+        let single = Bvh::build(&boxes[..1], &BuildOptions::default());
+        let nodes = collapse(&single, 4);
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].children[0].prim_count, 1);
+    }
+}
@@ -60,6 +60,7 @@ pub enum GlGlobalVarirableAsParam {
     gl_WorldToObjectEXT,       //mat4x3
     gl_WorldToObject3x4EXT,    //mat3x4
     gl_ObjectToWorld3x4EXT,    //mat3x4
+    gl_CurrentRayTimeNV,       //float
 }
 
 fn gl_global_variable_type(v: &GlGlobalVarirableAsParam) -> ast::TypeSpecifierNonArrayData {
@@ -76,7 +77,8 @@ fn gl_global_variable_type(v: &GlGlobalVarirableAsParam) -> ast::TypeSpecifierNo
         }
         GlGlobalVarirableAsParam::gl_RayTminEXT
         | GlGlobalVarirableAsParam::gl_RayTmaxEXT
-        | GlGlobalVarirableAsParam::gl_HitTEXT => ast::TypeSpecifierNonArrayData::Float,
+        | GlGlobalVarirableAsParam::gl_HitTEXT
+        | GlGlobalVarirableAsParam::gl_CurrentRayTimeNV => ast::TypeSpecifierNonArrayData::Float,
         GlGlobalVarirableAsParam::gl_IncomingRayFlagsEXT
         | GlGlobalVarirableAsParam::gl_HitKindEXT => ast::TypeSpecifierNonArrayData::UInt,
         GlGlobalVarirableAsParam::gl_ObjectToWorldEXT
@@ -10,7 +10,8 @@ const BP_RT_UNIFORM_PARAMS = 0;
 const BP_SBT = BP_RT_UNIFORM_PARAMS + RT_UNIFORM_PARAMS_NUM_BIND_LOCATIONS;
 const BP_TLAS_BVH_TREE_NODES = BP_SBT + 1;
 const BP_BLASES_BVH_TREE_NODES = BP_TLAS_BVH_TREE_NODES + 1;
-const BP_GEOM_BUFFERS_START = BP_BLASES_BVH_TREE_NODES + 1;
+const BP_BLASES_PRIMITIVE_REFS = BP_BLASES_BVH_TREE_NODES + 1;
+const BP_GEOM_BUFFERS_START = BP_BLASES_PRIMITIVE_REFS + 1;
 
 export class GPURayTracingAccelerationContainer_top_Impl implements GPURayTracingAccelerationContainer_top {
   private _tlas: Tlas;
@@ -25,6 +26,14 @@ export class GPURayTracingAccelerationContainer_top_Impl implements GPURayTracin
     this._tlas.build(device);
   }
 
+  hostUpdate(device: GPUDevice, instanceIndices: number[]) {
+    this._tlas.update(device, instanceIndices);
+  }
+
+  getBlasNodeFormat() {
+    return this._tlas.blasNodeFormat();
+  }
+
   getBvhGeometryBuffersAndDescriptors() {
     return this._tlas.allUniqueGeomBuffer();
   }
@@ -64,7 +73,7 @@ export class GPURayTracingAccelerationContainer_top_Impl implements GPURayTracin
       this._rtUniformParams.unmap();
     }
 
-    const [tlasBuffer, blasesBuffer] = this._tlas.getBvhTreeNodesBuffers();
+    const [tlasBuffer, blasesBuffer, blasesPrimitiveRefsBuffer] = this._tlas.getBvhTreeNodesBuffers();
 
     const entries: GPUBindGroupEntry[] = [{
       binding: BP_RT_UNIFORM_PARAMS,
@@ -86,6 +95,11 @@ export class GPURayTracingAccelerationContainer_top_Impl implements GPURayTracin
       resource: {
         buffer: blasesBuffer,
       },
+    }, {
+      binding: BP_BLASES_PRIMITIVE_REFS,
+      resource: {
+        buffer: blasesPrimitiveRefsBuffer,
+      },
     },];
 
     entries.push(...this._tlas.allGeomBuffersInOrder().map((b, i) => ({
@@ -95,10 +95,16 @@ export async function aggregateAndCompileShaders(device: GPUDevice, descriptor:
       d.iBufferIndex,
       d.vboOffset,
       d.vboStride,
+      d.vboFormat,
       (d.vioOffset || 0),
       (d.vioStride || 0),
+      d.vioFormat,
       d.owningGeometryType_todo_deprecate,
       d.owningGeometryFlags,
+      // mat4x3, one vec3 per column
+      `{${[0, 1, 2, 3].map(c => `{${Array.from(d.vboTransform.subarray(c * 3, c * 3 + 3)).join(',')}}`).join(',')}}`,
+      d.vBufferIndexEnd,
+      d.vboOffsetEnd,
     ].join(',')}}`).join(',');
   const userPrelude = `
 const uint ${GLOBAL_NAME__USER_NEXT_UNUSED_BIND_SET} = ${maxUsedBindSet + 1};
@@ -106,6 +112,7 @@ const uint ${GLOBAL_NAME__HIT_ATTRIBUTES_MAX_WORDS} = ${maxHitAttributesNumWords
 #define _CRT_USER_BVH_GEOM_BUFFERS_INITIALIZER_LIST {${bvhGeometriesDescArray}}
 #define _CRT_USER_DEFINE_GEO_BUFFERS DEFINE_GEO_BUFFER_x${numGeomBuffers}
 #define _CRT_USER_GEO_BUFFERS_ACCESSOR_CASES(wordIndex) _GET_FROM_BUFFER_CASE_x${numGeomBuffers}(wordIndex)
+#define _CRT_USER_BLAS_NODE_FORMAT ${(tlas as GPURayTracingAccelerationContainer_top_Impl).getBlasNodeFormat()}
   `;
 
   const userFunctionsTable = Object.values(GPUShaderStageRTX).map(stg => {
@@ -38,7 +38,7 @@ struct TlasBvhNode {
 
   // leaf data
   uint mask;
-  uint flags;              // TODO: INSTANCE_FORCE_OPAQUE_BIT_KHR
+  uint flags;              // InstanceFlags, see flags.rs
   uint instanceId;         // used for gl_InstanceId
   uint sbtInstanceOffset;  // The start hitGroupId for all
                            // geoms within this instance
@@ -51,18 +51,78 @@ struct TlasBvhNode {
 
   // For traversal
   uint blas_geometry_id_offset;
+  uint blas_primitive_ref_offset;
+  // BLAS root bounds, the quantization frame of compacted BLASes
+  AABB blas_aabb;
+  // Motion blur, see motion.rs. transformToWorld and transformToObject are
+  // at time 0. Matrix motion: column major 4x3 transforms in the first 12
+  // words. SRT motion: VkSRTDataNV.
+  uint motionType;
+  float motionStart[16];
+  float motionEnd[16];
+  // TransformClass in transform.rs, over time with motion
+  uint transformClass;
 };
 
 struct BlasBvhNode {
   AABB aabb;  // 2*3*4
-  uint entry_index_or_primitive_id;
+  // for interior node, this is the offset of the first child
+  // for leaf node, this is the offset of the first BlasPrimitiveRef
+  uint entry_index_or_first_primitive;
   uint exit_index;
   // uint axis;
 
-  // geometryId >= 0: BLAS leaf
+  // primitiveCount > 0: BLAS leaf
   // else: interior
+  uint primitiveCount;
+};
+
+// Compacted BLAS nodes, see NodeFormat in lib.rs. Bounds are quantized within
+// the BLAS root box (TlasBvhNode.blas_aabb).
+struct BlasBvhNodeQuantized16 {
+  uint bounds0;  // min.x | min.y << 16
+  uint bounds1;  // min.z | max.x << 16
+  uint bounds2;  // max.y | max.z << 16
+  uint entry_index_or_first_primitive;
+  uint exit_index;
+  uint primitiveCount;
+};
+
+// Wide BLAS nodes, see NodeFormat in lib.rs. Child boxes are stored side by
+// side, child c is in lane (c & 3) of element (c >> 2). Unused lanes have
+// WIDE_NODE_EMPTY_CHILD as index.
+#define WIDE_NODE_EMPTY_CHILD 0xffffffffU
+
+struct BlasBvhNodeWide4 {
+  vec4 minX[1];
+  vec4 minY[1];
+  vec4 minZ[1];
+  vec4 maxX[1];
+  vec4 maxY[1];
+  vec4 maxZ[1];
+  // interior child: node index, leaf child: offset of the first
+  // BlasPrimitiveRef
+  uvec4 childIndexOrFirstPrimitive[1];
+  // 0 for interior children
+  uvec4 childPrimitiveCount[1];
+};
+
+struct BlasBvhNodeWide8 {
+  vec4 minX[2];
+  vec4 minY[2];
+  vec4 minZ[2];
+  vec4 maxX[2];
+  vec4 maxY[2];
+  vec4 maxZ[2];
+  uvec4 childIndexOrFirstPrimitive[2];
+  uvec4 childPrimitiveCount[2];
+};
+
+// leaves reference contiguous ranges of the reordered BLAS primitives
+struct BlasPrimitiveRef {
   int geometryId;
-  // TODO: geometry type? flags
+  uint primitiveId;
+  uint flags;  // GeometryFlags of the geometry, see flags.rs
 };
 
 #endif  // _WEBRTX_COMMON_
\ No newline at end of file
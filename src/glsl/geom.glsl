@@ -10,6 +10,17 @@
 const uint GEOM_TYPE_TRIANGLE = 0u;
 const uint GEOM_TYPE_AABB = 1u;
 
+// see vertex.rs::VertexFormat
+const uint VERTEX_FORMAT_FLOAT32X3 = 0u;
+const uint VERTEX_FORMAT_FLOAT32X2 = 1u;
+const uint VERTEX_FORMAT_FLOAT16X4 = 2u;
+const uint VERTEX_FORMAT_SNORM16X4 = 3u;
+const uint VERTEX_FORMAT_UNORM16X4 = 4u;
+
+// see vertex.rs::IndexFormat
+const uint INDEX_FORMAT_UINT32 = 0u;
+const uint INDEX_FORMAT_UINT16 = 1u;
+
 // Note: this is included so that user_prelude knows the type
 // one for each geometry
 struct BvhGeometryDescriptor {
@@ -20,11 +31,20 @@ struct BvhGeometryDescriptor {
   int iBufferIndex;
   uint vboOffset;
   uint vboStride;
+  // position format, AABBs are always float32x3
+  uint vboFormat;
   uint vioOffset;
   uint vioStride;
+  uint vioFormat;
   // note that geometry info are duplicated for vbo and vio buffer
   uint owningGeometryType_todo_deprecate;
   uint owningGeometryFlags;  // Geometry.OPAQUE etc
+  // triangles only, applied to the vertex positions, identity if absent
+  mat4x3 vboTransform;
+  // triangles only, positions at time 1 in the format and stride of
+  // vBufferIndex, -1 if the geometry does not move
+  int vBufferIndexEnd;
+  uint vboOffsetEnd;
 };
 
 // this can be a uniform?
@@ -117,42 +137,78 @@ vec3 GET_VEC3_FROM_BUFFER(uint geoBufferIndex, uint wordIndex) {
 }
 
 uvec3 getTriVertIndices(uint iBufferIndex, uint offset, uint stride,
-                        uint primitiveId) {
-  uint vioWordOffset =
-      (offset + primitiveId * stride) / 4;  // byte offset => word offset
+                        uint format, uint primitiveId) {
+  uint vioByteOffset = offset + primitiveId * stride;
+  uint vioWordOffset = vioByteOffset / 4;  // byte offset => word offset
   vec3 f3 = GET_VEC3_FROM_BUFFER(iBufferIndex, vioWordOffset);
   // return floatBitsToUint(f3);
-  return uvec3(floatBitsToUint(f3.x), floatBitsToUint(f3.y),
-               floatBitsToUint(f3.z));
+  uvec3 u3 = uvec3(floatBitsToUint(f3.x), floatBitsToUint(f3.y),
+                   floatBitsToUint(f3.z));
+  if (format == INDEX_FORMAT_UINT16) {
+    // 3 indices in the first 2 words, starting at either half of the first
+    if ((vioByteOffset & 2u) == 0u) {
+      return uvec3(u3.x & 0xffffu, u3.x >> 16, u3.y & 0xffffu);
+    }
+    return uvec3(u3.x >> 16, u3.y & 0xffffu, u3.y >> 16);
+  }
+  return u3;
 }
 
 vec3 getTriVertPosition(uint vBufferIndex, uint offset, uint stride,
-                        uint vindex) {
+                        uint format, uint vindex) {
   uint vboWordOffset =
       (offset + vindex * stride) / 4;  // byte offset => word offset
-  return GET_VEC3_FROM_BUFFER(vBufferIndex, vboWordOffset);
+  vec3 f3 = GET_VEC3_FROM_BUFFER(vBufferIndex, vboWordOffset);
+  // 16 bit formats: first two words hold xy, zw
+  uvec2 u2 = uvec2(floatBitsToUint(f3.x), floatBitsToUint(f3.y));
+  switch (format) {
+    case VERTEX_FORMAT_FLOAT32X2:
+      return vec3(f3.xy, 0);
+    case VERTEX_FORMAT_FLOAT16X4:
+      return vec3(unpackHalf2x16(u2.x), unpackHalf2x16(u2.y).x);
+    case VERTEX_FORMAT_SNORM16X4:
+      return vec3(unpackSnorm2x16(u2.x), unpackSnorm2x16(u2.y).x);
+    case VERTEX_FORMAT_UNORM16X4:
+      return vec3(unpackUnorm2x16(u2.x), unpackUnorm2x16(u2.y).x);
+  }
+  return f3;
+}
+
+// Positions of moving triangles are interpolated before they are transformed.
+vec3 getTriVertPositionAt(BvhGeometryDescriptor g, uint vindex, float time) {
+  vec3 p = getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride,
+                              g.vboFormat, vindex);
+  if (g.vBufferIndexEnd >= 0) {
+    p = mix(p,
+            getTriVertPosition(uint(g.vBufferIndexEnd), g.vboOffsetEnd,
+                               g.vboStride, g.vboFormat, vindex),
+            time);
+  }
+  return g.vboTransform * vec4(p, 1);
 }
 
-vec3[3] getTriangleVertexPositions(BvhGeometryDescriptor g, uint primitiveId) {
+vec3[3] getTriangleVertexPositions(BvhGeometryDescriptor g, uint primitiveId,
+                                   float time) {
   uvec3 indices;
   if (g.iBufferIndex >= 0) {
     indices = getTriVertIndices(g.iBufferIndex, g.vioOffset, g.vioStride,
-                                primitiveId);
+                                g.vioFormat, primitiveId);
   } else {
     indices = uvec3(primitiveId * 3, primitiveId * 3 + 1, primitiveId * 3 + 2);
   }
-  return vec3[](
-      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride, indices[0]),
-      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride, indices[1]),
-      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride, indices[2]));
+  return vec3[](getTriVertPositionAt(g, indices[0], time),
+                getTriVertPositionAt(g, indices[1], time),
+                getTriVertPositionAt(g, indices[2], time));
 }
 
 AABB getGeometryAabb(BvhGeometryDescriptor g) {
   // aabb geometry only contains single primitive, primitiveId = 0
   const vec3 min =
-      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride, 0);
+      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride,
+                         VERTEX_FORMAT_FLOAT32X3, 0);
   const vec3 max =
-      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride, 1);
+      getTriVertPosition(g.vBufferIndex, g.vboOffset, g.vboStride,
+                         VERTEX_FORMAT_FLOAT32X3, 1);
   AABB aabb = {min, max};
   return aabb;
 }
@@ -8,6 +8,10 @@
 bool intersect_aabb(const vec3 rayOrigin, const vec3 invRayDir,
                     const float ray_tmin, const float ray_tmax,
                     const AABB aabb) {
+  // empty roots of empty BLASes and TLASes are inverted, min > max
+  if (aabb.min.x > aabb.max.x) {
+    return false;
+  }
   vec3 t0 = (aabb.min - rayOrigin) * invRayDir;
   vec3 t1 = (aabb.max - rayOrigin) * invRayDir;
   vec3 tmin = min(t0, t1);
@@ -10,9 +10,10 @@ const uint BP_RT_UNIFORM_PARAMS = 0;
 const uint BP_SBT = BP_RT_UNIFORM_PARAMS + RT_UNIFORM_PARAMS_NUM_BIND_LOCATIONS;
 const uint BP_TLAS_BVH_TREE_NODES = BP_SBT + 1;
 const uint BP_BLASES_BVH_TREE_NODES = BP_TLAS_BVH_TREE_NODES + 1;
+const uint BP_BLASES_PRIMITIVE_REFS = BP_BLASES_BVH_TREE_NODES + 1;
 
 // user defined offsets
-const uint BP_GEOM_BUFFERS_START = BP_BLASES_BVH_TREE_NODES + 1;
+const uint BP_GEOM_BUFFERS_START = BP_BLASES_PRIMITIVE_REFS + 1;
 
 layout(std140, set = RT_RESOURCES_BIND_SET,
        binding = BP_RT_UNIFORM_PARAMS) uniform RtUniforms {
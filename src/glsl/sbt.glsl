@@ -42,7 +42,8 @@ void invokeShaderIndirect_rayGen(uint sbtByteIndex) {
 }
 
 void invokeShaderIndirect_rayMiss(uint sbtByteIndex,
-                                  const vec3 _crt_WorldRayDirectionEXT) {
+                                  const vec3 _crt_WorldRayDirectionEXT,
+                                  const float _crt_CurrentRayTimeNV) {
   uint _CRT_PARAM_SHADER_RECORD_WORD_OFFSET = sbtByteIndex / 4;
   uint rmiss = _CRT_SBT_BUFFER_NAME[_CRT_PARAM_SHADER_RECORD_WORD_OFFSET];
   if (rmiss == WEBRTX_SHADER_UNUSED) {
@@ -58,7 +59,8 @@ void invokeShaderIndirect_closestHit(
     const float _crt_RayTminEXT, const vec3 _crt_WorldRayDirectionEXT,
     const float _crt_RayTmaxEXT,  // float _gl_RayTmaxEXT, uint _gl_HitKindEXT,
     const int _crt_GeometryIndexEXT, const uint _crt_PrimitiveID,
-    const float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS]
+    const float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS],
+    const float _crt_CurrentRayTimeNV
     // TODO: make these global for non-recursive ray tracing?
     // ,int gl_PrimitiveID, int gl_InstanceID, int gl_InstanceCustomIndexEXT,
     // int gl_GeometryIndexEXT
@@ -81,7 +83,8 @@ uint invokeShaderIndirect_anyHit(
     const float _crt_RayTminEXT, const vec3 _crt_WorldRayDirectionEXT,
     const float _crt_RayTmaxEXT,  //  uint _gl_HitKindEXT,
     const int _crt_GeometryIndexEXT, const uint _crt_PrimitiveID,
-    const float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS]) {
+    const float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS],
+    const float _crt_CurrentRayTimeNV) {
   uint _CRT_PARAM_SHADER_RECORD_WORD_OFFSET = sbtByteIndex / 4;
   uint hitShaderGroupIdentifier =
       _CRT_SBT_BUFFER_NAME[_CRT_PARAM_SHADER_RECORD_WORD_OFFSET];
@@ -114,7 +117,8 @@ bool invokeShaderIndirect_intersect(
     const vec3 _crt_ObjectRayDirectionEXT, const mat4x3 _crt_WorldToObjectEXT,
     const mat4x3 _crt_ObjectToWorldEXT, const int _crt_GeometryIndexEXT,
     const uint _crt_PrimitiveID,
-    out float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS]) {
+    out float _CRT_PARAM_HIT_ATTRIBUTES[_CRT_HIT_ATTRIBUTES_MAX_WORDS],
+    const float _crt_CurrentRayTimeNV) {
   uint _CRT_PARAM_SHADER_RECORD_WORD_OFFSET = sbtByteIndex / 4;
   uint hitShaderGroupIdentifier =
       _CRT_SBT_BUFFER_NAME[_CRT_PARAM_SHADER_RECORD_WORD_OFFSET];
@@ -14,9 +14,84 @@ layout(std430, set = RT_RESOURCES_BIND_SET,
   TlasBvhNode tlasBvhTreeNodes[];
 };
 
+// NodeFormat of all BLASes, see lib.rs
+#define BLAS_NODE_FORMAT_FULL 0
+#define BLAS_NODE_FORMAT_QUANTIZED16 2
+#define BLAS_NODE_FORMAT_WIDE4 3
+#define BLAS_NODE_FORMAT_WIDE8 4
+#ifndef _CRT_USER_BLAS_NODE_FORMAT
+#define _CRT_USER_BLAS_NODE_FORMAT BLAS_NODE_FORMAT_FULL
+#endif
+
+#if _CRT_USER_BLAS_NODE_FORMAT == BLAS_NODE_FORMAT_WIDE4
+#define BLAS_NODE_IS_WIDE 1
+#define BLAS_NODE_WIDTH 4
+#define BlasBvhNodeWide BlasBvhNodeWide4
+#elif _CRT_USER_BLAS_NODE_FORMAT == BLAS_NODE_FORMAT_WIDE8
+#define BLAS_NODE_IS_WIDE 1
+#define BLAS_NODE_WIDTH 8
+#define BlasBvhNodeWide BlasBvhNodeWide8
+#else
+#define BLAS_NODE_IS_WIDE 0
+#endif
+
+// Wide BLASes are traversed with a stack, entries are node indices or, with
+// BLAS_STACK_LEAF_BIT set, (node index << 3 | child) of a leaf child.
+// Children that do not fit are skipped.
+#define BLAS_TRAVERSAL_STACK_SIZE 64
+#define BLAS_STACK_LEAF_BIT 0x80000000U
+
 layout(std430, set = RT_RESOURCES_BIND_SET,
        binding = BP_BLASES_BVH_TREE_NODES) readonly buffer BlasesBvhTreeNodes {
+#if _CRT_USER_BLAS_NODE_FORMAT == BLAS_NODE_FORMAT_QUANTIZED16
+  BlasBvhNodeQuantized16 blasesBvhTreeNodes[];
+#elif BLAS_NODE_IS_WIDE
+  BlasBvhNodeWide blasesBvhTreeNodes[];
+#else
   BlasBvhNode blasesBvhTreeNodes[];
+#endif
+};
+
+// Same arithmetic as compact.rs so that decoded bounds stay conservative.
+AABB decodeBlasNodeAabb(uvec3 qmin, uvec3 qmax, float maxCode, AABB frame) {
+  vec3 scale = (frame.max - frame.min) / maxCode;
+  AABB aabb;
+  aabb.min = frame.min + vec3(qmin) * scale;
+  aabb.max = frame.min + vec3(qmax) * scale;
+  return aabb;
+}
+
+#if BLAS_NODE_IS_WIDE
+AABB wideNodeChildAabb(BlasBvhNodeWide node, uint c) {
+  uint i = c >> 2;
+  uint lane = c & 3U;
+  AABB aabb;
+  aabb.min = vec3(node.minX[i][lane], node.minY[i][lane], node.minZ[i][lane]);
+  aabb.max = vec3(node.maxX[i][lane], node.maxY[i][lane], node.maxZ[i][lane]);
+  return aabb;
+}
+#else
+BlasBvhNode loadBlasNode(uint index, AABB frame) {
+#if _CRT_USER_BLAS_NODE_FORMAT == BLAS_NODE_FORMAT_QUANTIZED16
+  BlasBvhNodeQuantized16 q = blasesBvhTreeNodes[index];
+  BlasBvhNode node;
+  node.aabb = decodeBlasNodeAabb(
+      uvec3(q.bounds0, q.bounds0 >> 16, q.bounds1) & 0xffffU,
+      uvec3(q.bounds1 >> 16, q.bounds2, q.bounds2 >> 16) & 0xffffU, 65535.0,
+      frame);
+  node.entry_index_or_first_primitive = q.entry_index_or_first_primitive;
+  node.exit_index = q.exit_index;
+  node.primitiveCount = q.primitiveCount;
+  return node;
+#else
+  return blasesBvhTreeNodes[index];
+#endif
+}
+#endif  // BLAS_NODE_IS_WIDE
+
+layout(std430, set = RT_RESOURCES_BIND_SET,
+       binding = BP_BLASES_PRIMITIVE_REFS) readonly buffer BlasesPrimitiveRefs {
+  BlasPrimitiveRef blasesPrimitiveRefs[];
 };
 
 #define terminateRayEXT                                    \
@@ -35,21 +110,111 @@ layout(std430, set = RT_RESOURCES_BIND_SET,
 #define FLOAT_EQUAL(v, expect) (abs((v) - (expect)) < 0.001)
 
 const float RAY_TMAX = 1e38f;
+// GeometryFlags and InstanceFlags in flags.rs
 const uint GEOMETRY_OPAQUE_BIT = 1;
+const uint INSTANCE_TRIANGLE_FACING_CULL_DISABLE_BIT = 1;
+const uint INSTANCE_TRIANGLE_FRONT_COUNTERCLOCKWISE_BIT = 2;
+const uint INSTANCE_FORCE_OPAQUE_BIT = 4;
+const uint INSTANCE_FORCE_NO_OPAQUE_BIT = 8;
 
 const uint TRAVERSE_MAX_INT = 0xffffffffu;
 
-void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
-                 uint cullMask,
-                 uint rayType,      // a.k.a sbtRecordOffset,
-                 uint numRayTypes,  // a.k.a sbtRecordStride,
-                 uint missIndex, const vec3 _crt_WorldRayOriginEXT,
-                 const float _crt_RayTminEXT,
-                 const vec3 _crt_WorldRayDirectionEXT, float _crt_RayTmaxEXT,
-                 int payload) {
+// InstanceMotionType in motion.rs, the arithmetic below mirrors motion.rs
+const uint INSTANCE_MOTION_STATIC = 0;
+const uint INSTANCE_MOTION_MATRIX = 1;
+const uint INSTANCE_MOTION_SRT = 2;
+
+// TransformClass in transform.rs, instances with singular transforms are not
+// in the tree
+const uint TRANSFORM_CLASS_IDENTITY = 0;
+
+vec4 slerp(vec4 q0, vec4 q1, float t) {
+  float d = dot(q0, q1);
+  if (d > 0.9995) {
+    // nearly parallel, sin(theta) would lose all precision
+    return normalize(mix(q0, q1, t));
+  }
+  float theta = acos(d);
+  return (q0 * sin((1.0 - t) * theta) + q1 * sin(t * theta)) *
+         (1.0 / sin(theta));
+}
+
+// The transform of a VkSRTDataNV
+mat4x3 srtTo4x3(float srt[16]) {
+  vec4 q = normalize(vec4(srt[9], srt[10], srt[11], srt[12]));
+  float x = q.x, y = q.y, z = q.z, w = q.w;
+  mat3 rotation = mat3(
+      1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w), 2.0 * (x * z - y * w),
+      2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + x * w),
+      2.0 * (x * z + y * w), 2.0 * (y * z - x * w), 1.0 - 2.0 * (x * x + y * y));
+  mat4x3 scale = mat4x3(vec3(srt[0], 0, 0), vec3(srt[1], srt[4], 0),
+                        vec3(srt[2], srt[5], srt[7]),
+                        vec3(srt[3], srt[6], srt[8]));
+  mat4x3 m = rotation * scale;
+  m[3] += vec3(srt[13], srt[14], srt[15]);
+  return m;
+}
+
+// Inverse of an affine transform from the cross products of its columns,
+// inverse() is not available for mat4x3 and not supported by naga.
+mat4x3 affineInverse(mat4x3 m) {
+  vec3 r0 = cross(m[1], m[2]);
+  float invDet = 1.0 / dot(m[0], r0);
+  r0 = r0 * invDet;
+  vec3 r1 = cross(m[2], m[0]) * invDet;
+  vec3 r2 = cross(m[0], m[1]) * invDet;
+  mat3 inv = transpose(mat3(r0, r1, r2));
+  return mat4x3(inv[0], inv[1], inv[2],
+                -vec3(dot(r0, m[3]), dot(r1, m[3]), dot(r2, m[3])));
+}
+
+// The object to world transform of a moving TLAS leaf at `time`, `start` is
+// node.transformToWorld.
+mat4x3 instanceTransformAt(TlasBvhNode node, mat4x3 start, float time) {
+  if (node.motionType == INSTANCE_MOTION_MATRIX) {
+    mat4x3 end =
+        mat4x3(vec3(node.motionEnd[0], node.motionEnd[1], node.motionEnd[2]),
+               vec3(node.motionEnd[3], node.motionEnd[4], node.motionEnd[5]),
+               vec3(node.motionEnd[6], node.motionEnd[7], node.motionEnd[8]),
+               vec3(node.motionEnd[9], node.motionEnd[10], node.motionEnd[11]));
+    return mat4x3(mix(start[0], end[0], time), mix(start[1], end[1], time),
+                  mix(start[2], end[2], time), mix(start[3], end[3], time));
+  }
+  // INSTANCE_MOTION_SRT, the quaternion is interpolated along the shorter arc
+  float srt[16];
+  for (uint k = 0; k < 16; k++) {
+    srt[k] = mix(node.motionStart[k], node.motionEnd[k], time);
+  }
+  vec4 q0 = normalize(vec4(node.motionStart[9], node.motionStart[10],
+                           node.motionStart[11], node.motionStart[12]));
+  vec4 q1 = normalize(vec4(node.motionEnd[9], node.motionEnd[10],
+                           node.motionEnd[11], node.motionEnd[12]));
+  if (dot(q0, q1) < 0.0) {
+    q1 = -q1;
+  }
+  vec4 q = slerp(q0, q1, time);
+  srt[9] = q.x;
+  srt[10] = q.y;
+  srt[11] = q.z;
+  srt[12] = q.w;
+  return srtTo4x3(srt);
+}
+
+// GLSL_NV_ray_tracing_motion_blur, `currentTime` is clamped to [0, 1].
+// Instances and geometries that do not move are hit the same at any time.
+void traceRayMotionNV(AccelerationStructureEXT topLevel, uint rayFlags,
+                      uint cullMask,
+                      uint rayType,      // a.k.a sbtRecordOffset,
+                      uint numRayTypes,  // a.k.a sbtRecordStride,
+                      uint missIndex, const vec3 _crt_WorldRayOriginEXT,
+                      const float _crt_RayTminEXT,
+                      const vec3 _crt_WorldRayDirectionEXT,
+                      float _crt_RayTmaxEXT, const float currentTime,
+                      int payload) {
   // float wgrt_RayTmaxEXT = RAY_TMAX;
   // uint wgrt_HitKindEXT = 0;
   vec3 invWorldRayDir = 1.0 / _crt_WorldRayDirectionEXT;
+  const float _crt_CurrentRayTimeNV = clamp(currentTime, 0.0, 1.0);
 
   float buf_closestHitAttributes[_CRT_HIT_ATTRIBUTES_MAX_WORDS];
 
@@ -72,8 +237,11 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
     }
 
     // TLAS leaf
+    uint instanceFlags = node.flags;
     uint sbtInstanceOffset = node.sbtInstanceOffset;
     uint blas_geometry_id_offset = node.blas_geometry_id_offset;
+    uint blas_primitive_ref_offset = node.blas_primitive_ref_offset;
+    AABB blas_aabb = node.blas_aabb;
     // mat4x3 _crt_ObjectToWorldEXT = node.transformToWorld;
     // mat4x3 _crt_WorldToObjectEXT = node.transformToObject;
     // TODO: https://bugs.chromium.org/p/tint/issues/detail?id=1049
@@ -95,13 +263,24 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
                     node.transformToObject[8]),
                vec3(node.transformToObject[9], node.transformToObject[10],
                     node.transformToObject[11]));
-    // // transpose
-    // mat3x4 _crt_ObjectToWorld3x4EXT;
-    // mat3x4 _crt_WorldToObject3x4EXT;
-    vec3 _crt_ObjectRayOriginEXT =
-        _crt_WorldToObjectEXT * vec4(_crt_WorldRayOriginEXT, 1.0);
-    vec3 _crt_ObjectRayDirectionEXT =
-        _crt_WorldToObjectEXT * vec4(_crt_WorldRayDirectionEXT, 0.0);
+    // identity instances use the world space ray
+    bool isIdentity = node.transformClass == TRANSFORM_CLASS_IDENTITY;
+    vec3 _crt_ObjectRayOriginEXT = _crt_WorldRayOriginEXT;
+    vec3 _crt_ObjectRayDirectionEXT = _crt_WorldRayDirectionEXT;
+    if (!isIdentity) {
+      if (node.motionType != INSTANCE_MOTION_STATIC) {
+        _crt_ObjectToWorldEXT = instanceTransformAt(node, _crt_ObjectToWorldEXT,
+                                                    _crt_CurrentRayTimeNV);
+        _crt_WorldToObjectEXT = affineInverse(_crt_ObjectToWorldEXT);
+      }
+      // // transpose
+      // mat3x4 _crt_ObjectToWorld3x4EXT;
+      // mat3x4 _crt_WorldToObject3x4EXT;
+      _crt_ObjectRayOriginEXT =
+          _crt_WorldToObjectEXT * vec4(_crt_WorldRayOriginEXT, 1.0);
+      _crt_ObjectRayDirectionEXT =
+          _crt_WorldToObjectEXT * vec4(_crt_WorldRayDirectionEXT, 0.0);
+    }
     vec3 invObjectRayDir = 1.0 / _crt_ObjectRayDirectionEXT;
     // reset previous instanceId?
 
@@ -109,10 +288,50 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
     _cur = node.entry_index;
     uint blas_index_offset = _cur;
     uint instance_exit_index = node.exit_index;
+    uint leafFirstPrimitive;
+    uint leafPrimitiveCount;
+#if BLAS_NODE_IS_WIDE
+    uint blasStack[BLAS_TRAVERSAL_STACK_SIZE];
+    uint blasStackSize = 1;
+    blasStack[0] = _cur;
+    while (blasStackSize > 0) {
+      uint entry = blasStack[--blasStackSize];
+      if ((entry & BLAS_STACK_LEAF_BIT) != 0) {
+        uint c = entry & 7U;
+        BlasBvhNodeWide leaf =
+            blasesBvhTreeNodes[(entry & ~BLAS_STACK_LEAF_BIT) >> 3];
+        leafFirstPrimitive = leaf.childIndexOrFirstPrimitive[c >> 2][c & 3U];
+        leafPrimitiveCount = leaf.childPrimitiveCount[c >> 2][c & 3U];
+      } else {
+        BlasBvhNodeWide node = blasesBvhTreeNodes[entry];
+        for (uint c = 0; c < BLAS_NODE_WIDTH; c++) {
+          uint child = node.childIndexOrFirstPrimitive[c >> 2][c & 3U];
+          if (child == WIDE_NODE_EMPTY_CHILD) {
+            break;  // lanes are filled in order
+          }
+          if (blasStackSize == BLAS_TRAVERSAL_STACK_SIZE ||
+              !intersect_aabb(_crt_ObjectRayOriginEXT, invObjectRayDir,
+                              _crt_RayTminEXT, _crt_RayTmaxEXT,
+                              wideNodeChildAabb(node, c))) {
+            continue;
+          }
+          blasStack[blasStackSize++] =
+              node.childPrimitiveCount[c >> 2][c & 3U] == 0
+                  ? child + blas_index_offset
+                  : (BLAS_STACK_LEAF_BIT | (entry << 3) | c);
+        }
+        continue;
+      }
+#else
     while (_cur < TRAVERSE_MAX_INT) {
-      BlasBvhNode node = blasesBvhTreeNodes[_cur];
-      // TODO: skip blas root if instance transform matrix is absent or identity
-      if (!intersect_aabb(_crt_ObjectRayOriginEXT, invObjectRayDir,
+      BlasBvhNode node = loadBlasNode(_cur, blas_aabb);
+      // the ray of an identity instance hit the TLAS leaf bounds, which are
+      // within the BLAS root bounds unless the root is the inverted one of an
+      // empty BLAS
+      bool skipAabb = isIdentity && _cur == blas_index_offset &&
+                      node.aabb.min.x <= node.aabb.max.x;
+      if (!skipAabb &&
+          !intersect_aabb(_crt_ObjectRayOriginEXT, invObjectRayDir,
                           _crt_RayTminEXT, _crt_RayTmaxEXT, node.aabb)) {
         if (node.exit_index ==
             TRAVERSE_MAX_INT) {  // leaving blas into tlas tree
@@ -122,85 +341,121 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
           _cur = node.exit_index + blas_index_offset;
         }
         continue;
-      } else if (node.geometryId < 0) {  // interior node
-        _cur = node.entry_index_or_primitive_id + blas_index_offset;
+      } else if (node.primitiveCount == 0) {  // interior node
+        _cur = node.entry_index_or_first_primitive + blas_index_offset;
         continue;
       }
+      leafFirstPrimitive = node.entry_index_or_first_primitive;
+      leafPrimitiveCount = node.primitiveCount;
+#endif
+
+      for (uint k = 0; k < leafPrimitiveCount; k++) {
+        BlasPrimitiveRef prim =
+            blasesPrimitiveRefs[blas_primitive_ref_offset +
+                                leafFirstPrimitive + k];
+        int geometryId = prim.geometryId;
+        uint primitiveId = prim.primitiveId;
+        // ray flags override instance flags, which override geometry flags
+        bool opaque =
+            (rayFlags & gl_RayFlagsOpaqueEXT) != 0 ||
+            ((rayFlags & gl_RayFlagsNoOpaqueEXT) == 0 &&
+             ((instanceFlags & INSTANCE_FORCE_OPAQUE_BIT) != 0 ||
+              ((instanceFlags & INSTANCE_FORCE_NO_OPAQUE_BIT) == 0 &&
+               (prim.flags & GEOMETRY_OPAQUE_BIT) != 0)));
+        if ((rayFlags & (opaque ? gl_RayFlagsCullOpaqueEXT
+                                : gl_RayFlagsCullNoOpaqueEXT)) != 0) {
+          continue;
+        }
 
-      float buf_hitAttributes[_CRT_HIT_ATTRIBUTES_MAX_WORDS];
-      float t = _crt_RayTminEXT - 1.0;
-      uint hitKind = 0;
-      // vBufferIndex always point to the geometry
-      BvhGeometryDescriptor g =
-          bvhReferencedGeomBuffer[node.geometryId + blas_geometry_id_offset];
-      uint sbtIndex = sbtHitGroupIndex(sbtInstanceOffset, node.geometryId,
-                                       numRayTypes, rayType);
-      uint terminate_or_ignore = _CRT_HIT_REPORT_IGNORE;
-      // TODO: per spec, all instances in the leaf node should contain same
-      // geom type? if (node.geometryType == GEOM_TYPE_TRIANGLE) {
-      bool hit = false;
-      if (g.owningGeometryType_todo_deprecate == GEOM_TYPE_TRIANGLE) {
-        vec3 positions[3] =
-            getTriangleVertexPositions(g, node.entry_index_or_primitive_id);
-        vec3 n;
-        // TODO: use object ray instead?
-        hit = intersect_triangle_branchless(
-            _crt_ObjectRayOriginEXT, _crt_RayTminEXT,
-            _crt_ObjectRayDirectionEXT, _crt_RayTmaxEXT, positions[0],
-            positions[1], positions[2], n, t, buf_hitAttributes[0],
-            buf_hitAttributes[1]);
+        float buf_hitAttributes[_CRT_HIT_ATTRIBUTES_MAX_WORDS];
+        float t = _crt_RayTminEXT - 1.0;
+        uint hitKind = 0;
+        // vBufferIndex always point to the geometry
+        BvhGeometryDescriptor g =
+            bvhReferencedGeomBuffer[geometryId + blas_geometry_id_offset];
+        uint sbtIndex = sbtHitGroupIndex(sbtInstanceOffset, geometryId,
+                                         numRayTypes, rayType);
+        uint terminate_or_ignore = _CRT_HIT_REPORT_IGNORE;
+        // TODO: per spec, all instances in the leaf node should contain same
+        // geom type? if (node.geometryType == GEOM_TYPE_TRIANGLE) {
+        bool hit = false;
+        if (g.owningGeometryType_todo_deprecate == GEOM_TYPE_TRIANGLE) {
+          vec3 positions[3] =
+              getTriangleVertexPositions(g, primitiveId, _crt_CurrentRayTimeNV);
+          vec3 n;
+          // TODO: use object ray instead?
+          hit = intersect_triangle_branchless(
+              _crt_ObjectRayOriginEXT, _crt_RayTminEXT,
+              _crt_ObjectRayDirectionEXT, _crt_RayTmaxEXT, positions[0],
+              positions[1], positions[2], n, t, buf_hitAttributes[0],
+              buf_hitAttributes[1]);
+          if (hit) {
+            n = normalize((n * _crt_WorldToObjectEXT).xyz);
+            buf_hitAttributes[2] = n.x;
+            buf_hitAttributes[3] = n.y;
+            buf_hitAttributes[4] = n.z;
+            bool frontFacing = dot(n, _crt_WorldRayDirectionEXT) > 0;
+            if ((instanceFlags & INSTANCE_TRIANGLE_FRONT_COUNTERCLOCKWISE_BIT) !=
+                0) {
+              frontFacing = !frontFacing;
+            }
+            hitKind = frontFacing ? gl_HitKindFrontFacingTriangleEXT
+                                  : gl_HitKindBackFacingTriangleEXT;
+            if ((instanceFlags & INSTANCE_TRIANGLE_FACING_CULL_DISABLE_BIT) ==
+                    0 &&
+                (rayFlags & (frontFacing
+                                 ? gl_RayFlagsCullFrontFacingTrianglesEXT
+                                 : gl_RayFlagsCullBackFacingTrianglesEXT)) !=
+                    0) {
+              hit = false;
+            }
+          }
+        } else {
+          // skip duplicated AABB test if containing only one primitive
+          // TODO: or always skip this aabb test?
+          // TODO: aabb test tmax
+          // if (node.numPrimitives == 1 ||
+          //     intersect_aabb(_crt_WorldRayOriginEXT, invRayDir,
+          //     getGeometryAabb(g))) {
+          hit = invokeShaderIndirect_intersect(
+              sbtIndex, _crt_WorldRayOriginEXT, _crt_RayTminEXT,
+              _crt_WorldRayDirectionEXT, _crt_RayTmaxEXT,
+              _crt_ObjectRayOriginEXT, t, _crt_ObjectRayDirectionEXT,
+              _crt_WorldToObjectEXT, _crt_ObjectToWorldEXT, geometryId,
+              primitiveId, buf_hitAttributes, _crt_CurrentRayTimeNV);
+          // }
+        }
+        // TODO: invoke more directly with identifier
+        // TODO: make sure hitT/rayTmax is correct here
+        // opaque hits are confirmed without invoking the any-hit shader
         if (hit) {
-          n = normalize((n * _crt_WorldToObjectEXT).xyz);
-          buf_hitAttributes[2] = n.x;
-          buf_hitAttributes[3] = n.y;
-          buf_hitAttributes[4] = n.z;
-          hitKind = dot(n, _crt_WorldRayDirectionEXT) > 0
-                        ? gl_HitKindFrontFacingTriangleEXT
-                        : gl_HitKindBackFacingTriangleEXT;
+          terminate_or_ignore =
+              opaque ? _CRT_HIT_REPORT_CONFIRMED
+                     : invokeShaderIndirect_anyHit(
+                           sbtIndex, _crt_WorldRayOriginEXT, _crt_RayTminEXT,
+                           _crt_WorldRayDirectionEXT, t /* _crt_RayTmaxEXT */,
+                           geometryId, primitiveId, buf_hitAttributes,
+                           _crt_CurrentRayTimeNV);
         }
-      } else {
-        // skip duplicated AABB test if containing only one primitive
-        // TODO: or always skip this aabb test?
-        // TODO: aabb test tmax
-        // if (node.numPrimitives == 1 ||
-        //     intersect_aabb(_crt_WorldRayOriginEXT, invRayDir,
-        //     getGeometryAabb(g))) {
-        hit = invokeShaderIndirect_intersect(
-            sbtIndex, _crt_WorldRayOriginEXT, _crt_RayTminEXT,
-            _crt_WorldRayDirectionEXT, _crt_RayTmaxEXT, _crt_ObjectRayOriginEXT,
-            t, _crt_ObjectRayDirectionEXT, _crt_WorldToObjectEXT,
-            _crt_ObjectToWorldEXT, node.geometryId,
-            node.entry_index_or_primitive_id, buf_hitAttributes);
-        // }
-      }
-      // TODO: invoke more directly with identifier
-      // TODO: make sure hitT/rayTmax is correct here
-      if (hit) {
-        terminate_or_ignore = invokeShaderIndirect_anyHit(
-            sbtIndex, _crt_WorldRayOriginEXT, _crt_RayTminEXT,
-            _crt_WorldRayDirectionEXT, t /* _crt_RayTmaxEXT */, node.geometryId,
-            node.entry_index_or_primitive_id, buf_hitAttributes);
-      }
 
-      if (terminate_or_ignore == _CRT_HIT_REPORT_TERMINATE) {
-        // TODO: need to invoke rchit?
-        // _cur = TRAVERSE_MAX_INT;
-        // break;
-        return;
-      }
-      // TODO(!!): this is wrong, skipping all hits
-      // opaque (or no anyhit shader)
-      // if ((g.owningGeometryFlags & GEOMETRY_OPAQUE_BIT) != 0)
-      if (terminate_or_ignore == _CRT_HIT_REPORT_CONFIRMED) {
-        // TODO(): make them global? if not supporting recursive call
-        // gl_HitKindEXT = hitKind;
-        closestSbtIndex = sbtIndex;
-        buf_closestHitAttributes = buf_hitAttributes;
-        _crt_RayTmaxEXT = t;
-        localGeometryId = node.geometryId;
-        localPrimitiveId = node.entry_index_or_primitive_id;
+        if (terminate_or_ignore == _CRT_HIT_REPORT_TERMINATE) {
+          // TODO: need to invoke rchit?
+          // _cur = TRAVERSE_MAX_INT;
+          // break;
+          return;
+        }
+        if (terminate_or_ignore == _CRT_HIT_REPORT_CONFIRMED) {
+          // TODO(): make them global? if not supporting recursive call
+          // gl_HitKindEXT = hitKind;
+          closestSbtIndex = sbtIndex;
+          buf_closestHitAttributes = buf_hitAttributes;
+          _crt_RayTmaxEXT = t;
+          localGeometryId = geometryId;
+          localPrimitiveId = primitiveId;
+        }
       }
 
+#if !BLAS_NODE_IS_WIDE
       if (node.exit_index == TRAVERSE_MAX_INT) {
         // leaving blas into tlas tree
         _cur = instance_exit_index;
@@ -208,7 +463,12 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
       } else {
         _cur = node.exit_index + blas_index_offset;
       }
+#endif
     }
+#if BLAS_NODE_IS_WIDE
+    // leaving blas into tlas tree
+    _cur = instance_exit_index;
+#endif
   }
 
   // TODO: rchit should select ray payload based on the index
@@ -216,11 +476,26 @@ void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
     invokeShaderIndirect_closestHit(closestSbtIndex, _crt_WorldRayOriginEXT,
                                     _crt_RayTminEXT, _crt_WorldRayDirectionEXT,
                                     _crt_RayTmaxEXT, localGeometryId,
-                                    localPrimitiveId, buf_closestHitAttributes);
+                                    localPrimitiveId, buf_closestHitAttributes,
+                                    _crt_CurrentRayTimeNV);
   } else {
     invokeShaderIndirect_rayMiss(sbtRayMissIndex(missIndex),
-                                 _crt_WorldRayDirectionEXT);
+                                 _crt_WorldRayDirectionEXT,
+                                 _crt_CurrentRayTimeNV);
   }
 }
 
+void traceRayEXT(AccelerationStructureEXT topLevel, uint rayFlags,
+                 uint cullMask,
+                 uint rayType,      // a.k.a sbtRecordOffset,
+                 uint numRayTypes,  // a.k.a sbtRecordStride,
+                 uint missIndex, const vec3 _crt_WorldRayOriginEXT,
+                 const float _crt_RayTminEXT,
+                 const vec3 _crt_WorldRayDirectionEXT, float _crt_RayTmaxEXT,
+                 int payload) {
+  traceRayMotionNV(topLevel, rayFlags, cullMask, rayType, numRayTypes,
+                   missIndex, _crt_WorldRayOriginEXT, _crt_RayTminEXT,
+                   _crt_WorldRayDirectionEXT, _crt_RayTmaxEXT, 0.0, payload);
+}
+
 #endif // _WEBRTX_TRACE_
\ No newline at end of file
@@ -58,23 +58,23 @@ function patch() {
   globalThis['WEBRTX_HIT_GROUP_ALL_SHADERS_UNUSED_HANDLE'] = 0xffffff;
   globalThis['GPURayTracingAccelerationContainerUsage'] = {
     NONE: 0 as _GPURayTracingAccelerationContainerUsage,
-    // ALLOW_UPDATE: 1 as _GPURayTracingAccelerationContainerUsage,
-    // ALLOW_COMPACTION: 2 as _GPURayTracingAccelerationContainerUsage,
-    // PREFER_FAST_TRACE: 4 as _GPURayTracingAccelerationContainerUsage,
-    // PREFER_FAST_BUILD: 8 as _GPURayTracingAccelerationContainerUsage,
-    // LOW_MEMORY: 0x10 as _GPURayTracingAccelerationContainerUsage,
+    ALLOW_UPDATE: 1 as _GPURayTracingAccelerationContainerUsage,
+    ALLOW_COMPACTION: 2 as _GPURayTracingAccelerationContainerUsage,
+    PREFER_FAST_TRACE: 4 as _GPURayTracingAccelerationContainerUsage,
+    PREFER_FAST_BUILD: 8 as _GPURayTracingAccelerationContainerUsage,
+    LOW_MEMORY: 0x10 as _GPURayTracingAccelerationContainerUsage,
   };
   globalThis['GPURayTracingAccelerationGeometryUsage'] = {
     NONE: 0 as _GPURayTracingAccelerationGeometryUsage,
-    // OPAQUE: 1 as _GPURayTracingAccelerationGeometryUsage,
-    // NO_DUPLICATE_ANY_HIT_INVOCATION: 2 as _GPURayTracingAccelerationGeometryUsage,
+    OPAQUE: 1 as _GPURayTracingAccelerationGeometryUsage,
+    NO_DUPLICATE_ANY_HIT_INVOCATION: 2 as _GPURayTracingAccelerationGeometryUsage,
   };
   globalThis['GPURayTracingAccelerationInstanceUsage'] = {
     NONE: 0 as _GPURayTracingAccelerationInstanceUsage,
-    // TRIANGLE_FACING_CULL_DISABLE: 1 as _GPURayTracingAccelerationInstanceUsage,
-    // TRIANGLE_FRONT_COUNTERCLOCKWISE: 2 as _GPURayTracingAccelerationInstanceUsage,
-    // FORCE_OPAQUE: 4 as _GPURayTracingAccelerationInstanceUsage,
-    // FORCE_NO_OPAQUE: 8 as _GPURayTracingAccelerationInstanceUsage,
+    TRIANGLE_FACING_CULL_DISABLE: 1 as _GPURayTracingAccelerationInstanceUsage,
+    TRIANGLE_FRONT_COUNTERCLOCKWISE: 2 as _GPURayTracingAccelerationInstanceUsage,
+    FORCE_OPAQUE: 4 as _GPURayTracingAccelerationInstanceUsage,
+    FORCE_NO_OPAQUE: 8 as _GPURayTracingAccelerationInstanceUsage,
   };
 
   const _maxGPUBufferUsage = Math.max(...(Object.values(GPUBufferUsage) as number[]));
@@ -217,4 +217,8 @@ function patch() {
     // TODO: impl
     (container as GPURayTracingAccelerationContainer_top_Impl).hostBuild(this);
   }
+
+  GPUDevice.prototype.hostUpdateRayTracingAccelerationContainer = function (container: GPURayTracingAccelerationContainer_top, instanceIndices: number[]): void {
+    (container as GPURayTracingAccelerationContainer_top_Impl).hostUpdate(this, instanceIndices);
+  }
 }
@@ -36,6 +36,13 @@ declare global {
      * @param container - The {@link GPURayTracingAccelerationContainer_top} to be built.
      */
     hostBuildRayTracingAccelerationContainer(container: GPURayTracingAccelerationContainer_top): void;
+    /**
+     * Refits a container built with ALLOW_UPDATE after the transforms of some
+     * of its instances changed, only the changed nodes are uploaded.
+     * @param container - The built {@link GPURayTracingAccelerationContainer_top}.
+     * @param instanceIndices - Indices of the changed instances.
+     */
+    hostUpdateRayTracingAccelerationContainer(container: GPURayTracingAccelerationContainer_top, instanceIndices: number[]): void;
     /**
      * Creates a new ray tracing pipeline object.
      * @param descriptor - Description of the {@link GPURayTracingPipeline} to create.
@@ -106,12 +113,32 @@ declare global {
    */
   var GPURayTracingAccelerationContainerUsage: {
     NONE: _GPURayTracingAccelerationContainerUsage,
-    // TODO: implement these
-    // ALLOW_UPDATE: _GPURayTracingAccelerationContainerUsage,
-    // ALLOW_COMPACTION: _GPURayTracingAccelerationContainerUsage,
-    // PREFER_FAST_TRACE: _GPURayTracingAccelerationContainerUsage,
-    // PREFER_FAST_BUILD: _GPURayTracingAccelerationContainerUsage,
-    // LOW_MEMORY: _GPURayTracingAccelerationContainerUsage,
+    /**
+     * The container can be refit after its geometries move, without
+     * rebuilding the tree.
+     */
+    ALLOW_UPDATE: _GPURayTracingAccelerationContainerUsage,
+    /**
+     * On a top level container, compacts all referenced bottom level
+     * containers after building by quantizing node bounds to 16 bits.
+     * Compacted containers cannot be refit.
+     */
+    ALLOW_COMPACTION: _GPURayTracingAccelerationContainerUsage,
+    /**
+     * Spend more build time on a higher quality tree. Bottom level containers
+     * use spatial splits, which may reference a primitive from more than one
+     * leaf.
+     */
+    PREFER_FAST_TRACE: _GPURayTracingAccelerationContainerUsage,
+    /**
+     * Build faster at the cost of a lower quality tree.
+     */
+    PREFER_FAST_BUILD: _GPURayTracingAccelerationContainerUsage,
+    /**
+     * Same as ALLOW_COMPACTION. Coarser bounds than 16 bits would cull too
+     * little, see compact.rs.
+     */
+    LOW_MEMORY: _GPURayTracingAccelerationContainerUsage,
   };
 
   /**
@@ -119,9 +146,8 @@ declare global {
    */
   var GPURayTracingAccelerationGeometryUsage: {
     NONE: _GPURayTracingAccelerationGeometryUsage,
-    // TODO: implement these
-    // OPAQUE: _GPURayTracingAccelerationGeometryUsage,
-    // NO_DUPLICATE_ANY_HIT_INVOCATION: _GPURayTracingAccelerationGeometryUsage,
+    OPAQUE: _GPURayTracingAccelerationGeometryUsage,
+    NO_DUPLICATE_ANY_HIT_INVOCATION: _GPURayTracingAccelerationGeometryUsage,
   };
 
   /**
@@ -129,11 +155,13 @@ declare global {
    */
   var GPURayTracingAccelerationInstanceUsage: {
     NONE: _GPURayTracingAccelerationInstanceUsage,
-    // TODO: implement these
-    // TRIANGLE_FACING_CULL_DISABLE: _GPURayTracingAccelerationInstanceUsage,
-    // TRIANGLE_FRONT_COUNTERCLOCKWISE: _GPURayTracingAccelerationInstanceUsage,
-    // FORCE_OPAQUE: _GPURayTracingAccelerationInstanceUsage,
-    // FORCE_NO_OPAQUE: _GPURayTracingAccelerationInstanceUsage,
+    TRIANGLE_FACING_CULL_DISABLE: _GPURayTracingAccelerationInstanceUsage,
+    TRIANGLE_FRONT_COUNTERCLOCKWISE: _GPURayTracingAccelerationInstanceUsage,
+    /**
+     * Can't be combined with FORCE_NO_OPAQUE.
+     */
+    FORCE_OPAQUE: _GPURayTracingAccelerationInstanceUsage,
+    FORCE_NO_OPAQUE: _GPURayTracingAccelerationInstanceUsage,
   };
 }
 
@@ -145,13 +173,14 @@ declare global {
 
   interface GPURayTracingAccelerationGeometryVertexDescriptor
     extends GPUBufferBinding {
-    format: 'float32x3', // TODO: support GPUVertexFormat;
+    // position attribute, other attributes may be interleaved within stride
+    format: 'float32x3' | 'float32x2' | 'float16x4' | 'snorm16x4' | 'unorm16x4';
     stride: GPUSize64;
   }
 
   interface GPURayTracingAccelerationGeometryIndexDescriptor
     extends GPUBufferBinding {
-    format: 'uint32', // TODO: support GPUIndexFormat;
+    format: GPUIndexFormat;
   }
 
   interface GPURayTracingAccelerationGeometryAABBDescriptor
@@ -165,7 +194,18 @@ declare global {
     type: 'triangles';
     vertex: GPURayTracingAccelerationGeometryVertexDescriptor;
     index?: GPURayTracingAccelerationGeometryIndexDescriptor;
-    // TODO: support optional transform matrix
+    /**
+     * 3x4 row-major affine transform matrix applied to the vertices, like
+     * VkAccelerationStructureGeometryTrianglesDataKHR::transformData.
+     */
+    transformMatrix?: Float32Array;
+    /**
+     * Vertex positions at ray time 1 for motion blur, in the format and
+     * stride of `vertex`, like
+     * VkAccelerationStructureGeometryMotionTrianglesDataNV. Positions are
+     * interpolated between `vertex` and `vertexEnd` at the time of each ray.
+     */
+    vertexEnd?: GPUBufferBinding;
   }
 
   interface GPURayTracingAccelerationGeometryDescriptor_aabbs {
@@ -178,6 +218,17 @@ declare global {
     | GPURayTracingAccelerationGeometryDescriptor_triangles
     | GPURayTracingAccelerationGeometryDescriptor_aabbs;
 
+  /**
+   * How an instance moves over ray times 0 to 1, like
+   * VkAccelerationStructureMotionInstanceNV. 'matrix' moves from
+   * `transformMatrix` to `transformMatrixEnd`, given like `transformMatrix`.
+   * 'srt' moves between two VkSRTDataNV transforms of 16 floats, which
+   * replace `transformMatrix`.
+   */
+  type GPURayTracingAccelerationInstanceMotion =
+    | { type: 'matrix'; transformMatrixEnd: Float32Array }
+    | { type: 'srt'; srtStart: Float32Array; srtEnd: Float32Array };
+
   interface GPURayTracingAccelerationInstanceDescriptor {
     usage: _GPURayTracingAccelerationInstanceUsage;
     /**
@@ -196,6 +247,10 @@ declare global {
      * 3x4 row-major affine transform matrix.
      */
     transformMatrix?: Float32Array;
+    /**
+     * Motion blur, traced with traceRayMotionNV. Static if absent.
+     */
+    motion?: GPURayTracingAccelerationInstanceMotion;
     // TODO: instead of specifying not-built descriptor, allow built TLAS or BLAS
     blas: GPURayTracingAccelerationContainerDescriptor_bottom,
   }
@@ -211,6 +266,20 @@ declare global {
     usage: _GPURayTracingAccelerationContainerUsage;
     level: 'top';
     instances: GPURayTracingAccelerationInstanceDescriptor[];
+    /**
+     * Collapse the referenced bottom level containers into 4-wide or 8-wide
+     * trees, defaults to 2 (binary). Takes precedence over ALLOW_COMPACTION
+     * and LOW_MEMORY.
+     */
+    blasBranchingFactor?: 2 | 4 | 8;
+    /**
+     * How instance bounds are computed: from the transformed root box of
+     * their bottom level container ('root-box'), from the boxes of its nodes
+     * near the root ('node-boxes'), or from its vertices ('vertices'), from
+     * the cheapest and loosest to the slowest and tightest. Defaults to
+     * 'node-boxes' with PREFER_FAST_TRACE, else 'root-box'.
+     */
+    instanceBounds?: 'root-box' | 'node-boxes' | 'vertices';
   }
 
   interface GPURayTracingShaderStageDescriptor {
@@ -1,4 +1,4 @@
-import type { BuiltBvh, StagingBuffer } from '../bvh/pkg';
+import type { BuiltBvh, BuiltScene, SceneBatch, StagingBuffer } from '../bvh/pkg';
 import { _assert, _debugAssert } from './util';
 
 declare module "../bvh/pkg" {
@@ -16,8 +16,14 @@ async function loadBvhWasmModule(): Promise<BvhWasmModule> {
   if (_wasm_bvh) {
     return _wasm_bvh;
   }
-  _wasm_bvh = await import('../bvh/pkg');
-  patchStagingBuffer(_wasm_bvh);
+  const wasm = await import('../bvh/pkg');
+  // only exported by builds with the `parallel` feature
+  const initThreadPool = (wasm as any).initThreadPool;
+  if (initThreadPool) {
+    await initThreadPool(navigator.hardwareConcurrency);
+  }
+  patchStagingBuffer(wasm);
+  _wasm_bvh = wasm;
   return _wasm_bvh;
 }
 
@@ -64,15 +70,51 @@ export const enum GeometryType {
   TRIANGLE = 0,
   AABB = 1,
 }
+// see vertex.rs::VertexFormat and geom.glsl
+export const enum VertexFormat {
+  FLOAT32X3 = 0,
+  FLOAT32X2 = 1,
+  FLOAT16X4 = 2,
+  SNORM16X4 = 3,
+  UNORM16X4 = 4,
+}
+function vertexFormat(format: GPURayTracingAccelerationGeometryVertexDescriptor['format']): VertexFormat {
+  switch (format) {
+    case 'float32x3': return VertexFormat.FLOAT32X3;
+    case 'float32x2': return VertexFormat.FLOAT32X2;
+    case 'float16x4': return VertexFormat.FLOAT16X4;
+    case 'snorm16x4': return VertexFormat.SNORM16X4;
+    case 'unorm16x4': return VertexFormat.UNORM16X4;
+  }
+  throw new Error(`unsupported vertex format ${format}`);
+}
+
+// see vertex.rs::IndexFormat and geom.glsl
+export const enum IndexFormat {
+  UINT32 = 0,
+  UINT16 = 1,
+}
+function indexFormat(format?: GPUIndexFormat): IndexFormat {
+  return format === 'uint16' ? IndexFormat.UINT16 : IndexFormat.UINT32;
+}
+function indexByteSize(format: IndexFormat): number {
+  return format === IndexFormat.UINT16 ? Uint16Array.BYTES_PER_ELEMENT : Uint32Array.BYTES_PER_ELEMENT;
+}
+
 type GeometryDesc = {
   vBufferIndex: number;
   iBufferIndex: number;
   vboOffset: number;
   vboStride: number;
+  vboFormat: VertexFormat;
   vioOffset: number;
   vioStride: number;
+  vioFormat: IndexFormat;
   owningGeometryType_todo_deprecate: GeometryType;
   owningGeometryFlags: number;
+  vboTransform: Float32Array; // 4x3 column major
+  vBufferIndexEnd: number; // -1 if the geometry does not move
+  vboOffsetEnd: number;
 };
 
 function retrieveStagingBuffer(buffer: GPUBuffer): StagingBuffer {
@@ -84,35 +126,53 @@ function retrieveStagingBuffer(buffer: GPUBuffer): StagingBuffer {
 }
 
 // see GeometryDescriptorField::NumFields
-const WASM_GEOMETRY_DESCRIPTOR_NUM_I32 = 6;
-function buildBlas(desc: GPURayTracingAccelerationContainerDescriptor_bottom, stagingBuffersToFree: Set<StagingBuffer>): BuiltBvh {
+const enum GeometryDescriptorField_wordsOffset {
+  HasTransform = 9,
+  Transform4x3 = 10,
+  Flags = 22,
+  EndVbufId = 23,
+  EndVbufByteOffset = 24,
+}
+const WASM_GEOMETRY_DESCRIPTOR_NUM_I32 = 25;
+// Writes the descriptor of a BLAS and adds it to the batch, returns the
+// descriptor, which must stay alive until the batch is built, and the BLAS
+// index. Collects the staging buffers of the geometry buffers.
+function addBlas(batch: SceneBatch, desc: GPURayTracingAccelerationContainerDescriptor_bottom, geometryBuffers: Map<StagingBuffer, GPUBuffer>): [StagingBuffer, number] {
   if (!_wasm_bvh) {
     throw 'bvh wasm module not loaded'
   }
   let numTotalPrimitives = 0;
-  // [ num_geoms, num_total_primitives, [geom_type, num_primitives, vbuf_id, ibuf_id]+ ]
+  // [ num_geoms, num_total_primitives, [geom_type, num_primitives, vbuf_id, vbuf_byte_offset, ibuf_id, ibuf_byte_offset, vertex_format, vertex_stride, index_format, has_transform, transform_4x3, flags, end_vbuf_id, end_vbuf_byte_offset]+ ]
   const geomBufferIds = allocateStagingBuffer(Int32Array.BYTES_PER_ELEMENT * (2 + WASM_GEOMETRY_DESCRIPTOR_NUM_I32 * desc.geometries.length));
   const geomBufferIds_i32 = geomBufferIds.i32_view();
   geomBufferIds_i32[0] = desc.geometries.length;
   for (let gi = 0; gi < desc.geometries.length; gi++) {
     const geom = desc.geometries[gi];
-    const vbuf = retrieveStagingBuffer(geom.type === 'triangles' ? geom.vertex.buffer : geom.aabb.buffer);
+    const vbufGPUBuffer = geom.type === 'triangles' ? geom.vertex.buffer : geom.aabb.buffer;
+    const vbuf = retrieveStagingBuffer(vbufGPUBuffer);
     const vbufByteOffset = ((geom.type === 'triangles' ? geom.vertex.offset : geom.aabb.offset) || 0);
-    stagingBuffersToFree.add(vbuf);
+    geometryBuffers.set(vbuf, vbufGPUBuffer);
     const vidx = vbuf.id;
     _assert(vidx !== undefined, '');
     let iidx: number | undefined = -1;
     let ibufByteOffset = 0;
+    let ibufFormat = IndexFormat.UINT32;
     let np = 1;
+    // AABB geometries are always 2 x float32x3
+    let format = VertexFormat.FLOAT32X3;
+    let stride = 3 * Float32Array.BYTES_PER_ELEMENT;
     if (geom.type === 'triangles') {
+      format = vertexFormat(geom.vertex.format);
+      stride = geom.vertex.stride;
       if (geom.index) {
         const ibuf = retrieveStagingBuffer(geom.index.buffer);
         ibufByteOffset = (geom.index.offset || 0);
-        stagingBuffersToFree.add(ibuf);
+        ibufFormat = indexFormat(geom.index.format);
+        geometryBuffers.set(ibuf, geom.index.buffer);
         iidx = ibuf.id;
         _assert(iidx !== undefined, '');
         _assert(geom.index.size! > 0, '');
-        np = Math.floor(geom.index.size! / (3 * Uint32Array.BYTES_PER_ELEMENT)); // 3 indices per primitive
+        np = Math.floor(geom.index.size! / (3 * indexByteSize(ibufFormat))); // 3 indices per primitive
       } else {
         _assert(geom.vertex.size! > 0, '');
         np = Math.floor(geom.vertex.size! / (3 * geom.vertex.stride)); // 3 vertices per primitive
@@ -126,24 +186,47 @@ function buildBlas(desc: GPURayTracingAccelerationContainerDescriptor_bottom, st
       vbufByteOffset,
       iidx!,
       ibufByteOffset,
+      format,
+      stride,
+      ibufFormat,
     ], 2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32);
+    const transform = geom.type === 'triangles' ? geom.transformMatrix : undefined;
+    geomBufferIds_i32[2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32 + GeometryDescriptorField_wordsOffset.HasTransform] = transform ? 1 : 0;
+    geomBufferIds.f32_view().set(
+      transform ? transformRowMajor3x4ToColMajor4x3(transform) : TRANSFORM_IDENTITY_COL_MAJOR_4x3,
+      2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32 + GeometryDescriptorField_wordsOffset.Transform4x3);
+    geomBufferIds_i32[2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32 + GeometryDescriptorField_wordsOffset.Flags] = geom.usage;
+    let endVidx: number | undefined = -1;
+    let endVbufByteOffset = 0;
+    if (geom.type === 'triangles' && geom.vertexEnd) {
+      const endVbuf = retrieveStagingBuffer(geom.vertexEnd.buffer);
+      endVbufByteOffset = (geom.vertexEnd.offset || 0);
+      geometryBuffers.set(endVbuf, geom.vertexEnd.buffer);
+      endVidx = endVbuf.id;
+      _assert(endVidx !== undefined, '');
+    }
+    geomBufferIds_i32[2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32 + GeometryDescriptorField_wordsOffset.EndVbufId] = endVidx!;
+    geomBufferIds_i32[2 + gi * WASM_GEOMETRY_DESCRIPTOR_NUM_I32 + GeometryDescriptorField_wordsOffset.EndVbufByteOffset] = endVbufByteOffset;
   }
   geomBufferIds_i32[1] = numTotalPrimitives;
 
-  const serialized = _wasm_bvh.build_blas(geomBufferIds.id);
-  geomBufferIds.free();
-  return serialized;
+  const options = _wasm_bvh.BuildOptions.from_usage(desc.usage);
+  try {
+    return [geomBufferIds, batch.add_blas(geomBufferIds.id, options)];
+  } finally {
+    options.free();
+  }
 }
 
 function _debugPrintTreeAabb(tree: BuiltBvh) {
-  const u8 = tree.serialized.u8_view() as Uint8Array;
+  const u8 = tree.serialized_view() as Uint8Array;
   _assert(!!u8, 'null built tree');
   const aabb = new Float32Array(u8.buffer, u8.byteOffset, 8); // note the alignment for vec3
   console.debug('tree aabb', aabb);
   // throw 'done'
 }
 
-// NOTE: keep in sync with lib.rs::TlasInstanceDescriptorJsInput
+// NOTE: keep in sync with lib.rs::TlasInstance
 const enum TlasInstanceDescriptorField_wordsOffset {
   Mask = 0,
   Flags,
@@ -152,10 +235,38 @@ const enum TlasInstanceDescriptorField_wordsOffset {
   InstanceCustomIndex,
   BlasEntryIndex,
   BlasGeometryIdOffset,
+  BlasPrimitiveRefOffset,
   BlasAabb,
   Transform4x3 = BlasAabb + 6,
+  MotionType = Transform4x3 + 12,
+  MotionKeyframes,
 
-  __numWords = Transform4x3 + 12,
+  __numWords = MotionKeyframes + 32,
+}
+
+// see motion.rs::InstanceMotionType
+const enum InstanceMotionType {
+  Static = 0,
+  Matrix = 1,
+  Srt = 2,
+}
+
+// The motion_type and motion_keyframes of an instance, see
+// lib.rs::TlasInstance
+function instanceMotion(inst: GPURayTracingAccelerationInstanceDescriptor): [InstanceMotionType, Float32Array] {
+  const keyframes = new Float32Array(32);
+  switch (inst.motion?.type) {
+    case 'matrix':
+      _assert(inst.motion.transformMatrixEnd.length === 12, 'expected a 3x4 transform matrix');
+      keyframes.set(inst.motion.transformMatrixEnd);
+      return [InstanceMotionType.Matrix, keyframes];
+    case 'srt':
+      _assert(inst.motion.srtStart.length === 16 && inst.motion.srtEnd.length === 16, 'expected VkSRTDataNV transforms');
+      keyframes.set(inst.motion.srtStart);
+      keyframes.set(inst.motion.srtEnd, 16);
+      return [InstanceMotionType.Srt, keyframes];
+  }
+  return [InstanceMotionType.Static, keyframes];
 }
 
 const TRANSFORM_IDENTITY_COL_MAJOR_4x3 = new Float32Array([
@@ -165,8 +276,91 @@ const TRANSFORM_IDENTITY_COL_MAJOR_4x3 = new Float32Array([
   0, 0, 0
 ]);
 
+function transformRowMajor3x4ToColMajor4x3(m: Float32Array): Float32Array {
+  _assert(m.length === 12, 'expected a 3x4 transform matrix');
+  const out = new Float32Array(12);
+  for (let c = 0; c < 4; c++) {
+    for (let r = 0; r < 3; r++) {
+      out[c * 3 + r] = m[r * 4 + c];
+    }
+  }
+  return out;
+}
+
+// see instance_bounds.rs::InstanceBounds
+const enum InstanceBounds {
+  RootBox = 0,
+  NodeBoxes = 1,
+  Vertices = 2,
+}
+
+const INSTANCE_BOUNDS: Record<NonNullable<GPURayTracingAccelerationContainerDescriptor_top['instanceBounds']>, InstanceBounds> = {
+  'root-box': InstanceBounds.RootBox,
+  'node-boxes': InstanceBounds.NodeBoxes,
+  'vertices': InstanceBounds.Vertices,
+};
+
+// NOTE: keep in sync with lib.rs::NodeFormat
+export const enum BlasNodeFormat {
+  Full = 0,
+  Quantized16 = 2,
+  Wide4 = 3,
+  Wide8 = 4,
+}
+
+type BuiltBlasInfo = [number/* blas_entry_index */, number/* blas_geometry_id_offset */, number/* blas_primitive_ref_offset */, Float32Array/*aabb*/];
+
+function writeTlasInstanceDescriptor(u32: Uint32Array, wordStart: number, inst: GPURayTracingAccelerationInstanceDescriptor, instanceIndex: number, builtBlas: BuiltBlasInfo) {
+  u32.set([
+    0xff, // inst.mask,
+    inst.usage, // flags
+    instanceIndex,
+    inst.instanceSBTRecordOffset,
+    (inst.instanceCustomIndex ?? -1),
+    builtBlas[0], // blas_entry_index,
+    builtBlas[1],//blas_geometry_id_offset,
+    builtBlas[2],//blas_primitive_ref_offset,
+  ], wordStart);
+
+  new Float32Array(
+    u32.buffer,
+    u32.byteOffset +
+    Uint32Array.BYTES_PER_ELEMENT *
+    (wordStart + TlasInstanceDescriptorField_wordsOffset.BlasAabb)
+  ).set(builtBlas[3]);
+
+  new Float32Array(
+    u32.buffer,
+    u32.byteOffset +
+    Uint32Array.BYTES_PER_ELEMENT *
+    (wordStart + TlasInstanceDescriptorField_wordsOffset.Transform4x3)
+  ).set(inst.transformMatrix || TRANSFORM_IDENTITY_COL_MAJOR_4x3);
+
+  const [motionType, motionKeyframes] = instanceMotion(inst);
+  u32[wordStart + TlasInstanceDescriptorField_wordsOffset.MotionType] = motionType;
+  new Float32Array(
+    u32.buffer,
+    u32.byteOffset +
+    Uint32Array.BYTES_PER_ELEMENT *
+    (wordStart + TlasInstanceDescriptorField_wordsOffset.MotionKeyframes)
+  ).set(motionKeyframes);
+}
+
+// The BLASes and TLAS built on the host, before they are uploaded. Built on
+// first use, the pipeline may be compiled before the container is built.
+type HostScene = {
+  // freed once uploaded by build()
+  scene: BuiltScene | undefined;
+  geometries: GeometryDesc[];
+  buffers: Map<GPUBuffer, number>;
+  builtBlasTreesInfo: Map<GPURayTracingAccelerationContainerDescriptor_bottom, BuiltBlasInfo>;
+};
+
 export class Tlas {
-  private _bufferBvhTree: [GPUBuffer, GPUBuffer] | undefined;
+  private _bufferBvhTree: [GPUBuffer, GPUBuffer, GPUBuffer] | undefined;
+  private _hostScene: HostScene | undefined;
+  // kept for update() when built with ALLOW_UPDATE
+  private _builtTlas: BuiltBvh | undefined;
   constructor(private readonly _descriptor: GPURayTracingAccelerationContainerDescriptor_top) {
   }
 
@@ -174,66 +368,27 @@ export class Tlas {
     return !!this._bufferBvhTree;
   }
 
-  allUniqueGeomBuffer(): [GeometryDesc[], Map<GPUBuffer, number>] {
-    const buffers = new Map<GPUBuffer, number>();
-    const descriptors: GeometryDesc[] = [];
-    const visitedBlas: Set<GPURayTracingAccelerationContainerDescriptor_bottom> = new Set();
-    for (const inst of this._descriptor.instances) {
-      if (visitedBlas.has(inst.blas)) {
-        continue;
-      }
-      visitedBlas.add(inst.blas);
-      for (const geom of inst.blas.geometries) {
-        if (geom.type === 'triangles') {
-          let vidx = buffers.get(geom.vertex.buffer);
-          if (vidx === undefined) {
-            vidx = buffers.size;
-            buffers.set(geom.vertex.buffer, vidx);
-          }
-
-          let iidx = -1;
-          if (geom.index) {
-            let idx = buffers.get(geom.index.buffer);
-            if (idx === undefined) {
-              idx = buffers.size;
-              buffers.set(geom.index.buffer, idx);
-            }
-            iidx = idx;
-          }
-
-          descriptors.push({
-            vBufferIndex: vidx,
-            iBufferIndex: iidx,
-            vboOffset: geom.vertex.offset!,
-            vioOffset: geom.index?.offset!,
-            vboStride: geom.vertex.stride,
-            vioStride: 12,
-            owningGeometryType_todo_deprecate: GeometryType.TRIANGLE,
-            owningGeometryFlags: 0,
-          });
-        } else {
-          let vidx = buffers.get(geom.aabb.buffer);
-          if (vidx === undefined) {
-            vidx = buffers.size;
-            buffers.set(geom.aabb.buffer, vidx);
-          }
-
-          descriptors.push({
-            vBufferIndex: vidx,
-            iBufferIndex: -1,
-            vboOffset: geom.aabb.offset!,
-            vioOffset: 0,
-            vboStride: geom.aabb.stride!,
-            vioStride: 0,
-            owningGeometryType_todo_deprecate: GeometryType.AABB,
-            owningGeometryFlags: 0,
-          });
-        }
-      }
+  // all BLASes share one node format, which is compiled into the pipeline
+  blasNodeFormat(): BlasNodeFormat {
+    if (this._descriptor.blasBranchingFactor === 4) {
+      return BlasNodeFormat.Wide4;
+    }
+    if (this._descriptor.blasBranchingFactor === 8) {
+      return BlasNodeFormat.Wide8;
+    }
+    const compact = GPURayTracingAccelerationContainerUsage.ALLOW_COMPACTION |
+      GPURayTracingAccelerationContainerUsage.LOW_MEMORY;
+    if (this._descriptor.usage & compact) {
+      return BlasNodeFormat.Quantized16;
     }
+    return BlasNodeFormat.Full;
+  }
 
-    console.debug('total buffers', buffers.size);
-    return [descriptors, buffers];
+  // the geometry table and its buffers, see SceneGeometry in scene_build.rs
+  allUniqueGeomBuffer(): [GeometryDesc[], Map<GPUBuffer, number>] {
+    const hostScene = this._hostBuild();
+    console.debug('total buffers', hostScene.buffers.size);
+    return [hostScene.geometries, hostScene.buffers];
   }
 
   //! Note that in current setup geometries are not recommended to be shared between BLASes
@@ -248,129 +403,209 @@ export class Tlas {
     return buffers.map(b => b[0]);
   }
 
-  getBvhTreeNodesBuffers(): [GPUBuffer, GPUBuffer] {
+  getBvhTreeNodesBuffers(): [GPUBuffer, GPUBuffer, GPUBuffer] {
     if (!this._bufferBvhTree) {
       throw 'getBvhTreeNodesBuffers but not built'
     }
     return this._bufferBvhTree;
   }
 
-  build(device: GPUDevice) {
-    if (this._bufferBvhTree) {
-      return;
+  private _hostBuild(): HostScene {
+    if (this._hostScene) {
+      return this._hostScene;
     }
     if (!_wasm_bvh) {
       throw 'bvh wasm module not loaded'
     }
-    let blasGPUBuffer: GPUBuffer | undefined;
-    // TODO: separate blas build and tlas build
-    const builtBlasTreesInfo: Map<GPURayTracingAccelerationContainerDescriptor_bottom, [number/* blas_entry_index */, number/* blas_geometry_id_offset */, Float32Array/*aabb*/]> = new Map();
-    {
-      const stagingBuffersToFree: Set<StagingBuffer> = new Set();
-      let blasTotalBufferSize = 0;
-      const trees: StagingBuffer[] = [];
-      let blas_geometry_id_offset = 0;
-      let blas_entry_index = 0; // TODO: if tlas is in the front, the offset for the first blas is unknown until tlas finishes building
+    const geometryBuffers: Map<StagingBuffer, GPUBuffer> = new Map();
+    // unique BLASes in instance order, built together with the TLAS
+    const blasIndices: Map<GPURayTracingAccelerationContainerDescriptor_bottom, number> = new Map();
+    const blasDescriptors: StagingBuffer[] = [];
+    const batch = new _wasm_bvh.SceneBatch();
+    let scene: BuiltScene;
+    try {
       for (let i = 0; i < this._descriptor.instances.length; i++) {
         const inst = this._descriptor.instances[i];
-        if (builtBlasTreesInfo.has(inst.blas)) {
-          continue;
+        let blasIndex = blasIndices.get(inst.blas);
+        if (blasIndex === undefined) {
+          const [blasDescriptor, index] = addBlas(batch, inst.blas, geometryBuffers);
+          blasDescriptors.push(blasDescriptor);
+          blasIndices.set(inst.blas, index);
+          blasIndex = index;
+        }
+        batch.add_instance(
+          blasIndex,
+          0xff, // inst.mask
+          inst.usage,
+          inst.instanceSBTRecordOffset,
+          (inst.instanceCustomIndex ?? -1),
+          inst.transformMatrix || TRANSFORM_IDENTITY_COL_MAJOR_4x3);
+        if (inst.motion) {
+          const [motionType, motionKeyframes] = instanceMotion(inst);
+          batch.set_instance_motion(i, motionType, motionKeyframes);
         }
-        const builtBlas = buildBlas(inst.blas, stagingBuffersToFree);
-        const u8 = builtBlas.serialized.u8_view() as Uint8Array;
-        _assert(!!u8, 'null built blas tree');
-        const aabb = new Float32Array(6);
-        aabb.set(new Float32Array(u8.buffer, u8.byteOffset, 3));
-        aabb.set(new Float32Array(u8.buffer, u8.byteOffset + 16, 3), 3); // note the vec3 alignment
-        builtBlasTreesInfo.set(inst.blas, [blas_entry_index, blas_geometry_id_offset, aabb]);
-
-        blas_entry_index += builtBlas.num_nodes;
-        blas_geometry_id_offset += inst.blas.geometries.length;
-        blasTotalBufferSize += u8.byteLength; // TODO: should verify element size
-        trees.push(builtBlas.serialized);
       }
-
-      blasGPUBuffer = device.createBuffer({
-        size: blasTotalBufferSize,
-        usage: GPUBufferUsage.STORAGE,
-        mappedAtCreation: true,
-      });
-      const buf = blasGPUBuffer.getMappedRange();
-      // same order as entry_index, geom_id_offset calculation
-      let byteOffset = 0;
-      for (const bt of trees) {
-        const u8 = bt.u8_view() as Uint8Array;
-        new Uint8Array(buf, byteOffset).set(u8);
-        byteOffset += u8.byteLength;
-        bt.free();
+      const options = _wasm_bvh.BuildOptions.from_usage(this._descriptor.usage);
+      if (this._descriptor.instanceBounds) {
+        options.instance_bounds = INSTANCE_BOUNDS[this._descriptor.instanceBounds] as number;
       }
-      // console.debug('@@gpu_blas', new Uint32Array(buf, 0, buf.byteLength / 4).toString());
-      blasGPUBuffer.unmap();
-      // TODO: make sure no other places can reference the same buffer (w/ different offsets)
-      for (const b of stagingBuffersToFree) {
-        b.free();
+      try {
+        // throws on malformed descriptors, see lib.rs::BvhBuildError
+        scene = batch.build(this.blasNodeFormat() as number, options);
+      } finally {
+        options.free();
       }
-    }
-    console.log('serialized # unique blas tree: ', builtBlasTreesInfo.size);
-
-    // TODO: instanceCustomIndex could be i32
-    const tlasInstanceDescriptors = allocateStagingBuffer(
-      Uint32Array.BYTES_PER_ELEMENT * (1 + TlasInstanceDescriptorField_wordsOffset.__numWords * this._descriptor.instances.length));
-    const tlasInstanceDescriptors_u32 = tlasInstanceDescriptors.u32_view();
-    tlasInstanceDescriptors_u32[0] = this._descriptor.instances.length;
-    for (let i = 0; i < this._descriptor.instances.length; i++) {
-      const inst = this._descriptor.instances[i];
-      const builtBlas = builtBlasTreesInfo.get(inst.blas);
-      if (!builtBlas) {
-        throw 'built blas tree not found'
+    } finally {
+      batch.free();
+      for (const d of blasDescriptors) {
+        d.free();
       }
-
-      const wordStart = 1 + i * TlasInstanceDescriptorField_wordsOffset.__numWords;
-      tlasInstanceDescriptors_u32.set([
-        0xff, // inst.mask,
-        0, // inst.flags
-        i,
-        inst.instanceSBTRecordOffset,
-        (inst.instanceCustomIndex ?? -1),
-        builtBlas[0], // blas_entry_index,
-        builtBlas[1],//blas_geometry_id_offset,
-      ], wordStart);
-
-      new Float32Array(
-        tlasInstanceDescriptors_u32.buffer,
-        tlasInstanceDescriptors_u32.byteOffset +
-        Uint32Array.BYTES_PER_ELEMENT *
-        (wordStart + TlasInstanceDescriptorField_wordsOffset.BlasAabb)
-      ).set(builtBlas[2]);
-
-      new Float32Array(
-        tlasInstanceDescriptors_u32.buffer,
-        tlasInstanceDescriptors_u32.byteOffset +
-        Uint32Array.BYTES_PER_ELEMENT *
-        (wordStart + TlasInstanceDescriptorField_wordsOffset.Transform4x3)
-      ).set(inst.transformMatrix || TRANSFORM_IDENTITY_COL_MAJOR_4x3);
     }
+    if (scene.num_inactive_primitives || scene.num_degenerate_primitives) {
+      console.debug('blas primitives left out, inactive:', scene.num_inactive_primitives, 'degenerate:', scene.num_degenerate_primitives);
+    }
+    console.log('serialized # unique blas tree: ', scene.num_unique_blases);
 
-    let tlasGPUBuffer: GPUBuffer | undefined;
-    {
-      const builtTlas = _wasm_bvh.build_tlas(tlasInstanceDescriptors.id);
-      tlasInstanceDescriptors.free();
-      _debugPrintTreeAabb(builtTlas);
-      const tlas_u8 = builtTlas.serialized.u8_view() as Uint8Array;
-
-      tlasGPUBuffer = device.createBuffer({
-        size: tlas_u8.byteLength,
-        usage: GPUBufferUsage.STORAGE,
-        mappedAtCreation: true,
+    const gpuBuffersById: Map<number, GPUBuffer> = new Map();
+    for (const [staging, buffer] of geometryBuffers) {
+      gpuBuffersById.set(staging.id, buffer);
+    }
+    const buffers: Map<GPUBuffer, number> = new Map();
+    for (const id of scene.geometry_buffer_ids()) {
+      buffers.set(gpuBuffersById.get(id)!, buffers.size);
+    }
+    const geometries: GeometryDesc[] = [];
+    for (let i = 0; i < scene.num_geometries(); i++) {
+      const g = scene.geometry(i);
+      geometries.push({
+        vBufferIndex: g.vbuf_index,
+        iBufferIndex: g.ibuf_index,
+        vboOffset: g.vbuf_byte_offset,
+        vboStride: g.vertex_stride,
+        vboFormat: g.vertex_format,
+        vioOffset: g.ibuf_byte_offset,
+        vioStride: g.index_stride,
+        vioFormat: g.index_format,
+        owningGeometryType_todo_deprecate: g.geometry_type,
+        owningGeometryFlags: g.flags,
+        vboTransform: g.transform_4x3(),
+        vBufferIndexEnd: g.end_vbuf_index,
+        vboOffsetEnd: g.end_vbuf_byte_offset,
       });
-      const buf = tlasGPUBuffer.getMappedRange();
-      new Uint8Array(buf, 0).set(tlas_u8);
-      // console.debug('@@gpu_tlas', new Uint32Array(tlas_u8.buffer, tlas_u8.byteOffset, tlas_u8.byteLength / 4));
-      tlasGPUBuffer.unmap();
-      builtTlas.serialized.free();
+      g.free();
     }
+    const builtBlasTreesInfo: Map<GPURayTracingAccelerationContainerDescriptor_bottom, BuiltBlasInfo> = new Map();
+    for (const [blas, index] of blasIndices) {
+      const offsets = scene.blas_offsets(index);
+      // for quantized formats this is the quantization frame
+      builtBlasTreesInfo.set(blas, [offsets[0], offsets[1], offsets[2], scene.blas_aabb(index)]);
+    }
+    // TODO: make sure no other places can reference the same buffer (w/ different offsets)
+    for (const b of geometryBuffers.keys()) {
+      b.free();
+    }
+    this._hostScene = { scene, geometries, buffers, builtBlasTreesInfo };
+    return this._hostScene;
+  }
 
-    this._bufferBvhTree = [tlasGPUBuffer, blasGPUBuffer];
+  build(device: GPUDevice) {
+    if (this._bufferBvhTree) {
+      return;
+    }
+    const hostScene = this._hostBuild();
+    const scene = hostScene.scene!;
+    hostScene.scene = undefined;
+
+    const blas_u8 = scene.blas_nodes_view() as Uint8Array;
+    _assert(!!blas_u8, 'null built blas trees');
+    const blasGPUBuffer = device.createBuffer({
+      size: blas_u8.byteLength,
+      usage: GPUBufferUsage.STORAGE,
+      mappedAtCreation: true,
+    });
+    new Uint8Array(blasGPUBuffer.getMappedRange()).set(blas_u8);
+    // console.debug('@@gpu_blas', new Uint32Array(blas_u8.buffer, blas_u8.byteOffset, blas_u8.byteLength / 4).toString());
+    blasGPUBuffer.unmap();
+
+    const refs_u8 = scene.blas_primitive_refs_view() as Uint8Array;
+    const blasPrimitiveRefsGPUBuffer = device.createBuffer({
+      // all BLASes may be empty, storage bindings must not be
+      size: Math.max(refs_u8.byteLength, 4),
+      usage: GPUBufferUsage.STORAGE,
+      mappedAtCreation: true,
+    });
+    new Uint8Array(blasPrimitiveRefsGPUBuffer.getMappedRange()).set(refs_u8);
+    blasPrimitiveRefsGPUBuffer.unmap();
+
+    // frees the BLAS buffers
+    const builtTlas = scene.into_tlas();
+    _debugPrintTreeAabb(builtTlas);
+    if (builtTlas.num_inactive) {
+      // inactive instances reference empty BLASes
+      console.debug('tlas instances left out, inactive:', builtTlas.num_inactive);
+    }
+    if (builtTlas.num_degenerate) {
+      // see transform.rs::TransformClass
+      console.warn('tlas instances with a singular or non-finite transform left out:', builtTlas.num_degenerate);
+    }
+    const tlas_u8 = builtTlas.serialized_view() as Uint8Array;
+
+    const allowUpdate = (this._descriptor.usage & GPURayTracingAccelerationContainerUsage.ALLOW_UPDATE) !== 0;
+    const tlasGPUBuffer = device.createBuffer({
+      size: tlas_u8.byteLength,
+      usage: GPUBufferUsage.STORAGE | (allowUpdate ? GPUBufferUsage.COPY_DST : 0),
+      mappedAtCreation: true,
+    });
+    new Uint8Array(tlasGPUBuffer.getMappedRange()).set(tlas_u8);
+    // console.debug('@@gpu_tlas', new Uint32Array(tlas_u8.buffer, tlas_u8.byteOffset, tlas_u8.byteLength / 4));
+    tlasGPUBuffer.unmap();
+    if (allowUpdate) {
+      this._builtTlas = builtTlas;
+    } else {
+      builtTlas.free();
+    }
+
+    this._bufferBvhTree = [tlasGPUBuffer, blasGPUBuffer, blasPrimitiveRefsGPUBuffer];
     return this._bufferBvhTree;
   }
+
+  //! Re-reads the descriptors of the given instances, e.g. after changing
+  //! their transformMatrix, and uploads only the changed TLAS nodes.
+  //! BLASes are not rebuilt.
+  update(device: GPUDevice, instanceIndices: number[]) {
+    if (!this._bufferBvhTree || !this._builtTlas || !this._hostScene) {
+      throw 'update requires a TLAS built with ALLOW_UPDATE'
+    }
+    if (!_wasm_bvh) {
+      throw 'bvh wasm module not loaded'
+    }
+    // [num_updates, [instance_index, TlasInstance]*]
+    const updateWords = 1 + TlasInstanceDescriptorField_wordsOffset.__numWords;
+    const updates = allocateStagingBuffer(
+      Uint32Array.BYTES_PER_ELEMENT * (1 + updateWords * instanceIndices.length));
+    const updates_u32 = updates.u32_view();
+    updates_u32[0] = instanceIndices.length;
+    instanceIndices.forEach((instanceIndex, i) => {
+      const inst = this._descriptor.instances[instanceIndex];
+      const builtBlas = this._hostScene!.builtBlasTreesInfo.get(inst.blas);
+      if (!builtBlas) {
+        throw 'built blas tree not found'
+      }
+      const wordStart = 1 + i * updateWords;
+      updates_u32[wordStart] = instanceIndex;
+      writeTlasInstanceDescriptor(updates_u32, wordStart + 1, inst, instanceIndex, builtBlas);
+    });
+
+    let dirtyRanges: Uint32Array;
+    try {
+      dirtyRanges = _wasm_bvh.update_tlas(this._builtTlas, updates.id);
+    } finally {
+      updates.free();
+    }
+    const tlas_u8 = this._builtTlas.serialized_view() as Uint8Array;
+    for (let i = 0; i < dirtyRanges.length; i += 2) {
+      device.queue.writeBuffer(this._bufferBvhTree[0], dirtyRanges[i],
+        tlas_u8.buffer, tlas_u8.byteOffset + dirtyRanges[i], dirtyRanges[i + 1] - dirtyRanges[i]);
+    }
+  }
 }